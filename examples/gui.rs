@@ -0,0 +1,131 @@
+//! Desktop speed test built on [`Session`], with egui.
+//!
+//! The GUI owns the main thread, so the tests run on a separate tokio
+//! runtime and the frame loop polls the session without blocking.
+//!
+//! ```console
+//! cargo run --example gui --features gui-example
+//! ```
+
+use std::time::Duration;
+
+use eframe::egui;
+use ndt7_client::client::{Client, ClientBuilder};
+use ndt7_client::error::Result;
+use ndt7_client::session::{Phase, Session, TestProgress};
+use ndt7_client::spec::TestKind;
+use ndt7_client::summary::Summary;
+use tokio::runtime::Runtime;
+
+fn main() -> eframe::Result {
+    let runtime = Runtime::new().expect("tokio runtime");
+    let client = ClientBuilder::new("ndt7-client-gui-example", env!("CARGO_PKG_VERSION")).build();
+    let app = SpeedTestApp {
+        runtime,
+        client,
+        session: None,
+        progress: TestProgress::default(),
+        result: None,
+    };
+    eframe::run_native(
+        "ndt7 speed test",
+        eframe::NativeOptions::default(),
+        Box::new(|_| Ok(Box::new(app))),
+    )
+}
+
+struct SpeedTestApp {
+    runtime: Runtime,
+    client: Client,
+    session: Option<Session>,
+    progress: TestProgress,
+    result: Option<Result<Summary>>,
+}
+
+impl eframe::App for SpeedTestApp {
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        if let Some(session) = &mut self.session {
+            if session.has_changed() {
+                self.progress = session.progress();
+            }
+            if let Some(result) = session.try_summary() {
+                self.result = Some(result);
+                self.session = None;
+            } else {
+                // Keep polling while the tests run, without busy looping.
+                ctx.request_repaint_after(Duration::from_millis(100));
+            }
+        }
+
+        egui::CentralPanel::default().show(ctx, |ui| {
+            ui.heading("ndt7 speed test");
+            ui.horizontal(|ui| {
+                let running = self.session.is_some();
+                if ui
+                    .add_enabled(!running, egui::Button::new("Start"))
+                    .clicked()
+                {
+                    self.result = None;
+                    self.progress = TestProgress::default();
+                    self.session = Some(Session::spawn(&self.client, self.runtime.handle()));
+                }
+                if ui
+                    .add_enabled(running, egui::Button::new("Cancel"))
+                    .clicked()
+                    && let Some(session) = &self.session
+                {
+                    session.cancel();
+                }
+            });
+            ui.separator();
+
+            let p = &self.progress;
+            ui.label(match (&self.session, p.phase) {
+                (None, _) => "Idle".to_string(),
+                (_, Phase::Connecting(kind)) => format!("Connecting for {}", name(kind)),
+                (_, Phase::Running(kind)) => format!("Running {}", name(kind)),
+                (_, Phase::Finished) => "Finishing".to_string(),
+            });
+            if let Some(server) = &p.server_fqdn {
+                ui.label(format!("Server: {server}"));
+            }
+            if let (Some(_), Some(timing)) = (&self.session, &p.progress) {
+                let remaining = timing.remaining_time.as_secs_f64();
+                ui.add(
+                    egui::ProgressBar::new(timing.fraction() as f32)
+                        .text(format!("{remaining:.0} s left")),
+                );
+            }
+            ui.label(format!("Download: {}", mbps(p.download_mbps)));
+            ui.label(format!("Upload: {}", mbps(p.upload_mbps)));
+            if let Some(error) = &p.error {
+                ui.colored_label(egui::Color32::RED, error);
+            }
+
+            match &self.result {
+                Some(Ok(summary)) => {
+                    ui.separator();
+                    if let Some(dl) = &summary.download {
+                        ui.label(format!("Latency: {:.1} ms", dl.latency_ms));
+                    }
+                }
+                Some(Err(e)) => {
+                    ui.separator();
+                    ui.colored_label(egui::Color32::RED, format!("Test failed: {e}"));
+                }
+                None => {}
+            }
+        });
+    }
+}
+
+fn name(kind: TestKind) -> &'static str {
+    match kind {
+        TestKind::Download => "download",
+        TestKind::Upload => "upload",
+    }
+}
+
+fn mbps(value: Option<f64>) -> String {
+    value.map_or("-".into(), |v| format!("{v:.1} Mbit/s"))
+}
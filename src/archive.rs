@@ -0,0 +1,218 @@
+//! M-Lab archive lookup.
+//!
+//! Once a test has been published to M-Lab's public BigQuery dataset (usually
+//! within a day), [`ArchiveClient::fetch`] retrieves the server-side result
+//! for the test UUID reported in [`ConnectionInfo`], so client-side numbers
+//! can be reconciled with the archived ones.
+//!
+//! Queries run through the BigQuery REST API and are billed to the caller's
+//! Google Cloud project, so an OAuth access token is required.
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::error::{Ndt7Error, Result};
+use crate::identity::NDT7_ARCHIVE_TABLE;
+use crate::spec::{ConnectionInfo, TestKind};
+
+/// Base URL of the BigQuery v2 REST API.
+pub const BIGQUERY_URL: &str = "https://bigquery.googleapis.com/bigquery/v2";
+
+/// Server-side result of a single test as published in the M-Lab archive.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ArchivedResult {
+    /// Test UUID assigned by the server.
+    #[serde(rename = "UUID")]
+    pub uuid: String,
+    /// Which subtest the row describes.
+    pub test: TestKind,
+    /// Test start time as recorded by the server.
+    pub test_time: Option<String>,
+    /// Mean throughput in megabits per second.
+    pub mean_throughput_mbps: Option<f64>,
+    /// Minimum RTT in milliseconds.
+    #[serde(rename = "MinRTT")]
+    pub min_rtt_ms: Option<f64>,
+    /// Fraction of segments lost (0.0 - 1.0).
+    pub loss_rate: Option<f64>,
+}
+
+/// Client for looking up archived results through BigQuery.
+pub struct ArchiveClient {
+    http: reqwest::Client,
+    project_id: String,
+    access_token: String,
+}
+
+impl ArchiveClient {
+    /// Create a client billing queries to `project_id`, authenticated with an
+    /// OAuth `access_token` (e.g. from `gcloud auth print-access-token`).
+    pub fn new(project_id: impl Into<String>, access_token: impl Into<String>) -> Self {
+        ArchiveClient {
+            http: reqwest::Client::new(),
+            project_id: project_id.into(),
+            access_token: access_token.into(),
+        }
+    }
+
+    /// Fetch the archived result for the test described by `conn`.
+    ///
+    /// Returns `Ok(None)` if the test has not been published yet.
+    pub async fn fetch(&self, conn: &ConnectionInfo) -> Result<Option<ArchivedResult>> {
+        let uuid = conn
+            .uuid
+            .as_deref()
+            .ok_or_else(|| Ndt7Error::ArchiveQuery("measurement has no UUID".into()))?;
+        // The archive is partitioned by date; restricting the scan keeps the
+        // query cheap. Allow a day either side for timezone and publishing skew.
+        let date = conn.start_time.as_deref().and_then(|t| t.get(..10));
+
+        let response = self
+            .http
+            .post(format!(
+                "{BIGQUERY_URL}/projects/{}/queries",
+                self.project_id
+            ))
+            .bearer_auth(&self.access_token)
+            .json(&query_request(uuid, date))
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let response: QueryResponse = response.json().await?;
+        parse_response(response)
+    }
+}
+
+fn query_request(uuid: &str, date: Option<&str>) -> serde_json::Value {
+    let mut query = format!(
+        "SELECT id, IF(raw.Download IS NOT NULL, 'download', 'upload'), \
+         CAST(a.TestTime AS STRING), a.MeanThroughputMbps, a.MinRTT, a.LossRate \
+         FROM `{NDT7_ARCHIVE_TABLE}` WHERE id = @uuid"
+    );
+    let mut parameters = vec![string_param("uuid", uuid)];
+    if let Some(date) = date {
+        query.push_str(
+            " AND date BETWEEN DATE_SUB(DATE(@date), INTERVAL 1 DAY) \
+             AND DATE_ADD(DATE(@date), INTERVAL 1 DAY)",
+        );
+        parameters.push(string_param("date", date));
+    }
+    query.push_str(" LIMIT 1");
+
+    json!({
+        "query": query,
+        "useLegacySql": false,
+        "parameterMode": "NAMED",
+        "queryParameters": parameters,
+    })
+}
+
+fn string_param(name: &str, value: &str) -> serde_json::Value {
+    json!({
+        "name": name,
+        "parameterType": {"type": "STRING"},
+        "parameterValue": {"value": value},
+    })
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct QueryResponse {
+    job_complete: bool,
+    #[serde(default)]
+    rows: Vec<Row>,
+}
+
+#[derive(Deserialize)]
+struct Row {
+    f: Vec<Cell>,
+}
+
+#[derive(Deserialize)]
+struct Cell {
+    v: Option<String>,
+}
+
+fn parse_response(response: QueryResponse) -> Result<Option<ArchivedResult>> {
+    if !response.job_complete {
+        return Err(Ndt7Error::ArchiveQuery(
+            "query did not complete in time".into(),
+        ));
+    }
+    let Some(row) = response.rows.into_iter().next() else {
+        return Ok(None);
+    };
+    let mut cells = row.f.into_iter().map(|c| c.v);
+    let mut next = || cells.next().flatten();
+
+    let uuid = next().ok_or_else(|| Ndt7Error::ArchiveQuery("row has no id".into()))?;
+    let test = match next().as_deref() {
+        Some("download") => TestKind::Download,
+        _ => TestKind::Upload,
+    };
+    Ok(Some(ArchivedResult {
+        uuid,
+        test,
+        test_time: next(),
+        mean_throughput_mbps: next().and_then(|v| v.parse().ok()),
+        min_rtt_ms: next().and_then(|v| v.parse().ok()),
+        loss_rate: next().and_then(|v| v.parse().ok()),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_request_restricts_date() {
+        let req = query_request("abc-1234", Some("2026-02-23"));
+        let query = req["query"].as_str().unwrap();
+        assert!(query.contains("id = @uuid"));
+        assert!(query.contains("DATE(@date)"));
+        assert_eq!(
+            req["queryParameters"][0]["parameterValue"]["value"],
+            "abc-1234"
+        );
+        assert_eq!(
+            req["queryParameters"][1]["parameterValue"]["value"],
+            "2026-02-23"
+        );
+
+        let req = query_request("abc-1234", None);
+        assert!(!req["query"].as_str().unwrap().contains("@date"));
+    }
+
+    #[test]
+    fn parse_published_row() {
+        let json = r#"{
+            "jobComplete": true,
+            "rows": [{"f": [
+                {"v": "abc-1234"},
+                {"v": "download"},
+                {"v": "2026-02-23 13:05:00+00"},
+                {"v": "95.5"},
+                {"v": "4.2"},
+                {"v": null}
+            ]}]
+        }"#;
+        let result = parse_response(serde_json::from_str(json).unwrap())
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(result.uuid, "abc-1234");
+        assert_eq!(result.test, TestKind::Download);
+        assert_eq!(result.mean_throughput_mbps, Some(95.5));
+        assert_eq!(result.min_rtt_ms, Some(4.2));
+        assert_eq!(result.loss_rate, None);
+    }
+
+    #[test]
+    fn parse_unpublished() {
+        let json = r#"{"jobComplete": true, "totalRows": "0"}"#;
+        let result = parse_response(serde_json::from_str(json).unwrap()).unwrap();
+        assert_eq!(result, None);
+    }
+}
@@ -1,26 +1,105 @@
 use std::io;
 use std::io::Write;
 use std::process::exit;
+use std::time::Duration;
 
 use clap::Parser;
-use ndt7_client::client::{AddressFamily, ClientBuilder};
-use ndt7_client::emitter::{Emitter, HumanReadableEmitter, JsonEmitter};
+use ndt7_client::client::{AddressFamily, Client, ClientBuilder, SkippedMeasurements, TestHandle};
+use ndt7_client::emitter::{
+    CompositeEmitter, Emitter, FilterEmitter, HumanReadableEmitter, JsonEmitter, MarkdownEmitter,
+    Progress, StatsdEmitter,
+};
 use ndt7_client::error::Ndt7Error;
-use ndt7_client::locate::Target;
+use ndt7_client::export::ArchivalResult;
+use ndt7_client::grade::Thresholds;
+use ndt7_client::host::{self, HostTuning};
+use ndt7_client::identity::ProbeIdentity;
+use ndt7_client::locate::{LocateQuery, ServerFilter, Target, TargetPolicy};
+use ndt7_client::metrics::MeasurementLog;
+use ndt7_client::replay::{Recorder, Recording};
+use ndt7_client::rotate::FileEmitter;
 use ndt7_client::spec::{Measurement, Origin, TestKind};
-use ndt7_client::summary::Summary;
-use ndt7_client::{locate, params};
+use ndt7_client::summary::{ServerLocation, SubtestOutcome, Summary};
+use ndt7_client::sweep::SweepReport;
+use ndt7_client::trace::WireTrace;
+use ndt7_client::upload::{PayloadConfig, PayloadFill};
+use ndt7_client::webhook::Webhook;
+use ndt7_client::{params, relay, sweep};
+use tokio::time::{Instant, Interval, MissedTickBehavior, timeout_at};
 
 const CLIENT_NAME: &str = "ndt7-client-rs";
 
+/// Event types accepted by --skip-event, as named by `TestEvent::name`.
+const EVENT_TYPES: [&str; 12] = [
+    "starting",
+    "error",
+    "server_closed",
+    "server_unhealthy",
+    "connected",
+    "measurement",
+    "progress",
+    "complete",
+    "summary",
+    "ping",
+    "sweep",
+    "warning",
+];
+
 #[derive(Clone, Debug, clap::ValueEnum)]
 enum Format {
     Human,
     Json,
+    JsonGo,
+    Dual,
+    Markdown,
+}
+
+/// Upload payload content, see [`PayloadFill`].
+#[derive(Clone, Debug, clap::ValueEnum)]
+enum Fill {
+    Random,
+    Zeros,
+    Pattern,
+}
+
+/// Side whose measurements are passed on, see [`Origin`].
+#[derive(Clone, Debug, clap::ValueEnum)]
+enum Side {
+    Client,
+    Server,
 }
 
 #[derive(Parser, Debug)]
+#[command(args_conflicts_with_subcommands = true)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    // Flat flags of the interface before subcommands, deprecated.
+    #[command(flatten, next_help_heading = "Deprecated options (use a subcommand)")]
+    legacy: LegacyArgs,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Run the download and upload tests (default)
+    Run(TestArgs),
+    /// Run a quick latency probe instead of the throughput tests
+    Ping(TestArgs),
+    /// List available target servers
+    #[command(visible_alias = "locate")]
+    Servers(ServersArgs),
+    /// Run latency probes and full tests on independent schedules until
+    /// interrupted
+    Schedule(ScheduleArgs),
+    /// Show the results of a run saved with --record
+    Replay(ReplayArgs),
+    /// Serve ndt7 tests to other clients, for point-to-point measurements
+    #[cfg(feature = "server")]
+    Serve(ServeArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct TestArgs {
     /// Server hostname. With --no-locate: connect directly (e.g. localhost:8080).
     /// Without --no-locate: select this server via locate API (gets access tokens).
     /// With no value: interactive server picker.
@@ -32,12 +111,52 @@ struct Cli {
     /// Skip locate API, connect directly to the server specified by --server
     #[arg(long, requires = "server")]
     no_locate: bool,
+    /// Locate the machine given by --server through the monitoring
+    /// endpoint with this monitoring token, for health checks
+    #[arg(
+        long,
+        value_name = "TOKEN",
+        requires = "server",
+        conflicts_with = "no_locate"
+    )]
+    monitoring_token: Option<String>,
+    #[command(flatten)]
+    locate: LocateArgs,
+    /// Test against SERVER, a hostname or service URL, if the Locate API
+    /// fails or is out of capacity. Repeat to try several in order
+    #[arg(long = "fallback-server", value_name = "SERVER", value_parser = parse_server)]
+    fallback_servers: Vec<String>,
+    /// Probe the N nearest located servers and test against the one with
+    /// the shortest TCP connect time
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    probe_servers: Option<u64>,
+    /// Check that each located server accepts connections before testing
+    /// against it, and skip to the next one if it does not
+    #[arg(long)]
+    health_check: bool,
+    /// Run the tests against each of the N nearest located servers in turn
+    /// and report the fastest, to tell path-specific problems apart
+    #[arg(
+        long,
+        value_name = "N",
+        group = "server_selection",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    servers: Option<u64>,
     /// Use unencrypted WebSocket (ws://) instead of TLS (wss://)
     #[arg(long)]
     no_tls: bool,
-    /// Output format to use: 'human' or 'json' for batch processing
+    /// Output format to use: 'human', 'json' for batch processing, 'json-go'
+    /// for JSON events and summary structured like ndt7-client-go's, 'dual'
+    /// for JSON on stdout and human-readable progress on stderr, or
+    /// 'markdown' for a report with a measurement table on stdout, to paste
+    /// into tickets
     #[arg(long, default_value = "human")]
     format: Format,
+    /// Show the tests in a full-screen terminal interface with live charts
+    #[cfg(feature = "tui")]
+    #[arg(long, conflicts_with = "format")]
+    tui: bool,
     /// Skip download measurement
     #[arg(long)]
     no_download: bool,
@@ -47,18 +166,344 @@ struct Cli {
     /// Emit summary and errors only
     #[arg(long)]
     quiet: bool,
+    /// Inspect host TCP settings, warn about suboptimal ones and include
+    /// them in the summary
+    #[arg(long)]
+    verbose: bool,
     /// Skip tls certificate verification
     #[arg(long)]
     no_verify: bool,
-    /// List available target servers and exit
-    #[arg(long)]
-    list_servers: bool,
-    /// Force IPv4 connections
-    #[arg(long, group = "ip_version")]
+    /// Also trust the root certificates in the PEM bundle at PATH, e.g. of
+    /// a private server; may be repeated
+    #[arg(long, value_name = "PATH", conflicts_with = "no_tls")]
+    ca_cert: Vec<std::path::PathBuf>,
+    /// Reach the Locate API and test servers through the proxy at URL
+    /// (socks5://, socks5h:// or http://, e.g. socks5h://proxy:1080)
+    #[arg(long, value_name = "URL")]
+    proxy: Option<String>,
+    /// Force IPv4 for server location and test connections
+    #[arg(short = '4', long, group = "ip_version")]
     ipv4: bool,
-    /// Force IPv6 connections
-    #[arg(long, group = "ip_version")]
+    /// Force IPv6 for server location and test connections
+    #[arg(short = '6', long, group = "ip_version")]
     ipv6: bool,
+    /// Connect from the local address IP, for multi-homed hosts
+    #[arg(long, value_name = "IP")]
+    source_ip: Option<std::net::IpAddr>,
+    /// Connect through the network interface NAME (e.g. eth1)
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "NAME")]
+    interface: Option<String>,
+    /// Mark test traffic with this DSCP class (0-63)
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=63))]
+    dscp: Option<u8>,
+    /// Upload payload content: random, or compressible zeros or repeating
+    /// bytes 0-255 ('pattern') to detect compression on the path
+    #[arg(long, value_name = "FILL", default_value = "random")]
+    payload_fill: Fill,
+    /// Seed for the upload payload, making it bit-identical across runs
+    #[arg(long)]
+    payload_seed: Option<u64>,
+    /// Load the seeded upload payload from this file, creating it if missing
+    /// or made from another seed
+    #[arg(long, value_name = "PATH", requires = "payload_seed")]
+    payload_cache: Option<std::path::PathBuf>,
+    /// Exit instead of testing when other traffic is active on the host
+    #[arg(long)]
+    refuse_concurrent: bool,
+    /// Stop each test after transferring BYTES of payload, for metered
+    /// connections
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u64).range(1..))]
+    max_bytes: Option<u64>,
+    /// Abort the run after SECS seconds, covering server location and both
+    /// tests, and report partial results
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    deadline: Option<u64>,
+    /// Stop the download test after SECS seconds (default 15; servers end
+    /// it after about 10)
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    download_duration: Option<u64>,
+    /// Stop the upload test after SECS seconds (default 10)
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    upload_duration: Option<u64>,
+    /// Give up connecting to the server of a test after SECS seconds
+    /// (default 7)
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    connect_timeout: Option<u64>,
+    /// Also report throughput on the wire, estimating WebSocket, TLS and
+    /// TCP/IP overhead
+    #[arg(long)]
+    wire_overhead: bool,
+    /// Send a WebSocket ping every MS milliseconds during the tests and
+    /// report the round-trip times, for latency under load
+    #[arg(long, value_name = "MS", value_parser = clap::value_parser!(u64).range(1..))]
+    ws_ping: Option<u64>,
+    /// Measure the idle round-trip time before the tests and report how
+    /// much latency grows under load
+    #[arg(long)]
+    idle_latency: bool,
+    /// Run msak's latency1 UDP test for SECS seconds before the tests and
+    /// report round-trip times and packet loss
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    latency1: Option<u64>,
+    /// Report changes from a previous summary read from FILE, e.g. the JSON
+    /// output of an earlier run
+    #[arg(long, value_name = "FILE")]
+    compare: Option<std::path::PathBuf>,
+    /// Grade the results against the use case requirements in FILE, JSON
+    /// like {"Gaming":{"DownloadMbps":20,"UploadMbps":5,"LatencyMs":30}},
+    /// instead of the defaults
+    #[arg(long, value_name = "FILE")]
+    grade_thresholds: Option<std::path::PathBuf>,
+    /// Fail a test on a malformed server measurement instead of skipping it
+    /// with a warning, for conformance testing
+    #[arg(long)]
+    strict_parsing: bool,
+    /// Stamp client measurements with the wall-clock UTC time, shown in the
+    /// JSON output
+    #[arg(long)]
+    utc_timestamps: bool,
+    /// Append a JSONL trace of every WebSocket message of the tests to PATH
+    #[arg(long, value_name = "PATH")]
+    wire_trace: Option<std::path::PathBuf>,
+    /// Append the events of the tests to PATH, for the replay command
+    #[arg(long, value_name = "PATH")]
+    record: Option<std::path::PathBuf>,
+    /// Write the complete result to PATH in the JSON format M-Lab archives
+    /// ndt7 tests in
+    #[arg(long, value_name = "PATH")]
+    archival_output: Option<std::path::PathBuf>,
+    /// Also push throughput, latency and retransmission gauges to the
+    /// statsd server at ADDR (e.g. localhost:8125)
+    #[arg(long, value_name = "ADDR")]
+    statsd: Option<String>,
+    /// Prefix of the statsd metric names
+    #[arg(
+        long,
+        value_name = "PREFIX",
+        default_value = "ndt7",
+        requires = "statsd"
+    )]
+    statsd_prefix: String,
+    /// Also publish every event as JSON to the MQTT broker at ADDR (e.g.
+    /// localhost:1883)
+    #[cfg(feature = "mqtt")]
+    #[arg(long, value_name = "ADDR")]
+    mqtt: Option<String>,
+    /// Prefix of the MQTT topics, followed by the event type
+    #[cfg(feature = "mqtt")]
+    #[arg(long, value_name = "PREFIX", default_value = "ndt7", requires = "mqtt")]
+    mqtt_topic: String,
+    /// Log in to the MQTT broker as USER, with the password in the
+    /// NDT7_MQTT_PASSWORD environment variable
+    #[cfg(feature = "mqtt")]
+    #[arg(long, value_name = "USER", requires = "mqtt")]
+    mqtt_username: Option<String>,
+    /// Connect to the MQTT broker over TLS
+    #[cfg(feature = "mqtt")]
+    #[arg(long, requires = "mqtt")]
+    mqtt_tls: bool,
+    /// Also append every event as JSON to PATH
+    #[arg(long, value_name = "PATH")]
+    events_file: Option<std::path::PathBuf>,
+    /// Rotate the events file before it grows past BYTES
+    #[arg(long, value_name = "BYTES", requires = "events_file")]
+    events_file_max_size: Option<u64>,
+    /// Rotate the events file daily (UTC)
+    #[arg(long, requires = "events_file")]
+    events_file_daily: bool,
+    /// Stream every event as JSON to WebSocket clients connecting to ADDR
+    /// (e.g. 0.0.0.0:9000), for live dashboards
+    #[arg(long, value_name = "ADDR")]
+    relay_listen: Option<String>,
+    /// Stream every event as JSON to the WebSocket server at URL
+    #[arg(long, value_name = "URL")]
+    relay_connect: Option<String>,
+    /// Also POST the summary as JSON to URL
+    #[arg(long, value_name = "URL")]
+    webhook: Option<String>,
+    /// Header sent with webhook requests; may be repeated
+    #[arg(long, value_name = "NAME:VALUE", requires = "webhook")]
+    webhook_header: Vec<String>,
+    /// POST every event to the webhook, not just the summary
+    #[arg(long, requires = "webhook")]
+    webhook_events: bool,
+    /// Pass on only the measurements of one side to the outputs
+    #[arg(long, value_name = "SIDE")]
+    measurement_origin: Option<Side>,
+    /// Pass on at most one measurement every MS milliseconds per test and
+    /// side to the outputs
+    #[arg(long, value_name = "MS", value_parser = clap::value_parser!(u64).range(1..))]
+    measurement_interval: Option<u64>,
+    /// Drop events of TYPE (e.g. progress) from the outputs; may be repeated
+    #[arg(long, value_name = "TYPE", value_parser = EVENT_TYPES)]
+    skip_event: Vec<String>,
+    /// Probe identifier recorded in the M-Lab archive as client metadata
+    #[arg(long)]
+    probe_id: Option<String>,
+    /// Deployment identifier recorded in the M-Lab archive as client metadata
+    #[arg(long)]
+    deployment_id: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct ServersArgs {
+    /// Output format to use: 'human', 'json' for batch processing, 'json-go'
+    /// for JSON events and summary structured like ndt7-client-go's, 'dual'
+    /// for JSON on stdout and human-readable progress on stderr, or
+    /// 'markdown' for a report with a measurement table on stdout, to paste
+    /// into tickets
+    #[arg(long, default_value = "human")]
+    format: Format,
+    #[command(flatten)]
+    locate: LocateArgs,
+    /// Reach the Locate API through the proxy at URL (socks5://, socks5h://
+    /// or http://)
+    #[arg(long, value_name = "URL")]
+    proxy: Option<String>,
+}
+
+// Constraints on the servers returned by the Locate API.
+#[derive(clap::Args, Debug, Clone)]
+struct LocateArgs {
+    /// Only locate servers in this country (ISO 3166-1 code, e.g. DE)
+    #[arg(long)]
+    country: Option<String>,
+    /// Only locate servers in this region (ISO 3166-2 code, e.g. US-NY)
+    #[arg(long)]
+    region: Option<String>,
+    /// Only locate servers at this M-Lab site (e.g. lga06)
+    #[arg(long)]
+    site: Option<String>,
+    /// Only locate servers operated by this organization (e.g. mlab)
+    #[arg(long)]
+    org: Option<String>,
+    /// API key for the Locate API, as issued by M-Lab to registered
+    /// integrations
+    #[arg(long, value_name = "KEY")]
+    locate_api_key: Option<String>,
+    /// Token for priority access through the Locate API
+    #[arg(long, value_name = "TOKEN")]
+    locate_priority_token: Option<String>,
+    /// Only test against this located machine or site (e.g. mlab1-lga06
+    /// or lga06). Repeat to allow several
+    #[arg(long = "allow-server", value_name = "NAME")]
+    allow_servers: Vec<String>,
+    /// Never test against this located machine or site. Repeat to block
+    /// several
+    #[arg(long = "block-server", value_name = "NAME")]
+    block_servers: Vec<String>,
+}
+
+impl LocateArgs {
+    fn query(&self) -> LocateQuery {
+        LocateQuery {
+            country: self.country.clone(),
+            region: self.region.clone(),
+            site: self.site.clone(),
+            org: self.org.clone(),
+            api_key: self.locate_api_key.clone(),
+            priority_token: self.locate_priority_token.clone(),
+        }
+    }
+
+    fn filter(&self) -> ServerFilter {
+        ServerFilter {
+            allow: self.allow_servers.clone(),
+            block: self.block_servers.clone(),
+        }
+    }
+}
+
+#[derive(clap::Args, Debug)]
+struct ScheduleArgs {
+    #[command(flatten)]
+    test: TestArgs,
+    /// Run a latency probe every SECS seconds
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    ping_interval: Option<u64>,
+    /// Run the full test every SECS seconds
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    test_interval: Option<u64>,
+}
+
+#[derive(clap::Args, Debug)]
+struct ReplayArgs {
+    /// Recording written by --record. Of several runs, the first is shown
+    path: std::path::PathBuf,
+    /// Output format to use: 'human', 'json' for batch processing, 'json-go'
+    /// for JSON events and summary structured like ndt7-client-go's, 'dual'
+    /// for JSON on stdout and human-readable progress on stderr, or
+    /// 'markdown' for a report with a measurement table on stdout, to paste
+    /// into tickets
+    #[arg(long, default_value = "human")]
+    format: Format,
+    /// Emit summary and errors only
+    #[arg(long)]
+    quiet: bool,
+    /// Grade the results against the use case requirements in FILE, as for
+    /// run
+    #[arg(long, value_name = "FILE")]
+    grade_thresholds: Option<std::path::PathBuf>,
+}
+
+#[cfg(feature = "server")]
+#[derive(clap::Args, Debug)]
+struct ServeArgs {
+    /// Address to listen on
+    #[arg(long, default_value = "0.0.0.0:8080")]
+    listen: String,
+    /// Duration of each test in seconds
+    #[arg(long, value_name = "SECS", default_value = "10", value_parser = clap::value_parser!(u64).range(1..))]
+    duration: u64,
+    /// Reject tests beyond this many at once
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    max_concurrent_tests: Option<u64>,
+}
+
+// Flags accepted without a subcommand, kept so existing scripts keep
+// working. Each invocation maps onto a subcommand with a warning.
+#[derive(clap::Args, Debug)]
+struct LegacyArgs {
+    #[command(flatten)]
+    test: TestArgs,
+    /// Deprecated: use `servers`
+    #[arg(long, hide = true)]
+    list_servers: bool,
+    /// Deprecated: use `ping`
+    #[arg(long, hide = true)]
+    ping: bool,
+    /// Deprecated: use `schedule --ping-interval`
+    #[arg(long, hide = true, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    ping_interval: Option<u64>,
+    /// Deprecated: use `schedule --test-interval`
+    #[arg(long, hide = true, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    test_interval: Option<u64>,
+}
+
+impl LegacyArgs {
+    /// The subcommand equivalent to these flags, and its name.
+    fn into_command(self) -> (Command, &'static str) {
+        if self.list_servers {
+            let args = ServersArgs {
+                format: self.test.format,
+                locate: self.test.locate,
+                proxy: self.test.proxy,
+            };
+            (Command::Servers(args), "servers")
+        } else if self.ping_interval.is_some() || self.test_interval.is_some() {
+            let args = ScheduleArgs {
+                test: self.test,
+                ping_interval: self.ping_interval,
+                test_interval: self.test_interval,
+            };
+            (Command::Schedule(args), "schedule")
+        } else if self.ping {
+            (Command::Ping(self.test), "ping")
+        } else {
+            (Command::Run(self.test), "run")
+        }
+    }
 }
 
 struct Targets {
@@ -66,10 +511,6 @@ struct Targets {
     upload_url: Option<String>,
 }
 
-fn user_agent() -> String {
-    format!("{CLIENT_NAME}/{}", env!("CARGO_PKG_VERSION"))
-}
-
 /// Parse a --service-url into download or upload target based on its path.
 fn resolve_from_service_url(url: &str) -> Result<Targets, Box<dyn std::error::Error>> {
     let parsed = url::Url::parse(url)?;
@@ -105,12 +546,13 @@ fn resolve_direct(server: &str, scheme: &str, no_download: bool, no_upload: bool
 
 /// Call locate API, present interactive picker, return chosen server's URLs.
 async fn resolve_interactive(
+    client: &Client,
     scheme: &str,
     no_download: bool,
     no_upload: bool,
 ) -> Result<Targets, Box<dyn std::error::Error>> {
-    let targets = locate::nearest(&user_agent()).await?;
-    print_targets(&targets);
+    let targets = client.targets().await?;
+    print_targets(&mut io::stdout(), &targets)?;
     let target = loop {
         print!("Select server [1-{}]: ", targets.len());
         io::stdout().flush()?;
@@ -131,18 +573,19 @@ async fn resolve_interactive(
 
 /// Call locate API, find a specific server by hostname, return its URLs with tokens.
 async fn resolve_from_locate(
+    client: &Client,
     server: &str,
     scheme: &str,
     no_download: bool,
     no_upload: bool,
 ) -> Result<Targets, Box<dyn std::error::Error>> {
-    let targets = locate::nearest(&user_agent()).await?;
+    let targets = client.targets().await?;
     let target = targets
         .iter()
         .find(|t| t.machine == server)
         .ok_or_else(|| {
             Ndt7Error::ServiceUnsupported(format!(
-                "server '{}' not found in locate results; use --list-servers to see available servers",
+                "server '{}' not found in locate results; run `ndt7-client servers` to see available servers",
                 server
             ))
         })?;
@@ -153,23 +596,29 @@ async fn resolve_from_locate(
     })
 }
 
-async fn resolve_targets(cli: &Cli) -> Result<Option<Targets>, Box<dyn std::error::Error>> {
-    let scheme = if cli.no_tls { "ws" } else { "wss" };
+async fn resolve_targets(
+    args: &TestArgs,
+    client: &Client,
+) -> Result<Option<Targets>, Box<dyn std::error::Error>> {
+    let scheme = if args.no_tls { "ws" } else { "wss" };
 
-    let targets = if let Some(ref url) = cli.service_url {
+    let targets = if let Some(ref url) = args.service_url {
         Some(resolve_from_service_url(url)?)
-    } else if let Some(ref server) = cli.server {
-        if cli.no_locate {
+    } else if let Some(ref server) = args.server {
+        if args.no_locate {
             Some(resolve_direct(
                 server,
                 scheme,
-                cli.no_download,
-                cli.no_upload,
+                args.no_download,
+                args.no_upload,
             ))
         } else if server.is_empty() {
-            Some(resolve_interactive(scheme, cli.no_download, cli.no_upload).await?)
+            Some(resolve_interactive(client, scheme, args.no_download, args.no_upload).await?)
         } else {
-            Some(resolve_from_locate(server, scheme, cli.no_download, cli.no_upload).await?)
+            Some(
+                resolve_from_locate(client, server, scheme, args.no_download, args.no_upload)
+                    .await?,
+            )
         }
     } else {
         None
@@ -177,26 +626,83 @@ async fn resolve_targets(cli: &Cli) -> Result<Option<Targets>, Box<dyn std::erro
     Ok(targets)
 }
 
-fn print_targets(targets: &[Target]) {
-    println!("{:<4} {:<65} Location", "#", "Server");
+fn print_targets(out: &mut dyn Write, targets: &[Target]) -> io::Result<()> {
+    writeln!(out, "{:<4} {:<65} {:<8} Location", "#", "Server", "Site")?;
     for (pos, target) in targets.iter().enumerate() {
         let location = match &target.location {
             Some(loc) if !loc.city.is_empty() => format!("{}, {}", loc.city, loc.country),
             Some(loc) => loc.country.clone(),
             None => "-".to_string(),
         };
-        println!("{:<4} {:<65} {}", pos + 1, target.machine, location);
+        let site = target
+            .machine_name()
+            .map_or("-".to_string(), |name| name.site);
+        writeln!(
+            out,
+            "{:<4} {:<65} {:<8} {}",
+            pos + 1,
+            target.machine,
+            site,
+            location
+        )?;
+    }
+    Ok(())
+}
+
+/// How a subtest ended.
+struct TestOutcome {
+    /// The test was aborted by the deadline.
+    truncated: bool,
+    /// The test ended without an error.
+    complete: bool,
+}
+
+/// Start a subtest, or return `None` if the deadline expired first.
+async fn start_test(
+    client: &Client,
+    kind: TestKind,
+    url: Option<&str>,
+    recorder: Option<&Recorder>,
+    emitter: &mut dyn Emitter,
+) -> ndt7_client::error::Result<Option<TestHandle>> {
+    emitter.on_starting(kind)?;
+    let handle = match kind {
+        TestKind::Download => client.start_download(url).await,
+        TestKind::Upload => client.start_upload(url).await,
+    };
+    match handle {
+        Ok(handle) => {
+            let handle = match recorder {
+                Some(recorder) => recorder.tee(handle, kind),
+                None => handle,
+            };
+            for server in &handle.unhealthy {
+                emitter.on_server_unhealthy(kind, &server.fqdn, &server.reason)?;
+            }
+            emitter.on_connected(kind, &handle.server_fqdn, &handle.connect_info)?;
+            Ok(Some(handle))
+        }
+        Err(e @ Ndt7Error::TestDeadline { .. }) => {
+            emitter.on_error(kind, &e.to_string())?;
+            Ok(None)
+        }
+        Err(e) => Err(e),
     }
 }
 
 async fn run_test(
     mut rx: tokio::sync::mpsc::Receiver<ndt7_client::error::Result<Measurement>>,
     kind: TestKind,
+    duration: Duration,
+    skipped: &SkippedMeasurements,
     emitter: &mut dyn Emitter,
     quiet: bool,
-) -> Result<(Option<Measurement>, Option<Measurement>), Box<dyn std::error::Error>> {
-    let mut client_measurement = None;
-    let mut server_measurement = None;
+    log: &mut MeasurementLog,
+) -> Result<TestOutcome, Box<dyn std::error::Error>> {
+    let mut outcome = TestOutcome {
+        truncated: false,
+        complete: true,
+    };
 
     while let Some(result) = rx.recv().await {
         match result {
@@ -206,18 +712,31 @@ async fn run_test(
                         TestKind::Download => emitter.on_download_event(&m)?,
                         TestKind::Upload => emitter.on_upload_event(&m)?,
                     }
+                    // Client measurements arrive at a steady interval.
+                    if m.origin == Some(Origin::Client)
+                        && let Some(app) = &m.app_info
+                    {
+                        emitter.on_progress(kind, &Progress::new(app.elapsed_time, duration))?;
+                    }
                 }
-                match m.origin {
-                    Some(Origin::Client) => client_measurement = Some(m),
-                    Some(Origin::Server) => server_measurement = Some(m),
-                    None => {}
-                }
+                log.push(kind, m);
+            }
+            Err(Ndt7Error::ServerClosed { code, reason }) => {
+                outcome.complete = false;
+                emitter.on_server_closed(kind, code, &reason)?
+            }
+            Err(e) => {
+                outcome.complete = false;
+                outcome.truncated |= matches!(e, Ndt7Error::TestDeadline { .. });
+                emitter.on_error(kind, &e.to_string())?
             }
-            Err(e) => emitter.on_error(kind, &e.to_string())?,
         }
     }
+    for error in skipped.errors() {
+        emitter.on_warning(&format!("skipped malformed server measurement: {error}"))?;
+    }
     emitter.on_complete(kind)?;
-    Ok((client_measurement, server_measurement))
+    Ok(outcome)
 }
 
 #[tokio::main]
@@ -231,113 +750,712 @@ async fn main() {
 async fn run() -> Result<(), Box<dyn std::error::Error>> {
     let cli = Cli::parse();
 
-    if cli.no_locate && cli.server.as_deref() == Some("") {
+    let command = match cli.command {
+        Some(command) => command,
+        None => {
+            let (command, name) = cli.legacy.into_command();
+            // A bare invocation is still the documented way to run a test.
+            if std::env::args_os().len() > 1 {
+                eprintln!(
+                    "warning: flags without a subcommand are deprecated and will be \
+                     removed in a future release; use `{} {name} ...` instead",
+                    env!("CARGO_BIN_NAME")
+                );
+            }
+            command
+        }
+    };
+
+    let args = match &command {
+        Command::Servers(args) => return list_servers(args).await,
+        Command::Replay(args) => return run_replay(args, &mut *new_emitter(&args.format)).await,
+        #[cfg(feature = "server")]
+        Command::Serve(args) => return serve(args).await,
+        Command::Run(args) | Command::Ping(args) => args,
+        Command::Schedule(args) => &args.test,
+    };
+    if args.no_locate && args.server.as_deref() == Some("") {
         eprintln!("error: --no-locate requires a server hostname");
         exit(1);
     }
 
-    if cli.list_servers {
-        let targets = locate::nearest(&user_agent()).await?;
-        if targets.is_empty() {
-            eprintln!("no targets");
-            exit(1)
+    let mut emitter = new_emitter(&args.format);
+    #[cfg(feature = "tui")]
+    if args.tui {
+        if matches!(command, Command::Schedule(_)) {
+            eprintln!("error: --tui cannot be used with scheduled runs");
+            exit(1);
         }
-        match cli.format {
-            Format::Human => print_targets(&targets),
-            Format::Json => {
-                let out = serde_json::to_string_pretty(&targets)?;
-                println!("{out}")
+        emitter = Box::new(ndt7_client::tui::TuiEmitter::new());
+    }
+    if let Some(addr) = &args.statsd {
+        let statsd = StatsdEmitter::new(addr.as_str())?.prefix(&args.statsd_prefix);
+        emitter = Box::new(CompositeEmitter::new(vec![emitter, Box::new(statsd)]));
+    }
+    #[cfg(feature = "mqtt")]
+    let mut published = None;
+    #[cfg(feature = "mqtt")]
+    if let Some(addr) = &args.mqtt {
+        // The probe identifier names the client, so a probe reconnecting
+        // replaces its previous session.
+        let client_id = match &args.probe_id {
+            Some(id) => format!("{CLIENT_NAME}-{id}"),
+            None => format!("{CLIENT_NAME}-{}", std::process::id()),
+        };
+        let mut mqtt = ndt7_client::mqtt::Mqtt::new(addr, client_id).topic_prefix(&args.mqtt_topic);
+        if let Some(username) = &args.mqtt_username {
+            let password = std::env::var("NDT7_MQTT_PASSWORD").unwrap_or_default();
+            mqtt = mqtt.credentials(username, password);
+        }
+        if args.mqtt_tls {
+            mqtt = mqtt.tls();
+        }
+        let (mqtt, task) = mqtt.connect().await?;
+        emitter = Box::new(CompositeEmitter::new(vec![emitter, Box::new(mqtt)]));
+        published = Some(task);
+    }
+    if let Some(path) = &args.events_file {
+        let mut file = FileEmitter::open(path)?;
+        if let Some(bytes) = args.events_file_max_size {
+            file = file.max_size(bytes);
+        }
+        if args.events_file_daily {
+            file = file.daily();
+        }
+        emitter = Box::new(CompositeEmitter::new(vec![emitter, Box::new(file)]));
+    }
+    let mut relays = Vec::new();
+    if let Some(addr) = &args.relay_listen {
+        let (relay, task) = relay::serve(addr.as_str()).await?;
+        if let Some(addr) = task.local_addr() {
+            eprintln!("relaying events on ws://{addr}");
+        }
+        emitter = Box::new(CompositeEmitter::new(vec![emitter, Box::new(relay)]));
+        relays.push(task);
+    }
+    if let Some(url) = &args.relay_connect {
+        let (relay, task) = relay::connect(url).await?;
+        emitter = Box::new(CompositeEmitter::new(vec![emitter, Box::new(relay)]));
+        relays.push(task);
+    }
+    let mut delivery = None;
+    if let Some(url) = &args.webhook {
+        let mut webhook = Webhook::new(url);
+        for header in &args.webhook_header {
+            let Some((name, value)) = header.split_once(':') else {
+                eprintln!("error: --webhook-header must be NAME:VALUE, got {header:?}");
+                exit(1);
+            };
+            webhook = webhook.header(name.trim(), value.trim());
+        }
+        if args.webhook_events {
+            webhook = webhook.all_events();
+        }
+        let (webhook, task) = webhook.spawn()?;
+        emitter = Box::new(CompositeEmitter::new(vec![emitter, Box::new(webhook)]));
+        delivery = Some(task);
+    }
+    if args.measurement_origin.is_some()
+        || args.measurement_interval.is_some()
+        || !args.skip_event.is_empty()
+    {
+        let mut filter = FilterEmitter::new(emitter);
+        if let Some(side) = &args.measurement_origin {
+            filter = filter.origin(match side {
+                Side::Client => Origin::Client,
+                Side::Server => Origin::Server,
+            });
+        }
+        if let Some(ms) = args.measurement_interval {
+            filter = filter.measurement_interval(Duration::from_millis(ms));
+        }
+        for name in &args.skip_event {
+            filter = filter.skip(name);
+        }
+        emitter = Box::new(filter);
+    }
+    // Shared by scheduled runs, so located servers are reused while their
+    // access tokens are valid.
+    let mut client = build_client(args)?;
+
+    let result = match &command {
+        Command::Schedule(args) => {
+            if args.ping_interval.is_none() && args.test_interval.is_none() {
+                eprintln!("error: schedule requires --ping-interval or --test-interval");
+                exit(1);
+            }
+            if args.test.server.as_deref() == Some("") {
+                eprintln!("error: scheduled runs require a server hostname");
+                exit(1);
             }
+            run_scheduled(args, &mut client, &mut *emitter).await
         }
-        return Ok(());
+        Command::Ping(args) => run_ping(args, &mut client, &mut *emitter).await,
+        _ => match args.servers {
+            Some(count) => run_sweep(args, &client, count as usize, &mut *emitter).await,
+            None => run_full(args, &mut client, &mut *emitter).await.map(drop),
+        },
+    };
+    // Closing the emitter ends the queues of the relays, the webhook and
+    // the MQTT connection.
+    drop(emitter);
+    for relay in relays {
+        relay.finish().await;
     }
+    let delivered = match delivery {
+        Some(delivery) => delivery.finish().await,
+        None => Ok(()),
+    };
+    #[cfg(feature = "mqtt")]
+    let published = match published {
+        Some(published) => published.finish().await,
+        None => Ok(()),
+    };
+    result?;
+    delivered?;
+    #[cfg(feature = "mqtt")]
+    published?;
+    Ok(())
+}
 
-    let mut emitter: Box<dyn Emitter> = match cli.format {
+fn new_emitter(format: &Format) -> Box<dyn Emitter> {
+    match format {
         Format::Human => Box::new(HumanReadableEmitter::new(std::io::stdout())),
         Format::Json => Box::new(JsonEmitter::new(std::io::stdout())),
-    };
+        Format::JsonGo => Box::new(JsonEmitter::new(std::io::stdout()).go_events()),
+        Format::Dual => Box::new(CompositeEmitter::new(vec![
+            Box::new(HumanReadableEmitter::new(std::io::stderr())),
+            Box::new(JsonEmitter::new(std::io::stdout())),
+        ])),
+        Format::Markdown => Box::new(CompositeEmitter::new(vec![
+            Box::new(HumanReadableEmitter::new(std::io::stderr())),
+            Box::new(MarkdownEmitter::new(std::io::stdout()).measurements()),
+        ])),
+    }
+}
 
-    let mut builder = ClientBuilder::new(CLIENT_NAME, env!("CARGO_PKG_VERSION"));
-    if cli.no_verify {
+async fn list_servers(args: &ServersArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let mut builder = ClientBuilder::new(CLIENT_NAME, env!("CARGO_PKG_VERSION"))
+        .locate_query(args.locate.query())
+        .server_filter(args.locate.filter());
+    if let Some(url) = &args.proxy {
+        builder = builder.proxy(url);
+    }
+    let client = builder.try_build()?;
+    let targets = client.list_targets().await?;
+    if targets.is_empty() {
+        eprintln!("no targets");
+        exit(1)
+    }
+    match args.format {
+        Format::Human | Format::Markdown => print_targets(&mut io::stdout(), &targets)?,
+        Format::Json | Format::JsonGo => {
+            let out = serde_json::to_string_pretty(&targets)?;
+            println!("{out}")
+        }
+        Format::Dual => {
+            print_targets(&mut io::stderr(), &targets)?;
+            let out = serde_json::to_string_pretty(&targets)?;
+            println!("{out}")
+        }
+    }
+    Ok(())
+}
+
+fn build_client(args: &TestArgs) -> Result<Client, Box<dyn std::error::Error>> {
+    let mut builder = ClientBuilder::new(CLIENT_NAME, env!("CARGO_PKG_VERSION"))
+        .locate_query(args.locate.query())
+        .server_filter(args.locate.filter());
+    // The machine named with --server is then the only located target.
+    if let (Some(server), Some(token)) = (&args.server, &args.monitoring_token) {
+        builder = builder.monitoring(server, token);
+    }
+    if let Some(count) = args.probe_servers {
+        builder = builder.target_policy(TargetPolicy::Fastest(count as usize));
+    }
+    if args.health_check {
+        builder = builder.health_check();
+    }
+    if args.no_verify {
         builder = builder.no_verify_tls();
     }
-    if cli.no_tls {
+    if let Some(url) = &args.proxy {
+        builder = builder.proxy(url);
+    }
+    for path in &args.ca_cert {
+        let pem = std::fs::read(path).map_err(|e| format!("{}: {e}", path.display()))?;
+        builder = builder.root_certificates_pem(&pem);
+    }
+    if args.no_tls {
         builder = builder.no_tls();
     }
-    let af = match (cli.ipv4, cli.ipv6) {
+    if let Some(ip) = args.source_ip {
+        builder = builder.local_address(ip);
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(name) = &args.interface {
+        builder = builder.interface(name);
+    }
+    if let Some(dscp) = args.dscp {
+        builder = builder.dscp(dscp);
+    }
+    if let Some(bytes) = args.max_bytes {
+        builder = builder.max_bytes(bytes);
+    }
+    if let Some(secs) = args.deadline {
+        builder = builder.deadline(Duration::from_secs(secs));
+    }
+    if let Some(secs) = args.download_duration {
+        builder = builder.download_duration(Duration::from_secs(secs));
+    }
+    if let Some(secs) = args.upload_duration {
+        builder = builder.upload_duration(Duration::from_secs(secs));
+    }
+    if let Some(secs) = args.connect_timeout {
+        builder = builder.connect_timeout(Duration::from_secs(secs));
+    }
+    if args.wire_overhead {
+        builder = builder.wire_overhead();
+    }
+    if args.strict_parsing {
+        builder = builder.strict_parsing();
+    }
+    if args.utc_timestamps {
+        builder = builder.utc_timestamps();
+    }
+    if let Some(ms) = args.ws_ping {
+        builder = builder.ws_ping_interval(Duration::from_millis(ms));
+    }
+    if let Some(path) = &args.wire_trace {
+        // Appended to, so scheduled runs add to the same trace.
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        builder = builder.wire_trace(WireTrace::new(std::io::BufWriter::new(file)));
+    }
+    let af = match (args.ipv4, args.ipv6) {
         (true, _) => AddressFamily::Ipv4Only,
         (_, true) => AddressFamily::Ipv6Only,
         _ => AddressFamily::Any,
     };
-    let mut client = builder.address_family(af).build();
-    let targets = resolve_targets(&cli).await?;
+    let identity = ProbeIdentity {
+        probe_id: args.probe_id.clone(),
+        deployment_id: args.deployment_id.clone(),
+    };
+    let fill = match args.payload_fill {
+        Fill::Random => PayloadFill::Random,
+        Fill::Zeros => PayloadFill::Zeros,
+        Fill::Pattern => PayloadFill::Pattern((0..=255).collect()),
+    };
+    let payload = PayloadConfig {
+        fill,
+        seed: args.payload_seed,
+        cache_path: args.payload_cache.clone(),
+    };
+    Ok(builder
+        .address_family(af)
+        .probe_identity(identity)
+        .payload(payload)
+        .fallback_servers(&args.fallback_servers)
+        .try_build()?)
+}
 
-    let mut dl_client_measurement: Option<Measurement> = None;
-    let mut dl_server_measurement: Option<Measurement> = None;
-    let mut ul_measurement: Option<Measurement> = None;
-    let mut server_fqdn = String::new();
+/// Check that a --fallback-server is a hostname or URL.
+fn parse_server(server: &str) -> Result<String, String> {
+    Target::from_server(server)
+        .map(|_| server.to_string())
+        .map_err(|e| e.to_string())
+}
+
+/// Run a single latency probe and emit its result.
+async fn run_ping(
+    args: &TestArgs,
+    client: &mut Client,
+    emitter: &mut dyn Emitter,
+) -> Result<(), Box<dyn std::error::Error>> {
+    client.reset_deadline();
+    let targets = resolve_targets(args, client).await?;
+    let url = match &targets {
+        Some(targets) => Some(targets.download_url.as_deref().ok_or_else(|| {
+            Ndt7Error::ServiceUnsupported("latency probe requires a download URL".into())
+        })?),
+        None => None,
+    };
+    let result = client.ping(url).await?;
+    emitter.on_ping(&result)?;
+    Ok(())
+}
+
+/// Run the download and upload tests, emit the summary and return it.
+async fn run_full(
+    args: &TestArgs,
+    client: &mut Client,
+    emitter: &mut dyn Emitter,
+) -> Result<Summary, Box<dyn std::error::Error>> {
+    // Read the baseline first, so a bad path fails before any test runs.
+    let previous = match &args.compare {
+        Some(path) => Some(Summary::from_json(&std::fs::read_to_string(path)?)?),
+        None => None,
+    };
+    let thresholds = thresholds(args.grade_thresholds.as_deref())?;
+    let host_tuning = args.verbose.then(HostTuning::inspect);
+    if let Some(tuning) = &host_tuning {
+        for warning in &tuning.warnings {
+            emitter.on_warning(warning)?;
+        }
+    }
+
+    // Sample other traffic on the host while the servers are located, so
+    // the sample does not delay the run.
+    let sampling = tokio::spawn(host::background_traffic_mbps(host::CONTENTION_SAMPLE));
 
-    match targets {
+    let started = Instant::now();
+    let deadline = args
+        .deadline
+        .map(|secs| started + Duration::from_secs(secs));
+    client.reset_deadline();
+    let mut truncated = false;
+    let targets = match deadline {
+        Some(deadline) => match timeout_at(deadline, resolve_targets(args, client)).await {
+            Ok(targets) => targets?,
+            Err(_) => {
+                let e = Ndt7Error::TestDeadline {
+                    elapsed: started.elapsed(),
+                };
+                emitter.on_error(TestKind::Download, &e.to_string())?;
+                truncated = true;
+                Some(Targets {
+                    download_url: None,
+                    upload_url: None,
+                })
+            }
+        },
+        None => resolve_targets(args, client).await?,
+    };
+
+    // Flag results measured alongside other transfers as contended, or
+    // refuse to compete with them if asked to.
+    let background_mbps = sampling.await.ok().flatten();
+    let contended = background_mbps.is_some_and(|mbps| mbps > host::CONTENTION_THRESHOLD_MBPS);
+    if contended {
+        let mbps = background_mbps.unwrap_or_default();
+        if args.refuse_concurrent {
+            return Err(format!("{mbps:.1} Mbit/s of other traffic on this host").into());
+        }
+        emitter.on_warning(&format!(
+            "{mbps:.1} Mbit/s of other traffic on this host, results may be low \
+             and are flagged as contended"
+        ))?;
+    }
+
+    // For each subtest to run: the URL to use, or `None` to auto-locate.
+    let (download, upload) = match targets {
         Some(targets) => {
-            if targets.download_url.is_none() && targets.upload_url.is_none() {
+            if !truncated && targets.download_url.is_none() && targets.upload_url.is_none() {
                 eprintln!("error: nothing to do");
                 std::process::exit(1);
             }
-            if let Some(ref url) = targets.download_url {
-                emitter.on_starting(TestKind::Download)?;
-                let handle = client.start_download(Some(url)).await?;
-                server_fqdn = handle.server_fqdn;
-                emitter.on_connected(TestKind::Download, &server_fqdn)?;
-                let (dl_c, dl_s) =
-                    run_test(handle.rx, TestKind::Download, &mut *emitter, cli.quiet).await?;
-                dl_client_measurement = dl_c;
-                dl_server_measurement = dl_s;
-            }
-            if let Some(ref url) = targets.upload_url {
-                emitter.on_starting(TestKind::Upload)?;
-                let handle = client.start_upload(Some(url)).await?;
-                server_fqdn = handle.server_fqdn;
-                emitter.on_connected(TestKind::Upload, &server_fqdn)?;
-                let (_, ul) =
-                    run_test(handle.rx, TestKind::Upload, &mut *emitter, cli.quiet).await?;
-                ul_measurement = ul;
-            }
+            (targets.download_url.map(Some), targets.upload_url.map(Some))
         }
         None => {
-            if cli.no_download && cli.no_upload {
+            if args.no_download && args.no_upload {
                 eprintln!("error: nothing to do");
                 std::process::exit(1);
             }
-            if !cli.no_download {
-                emitter.on_starting(TestKind::Download)?;
-                let handle = client.start_download(None).await?;
+            (
+                (!args.no_download).then_some(None),
+                (!args.no_upload).then_some(None),
+            )
+        }
+    };
+
+    // Idle baseline for the latency under load, probed on the download
+    // endpoint before any data flows.
+    let mut idle_latency_ms = None;
+    if let Some(url) = download.as_ref().filter(|_| args.idle_latency) {
+        match client.ping(url.as_deref()).await {
+            Ok(probe) => idle_latency_ms = probe.best_rtt_ms().or(probe.min_rtt_ms),
+            Err(e) => emitter.on_warning(&format!("idle latency probe failed: {e}"))?,
+        }
+    }
+    let mut latency = None;
+    if let Some(secs) = args.latency1 {
+        match client.latency1(None, Duration::from_secs(secs)).await {
+            Ok(result) => latency = Some(result),
+            Err(e) => emitter.on_warning(&format!("latency1 test failed: {e}"))?,
+        }
+    }
+
+    let recorder = match &args.record {
+        // Appended to, like the wire trace.
+        Some(path) => Some(Recorder::new(std::io::BufWriter::new(
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?,
+        ))),
+        None => None,
+    };
+
+    let mut dl_result = None;
+    let mut ul_result = None;
+    let mut log = MeasurementLog::new();
+    let mut server_fqdn = String::new();
+    let mut server_location = None;
+    // A subtest that failed to start ends the run, but results collected so
+    // far are still reported before the error.
+    let mut failure = None;
+
+    if let Some(url) = download {
+        let recorder = recorder.as_ref();
+        match start_test(
+            client,
+            TestKind::Download,
+            url.as_deref(),
+            recorder,
+            emitter,
+        )
+        .await
+        {
+            Ok(Some(handle)) => {
                 server_fqdn = handle.server_fqdn;
-                emitter.on_connected(TestKind::Download, &server_fqdn)?;
-                let (dl_c, dl_s) =
-                    run_test(handle.rx, TestKind::Download, &mut *emitter, cli.quiet).await?;
-                dl_client_measurement = dl_c;
-                dl_server_measurement = dl_s;
+                server_location = handle.server_location;
+                let outcome = run_test(
+                    handle.rx,
+                    TestKind::Download,
+                    handle.duration,
+                    &handle.skipped,
+                    emitter,
+                    args.quiet,
+                    &mut log,
+                )
+                .await?;
+                truncated |= outcome.truncated;
+                dl_result = Some(SubtestOutcome {
+                    connect_info: handle.connect_info,
+                    complete: outcome.complete,
+                });
             }
-            if !cli.no_upload {
-                emitter.on_starting(TestKind::Upload)?;
-                let handle = client.start_upload(None).await?;
+            Ok(None) => truncated = true,
+            Err(e) => failure = Some(e),
+        }
+    }
+    if let Some(url) = upload.filter(|_| !truncated && failure.is_none()) {
+        let recorder = recorder.as_ref();
+        match start_test(client, TestKind::Upload, url.as_deref(), recorder, emitter).await {
+            Ok(Some(handle)) => {
                 server_fqdn = handle.server_fqdn;
-                emitter.on_connected(TestKind::Upload, &server_fqdn)?;
-                let (_, ul) =
-                    run_test(handle.rx, TestKind::Upload, &mut *emitter, cli.quiet).await?;
-                ul_measurement = ul;
+                server_location = handle.server_location;
+                let outcome = run_test(
+                    handle.rx,
+                    TestKind::Upload,
+                    handle.duration,
+                    &handle.skipped,
+                    emitter,
+                    args.quiet,
+                    &mut log,
+                )
+                .await?;
+                truncated |= outcome.truncated;
+                ul_result = Some(SubtestOutcome {
+                    connect_info: handle.connect_info,
+                    complete: outcome.complete,
+                });
             }
+            Ok(None) => truncated = true,
+            Err(e) => failure = Some(e),
         }
     }
 
-    let summary = Summary::from_measurements(
-        server_fqdn,
-        dl_client_measurement.as_ref(),
-        dl_server_measurement.as_ref(),
-        ul_measurement.as_ref(),
-    );
+    if let Some(path) = &args.archival_output {
+        ArchivalResult::from_log(&log)
+            .client_metadata(client.client_metadata())
+            .write(std::io::BufWriter::new(std::fs::File::create(path)?))?;
+    }
+
+    let mut summary = Summary::from_run(server_fqdn, &log, dl_result, ul_result);
+    summary.server_location = server_location.map(ServerLocation::from);
+    if let Some(idle) = idle_latency_ms {
+        summary.set_idle_latency(idle);
+    }
+    if let Some(latency) = &latency {
+        summary.set_latency(latency);
+    }
+    summary.dscp = client.dscp();
+    summary.host_tuning = host_tuning;
+    summary.background_mbps = background_mbps;
+    summary.contended = contended;
+    summary.truncated = truncated;
+    summary.set_grade(&thresholds);
+    if let Some(previous) = &previous {
+        summary.set_previous(previous);
+    }
+
+    if failure.is_none() || summary.download.is_some() || summary.upload.is_some() {
+        emitter.on_summary(&summary)?;
+    }
+    match failure {
+        Some(e) => Err(e.into()),
+        None => Ok(summary),
+    }
+}
+
+/// Run the full tests against each of the `count` nearest located servers
+/// in turn, then report the fastest.
+async fn run_sweep(
+    args: &TestArgs,
+    client: &Client,
+    count: usize,
+    emitter: &mut dyn Emitter,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Failed subtests are reported by run_full with their test; errors
+    // that kept a server's run from starting go into the sweep report.
+    let results = sweep::sweep_with(client, count, async |client: &mut Client| {
+        run_full(args, client, emitter).await
+    })
+    .await?;
+    let report = SweepReport::new(&results);
+    emitter.on_sweep(&report)?;
+    if report.fastest.is_none() {
+        return Err("no server completed a download test".into());
+    }
+    Ok(())
+}
+
+/// Grading thresholds read from the JSON file at `path`, or the defaults.
+fn thresholds(path: Option<&std::path::Path>) -> Result<Thresholds, Box<dyn std::error::Error>> {
+    match path {
+        Some(path) => Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?),
+        None => Ok(Thresholds::default()),
+    }
+}
+
+/// Feed a recorded run through the emitter and emit its summary.
+async fn run_replay(
+    args: &ReplayArgs,
+    emitter: &mut dyn Emitter,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let recording = Recording::open(&args.path)?;
+    let thresholds = thresholds(args.grade_thresholds.as_deref())?;
+    let mut results = [None, None];
+    let mut server_location = None;
+    let mut log = MeasurementLog::new();
+    let mut server_fqdn = String::new();
+    let mut truncated = false;
+    for (kind, result) in [TestKind::Download, TestKind::Upload]
+        .into_iter()
+        .zip(&mut results)
+    {
+        let Some(handle) = recording.handle(kind) else {
+            continue;
+        };
+        emitter.on_starting(kind)?;
+        emitter.on_connected(kind, &handle.server_fqdn, &handle.connect_info)?;
+        let outcome = run_test(
+            handle.rx,
+            kind,
+            handle.duration,
+            &handle.skipped,
+            emitter,
+            args.quiet,
+            &mut log,
+        )
+        .await?;
+        server_fqdn = handle.server_fqdn;
+        server_location = handle.server_location;
+        truncated |= outcome.truncated;
+        *result = Some(SubtestOutcome {
+            connect_info: handle.connect_info,
+            complete: outcome.complete,
+        });
+    }
+    let [download, upload] = results;
+    if download.is_none() && upload.is_none() {
+        return Err(format!("no tests recorded in {}", args.path.display()).into());
+    }
 
+    let mut summary = Summary::from_run(server_fqdn, &log, download, upload);
+    summary.server_location = server_location.map(ServerLocation::from);
+    summary.truncated = truncated;
+    summary.set_grade(&thresholds);
     emitter.on_summary(&summary)?;
+    Ok(())
+}
 
+/// Serve tests until interrupted.
+#[cfg(feature = "server")]
+async fn serve(args: &ServeArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let mut builder =
+        ndt7_client::server::ServerBuilder::new().duration(Duration::from_secs(args.duration));
+    if let Some(limit) = args.max_concurrent_tests {
+        builder = builder.max_concurrent_tests(limit as usize);
+    }
+    let server = builder.bind(&args.listen).await?;
+    eprintln!("serving ndt7 tests on ws://{}", server.local_addr()?);
+    server.serve().await;
     Ok(())
 }
+
+/// Run latency probes and full tests on independent schedules until
+/// interrupted. All records go to the same emitter, distinguished by event
+/// type. A failed run is reported and does not stop the schedule; after a
+/// rate limit, the schedule pauses for as long as the Locate API asked.
+///
+/// Runs never overlap: a probe due while a full test is in progress waits
+/// for it to finish, so the two cannot skew each other's results.
+async fn run_scheduled(
+    args: &ScheduleArgs,
+    client: &mut Client,
+    emitter: &mut dyn Emitter,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut ping_timer = args.ping_interval.map(schedule);
+    let mut test_timer = args.test_interval.map(schedule);
+
+    loop {
+        tokio::select! {
+            // Full tests take precedence when both are due.
+            biased;
+            _ = tick(&mut test_timer) => {
+                if let Err(e) = run_full(&args.test, client, emitter).await {
+                    emitter.on_error(TestKind::Download, &e.to_string())?;
+                    back_off(e.as_ref()).await;
+                }
+            }
+            _ = tick(&mut ping_timer) => {
+                // A failed probe is not a failed download; keep it out of
+                // the subtests' error counts.
+                if let Err(e) = run_ping(&args.test, client, emitter).await {
+                    emitter.on_warning(&format!("latency probe failed: {e}"))?;
+                    back_off(e.as_ref()).await;
+                }
+            }
+        }
+    }
+}
+
+/// Wait out a rate limit reported by the Locate API, so the next run does
+/// not run into it again.
+async fn back_off(e: &(dyn std::error::Error + 'static)) {
+    if let Some(Ndt7Error::RateLimited {
+        retry_after: Some(wait),
+    }) = e.downcast_ref()
+    {
+        tokio::time::sleep(*wait).await;
+    }
+}
+
+fn schedule(secs: u64) -> Interval {
+    let mut timer = tokio::time::interval(Duration::from_secs(secs));
+    timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    timer
+}
+
+async fn tick(timer: &mut Option<Interval>) {
+    match timer {
+        Some(timer) => {
+            timer.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
@@ -1,21 +1,39 @@
 //! High-level ndt7 test client.
 
-use std::net::SocketAddr;
-use std::sync::Arc;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
-use tokio::net::TcpStream;
-use tokio::sync::mpsc;
-use tokio::time::timeout;
+use bytes::Bytes;
+use futures_util::future::{join_all, try_join_all};
+use futures_util::{Sink, Stream};
+use rustls::RootCertStore;
+use rustls::pki_types::CertificateDer;
+use rustls::pki_types::pem::PemObject;
+use serde::{Deserialize, Serialize};
+use tokio::net::{TcpSocket, TcpStream, UdpSocket};
+use tokio::sync::{OnceCell, mpsc};
+use tokio::time::{Instant, timeout, timeout_at};
+use tokio_tungstenite::tungstenite::Message;
 use tokio_tungstenite::tungstenite::client::IntoClientRequest;
-use tokio_tungstenite::tungstenite::http::Request;
+use tokio_tungstenite::tungstenite::error::{Error as WsError, ProtocolError};
+use tokio_tungstenite::tungstenite::http::{Request, Response};
 use tokio_tungstenite::{Connector, MaybeTlsStream, client_async_tls_with_config};
 use url::Url;
 
 use crate::download;
-use crate::error::{Ndt7Error, Result};
-use crate::locate::Target;
+use crate::error::{ConfigError, Ndt7Error, Result};
+use crate::identity::ProbeIdentity;
+use crate::latency::{self, LatencyPacket, LatencyResult};
+use crate::locate::{LocateQuery, Location, ServerFilter, Target, TargetPolicy};
+use crate::msak::{self, ThroughputConfig, ThroughputHandle};
+use crate::params::{MeasurementInterval, TestParams};
+use crate::ping::{self, PingResult};
+use crate::proxy::Proxy;
 use crate::spec::{Measurement, TestKind};
-use crate::upload;
+use crate::trace::WireTrace;
+use crate::upload::{self, PayloadConfig, PayloadFill};
 use crate::{locate, params};
 
 /// A certificate verifier that accepts any certificate.
@@ -63,7 +81,45 @@ impl rustls::client::danger::ServerCertVerifier for NoVerifier {
 /// Type alias for the WebSocket stream used by download and upload tests.
 pub type WsStream = tokio_tungstenite::WebSocketStream<MaybeTlsStream<TcpStream>>;
 
-/// IP address family preference for test connections.
+/// A WebSocket connection the download and upload tests run on.
+///
+/// Implemented by [`WsStream`]. Tests can implement it for a scripted
+/// stream of server messages to exercise the test loops without sockets;
+/// such a transport has no TCP statistics and is assumed to be plain TCP.
+pub trait Transport:
+    Stream<Item = std::result::Result<Message, WsError>>
+    + Sink<Message, Error = WsError>
+    + Unpin
+    + Send
+    + 'static
+{
+    /// The TCP connection underneath, if any.
+    fn tcp_stream(&self) -> Option<&TcpStream> {
+        None
+    }
+
+    /// Whether the connection is encrypted with TLS.
+    fn is_tls(&self) -> bool {
+        false
+    }
+}
+
+impl Transport for WsStream {
+    fn tcp_stream(&self) -> Option<&TcpStream> {
+        match self.get_ref() {
+            MaybeTlsStream::Plain(tcp) => Some(tcp),
+            MaybeTlsStream::Rustls(tls) => Some(tls.get_ref().0),
+            _ => None,
+        }
+    }
+
+    fn is_tls(&self) -> bool {
+        matches!(self.get_ref(), MaybeTlsStream::Rustls(_))
+    }
+}
+
+/// IP address family preference for test connections and Locate API
+/// requests.
 #[derive(Debug, Default, Clone, Copy, PartialEq)]
 pub enum AddressFamily {
     /// Use whichever address family DNS resolution returns first.
@@ -84,6 +140,24 @@ impl AddressFamily {
             AddressFamily::Ipv6Only => addrs.into_iter().find(|a| a.is_ipv6()),
         }
     }
+
+    /// Family of `ip`.
+    fn of(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(_) => AddressFamily::Ipv4Only,
+            IpAddr::V6(_) => AddressFamily::Ipv6Only,
+        }
+    }
+
+    /// Unspecified local address of this family, binding to which keeps
+    /// connections to the family.
+    fn local_address(&self) -> Option<IpAddr> {
+        match self {
+            AddressFamily::Any => None,
+            AddressFamily::Ipv4Only => Some(Ipv4Addr::UNSPECIFIED.into()),
+            AddressFamily::Ipv6Only => Some(Ipv6Addr::UNSPECIFIED.into()),
+        }
+    }
 }
 
 impl std::fmt::Display for AddressFamily {
@@ -96,12 +170,124 @@ impl std::fmt::Display for AddressFamily {
     }
 }
 
+/// Details of the server's WebSocket upgrade response, useful for debugging
+/// server-side issues.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ConnectInfo {
+    /// HTTP status code of the upgrade response.
+    pub status: u16,
+    /// Server software and version from the `Server` header.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server: Option<String>,
+    /// Subprotocol negotiated via the `Sec-WebSocket-Protocol` header.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subprotocol: Option<String>,
+    /// Value of the `Retry-After` header, if the server sent one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<String>,
+    /// Address of the server the connection was made to, which shows the
+    /// address family used.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peer_addr: Option<SocketAddr>,
+    /// Local address the connection was made from.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub local_addr: Option<SocketAddr>,
+}
+
+impl ConnectInfo {
+    /// Extract connection details from an HTTP upgrade response.
+    pub fn from_response<T>(response: &Response<T>) -> Self {
+        let header = |name: &str| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        };
+        ConnectInfo {
+            status: response.status().as_u16(),
+            server: header("Server"),
+            subprotocol: header("Sec-WebSocket-Protocol"),
+            retry_after: header("Retry-After"),
+            peer_addr: None,
+            local_addr: None,
+        }
+    }
+}
+
 /// Handle to a running ndt7 test, returned by [`Client::start_download`] and [`Client::start_upload`].
 pub struct TestHandle {
     /// Fully qualified domain name of the server running the test.
     pub server_fqdn: String,
-    /// Channel of measurement results from the running test.
+    /// Location of the server, if it was found through the Locate API.
+    pub server_location: Option<Location>,
+    /// Details of the server's WebSocket upgrade response.
+    pub connect_info: ConnectInfo,
+    /// Located servers skipped before this one because they failed the
+    /// health check, see [`ClientBuilder::health_check`].
+    pub unhealthy: Vec<UnhealthyServer>,
+    /// How long the test runs unless the server closes the connection or a
+    /// byte cap is reached first.
+    pub duration: Duration,
+    /// Channel of measurement results from the running test. Dropping it
+    /// stops the test.
     pub rx: mpsc::Receiver<Result<Measurement>>,
+    /// Server measurements the test skipped because they could not be
+    /// parsed.
+    pub skipped: SkippedMeasurements,
+}
+
+/// Server measurements of a test that were skipped because they could not
+/// be parsed, see [`TestParams::strict_parsing`].
+///
+/// Shared with the running test, so it grows as the test goes on. Cheap to
+/// clone; clones see the same list.
+#[derive(Debug, Clone, Default)]
+pub struct SkippedMeasurements(Arc<Mutex<Vec<String>>>);
+
+impl SkippedMeasurements {
+    /// Number of measurements skipped so far.
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Whether no measurement was skipped so far.
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    /// Why each measurement skipped so far could not be parsed, oldest
+    /// first.
+    pub fn errors(&self) -> Vec<String> {
+        self.lock().clone()
+    }
+
+    pub(crate) fn push(&self, error: String) {
+        self.lock().push(error);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<String>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A located server skipped because it failed the health check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnhealthyServer {
+    /// FQDN of the server.
+    pub fqdn: String,
+    /// Why the check failed.
+    pub reason: String,
+}
+
+/// Connection of a subtest to the server that accepted it.
+struct Connected {
+    ws: WsStream,
+    server_fqdn: String,
+    server_location: Option<Location>,
+    connect_info: ConnectInfo,
+    unhealthy: Vec<UnhealthyServer>,
 }
 
 /// An ndt7 test client.
@@ -109,13 +295,123 @@ pub struct TestHandle {
 /// Use [`ClientBuilder`] to create a client, then [`Client::start_download`] /
 /// [`Client::start_upload`] to run tests. Pass `None` to auto-locate the nearest
 /// M-Lab server with retry, or `Some(url)` for a specific server.
+///
+/// A client is cheap to clone and can be shared across tasks, e.g. held in
+/// server application state and used from several request handlers at
+/// once. Clones share the immutable configuration, the TLS configuration,
+/// the cache of located servers and a reproducible upload payload; each
+/// clone has its own deadline.
+#[derive(Clone)]
 pub struct Client {
+    config: Arc<Config>,
+    deadline: Option<Deadline>,
+    targets: Arc<tokio::sync::Mutex<Option<Located>>>,
+    rotation: Arc<Mutex<Rotation>>,
+    msak_targets: Arc<tokio::sync::Mutex<Option<Located>>>,
+    latency_targets: Arc<tokio::sync::Mutex<Option<Located>>>,
+    corpus: Arc<OnceCell<Bytes>>,
+}
+
+/// Servers returned by the last ndt7 Locate request.
+#[derive(Debug)]
+struct Located {
+    targets: Arc<[Target]>,
+    /// When the first access token in the servers' URLs expires.
+    expiry: Option<SystemTime>,
+}
+
+impl Located {
+    fn new(targets: Vec<Target>) -> Self {
+        let expiry = targets.iter().filter_map(Target::token_expiry).min();
+        Located {
+            targets: targets.into(),
+            expiry,
+        }
+    }
+
+    /// Whether the access tokens are still valid for a connection attempt,
+    /// allowing [`TOKEN_EXPIRY_MARGIN`] for it. Servers without tokens
+    /// stay valid.
+    fn is_fresh(&self) -> bool {
+        self.expiry
+            .is_none_or(|expiry| SystemTime::now() + TOKEN_EXPIRY_MARGIN < expiry)
+    }
+}
+
+/// Time before their access tokens expire after which located servers are
+/// located again rather than reused.
+const TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(10);
+
+/// Where in the located servers the runs of a client start, for
+/// [`TargetPolicy`].
+#[derive(Debug, Default)]
+struct Rotation {
+    /// Runs started so far.
+    runs: usize,
+    /// Start of the last download, reused by the upload that follows it so
+    /// both subtests of a run go to the same server.
+    pending: Option<usize>,
+}
+
+/// Machine and token for the Locate API's monitoring endpoint.
+#[derive(Clone)]
+struct Monitoring {
+    machine: String,
+    token: String,
+}
+
+/// Settings fixed when the client is built.
+#[derive(Clone)]
+struct Config {
     client_name: String,
     client_version: String,
-    no_verify_tls: bool,
     no_tls: bool,
     address_family: AddressFamily,
-    targets: Option<Vec<Target>>,
+    local_address: Option<IpAddr>,
+    #[cfg(target_os = "linux")]
+    interface: Option<String>,
+    probe_identity: ProbeIdentity,
+    target_policy: TargetPolicy,
+    locate_query: LocateQuery,
+    server_filter: ServerFilter,
+    health_check: bool,
+    monitoring: Option<Monitoring>,
+    fallback: Vec<Target>,
+    send_buffer_size: Option<u32>,
+    recv_buffer_size: Option<u32>,
+    #[cfg(target_os = "linux")]
+    notsent_lowat: Option<u32>,
+    dscp: Option<u8>,
+    payload: PayloadConfig,
+    test_params: TestParams,
+    deadline_after: Option<Duration>,
+    /// `None` when certificate verification is on but no root certificates
+    /// are available.
+    tls: Option<Connector>,
+    /// HTTP client for the Locate API and latency1 authorization, sharing
+    /// the TLS configuration of the WebSocket connections.
+    http: reqwest::Client,
+    proxy: Option<Proxy>,
+    wire_trace: Option<Arc<WireTrace>>,
+}
+
+impl Config {
+    /// Family of the server addresses connected to, that of the local
+    /// address if one is set.
+    fn family(&self) -> AddressFamily {
+        self.local_address
+            .map_or(self.address_family, AddressFamily::of)
+    }
+
+    /// Local address to bind test sockets to for a connection to `addr`.
+    fn bind_addr(&self, addr: SocketAddr) -> SocketAddr {
+        let unspecified = if addr.is_ipv4() {
+            Ipv4Addr::UNSPECIFIED.into()
+        } else {
+            Ipv6Addr::UNSPECIFIED.into()
+        };
+        (self.local_address.unwrap_or(unspecified), 0).into()
+    }
 }
 
 /// Builder for [`Client`].
@@ -130,6 +426,27 @@ pub struct ClientBuilder {
     no_verify_tls: bool,
     no_tls: bool,
     address_family: AddressFamily,
+    local_address: Option<IpAddr>,
+    #[cfg(target_os = "linux")]
+    interface: Option<String>,
+    probe_identity: ProbeIdentity,
+    target_policy: TargetPolicy,
+    locate_query: LocateQuery,
+    server_filter: ServerFilter,
+    health_check: bool,
+    monitoring: Option<Monitoring>,
+    fallback_servers: Vec<String>,
+    send_buffer_size: Option<u32>,
+    recv_buffer_size: Option<u32>,
+    #[cfg(target_os = "linux")]
+    notsent_lowat: Option<u32>,
+    dscp: Option<u8>,
+    payload: PayloadConfig,
+    test_params: TestParams,
+    deadline: Option<Duration>,
+    root_certificates: Vec<Vec<u8>>,
+    proxy: Option<String>,
+    wire_trace: Option<Arc<WireTrace>>,
 }
 
 impl ClientBuilder {
@@ -142,6 +459,27 @@ impl ClientBuilder {
             no_verify_tls: false,
             no_tls: false,
             address_family: AddressFamily::Any,
+            local_address: None,
+            #[cfg(target_os = "linux")]
+            interface: None,
+            probe_identity: ProbeIdentity::default(),
+            target_policy: TargetPolicy::default(),
+            locate_query: LocateQuery::default(),
+            server_filter: ServerFilter::default(),
+            health_check: false,
+            monitoring: None,
+            fallback_servers: Vec::new(),
+            send_buffer_size: None,
+            recv_buffer_size: None,
+            #[cfg(target_os = "linux")]
+            notsent_lowat: None,
+            dscp: None,
+            payload: PayloadConfig::default(),
+            test_params: TestParams::default(),
+            deadline: None,
+            root_certificates: Vec::new(),
+            proxy: None,
+            wire_trace: None,
         }
     }
 
@@ -151,200 +489,1468 @@ impl ClientBuilder {
         self
     }
 
-    /// Use unencrypted ws:// connection
-    pub fn no_tls(mut self) -> Self {
-        self.no_tls = true;
-        self
+    /// Trust the root certificates in the PEM bundle `pem`, in addition to
+    /// those enabled by the `webpki-roots` and `native-roots` features.
+    ///
+    /// Use this where neither source has CA data, e.g. in minimal containers
+    /// built without default features. Without any roots, connecting fails
+    /// with [`Ndt7Error::NoTrustAnchors`].
+    pub fn root_certificates_pem(mut self, pem: &[u8]) -> Self {
+        self.root_certificates.push(pem.to_vec());
+        self
+    }
+
+    /// Record every WebSocket message of the tests to `trace`, for
+    /// debugging. See [`crate::trace`].
+    pub fn wire_trace(mut self, trace: WireTrace) -> Self {
+        self.wire_trace = Some(Arc::new(trace));
+        self
+    }
+
+    /// Use unencrypted ws:// connection
+    pub fn no_tls(mut self) -> Self {
+        self.no_tls = true;
+        self
+    }
+
+    /// Set the preferred IP address family for connections.
+    pub fn address_family(mut self, af: AddressFamily) -> Self {
+        self.address_family = af;
+        self
+    }
+
+    /// Reach the Locate API and test servers through the proxy at `url`,
+    /// e.g. `socks5://proxy:1080` or `http://proxy:3128`. See
+    /// [`Proxy::parse`] for the supported schemes.
+    ///
+    /// Latency probes over UDP bypass the proxy, and server health checks
+    /// time the tunnel setup instead of a direct connection.
+    pub fn proxy(mut self, url: impl Into<String>) -> Self {
+        self.proxy = Some(url.into());
+        self
+    }
+
+    /// Send test connections, Locate API requests and latency probes from
+    /// the local address `ip`, e.g. to measure one uplink of a multi-homed
+    /// host. Servers are then reached over the family of `ip` only.
+    pub fn local_address(mut self, ip: IpAddr) -> Self {
+        self.local_address = Some(ip);
+        self
+    }
+
+    /// Send test connections, Locate API requests and latency probes
+    /// through the network interface `name`, e.g. `eth1`, with
+    /// `SO_BINDTODEVICE` (Linux only).
+    #[cfg(target_os = "linux")]
+    pub fn interface(mut self, name: impl Into<String>) -> Self {
+        self.interface = Some(name.into());
+        self
+    }
+
+    /// Tag test requests with a probe identity, archived by M-Lab as
+    /// client metadata.
+    pub fn probe_identity(mut self, identity: ProbeIdentity) -> Self {
+        self.probe_identity = identity;
+        self
+    }
+
+    /// Choose which of the located servers auto-located tests connect to.
+    /// Defaults to [`TargetPolicy::First`], the nearest.
+    pub fn target_policy(mut self, policy: TargetPolicy) -> Self {
+        self.target_policy = policy;
+        self
+    }
+
+    /// Restrict auto-located servers to a country, region, site or
+    /// organization, e.g. to test against a distant region deliberately.
+    pub fn locate_query(mut self, query: LocateQuery) -> Self {
+        self.locate_query = query;
+        self
+    }
+
+    /// Exclude located servers from tests, or restrict tests to an approved
+    /// set of them, by machine or site. The filter applies to the servers
+    /// returned by the Locate API only; fallback servers and the machine
+    /// set with [`ClientBuilder::monitoring`] are always used.
+    pub fn server_filter(mut self, filter: ServerFilter) -> Self {
+        self.server_filter = filter;
+        self
+    }
+
+    /// Check that each located server accepts TCP connections within
+    /// [`params::TARGET_PROBE_TIMEOUT`] before starting a test on it, and
+    /// skip to the next one if it does not, rather than waiting for the
+    /// test's I/O timeout on a dead machine. Skipped servers are listed in
+    /// [`TestHandle::unhealthy`].
+    pub fn health_check(mut self) -> Self {
+        self.health_check = true;
+        self
+    }
+
+    /// Run auto-located ndt7 tests against `machine` only, locating it
+    /// through the monitoring endpoint with a monitoring `token` issued by
+    /// M-Lab, for health checks of specific machines. See
+    /// [`locate::monitoring`].
+    pub fn monitoring(mut self, machine: impl Into<String>, token: impl Into<String>) -> Self {
+        self.monitoring = Some(Monitoring {
+            machine: machine.into(),
+            token: token.into(),
+        });
+        self
+    }
+
+    /// Test against `servers` when the Locate API fails or is out of
+    /// capacity, e.g. for air-gapped or self-hosted deployments. Each is a
+    /// hostname or a service URL, see [`Target::from_server`]; they are
+    /// tried in order.
+    pub fn fallback_servers(
+        mut self,
+        servers: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.fallback_servers = servers.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Pass the API key M-Lab issued to this integration to the Locate API.
+    /// See [`LocateQuery::api_key`].
+    pub fn locate_api_key(mut self, key: impl Into<String>) -> Self {
+        self.locate_query.api_key = Some(key.into());
+        self
+    }
+
+    /// Pin the socket send buffer size (`SO_SNDBUF`) in bytes instead of
+    /// relying on kernel autotuning.
+    pub fn send_buffer_size(mut self, size: u32) -> Self {
+        self.send_buffer_size = Some(size);
+        self
+    }
+
+    /// Pin the socket receive buffer size (`SO_RCVBUF`) in bytes instead of
+    /// relying on kernel autotuning.
+    pub fn recv_buffer_size(mut self, size: u32) -> Self {
+        self.recv_buffer_size = Some(size);
+        self
+    }
+
+    /// Set `TCP_NOTSENT_LOWAT` on the upload socket (Linux only).
+    ///
+    /// Limits how much unsent data may queue in the kernel send buffer, so
+    /// client-side upload byte counts track what was actually transmitted.
+    #[cfg(target_os = "linux")]
+    pub fn tcp_notsent_lowat(mut self, bytes: u32) -> Self {
+        self.notsent_lowat = Some(bytes);
+        self
+    }
+
+    /// Mark test traffic with the given DSCP class (0-63) by setting
+    /// `IP_TOS` / `IPV6_TCLASS` on the socket before connecting.
+    ///
+    /// The marking is also sent to the server as `client_dscp` metadata.
+    pub fn dscp(mut self, dscp: u8) -> Self {
+        self.dscp = Some(dscp);
+        self
+    }
+
+    /// Configure the random payload sent during the upload test, e.g. to
+    /// send bit-identical data across runs.
+    pub fn payload(mut self, payload: PayloadConfig) -> Self {
+        self.payload = payload;
+        self
+    }
+
+    /// Stop each download and upload test once `bytes` of payload have been
+    /// transferred, for metered connections. The connection is closed
+    /// cleanly and the results cover the data transferred so far.
+    pub fn max_bytes(mut self, bytes: u64) -> Self {
+        self.test_params.max_bytes = Some(bytes);
+        self
+    }
+
+    /// Throttle the upload to at most `bytes_per_sec`, e.g. to check how an
+    /// application behaves on a slow uplink.
+    pub fn upload_rate(mut self, bytes_per_sec: u64) -> Self {
+        self.test_params.upload_rate = Some(bytes_per_sec);
+        self
+    }
+
+    /// Send a WebSocket ping every `interval` during the tests and report
+    /// the round-trip times, for latency under load. See
+    /// [`TestParams::ws_ping_interval`].
+    pub fn ws_ping_interval(mut self, interval: Duration) -> Self {
+        self.test_params.ws_ping_interval = Some(interval);
+        self
+    }
+
+    /// Report estimated bytes on the wire, including WebSocket, TLS and
+    /// TCP/IP overhead, alongside the payload counts of client measurements.
+    /// See [`TestParams::wire_overhead`].
+    pub fn wire_overhead(mut self) -> Self {
+        self.test_params.wire_overhead = true;
+        self
+    }
+
+    /// Fail a test on a malformed server measurement instead of skipping it
+    /// and listing it in [`TestHandle::skipped`]. See [`TestParams::strict_parsing`].
+    pub fn strict_parsing(mut self) -> Self {
+        self.test_params.strict_parsing = true;
+        self
+    }
+
+    /// Stamp client measurements with the wall-clock UTC time. See
+    /// [`TestParams::utc_timestamps`].
+    pub fn utc_timestamps(mut self) -> Self {
+        self.test_params.utc_timestamps = true;
+        self
+    }
+
+    /// Stop the download test after `duration`. See
+    /// [`TestParams::download_duration`].
+    pub fn download_duration(mut self, duration: Duration) -> Self {
+        self.test_params.download_duration = duration;
+        self
+    }
+
+    /// Stop the upload test after `duration`. See
+    /// [`TestParams::upload_duration`].
+    pub fn upload_duration(mut self, duration: Duration) -> Self {
+        self.test_params.upload_duration = duration;
+        self
+    }
+
+    /// Give up establishing the connection of a test after `timeout`. See
+    /// [`TestParams::connect_timeout`].
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.test_params.connect_timeout = timeout;
+        self
+    }
+
+    /// Sleep for `delay` after each message read during the download. The
+    /// receive window fills up while the client sleeps, so the server slows
+    /// down through TCP flow control.
+    pub fn download_read_delay(mut self, delay: Duration) -> Self {
+        self.test_params.download_read_delay = Some(delay);
+        self
+    }
+
+    /// Limit uploaded messages to `bytes`, e.g. to bound memory use on
+    /// embedded devices. See [`TestParams::max_message_size`].
+    pub fn max_message_size(mut self, bytes: usize) -> Self {
+        self.test_params.max_message_size = bytes;
+        self
+    }
+
+    /// Control how often client-side measurements are generated, e.g. less
+    /// often than every [`params::UPDATE_INTERVAL`] for slow consumers. An
+    /// adaptive interval's `min` must not exceed its `max`.
+    pub fn measurement_interval(mut self, interval: MeasurementInterval) -> Self {
+        self.test_params.measurement_interval = interval;
+        self
+    }
+
+    /// Replace all per-test settings at once.
+    pub fn test_params(mut self, test_params: TestParams) -> Self {
+        self.test_params = test_params;
+        self
+    }
+
+    /// Bound the total run time of the client, covering server location,
+    /// connection setup and all tests.
+    ///
+    /// The clock starts when the client is built (see
+    /// [`Client::reset_deadline`]). Once it expires, running tests are
+    /// aborted and end with [`Ndt7Error::TestDeadline`], and new tests
+    /// fail to start with the same error.
+    pub fn deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Validate the settings and build the [`Client`].
+    ///
+    /// Fails if an option is out of range (DSCP above 63, zero buffer sizes,
+    /// byte cap, upload rate, read delay, measurement interval, test durations,
+    /// connect timeout or deadline,
+    /// empty identifiers, locate constraints or credentials, message sizes above [`params::MAX_MESSAGE_SIZE`]),
+    /// a root certificate bundle, proxy URL or fallback server does not
+    /// parse, options conflict
+    /// (`no_verify_tls` has no effect together with `no_tls`) or lack one
+    /// they require (a payload cache requires a seed), or the HTTP
+    /// client cannot be built from the settings.
+    pub fn try_build(self) -> std::result::Result<Client, ConfigError> {
+        self.validate()?;
+        self.assemble()
+    }
+
+    fn validate(&self) -> std::result::Result<(), ConfigError> {
+        #[cfg(target_os = "linux")]
+        let interface = self.interface.as_ref();
+        #[cfg(not(target_os = "linux"))]
+        let interface = None;
+        for (name, value) in [
+            ("interface", interface),
+            ("client_name", Some(&self.client_name)),
+            ("client_version", Some(&self.client_version)),
+            ("probe_id", self.probe_identity.probe_id.as_ref()),
+            ("deployment_id", self.probe_identity.deployment_id.as_ref()),
+            ("country", self.locate_query.country.as_ref()),
+            ("region", self.locate_query.region.as_ref()),
+            ("site", self.locate_query.site.as_ref()),
+            ("org", self.locate_query.org.as_ref()),
+            ("api_key", self.locate_query.api_key.as_ref()),
+            ("priority_token", self.locate_query.priority_token.as_ref()),
+            ("machine", self.monitoring.as_ref().map(|m| &m.machine)),
+            (
+                "monitoring_token",
+                self.monitoring.as_ref().map(|m| &m.token),
+            ),
+        ] {
+            if value.is_some_and(|v| v.is_empty()) {
+                return Err(ConfigError::Empty(name));
+            }
+        }
+        #[cfg(target_os = "linux")]
+        let notsent_lowat = self.notsent_lowat;
+        #[cfg(not(target_os = "linux"))]
+        let notsent_lowat = None;
+        for (name, value) in [
+            ("send_buffer_size", self.send_buffer_size),
+            ("recv_buffer_size", self.recv_buffer_size),
+            ("tcp_notsent_lowat", notsent_lowat),
+        ] {
+            if value == Some(0) {
+                return Err(ConfigError::Zero(name));
+            }
+        }
+        let test_params = &self.test_params;
+        for (name, value) in [
+            ("max_bytes", test_params.max_bytes),
+            ("upload_rate", test_params.upload_rate),
+            (
+                "initial_message_size",
+                Some(test_params.initial_message_size as u64),
+            ),
+            (
+                "max_message_size",
+                Some(test_params.max_message_size as u64),
+            ),
+            (
+                "scaling_fraction",
+                Some(test_params.scaling_fraction as u64),
+            ),
+        ] {
+            if value == Some(0) {
+                return Err(ConfigError::Zero(name));
+            }
+        }
+        if test_params.max_message_size > params::MAX_MESSAGE_SIZE {
+            return Err(ConfigError::TooLarge(
+                "max_message_size",
+                params::MAX_MESSAGE_SIZE,
+            ));
+        }
+        let interval = match test_params.measurement_interval {
+            MeasurementInterval::Fixed(interval) => interval,
+            MeasurementInterval::Adaptive { min, max } if min > max => {
+                return Err(ConfigError::InvalidRange("measurement_interval"));
+            }
+            MeasurementInterval::Adaptive { min, .. } => min,
+        };
+        for (name, value) in [
+            ("measurement_interval", Some(interval)),
+            ("download_read_delay", test_params.download_read_delay),
+            ("ws_ping_interval", test_params.ws_ping_interval),
+            ("download_duration", Some(test_params.download_duration)),
+            ("upload_duration", Some(test_params.upload_duration)),
+            ("connect_timeout", Some(test_params.connect_timeout)),
+            ("deadline", self.deadline),
+        ] {
+            if value == Some(Duration::ZERO) {
+                return Err(ConfigError::Zero(name));
+            }
+        }
+        if let Some(dscp) = self.dscp.filter(|&d| d > 63) {
+            return Err(ConfigError::DscpOutOfRange(dscp));
+        }
+        if self.no_tls && self.no_verify_tls {
+            return Err(ConfigError::Conflict("no_verify_tls", "no_tls"));
+        }
+        if let Some(ip) = self.local_address
+            && ![AddressFamily::Any, AddressFamily::of(ip)].contains(&self.address_family)
+        {
+            return Err(ConfigError::Conflict("local_address", "address_family"));
+        }
+        let payload = &self.payload;
+        if payload.fill == PayloadFill::Random
+            && payload.cache_path.is_some()
+            && payload.seed.is_none()
+        {
+            return Err(ConfigError::Requires("cache_path", "seed"));
+        }
+        for pem in &self.root_certificates {
+            parse_root_certificates(pem)?;
+        }
+        if let Some(url) = &self.proxy {
+            Proxy::parse(url)?;
+        }
+        for server in &self.fallback_servers {
+            Target::from_server(server)
+                .map_err(|e| ConfigError::InvalidServer(format!("{server}: {e}")))?;
+        }
+        Ok(())
+    }
+
+    /// Build the [`Client`] without validating the settings; see
+    /// [`ClientBuilder::try_build`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the proxy URL or a root certificate bundle does not parse,
+    /// or the HTTP client cannot be built from the settings.
+    pub fn build(self) -> Client {
+        self.assemble()
+            .unwrap_or_else(|e| panic!("invalid client configuration: {e}"))
+    }
+
+    fn assemble(self) -> std::result::Result<Client, ConfigError> {
+        let mut extra_roots = Vec::new();
+        for pem in &self.root_certificates {
+            extra_roots.extend(parse_root_certificates(pem)?);
+        }
+        let tls = tls_config(self.no_verify_tls, extra_roots);
+        let user_agent = user_agent(&self.client_name, &self.client_version);
+        let proxy = self.proxy.as_deref().map(Proxy::parse).transpose()?;
+        let http = http_client(
+            &user_agent,
+            tls.as_deref(),
+            proxy.as_ref(),
+            self.local_address.or(self.address_family.local_address()),
+            #[cfg(target_os = "linux")]
+            self.interface.as_deref(),
+        )
+        .map_err(|e| ConfigError::HttpClient(e.to_string()))?;
+        let config = Config {
+            client_name: self.client_name,
+            client_version: self.client_version,
+            no_tls: self.no_tls,
+            address_family: self.address_family,
+            local_address: self.local_address,
+            #[cfg(target_os = "linux")]
+            interface: self.interface,
+            probe_identity: self.probe_identity,
+            target_policy: self.target_policy,
+            locate_query: self.locate_query,
+            server_filter: self.server_filter,
+            health_check: self.health_check,
+            monitoring: self.monitoring,
+            fallback: self
+                .fallback_servers
+                .iter()
+                .filter_map(|server| Target::from_server(server).ok())
+                .collect(),
+            send_buffer_size: self.send_buffer_size,
+            recv_buffer_size: self.recv_buffer_size,
+            #[cfg(target_os = "linux")]
+            notsent_lowat: self.notsent_lowat,
+            dscp: self.dscp,
+            payload: self.payload,
+            test_params: self.test_params,
+            deadline_after: self.deadline,
+            tls: tls.clone().map(Connector::Rustls),
+            http,
+            proxy,
+            wire_trace: self.wire_trace,
+        };
+        Ok(Client {
+            config: Arc::new(config),
+            deadline: self.deadline.map(Deadline::start),
+            targets: Arc::default(),
+            rotation: Arc::default(),
+            msak_targets: Arc::default(),
+            latency_targets: Arc::default(),
+            corpus: Arc::new(OnceCell::new()),
+        })
+    }
+}
+
+impl Client {
+    /// Establish a WebSocket connection to the given service URL.
+    ///
+    /// `service_url` is the full URL from the Locate API, e.g.
+    /// "wss://mlab1-lga06:4443/ndt/v7/download?access_token=..."
+    ///
+    /// Returns the stream together with details of the upgrade response.
+    pub async fn connect(&self, service_url: &str) -> Result<(WsStream, ConnectInfo)> {
+        let url = self.service_url(service_url)?;
+        self.connect_url(&url, params::SEC_WEBSOCKET_PROTOCOL).await
+    }
+
+    /// Establish a WebSocket connection to `url` negotiating `protocol`.
+    async fn connect_url(&self, url: &Url, protocol: &str) -> Result<(WsStream, ConnectInfo)> {
+        // Build the HTTP request with required headers.
+        let mut request = url.to_string().into_client_request()?;
+        request
+            .headers_mut()
+            .insert("Sec-WebSocket-Protocol", protocol.parse().unwrap());
+        request
+            .headers_mut()
+            .insert("User-Agent", self.user_agent().parse().unwrap());
+
+        let start = Instant::now();
+        let connect_timeout = self.config.test_params.connect_timeout;
+        timeout(connect_timeout, self.connect_ws(request, url, protocol))
+            .await
+            .map_err(|_| Ndt7Error::ConnectTimeout {
+                elapsed: start.elapsed(),
+            })?
+    }
+
+    /// Client metadata sent to the server as query parameters, which
+    /// ndt-server archives as the test's `ClientMetadata`.
+    pub fn client_metadata(&self) -> Vec<(&'static str, String)> {
+        let mut metadata = vec![
+            ("client_name", self.config.client_name.clone()),
+            ("client_version", self.config.client_version.clone()),
+            ("client_os", std::env::consts::OS.to_string()),
+            ("client_arch", std::env::consts::ARCH.to_string()),
+            (
+                "client_library_name",
+                format!("{}-rs", env!("CARGO_PKG_NAME")),
+            ),
+            (
+                "client_library_version",
+                env!("CARGO_PKG_VERSION").to_string(),
+            ),
+        ];
+        for (name, value) in self.config.probe_identity.query_pairs() {
+            metadata.push((name, value.to_string()));
+        }
+        if let Some(dscp) = self.config.dscp {
+            metadata.push(("client_dscp", dscp.to_string()));
+        }
+        metadata
+    }
+
+    /// Parse the URL and append client metadata as query parameters.
+    fn service_url(&self, service_url: &str) -> Result<Url> {
+        let mut url = Url::parse(service_url)?;
+        url.query_pairs_mut().extend_pairs(self.client_metadata());
+        Ok(url)
+    }
+
+    async fn connect_ws(
+        &self,
+        request: Request<()>,
+        url: &Url,
+        protocol: &str,
+    ) -> Result<(WsStream, ConnectInfo)> {
+        let connector = match url.scheme() {
+            "wss" => Some(self.config.tls.clone().ok_or(Ndt7Error::NoTrustAnchors)?),
+            _ => None,
+        };
+
+        // TCP + TLS + WebSocket
+        let (tcp, addr) = self.connect_tcp(url).await?;
+        let local_addr = tcp.local_addr().ok();
+        let (ws_stream, response) = client_async_tls_with_config(request, tcp, None, connector)
+            .await
+            .map_err(|e| match e {
+                WsError::Protocol(ProtocolError::SecWebSocketSubProtocolError(e)) => {
+                    Ndt7Error::ProtocolViolation(format!("subprotocol negotiation failed: {e}"))
+                }
+                e => e.into(),
+            })?;
+
+        let info = ConnectInfo {
+            peer_addr: addr,
+            local_addr,
+            ..ConnectInfo::from_response(&response)
+        };
+        if info.subprotocol.as_deref() != Some(protocol) {
+            return Err(Ndt7Error::ProtocolViolation(format!(
+                "server negotiated subprotocol {:?}, expected {:?}",
+                info.subprotocol.as_deref().unwrap_or(""),
+                protocol
+            )));
+        }
+        Ok((ws_stream, info))
+    }
+
+    /// Resolve the server of `url` to an address of the configured family.
+    async fn resolve(&self, url: &Url) -> Result<SocketAddr> {
+        let (host, port) = host_port(url)?;
+        self.resolve_host(host, port).await
+    }
+
+    /// Resolve `host` to an address of the configured family.
+    async fn resolve_host(&self, host: &str, port: u16) -> Result<SocketAddr> {
+        let addrs = tokio::net::lookup_host((host, port)).await?;
+        let family = self.config.family();
+        family
+            .select_addr(addrs)
+            .ok_or(Ndt7Error::NoAddressFound(family))
+    }
+
+    /// Open a TCP connection to the server of `url`, through the proxy if
+    /// one is configured. Returns the address of the server, which is not
+    /// known behind a proxy resolving names itself.
+    async fn connect_tcp(&self, url: &Url) -> Result<(TcpStream, Option<SocketAddr>)> {
+        let Some(proxy) = &self.config.proxy else {
+            let addr = self.resolve(url).await?;
+            let tcp = self.tcp_socket(addr)?.connect(addr).await?;
+            return Ok((tcp, Some(addr)));
+        };
+        let (host, port) = host_port(url)?;
+        let server = match proxy.resolves_locally() {
+            true => Some(self.resolve_host(host, port).await?),
+            false => None,
+        };
+        let proxy_addr = self.resolve_host(proxy.host(), proxy.port()).await?;
+        let tcp = self.tcp_socket(proxy_addr)?.connect(proxy_addr).await?;
+        let tcp = match server {
+            Some(addr) => proxy.tunnel(tcp, &addr.ip().to_string(), port).await?,
+            None => proxy.tunnel(tcp, host, port).await?,
+        };
+        Ok((tcp, server))
+    }
+
+    /// Create a socket for `addr` with the configured options applied.
+    ///
+    /// Buffer sizes must be set before connecting so they are taken into
+    /// account when the TCP window scale is negotiated.
+    fn tcp_socket(&self, addr: SocketAddr) -> Result<TcpSocket> {
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        if self.config.local_address.is_some() {
+            socket.bind(self.config.bind_addr(addr))?;
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(name) = &self.config.interface {
+            socket.bind_device(Some(name.as_bytes()))?;
+        }
+        if let Some(size) = self.config.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.config.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(dscp) = self.config.dscp {
+            // DSCP occupies the upper six bits of the TOS / traffic class byte.
+            let tos = u32::from(dscp) << 2;
+            if addr.is_ipv4() {
+                set_tos_v4(&socket, tos)?;
+            } else {
+                set_tclass_v6(&socket, tos)?;
+            }
+        }
+        Ok(socket)
+    }
+
+    /// DSCP class applied to test traffic, if any.
+    pub fn dscp(&self) -> Option<u8> {
+        self.config.dscp
+    }
+
+    /// Start a download test and return a channel of [`Measurement`] results.
+    ///
+    /// The test runs in a background task. Each item is `Ok(measurement)` or
+    /// `Err(error)` if the test fails mid-stream. An error is always the last
+    /// item - the channel closes immediately after. Malformed server
+    /// measurements are skipped and listed in [`TestHandle::skipped`].
+    pub async fn start_download(&self, url: Option<&str>) -> Result<TestHandle> {
+        let deadline = self.deadline;
+        let connected =
+            with_deadline(deadline, self.connect_with_retry(url, TestKind::Download)).await?;
+        let (tx, rx) = mpsc::channel(64);
+        let skipped = SkippedMeasurements::default();
+        spawn_test(
+            deadline,
+            tx.clone(),
+            download::run(
+                connected.ws,
+                self.config.test_params,
+                self.config.wire_trace.clone(),
+                skipped.clone(),
+                tx,
+            ),
+        );
+        Ok(TestHandle {
+            server_fqdn: connected.server_fqdn,
+            server_location: connected.server_location,
+            connect_info: connected.connect_info,
+            unhealthy: connected.unhealthy,
+            duration: self.config.test_params.download_duration,
+            rx,
+            skipped,
+        })
+    }
+
+    /// Start an upload test and return a channel of [`Measurement`] results.
+    ///
+    /// The test runs in a background task. Each item is `Ok(measurement)` or
+    /// `Err(error)` if the test fails mid-stream. An error is always the last
+    /// item - the channel closes immediately after. Malformed server
+    /// measurements are skipped and listed in [`TestHandle::skipped`].
+    pub async fn start_upload(&self, url: Option<&str>) -> Result<TestHandle> {
+        let corpus = self.upload_corpus().await?;
+        let deadline = self.deadline;
+        let connected =
+            with_deadline(deadline, self.connect_with_retry(url, TestKind::Upload)).await?;
+        #[cfg(target_os = "linux")]
+        if let Some(lowat) = self.config.notsent_lowat {
+            set_notsent_lowat(&connected.ws, lowat)?;
+        }
+        let (tx, rx) = mpsc::channel(64);
+        let skipped = SkippedMeasurements::default();
+        spawn_test(
+            deadline,
+            tx.clone(),
+            upload::run(
+                connected.ws,
+                corpus,
+                self.config.test_params,
+                self.config.wire_trace.clone(),
+                skipped.clone(),
+                tx,
+            ),
+        );
+        Ok(TestHandle {
+            server_fqdn: connected.server_fqdn,
+            server_location: connected.server_location,
+            connect_info: connected.connect_info,
+            unhealthy: connected.unhealthy,
+            duration: self.config.test_params.upload_duration,
+            rx,
+            skipped,
+        })
+    }
+
+    /// Start an msak throughput1 `test` over `config.streams` concurrent
+    /// connections and return a channel of their measurements.
+    ///
+    /// All streams connect to `url` if given, otherwise to the nearest
+    /// server offering throughput1. The streams run in a background task;
+    /// see [`ThroughputHandle`]. Fails with [`ConfigError::Zero`] if
+    /// `config.streams` is zero.
+    pub async fn start_throughput(
+        &self,
+        test: TestKind,
+        url: Option<&str>,
+        config: &ThroughputConfig,
+    ) -> Result<ThroughputHandle> {
+        if config.streams == 0 {
+            return Err(ConfigError::Zero("streams").into());
+        }
+        let corpus = match test {
+            TestKind::Download => Bytes::new(),
+            TestKind::Upload => self.upload_corpus().await?,
+        };
+        let deadline = self.deadline;
+        let mid = msak::measurement_id();
+        let (first, server_fqdn, connect_info, url) =
+            with_deadline(deadline, self.connect_throughput(test, url, &mid, config)).await?;
+        let rest = with_deadline(
+            deadline,
+            try_join_all((1..config.streams).map(|_| self.connect_url(&url, msak::PROTOCOL))),
+        )
+        .await?;
+
+        let (tx, rx) = mpsc::channel(64);
+        let skipped = SkippedMeasurements::default();
+        let strict_parsing = self.config.test_params.strict_parsing;
+        let streams = std::iter::once(first)
+            .chain(rest.into_iter().map(|(ws, _)| ws))
+            .enumerate()
+            .map(|(index, ws)| {
+                let stream = msak::Stream {
+                    test,
+                    index,
+                    strict_parsing,
+                    skipped: skipped.clone(),
+                };
+                msak::run_stream(ws, stream, corpus.clone(), config.clone(), tx.clone())
+            })
+            .collect::<Vec<_>>();
+        spawn_test(deadline, tx, async {
+            join_all(streams).await;
+        });
+        Ok(ThroughputHandle {
+            server_fqdn,
+            connect_info,
+            mid,
+            duration: config.duration,
+            rx,
+            skipped,
+        })
+    }
+
+    /// Run an msak latency1 test for `duration`.
+    ///
+    /// `url` is the authorize URL of a server, with its access token as
+    /// listed by the Locate API. Without it the nearest server offering
+    /// latency1 is located.
+    pub async fn latency1(&self, url: Option<&str>, duration: Duration) -> Result<LatencyResult> {
+        with_deadline(self.deadline, async {
+            let (kickoff, server_fqdn) = self.authorize_latency(url).await?;
+            let addrs = tokio::net::lookup_host((server_fqdn.as_str(), latency::UDP_PORT)).await?;
+            let family = self.config.family();
+            let addr = family
+                .select_addr(addrs)
+                .ok_or(Ndt7Error::NoAddressFound(family))?;
+            let socket = UdpSocket::bind(self.config.bind_addr(addr)).await?;
+            #[cfg(target_os = "linux")]
+            if let Some(name) = &self.config.interface {
+                socket.bind_device(Some(name.as_bytes()))?;
+            }
+            socket.connect(addr).await?;
+            let result = latency::run(&socket, &kickoff, duration).await?;
+            Ok(LatencyResult {
+                server_fqdn,
+                ..result
+            })
+        })
+        .await
+    }
+
+    /// Run a quick latency probe against the download endpoint.
+    ///
+    /// Unlike the full tests this returns within about
+    /// [`params::PING_TIMEOUT`] after connecting and transfers only a few KB,
+    /// making it suitable for frequent reachability checks.
+    pub async fn ping(&self, url: Option<&str>) -> Result<PingResult> {
+        // A small receive window caps the download data the server can push
+        // while the probe is running, unless the caller pinned a size.
+        let mut probe = self.clone();
+        if probe.config.recv_buffer_size.is_none() {
+            Arc::make_mut(&mut probe.config).recv_buffer_size = Some(params::PING_RECV_BUFFER_SIZE);
+        }
+        let deadline = self.deadline;
+        let start = Instant::now();
+        let Connected {
+            ws, server_fqdn, ..
+        } = with_deadline(deadline, probe.connect_with_retry(url, TestKind::Download)).await?;
+        let connect_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+        let result = with_deadline(deadline, ping::run(ws)).await?;
+        Ok(PingResult {
+            server_fqdn,
+            connect_ms,
+            ..result
+        })
+    }
+
+    /// Restart the deadline clock configured with [`ClientBuilder::deadline`],
+    /// e.g. before reusing the client for another run.
+    pub fn reset_deadline(&mut self) {
+        self.deadline = self.config.deadline_after.map(Deadline::start);
+    }
+
+    async fn connect_with_retry(
+        &self,
+        url: Option<&str>,
+        test_kind: TestKind,
+    ) -> Result<Connected> {
+        if let Some(url) = url {
+            let (ws, connect_info) = self.connect(url).await?;
+            let fqdn = Url::parse(url)?.host_str().unwrap_or("unknown").to_string();
+            Ok(Connected {
+                ws,
+                server_fqdn: fqdn,
+                server_location: None,
+                connect_info,
+                unhealthy: Vec::new(),
+            })
+        } else {
+            let scheme = if self.config.no_tls { "ws" } else { "wss" };
+            let mut last_err = Ndt7Error::NoTargets;
+            let mut unhealthy = Vec::new();
+            let targets = self.targets().await?;
+            let probed = match self.config.target_policy {
+                TargetPolicy::Fastest(count) if !self.upload_pending(test_kind) => Some(
+                    self.fastest_target(&targets, count, test_kind, scheme)
+                        .await,
+                ),
+                _ => None,
+            };
+            let start = self.target_start(test_kind, targets.len(), probed);
+            for t in self.config.target_policy.order(&targets, start) {
+                let url = match test_kind {
+                    TestKind::Download => t.service_urls(scheme).download,
+                    TestKind::Upload => t.service_urls(scheme).upload,
+                };
+                let Some(url) = url else { continue };
+                if self.config.health_check
+                    && let Err(e) = self.check_health(&url).await
+                {
+                    unhealthy.push(UnhealthyServer {
+                        fqdn: t.machine.clone(),
+                        reason: e.to_string(),
+                    });
+                    last_err = e;
+                    continue;
+                }
+                match self.connect(&url).await {
+                    Ok((ws, connect_info)) => {
+                        return Ok(Connected {
+                            ws,
+                            server_fqdn: t.machine.clone(),
+                            server_location: t.location.clone(),
+                            connect_info,
+                            unhealthy,
+                        });
+                    }
+                    Err(e) => {
+                        last_err = e;
+                    }
+                }
+            }
+            Err(last_err)
+        }
+    }
+
+    /// Check that the server of `url` accepts TCP connections within
+    /// [`params::TARGET_PROBE_TIMEOUT`].
+    async fn check_health(&self, url: &str) -> Result<()> {
+        let url = Url::parse(url)?;
+        let start = Instant::now();
+        timeout(params::TARGET_PROBE_TIMEOUT, self.connect_time(&url))
+            .await
+            .map_err(|_| Ndt7Error::ConnectTimeout {
+                elapsed: start.elapsed(),
+            })??;
+        Ok(())
+    }
+
+    /// Connect the first stream of a throughput1 test, returning the URL the
+    /// remaining streams use.
+    async fn connect_throughput(
+        &self,
+        test: TestKind,
+        url: Option<&str>,
+        mid: &str,
+        config: &ThroughputConfig,
+    ) -> Result<(WsStream, String, ConnectInfo, Url)> {
+        if let Some(url) = url {
+            let url = msak::stream_url(self.service_url(url)?, mid, config);
+            let (ws, info) = self.connect_url(&url, msak::PROTOCOL).await?;
+            let fqdn = url.host_str().unwrap_or("unknown").to_string();
+            return Ok((ws, fqdn, info, url));
+        }
+        let scheme = if self.config.no_tls { "ws" } else { "wss" };
+        let mut last_err = Ndt7Error::NoTargets;
+        for t in self.get_msak_targets().await?.iter() {
+            let Some(url) = t.service_url(scheme, msak::url_path(test)) else {
+                continue;
+            };
+            let url = msak::stream_url(self.service_url(url)?, mid, config);
+            match self.connect_url(&url, msak::PROTOCOL).await {
+                Ok((ws, info)) => return Ok((ws, t.machine.clone(), info, url)),
+                Err(e) => {
+                    last_err = e;
+                }
+            }
+        }
+        Err(last_err)
+    }
+
+    /// Fetch a latency1 session from `url` or the first located server that
+    /// grants one, returning its kickoff packet and the server's FQDN.
+    async fn authorize_latency(&self, url: Option<&str>) -> Result<(LatencyPacket, String)> {
+        if let Some(url) = url {
+            return self.authorize(url).await;
+        }
+        let scheme = if self.config.no_tls { "http" } else { "https" };
+        let mut last_err = Ndt7Error::NoTargets;
+        for t in self.get_latency_targets().await?.iter() {
+            let Some(url) = t.service_url(scheme, latency::AUTHORIZE_URL_PATH) else {
+                continue;
+            };
+            match self.authorize(url).await {
+                Ok(session) => return Ok(session),
+                Err(e) => {
+                    last_err = e;
+                }
+            }
+        }
+        Err(last_err)
+    }
+
+    async fn authorize(&self, url: &str) -> Result<(LatencyPacket, String)> {
+        let mut url = self.service_url(url)?;
+        url.query_pairs_mut()
+            .append_pair("mid", &msak::measurement_id());
+        let host = url
+            .host_str()
+            .ok_or(Ndt7Error::ServiceUnsupported("missing host in URL".into()))?
+            .to_string();
+        let kickoff = io_timeout(async {
+            self.config
+                .http
+                .get(url)
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<LatencyPacket>()
+                .await
+        })
+        .await??;
+        Ok((kickoff, host))
+    }
+
+    /// Locate latency1 servers on first use, like [`Client::targets`].
+    async fn get_latency_targets(&self) -> Result<Arc<[Target]>> {
+        self.located(&self.latency_targets, locate::MSAK_LATENCY1_URL)
+            .await
+    }
+
+    /// Locate throughput1 servers on first use, like [`Client::targets`].
+    async fn get_msak_targets(&self) -> Result<Arc<[Target]>> {
+        self.located(&self.msak_targets, locate::MSAK_THROUGHPUT1_URL)
+            .await
+    }
+
+    /// The servers in `cache`, or those located afresh through the Locate
+    /// API at `url` once the cached access tokens are about to expire.
+    async fn located(
+        &self,
+        cache: &tokio::sync::Mutex<Option<Located>>,
+        url: &str,
+    ) -> Result<Arc<[Target]>> {
+        let mut located = cache.lock().await;
+        if let Some(located) = located.as_ref().filter(|l| l.is_fresh()) {
+            return Ok(located.targets.clone());
+        }
+        let targets =
+            locate::nearest_with(&self.config.http, url, &self.config.locate_query).await?;
+        let targets = self.config.server_filter.apply(targets);
+        Ok(located.insert(Located::new(targets)).targets.clone())
+    }
+
+    /// The ndt7 servers found through the Locate API that pass the
+    /// [`ClientBuilder::server_filter`], nearest first, or just the machine
+    /// set with [`ClientBuilder::monitoring`].
+    ///
+    /// Servers are located on first use and reused until the access tokens
+    /// in their URLs are about to expire, then located again. Concurrent
+    /// callers share one lookup, and clones of the client share the result.
+    ///
+    /// If the Locate API fails, the [`ClientBuilder::fallback_servers`] are
+    /// returned instead, and the next call tries the Locate API again.
+    pub async fn targets(&self) -> Result<Arc<[Target]>> {
+        let mut located = self.targets.lock().await;
+        if let Some(located) = located.as_ref().filter(|l| l.is_fresh()) {
+            return Ok(located.targets.clone());
+        }
+        let targets = match &self.config.monitoring {
+            Some(m) => {
+                let url = locate::MONITORING_URL;
+                vec![locate::monitoring_with(&self.config.http, url, &m.machine, &m.token).await?]
+            }
+            None => match locate::nearest_with(
+                &self.config.http,
+                locate::LOCATE_URL,
+                &self.config.locate_query,
+            )
+            .await
+            {
+                Ok(targets) => self.config.server_filter.apply(targets),
+                Err(_) if !self.config.fallback.is_empty() => {
+                    return Ok(self.config.fallback.clone().into());
+                }
+                Err(e) => return Err(e),
+            },
+        };
+        let targets = located.insert(Located::new(targets)).targets.clone();
+        Ok(targets)
+    }
+
+    /// Every ndt7 server the Locate API returns for the client's
+    /// [`LocateQuery`] and [`ServerFilter`], nearest first, with all their
+    /// metadata, e.g. for choosing a server manually.
+    ///
+    /// Unlike [`Client::targets`], the servers are located afresh on every
+    /// call, the result is not reused by tests, and neither the monitoring
+    /// machine nor the fallback servers are used.
+    pub async fn list_targets(&self) -> Result<Vec<Target>> {
+        let targets = locate::nearest_with(
+            &self.config.http,
+            locate::LOCATE_URL,
+            &self.config.locate_query,
+        )
+        .await?;
+        Ok(self.config.server_filter.apply(targets))
+    }
+
+    /// A client testing against `targets` only instead of located servers,
+    /// sharing the configuration of this one, e.g. to test against each of
+    /// the servers returned by [`Client::targets`] in turn.
+    ///
+    /// The servers are used as given even after the access tokens in their
+    /// URLs expire.
+    pub fn with_targets(&self, targets: Vec<Target>) -> Client {
+        let located = Located {
+            targets: targets.into(),
+            expiry: None,
+        };
+        Client {
+            targets: Arc::new(tokio::sync::Mutex::new(Some(located))),
+            rotation: Arc::default(),
+            ..self.clone()
+        }
+    }
+
+    /// Index of the located server a `test` starts at under the client's
+    /// [`TargetPolicy`], or at the `probed` fastest server.
+    fn target_start(&self, test: TestKind, len: usize, probed: Option<usize>) -> usize {
+        let mut rotation = self.rotation.lock().unwrap_or_else(|e| e.into_inner());
+        if test == TestKind::Upload
+            && let Some(start) = rotation.pending.take()
+        {
+            return start;
+        }
+        let start = probed.unwrap_or_else(|| self.config.target_policy.start(rotation.runs, len));
+        rotation.runs += 1;
+        if test == TestKind::Download {
+            rotation.pending = Some(start);
+        }
+        start
+    }
+
+    /// Whether `test` is an upload that reuses the server of the download
+    /// before it, see [`Rotation::pending`].
+    fn upload_pending(&self, test: TestKind) -> bool {
+        let rotation = self.rotation.lock().unwrap_or_else(|e| e.into_inner());
+        test == TestKind::Upload && rotation.pending.is_some()
+    }
+
+    /// Index of the server with the shortest TCP connect time for `test`
+    /// among the first `count` of `targets`, or 0 if none could be reached.
+    async fn fastest_target(
+        &self,
+        targets: &[Target],
+        count: usize,
+        test: TestKind,
+        scheme: &str,
+    ) -> usize {
+        let probes = targets.iter().take(count).map(|t| async move {
+            let urls = t.service_urls(scheme);
+            let url = match test {
+                TestKind::Download => urls.download,
+                TestKind::Upload => urls.upload,
+            };
+            let url = Url::parse(&url?).ok()?;
+            timeout(params::TARGET_PROBE_TIMEOUT, self.connect_time(&url))
+                .await
+                .ok()?
+                .ok()
+        });
+        join_all(probes)
+            .await
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, rtt)| Some((rtt?, i)))
+            .min()
+            .map_or(0, |(_, i)| i)
+    }
+
+    /// Time to establish a TCP connection to the server of `url`, which
+    /// is closed right away.
+    async fn connect_time(&self, url: &Url) -> Result<Duration> {
+        if self.config.proxy.is_some() {
+            let start = Instant::now();
+            self.connect_tcp(url).await?;
+            return Ok(start.elapsed());
+        }
+        let addr = self.resolve(url).await?;
+        let socket = self.tcp_socket(addr)?;
+        let start = Instant::now();
+        socket.connect(addr).await?;
+        Ok(start.elapsed())
+    }
+
+    fn user_agent(&self) -> String {
+        user_agent(&self.config.client_name, &self.config.client_version)
     }
 
-    /// Set the preferred IP address family for connections.
-    pub fn address_family(mut self, af: AddressFamily) -> Self {
-        self.address_family = af;
-        self
+    /// Upload payload for the next test. A reproducible payload is the same
+    /// for every test, so it is generated once and shared.
+    async fn upload_corpus(&self) -> Result<Bytes> {
+        let payload = &self.config.payload;
+        let size = self.config.test_params.max_message_size;
+        if !payload.is_reproducible() {
+            return payload.corpus_of_size(size);
+        }
+        self.corpus
+            .get_or_try_init(|| async { payload.corpus_of_size(size) })
+            .await
+            .cloned()
     }
 
-    /// Build the [`Client`].
-    pub fn build(self) -> Client {
-        Client {
-            client_name: self.client_name,
-            client_version: self.client_version,
-            no_verify_tls: self.no_verify_tls,
-            no_tls: self.no_tls,
-            address_family: self.address_family,
-            targets: None,
-        }
+    #[cfg(test)]
+    pub(crate) fn set_targets(&mut self, targets: Vec<Target>) {
+        self.targets = Arc::new(tokio::sync::Mutex::new(Some(Located::new(targets))));
     }
 }
 
-impl Client {
-    /// Establish a WebSocket connection to the given service URL.
-    ///
-    /// `service_url` is the full URL from the Locate API, e.g.
-    /// "wss://mlab1-lga06:4443/ndt/v7/download?access_token=..."
-    pub async fn connect(&self, service_url: &str) -> Result<WsStream> {
-        // Parse the URL and append client metadata as query parameters.
-        let mut url = Url::parse(service_url)?;
-        url.query_pairs_mut()
-            .append_pair("client_name", &self.client_name)
-            .append_pair("client_version", &self.client_version)
-            .append_pair("client_os", std::env::consts::OS)
-            .append_pair("client_arch", std::env::consts::ARCH)
-            .append_pair(
-                "client_library_name",
-                &format!("{}-rs", env!("CARGO_PKG_NAME")),
-            )
-            .append_pair("client_library_version", env!("CARGO_PKG_VERSION"));
+/// Build the TLS configuration shared by all connections of a client, or
+/// `None` if certificates are verified but no root certificates are
+/// available to verify them against.
+pub(crate) fn tls_config(
+    no_verify_tls: bool,
+    extra_roots: impl IntoIterator<Item = CertificateDer<'static>>,
+) -> Option<Arc<rustls::ClientConfig>> {
+    let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+    let tls_config = if no_verify_tls {
+        rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerifier))
+            .with_no_client_auth()
+    } else {
+        let root_store = root_store(extra_roots);
+        if root_store.is_empty() {
+            return None;
+        }
+        rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(root_store)
+            .with_no_client_auth()
+    };
+    Some(Arc::new(tls_config))
+}
 
-        // Build the HTTP request with required headers.
-        let mut request = url.to_string().into_client_request()?;
-        request.headers_mut().insert(
-            "Sec-WebSocket-Protocol",
-            params::SEC_WEBSOCKET_PROTOCOL.parse().unwrap(),
-        );
-        request
-            .headers_mut()
-            .insert("User-Agent", self.user_agent().parse().unwrap());
+/// HTTP client identifying as `user_agent` and verifying servers with
+/// `tls`, or with reqwest's defaults if there are no root certificates,
+/// connecting from `local` and through `interface`. Without `proxy`,
+/// proxies are taken from the environment.
+fn http_client(
+    user_agent: &str,
+    tls: Option<&rustls::ClientConfig>,
+    proxy: Option<&Proxy>,
+    local: Option<IpAddr>,
+    #[cfg(target_os = "linux")] interface: Option<&str>,
+) -> reqwest::Result<reqwest::Client> {
+    let builder = reqwest::Client::builder()
+        .user_agent(user_agent)
+        .local_address(local);
+    #[cfg(target_os = "linux")]
+    let builder = match interface {
+        Some(name) => builder.interface(name),
+        None => builder,
+    };
+    let builder = match proxy {
+        Some(proxy) => builder.proxy(reqwest::Proxy::all(proxy.url())?),
+        None => builder,
+    };
+    let builder = match tls {
+        Some(tls) => builder.tls_backend_preconfigured(tls.clone()),
+        None => builder,
+    };
+    builder.build()
+}
 
-        timeout(params::IO_TIMEOUT, self.connect_ws(request, &url)).await?
-    }
+/// Host, without the brackets of an IPv6 address, and port of `url`.
+fn host_port(url: &Url) -> Result<(&str, u16)> {
+    let host = url
+        .host_str()
+        .ok_or(Ndt7Error::ServiceUnsupported("missing host in URL".into()))?;
+    let port = url
+        .port_or_known_default()
+        .ok_or(Ndt7Error::ServiceUnsupported("missing port".into()))?;
+    let host = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host);
+    Ok((host, port))
+}
 
-    async fn connect_ws(&self, request: Request<()>, url: &Url) -> Result<WsStream> {
-        let connector = (url.scheme() == "wss").then(|| self.tls_connector());
+/// User agent of requests to the Locate API, naming the application and
+/// this library.
+fn user_agent(client_name: &str, client_version: &str) -> String {
+    format!(
+        "{client_name}/{client_version} {}-rs/{}",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION")
+    )
+}
 
-        // DNS resolution
-        let host = url
-            .host_str()
-            .ok_or(Ndt7Error::ServiceUnsupported("missing host in URL".into()))?;
-        let port = url
-            .port_or_known_default()
-            .ok_or(Ndt7Error::ServiceUnsupported("missing port".into()))?;
-        let addrs = tokio::net::lookup_host((host, port)).await?;
+/// Collect the root certificates from the enabled sources and `extra_roots`.
+fn root_store(extra_roots: impl IntoIterator<Item = CertificateDer<'static>>) -> RootCertStore {
+    let mut store = RootCertStore::empty();
+    #[cfg(feature = "webpki-roots")]
+    store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    #[cfg(feature = "native-roots")]
+    store.add_parsable_certificates(rustls_native_certs::load_native_certs().certs);
+    store.add_parsable_certificates(extra_roots);
+    store
+}
 
-        // Filter by address family
-        let addr = self
-            .address_family
-            .select_addr(addrs)
-            .ok_or(Ndt7Error::NoAddressFound(self.address_family))?;
+/// Parse the certificates in a PEM bundle, which must hold at least one.
+fn parse_root_certificates(
+    pem: &[u8],
+) -> std::result::Result<Vec<CertificateDer<'static>>, ConfigError> {
+    let certs = CertificateDer::pem_slice_iter(pem)
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|e| ConfigError::InvalidRootCertificates(e.to_string()))?;
+    if certs.is_empty() {
+        return Err(ConfigError::InvalidRootCertificates(
+            "no certificates found".into(),
+        ));
+    }
+    Ok(certs)
+}
 
-        // TCP + TLS + WebSocket
-        let tcp = TcpStream::connect(addr).await?;
-        let (ws_stream, _response) =
-            client_async_tls_with_config(request, tcp, None, connector).await?;
+/// Point in time after which a client stops running tests.
+#[derive(Debug, Clone, Copy)]
+struct Deadline {
+    started: Instant,
+    at: Instant,
+}
 
-        Ok(ws_stream)
+impl Deadline {
+    fn start(limit: Duration) -> Deadline {
+        let started = Instant::now();
+        Deadline {
+            started,
+            at: started + limit,
+        }
     }
 
-    /// Start a download test and return a channel of [`Measurement`] results.
-    ///
-    /// The test runs in a background task. Each item is `Ok(measurement)` or
-    /// `Err(error)` if the test fails mid-stream. An error is always the last
-    /// item - the channel closes immediately after.
-    pub async fn start_download(&mut self, url: Option<&str>) -> Result<TestHandle> {
-        let (ws, server_fqdn) = self.connect_with_retry(url, TestKind::Download).await?;
-        let (tx, rx) = mpsc::channel(64);
-        tokio::spawn(async move {
-            download::run(ws, tx).await;
-        });
-        Ok(TestHandle { server_fqdn, rx })
+    fn exceeded(&self) -> Ndt7Error {
+        Ndt7Error::TestDeadline {
+            elapsed: self.started.elapsed(),
+        }
     }
+}
 
-    /// Start an upload test and return a channel of [`Measurement`] results.
-    ///
-    /// The test runs in a background task. Each item is `Ok(measurement)` or
-    /// `Err(error)` if the test fails mid-stream. An error is always the last
-    /// item - the channel closes immediately after.
-    pub async fn start_upload(&mut self, url: Option<&str>) -> Result<TestHandle> {
-        let (ws, server_fqdn) = self.connect_with_retry(url, TestKind::Upload).await?;
-        let (tx, rx) = mpsc::channel(64);
-        tokio::spawn(async move {
-            upload::run(ws, tx).await;
-        });
-        Ok(TestHandle { server_fqdn, rx })
+/// Await `fut`, failing with [`Ndt7Error::TestDeadline`] once `deadline` passes.
+async fn with_deadline<T>(
+    deadline: Option<Deadline>,
+    fut: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    match deadline {
+        Some(deadline) if Instant::now() >= deadline.at => Err(deadline.exceeded()),
+        Some(deadline) => timeout_at(deadline.at, fut)
+            .await
+            .map_err(|_| deadline.exceeded())?,
+        None => fut.await,
     }
+}
 
-    async fn connect_with_retry(
-        &mut self,
-        url: Option<&str>,
-        test_kind: TestKind,
-    ) -> Result<(WsStream, String)> {
-        if let Some(url) = url {
-            let ws = self.connect(url).await?;
-            let fqdn = Url::parse(url)?.host_str().unwrap_or("unknown").to_string();
-            Ok((ws, fqdn))
-        } else {
-            let scheme = if self.no_tls { "ws" } else { "wss" };
-            let mut last_err = Ndt7Error::NoTargets;
-            let targets = self.get_targets().await?.to_vec();
-            for t in &targets {
-                let url = match test_kind {
-                    TestKind::Download => t.service_urls(scheme).download,
-                    TestKind::Upload => t.service_urls(scheme).upload,
-                };
-                let Some(url) = url else { continue };
-                match self.connect(&url).await {
-                    Ok(ws) => return Ok((ws, t.machine.clone())),
-                    Err(e) => {
-                        last_err = e;
-                    }
-                }
+/// Await one step of a running test, failing with
+/// [`Ndt7Error::StallTimeout`] if it makes no progress within
+/// [`params::IO_TIMEOUT`].
+pub(crate) async fn io_timeout<T>(fut: impl Future<Output = T>) -> Result<T> {
+    timeout(params::IO_TIMEOUT, fut)
+        .await
+        .map_err(|_| Ndt7Error::StallTimeout {
+            elapsed: params::IO_TIMEOUT,
+        })
+}
+
+/// Run a test in a background task. If `deadline` passes first, the test is
+/// dropped (closing its connection) and [`Ndt7Error::TestDeadline`] is
+/// sent as the final item on `tx`.
+fn spawn_test<T: Send + 'static>(
+    deadline: Option<Deadline>,
+    tx: mpsc::Sender<Result<T>>,
+    test: impl Future<Output = ()> + Send + 'static,
+) {
+    tokio::spawn(async move {
+        // Stop once nobody listens for the results, e.g. a cancelled caller.
+        let test = async {
+            tokio::select! {
+                () = test => {}
+                () = tx.closed() => {}
             }
-            Err(last_err)
+        };
+        let Some(deadline) = deadline else {
+            return test.await;
+        };
+        if timeout_at(deadline.at, test).await.is_err() {
+            let _ = tx.send(Err(deadline.exceeded())).await;
         }
-    }
+    });
+}
 
-    async fn get_targets(&mut self) -> Result<&[Target]> {
-        if self.targets.is_none() {
-            self.targets = Some(locate::nearest(&self.user_agent()).await?);
-        }
-        Ok(self.targets.as_deref().unwrap())
-    }
+#[cfg(not(any(
+    target_os = "fuchsia",
+    target_os = "redox",
+    target_os = "solaris",
+    target_os = "illumos",
+    target_os = "haiku"
+)))]
+fn set_tos_v4(socket: &TcpSocket, tos: u32) -> std::io::Result<()> {
+    socket.set_tos_v4(tos)
+}
 
-    fn tls_connector(&self) -> Connector {
-        let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
-        let tls_config = if self.no_verify_tls {
-            rustls::ClientConfig::builder_with_provider(provider)
-                .with_safe_default_protocol_versions()
-                .unwrap()
-                .dangerous()
-                .with_custom_certificate_verifier(Arc::new(NoVerifier))
-                .with_no_client_auth()
-        } else {
-            let root_store =
-                rustls::RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
-            rustls::ClientConfig::builder_with_provider(provider)
-                .with_safe_default_protocol_versions()
-                .unwrap()
-                .with_root_certificates(root_store)
-                .with_no_client_auth()
-        };
-        Connector::Rustls(Arc::new(tls_config))
-    }
+#[cfg(any(
+    target_os = "fuchsia",
+    target_os = "redox",
+    target_os = "solaris",
+    target_os = "illumos",
+    target_os = "haiku"
+))]
+fn set_tos_v4(_socket: &TcpSocket, _tos: u32) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "IP_TOS is not supported on this platform",
+    ))
+}
 
-    fn user_agent(&self) -> String {
-        format!(
-            "{}/{} {}-rs/{}",
-            &self.client_name,
-            &self.client_version,
-            env!("CARGO_PKG_NAME"),
-            env!("CARGO_PKG_VERSION")
-        )
-    }
+#[cfg(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "fuchsia",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd",
+))]
+fn set_tclass_v6(socket: &TcpSocket, tclass: u32) -> std::io::Result<()> {
+    socket.set_tclass_v6(tclass)
+}
 
-    #[cfg(test)]
-    fn set_targets(&mut self, targets: Vec<Target>) {
-        self.targets = Some(targets);
+#[cfg(not(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "fuchsia",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd",
+)))]
+fn set_tclass_v6(_socket: &TcpSocket, _tclass: u32) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "IPV6_TCLASS is not supported on this platform",
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn set_notsent_lowat(ws: &WsStream, lowat: u32) -> Result<()> {
+    if let Some(tcp) = ws.tcp_stream() {
+        socket2::SockRef::from(tcp).set_tcp_notsent_lowat(lowat)?;
     }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_util::accept_ndt7;
 
     use futures_util::{SinkExt, StreamExt};
     use std::{collections::HashMap, net::SocketAddr};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
     use tokio::net::TcpListener;
     use tokio_tungstenite::tungstenite::Message;
-    use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
 
     fn addr(s: &str) -> SocketAddr {
         s.parse().unwrap()
@@ -399,6 +2005,61 @@ mod tests {
         assert_eq!(AddressFamily::Ipv6Only.select_addr(addrs.into_iter()), None);
     }
 
+    #[test]
+    fn service_url_includes_probe_identity() {
+        let client = ClientBuilder::new("test", "1.0")
+            .probe_identity(ProbeIdentity::new("probe-1").with_deployment("fleet-a"))
+            .build();
+        let url = client
+            .service_url("ws://localhost/ndt/v7/download?access_token=abc")
+            .unwrap();
+        let pairs: HashMap<_, _> = url.query_pairs().into_owned().collect();
+
+        assert_eq!(pairs["access_token"], "abc");
+        assert_eq!(pairs["client_name"], "test");
+        assert_eq!(pairs["probe_id"], "probe-1");
+        assert_eq!(pairs["deployment_id"], "fleet-a");
+    }
+
+    #[test]
+    fn tcp_socket_applies_buffer_sizes() {
+        let client = ClientBuilder::new("test", "1.0")
+            .send_buffer_size(256 * 1024)
+            .recv_buffer_size(128 * 1024)
+            .build();
+        let socket = client.tcp_socket(addr("127.0.0.1:443")).unwrap();
+
+        // Kernels may round up (Linux doubles the value for bookkeeping).
+        assert!(socket.send_buffer_size().unwrap() >= 256 * 1024);
+        assert!(socket.recv_buffer_size().unwrap() >= 128 * 1024);
+    }
+
+    #[test]
+    fn tcp_socket_binds_local_address() {
+        let client = ClientBuilder::new("test", "1.0")
+            .local_address(Ipv4Addr::LOCALHOST.into())
+            .build();
+        let socket = client.tcp_socket(addr("127.0.0.1:443")).unwrap();
+
+        assert_eq!(socket.local_addr().unwrap().ip(), Ipv4Addr::LOCALHOST);
+        assert_eq!(client.config.family(), AddressFamily::Ipv4Only);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn tcp_socket_applies_dscp() {
+        let client = ClientBuilder::new("test", "1.0").dscp(46).build();
+
+        let socket = client.tcp_socket(addr("127.0.0.1:443")).unwrap();
+        assert_eq!(socket.tos_v4().unwrap(), 46 << 2);
+
+        let url = client
+            .service_url("ws://localhost/ndt/v7/download")
+            .unwrap();
+        let pairs: HashMap<_, _> = url.query_pairs().into_owned().collect();
+        assert_eq!(pairs["client_dscp"], "46");
+    }
+
     async fn mock_refusing_server() -> std::net::SocketAddr {
         let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
         let addr = listener.local_addr().unwrap();
@@ -416,18 +2077,7 @@ mod tests {
 
         tokio::spawn(async move {
             let (stream, _) = listener.accept().await.unwrap();
-            #[allow(clippy::result_large_err)]
-            let ws_stream =
-                tokio_tungstenite::accept_hdr_async(stream, |req: &Request, mut resp: Response| {
-                    // to mitigate SecWebSocketSubProtocolError(NoSubProtocol)
-                    if let Some(proto) = req.headers().get("Sec-WebSocket-Protocol") {
-                        resp.headers_mut()
-                            .insert("Sec-WebSocket-Protocol", proto.clone());
-                    }
-                    Ok(resp)
-                })
-                .await
-                .unwrap();
+            let ws_stream = accept_ndt7(stream).await;
             let (mut sink, _stream) = ws_stream.split();
             sink.send(Message::Text(
                 r#"{"AppInfo":{"ElapsedTime":1000,"NumBytes":8192}}"#.into(),
@@ -440,6 +2090,18 @@ mod tests {
         addr
     }
 
+    async fn mock_no_subprotocol_server() -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            // Completes the handshake without echoing Sec-WebSocket-Protocol.
+            let _ = tokio_tungstenite::accept_async(stream).await;
+        });
+        addr
+    }
+
     fn local_target(addr: &std::net::SocketAddr) -> Target {
         let machine = addr.ip().to_string();
         let urls = HashMap::from([(
@@ -449,10 +2111,77 @@ mod tests {
         Target {
             machine,
             urls,
-            location: None,
+            ..Default::default()
         }
     }
 
+    /// An address nothing listens on: the port of a dropped listener.
+    async fn closed_port() -> std::net::SocketAddr {
+        TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_health_check_skips_dead_server() {
+        let dead_server = closed_port().await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let good_server = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            // The health check connects first, then the test.
+            drop(listener.accept().await.unwrap());
+            let (stream, _) = listener.accept().await.unwrap();
+            let _ws = accept_ndt7(stream).await;
+            futures_util::future::pending::<()>().await;
+        });
+        let mut client = ClientBuilder::new("test", "test")
+            .no_tls()
+            .health_check()
+            .build();
+        client.set_targets(vec![local_target(&dead_server), local_target(&good_server)]);
+
+        let handle = client.start_download(None).await.unwrap();
+        assert_eq!(handle.connect_info.status, 101);
+        assert_eq!(handle.unhealthy.len(), 1);
+        assert_eq!(handle.unhealthy[0].fqdn, dead_server.ip().to_string());
+    }
+
+    #[tokio::test]
+    async fn test_download_through_proxy() {
+        let server = mock_server().await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let connect = tokio::spawn(async move {
+            let (mut client, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                request.push(client.read_u8().await.unwrap());
+            }
+            let mut upstream = TcpStream::connect(server).await.unwrap();
+            client.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await.unwrap();
+            tokio::spawn(async move {
+                let _ = tokio::io::copy_bidirectional(&mut client, &mut upstream).await;
+            });
+            String::from_utf8(request).unwrap()
+        });
+
+        let mut client = ClientBuilder::new("test", "test")
+            .no_tls()
+            .proxy(format!("http://{proxy}"))
+            .build();
+        client.set_targets(vec![local_target(&server)]);
+        let handle = client.start_download(None).await.unwrap();
+        let mut rx = handle.rx;
+        let first = rx.recv().await.unwrap().unwrap();
+
+        assert_eq!(handle.connect_info.peer_addr, None);
+        assert!(first.app_info.is_some());
+        let request = connect.await.unwrap();
+        assert!(request.starts_with(&format!("CONNECT {server} HTTP/1.1\r\n")));
+    }
+
     #[tokio::test]
     async fn test_retry() {
         let bad_server = mock_refusing_server().await;
@@ -466,6 +2195,13 @@ mod tests {
         let handle = client.start_download(None).await.unwrap();
 
         assert_eq!(handle.server_fqdn, good_server.ip().to_string());
+        assert_eq!(handle.connect_info.status, 101);
+        assert_eq!(handle.connect_info.peer_addr, Some(good_server));
+        assert!(handle.connect_info.local_addr.is_some());
+        assert_eq!(
+            handle.connect_info.subprotocol.as_deref(),
+            Some(params::SEC_WEBSOCKET_PROTOCOL)
+        );
         let mut rx = handle.rx;
 
         while let Some(result) = rx.recv().await {
@@ -476,10 +2212,274 @@ mod tests {
         assert!(results[0].is_ok());
     }
 
+    #[test]
+    fn try_build_validates() {
+        assert!(
+            ClientBuilder::new("test", "1.0")
+                .dscp(46)
+                .try_build()
+                .is_ok()
+        );
+
+        let err = |b: ClientBuilder| b.try_build().err().unwrap();
+        assert_eq!(
+            err(ClientBuilder::new("test", "1.0").dscp(64)),
+            ConfigError::DscpOutOfRange(64)
+        );
+        assert_eq!(
+            err(ClientBuilder::new("", "1.0")),
+            ConfigError::Empty("client_name")
+        );
+        assert_eq!(
+            err(ClientBuilder::new("test", "1.0").recv_buffer_size(0)),
+            ConfigError::Zero("recv_buffer_size")
+        );
+        assert_eq!(
+            err(ClientBuilder::new("test", "1.0").deadline(Duration::ZERO)),
+            ConfigError::Zero("deadline")
+        );
+        assert_eq!(
+            err(ClientBuilder::new("test", "1.0").upload_duration(Duration::ZERO)),
+            ConfigError::Zero("upload_duration")
+        );
+        assert_eq!(
+            err(ClientBuilder::new("test", "1.0")
+                .local_address(Ipv4Addr::LOCALHOST.into())
+                .address_family(AddressFamily::Ipv6Only)),
+            ConfigError::Conflict("local_address", "address_family")
+        );
+        assert_eq!(
+            err(ClientBuilder::new("test", "1.0").upload_rate(0)),
+            ConfigError::Zero("upload_rate")
+        );
+        assert_eq!(
+            err(ClientBuilder::new("test", "1.0").max_message_size(2 << 20)),
+            ConfigError::TooLarge("max_message_size", params::MAX_MESSAGE_SIZE)
+        );
+        assert_eq!(
+            err(ClientBuilder::new("test", "1.0").measurement_interval(
+                MeasurementInterval::Adaptive {
+                    min: Duration::from_secs(2),
+                    max: Duration::from_secs(1),
+                }
+            )),
+            ConfigError::InvalidRange("measurement_interval")
+        );
+        assert_eq!(
+            err(ClientBuilder::new("test", "1.0").no_tls().no_verify_tls()),
+            ConfigError::Conflict("no_verify_tls", "no_tls")
+        );
+        assert_eq!(
+            err(ClientBuilder::new("test", "1.0").payload(PayloadConfig {
+                cache_path: Some("corpus".into()),
+                ..Default::default()
+            })),
+            ConfigError::Requires("cache_path", "seed")
+        );
+        assert!(matches!(
+            err(ClientBuilder::new("test", "1.0").root_certificates_pem(b"not a certificate")),
+            ConfigError::InvalidRootCertificates(_)
+        ));
+        assert!(matches!(
+            err(ClientBuilder::new("test", "1.0").fallback_servers(["http://"])),
+            ConfigError::InvalidServer(_)
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid proxy")]
+    fn build_panics_on_invalid_proxy() {
+        ClientBuilder::new("test", "1.0")
+            .proxy("ftp://proxy.example")
+            .build();
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid root certificates")]
+    fn build_panics_on_invalid_root_certificates() {
+        ClientBuilder::new("test", "1.0")
+            .root_certificates_pem(b"not a certificate")
+            .build();
+    }
+
+    #[tokio::test]
+    async fn test_clone_shared_across_tasks() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Client>();
+
+        let server = mock_server().await;
+        let mut client = ClientBuilder::new("test", "test").no_tls().build();
+        client.set_targets(vec![local_target(&server)]);
+
+        // Clones share configuration and the located servers.
+        let shared = client.clone();
+        assert!(Arc::ptr_eq(&client.config, &shared.config));
+        assert!(Arc::ptr_eq(&client.targets, &shared.targets));
+
+        let handle = tokio::spawn(async move { shared.start_download(None).await })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(handle.server_fqdn, server.ip().to_string());
+    }
+
+    #[test]
+    fn test_http_client_shares_tls() {
+        let tls = tls_config(true, []).unwrap();
+        let local = Some(Ipv4Addr::LOCALHOST.into());
+        #[cfg(target_os = "linux")]
+        assert!(http_client("test", Some(&tls), None, local, None).is_ok());
+        #[cfg(not(target_os = "linux"))]
+        assert!(http_client("test", Some(&tls), None, local).is_ok());
+    }
+
+    #[test]
+    fn test_located_expiry() {
+        let mut located = Located::new(Vec::new());
+        assert!(located.is_fresh());
+        located.expiry = Some(SystemTime::now() + Duration::from_secs(60));
+        assert!(located.is_fresh());
+        // Too close to the expiry for another connection attempt.
+        located.expiry = Some(SystemTime::now() + Duration::from_secs(5));
+        assert!(!located.is_fresh());
+    }
+
+    #[test]
+    fn test_round_robin_pairs_subtests() {
+        let client = ClientBuilder::new("test", "test")
+            .target_policy(TargetPolicy::RoundRobin)
+            .build();
+        let starts: Vec<_> = [
+            TestKind::Download,
+            TestKind::Upload,
+            TestKind::Download,
+            TestKind::Upload,
+            TestKind::Upload,
+        ]
+        .into_iter()
+        .map(|test| client.target_start(test, 3, None))
+        .collect();
+        // An upload goes to the server of the download before it.
+        assert_eq!(starts, [0, 0, 1, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_fastest_target_skips_unreachable() {
+        let closed = closed_port().await;
+        let good_server = mock_server().await;
+        let targets = [local_target(&closed), local_target(&good_server)];
+        let client = ClientBuilder::new("test", "test")
+            .target_policy(TargetPolicy::Fastest(2))
+            .build();
+        let fastest = client
+            .fastest_target(&targets, 2, TestKind::Download, "ws")
+            .await;
+        assert_eq!(fastest, 1);
+        // Only the unreachable server is probed.
+        let fastest = client
+            .fastest_target(&targets, 1, TestKind::Download, "ws")
+            .await;
+        assert_eq!(fastest, 0);
+    }
+
+    async fn mock_slow_server() -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let ws_stream = accept_ndt7(stream).await;
+            let (mut sink, _stream) = ws_stream.split();
+            loop {
+                sink.send(Message::Binary(vec![0u8; 1024].into()))
+                    .await
+                    .unwrap();
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_deadline_aborts_test() {
+        let server = mock_slow_server().await;
+        let client = ClientBuilder::new("test", "test")
+            .no_tls()
+            .deadline(Duration::from_millis(300))
+            .build();
+
+        let handle = client
+            .start_download(Some(&format!("ws://{server}/ndt/v7/download")))
+            .await
+            .unwrap();
+        let mut rx = handle.rx;
+        let mut last = None;
+        while let Some(result) = rx.recv().await {
+            last = Some(result);
+        }
+        assert!(matches!(last, Some(Err(Ndt7Error::TestDeadline { .. }))));
+
+        // Once expired, new tests fail to start.
+        let result = client
+            .start_upload(Some("ws://127.0.0.1:1/ndt/v7/upload"))
+            .await;
+        assert!(matches!(result, Err(Ndt7Error::TestDeadline { .. })));
+    }
+
+    #[tokio::test]
+    async fn connect_timeout_measured() {
+        // Accepts connections into the backlog but never answers the
+        // handshake.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = listener.local_addr().unwrap();
+        let client = ClientBuilder::new("test", "test")
+            .no_tls()
+            .connect_timeout(Duration::from_millis(200))
+            .build();
+
+        let result = client
+            .connect(&format!("ws://{server}/ndt/v7/download"))
+            .await;
+        let Err(Ndt7Error::ConnectTimeout { elapsed }) = result else {
+            panic!("expected a connect timeout");
+        };
+        assert!(elapsed >= Duration::from_millis(200));
+        drop(listener);
+    }
+
+    #[tokio::test]
+    async fn test_missing_subprotocol() {
+        let server = mock_no_subprotocol_server().await;
+        let client = ClientBuilder::new("test", "test").no_tls().build();
+
+        let result = client
+            .connect(&format!("ws://{server}/ndt/v7/download"))
+            .await;
+
+        assert!(matches!(result, Err(Ndt7Error::ProtocolViolation(_))));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_notsent_lowat() {
+        let server = mock_server().await;
+        let client = ClientBuilder::new("test", "test").no_tls().build();
+        let (ws, _) = client
+            .connect(&format!("ws://{server}/ndt/v7/upload"))
+            .await
+            .unwrap();
+
+        set_notsent_lowat(&ws, 16384).unwrap();
+
+        let tcp = ws.tcp_stream().unwrap();
+        let lowat = socket2::SockRef::from(tcp).tcp_notsent_lowat().unwrap();
+        assert_eq!(lowat, 16384);
+    }
+
     #[tokio::test]
     #[ignore]
     async fn test_download_real_server() {
-        let mut client = ClientBuilder::new("ndt7-client-rust", env!("CARGO_PKG_VERSION")).build();
+        let client = ClientBuilder::new("ndt7-client-rust", env!("CARGO_PKG_VERSION")).build();
         let handle = client.start_download(None).await.unwrap();
         println!("connected to {}", handle.server_fqdn);
         let mut rx = handle.rx;
@@ -495,7 +2495,7 @@ mod tests {
     #[tokio::test]
     #[ignore]
     async fn test_upload_real_server() {
-        let mut client = ClientBuilder::new("ndt7-client-rust", env!("CARGO_PKG_VERSION")).build();
+        let client = ClientBuilder::new("ndt7-client-rust", env!("CARGO_PKG_VERSION")).build();
         let handle = client.start_upload(None).await.unwrap();
         println!("connected to {}", handle.server_fqdn);
         let mut rx = handle.rx;
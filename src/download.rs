@@ -1,26 +1,65 @@
 //! ndt7 download test implementation.
 //!
 //! Receives binary and text WebSocket messages from the server until the
-//! connection closes or [`params::DOWNLOAD_TIMEOUT`] elapses.
+//! connection closes or [`TestParams::download_duration`] elapses.
 
-use futures_util::StreamExt;
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::{Duration, SystemTime};
+
+use bytes::Bytes;
+use futures_util::{SinkExt, StreamExt};
 use tokio::sync::mpsc;
-use tokio::time::{Instant, timeout};
-use tokio_tungstenite::tungstenite::Message;
+use tokio::time::{Instant, sleep, sleep_until, timeout};
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
 
-use crate::client::WsStream;
-use crate::error::Result;
-use crate::params;
-use crate::spec::{AppInfo, Measurement, Origin, TestKind};
+use crate::client::{SkippedMeasurements, Transport, io_timeout};
+use crate::error::{Ndt7Error, Result};
+use crate::overhead::Framing;
+use crate::params::{MeasurementInterval, TestParams};
+use crate::spec::{AppInfo, ByteCount, Measurement, Micros, Origin, TCPInfo, TestKind, WSPingInfo};
+use crate::tcpinfo::TcpInfoSource;
+use crate::trace::{Direction, WireTrace};
 
 /// Run the download test on an established WebSocket connection.
 ///
 /// Measurements are sent on `tx` as they arrive. If a mid-test error
 /// occurs (connection reset, malformed frame), it is sent as the final
 /// item on the channel before it closes. The function returns when
-/// the server closes the connection or the timeout expires.
-pub async fn run(mut ws: WsStream, tx: mpsc::Sender<Result<Measurement>>) {
-    let result = timeout(params::DOWNLOAD_TIMEOUT, download_loop(&mut ws, &tx)).await;
+/// the server closes the connection, the timeout expires or, if
+/// [`TestParams::max_bytes`] is set, that many bytes have been received and
+/// the connection is closed. Messages are recorded to `trace`, if set.
+/// Malformed server measurements fail the test with strict parsing and are
+/// otherwise added to `skipped`.
+pub async fn run<T: Transport>(
+    mut ws: T,
+    test_params: TestParams,
+    trace: Option<Arc<WireTrace>>,
+    skipped: SkippedMeasurements,
+    tx: mpsc::Sender<Result<Measurement>>,
+) {
+    let tcp_info = TcpInfoSource::new(&ws);
+    let ctx = TestContext {
+        test: TestKind::Download,
+        start: Instant::now(),
+        tcp_info: tcp_info.as_ref(),
+        framing: test_params
+            .wire_overhead
+            .then(|| Framing::new(&ws, TestKind::Download)),
+        trace: trace.as_deref(),
+        strict_parsing: test_params.strict_parsing,
+        skipped: Some(&skipped),
+        utc_timestamps: test_params.utc_timestamps,
+    };
+    let result = timeout(
+        test_params.download_duration,
+        download_loop(&mut ws, &ctx, test_params, &tx),
+    )
+    .await;
+    if let Some(trace) = &trace {
+        trace.flush();
+    }
 
     // Overall timeout (Err) is normal completion, test ran its full duration.
     // Only errors from download_loop (Ok(Err)), like per-message IO timeouts,
@@ -30,54 +69,510 @@ pub async fn run(mut ws: WsStream, tx: mpsc::Sender<Result<Measurement>>) {
     }
 }
 
-async fn download_loop(ws: &mut WsStream, tx: &mpsc::Sender<Result<Measurement>>) -> Result<()> {
-    let start = Instant::now();
-    let mut prev_update = start;
-    let mut total_bytes: i64 = 0;
+/// Bytes received so far, shared between the read path and the ticker.
+#[derive(Default)]
+struct Totals {
+    /// Message payload bytes.
+    payload: AtomicI64,
+    /// Bytes in the TCP stream, counted only when overhead is accounted.
+    stream: AtomicI64,
+}
+
+impl Totals {
+    /// Client measurement of the totals so far.
+    fn measurement(&self, ctx: &TestContext) -> Measurement {
+        ctx.measurement(
+            self.payload.load(Ordering::Relaxed),
+            self.stream.load(Ordering::Relaxed) as u64,
+        )
+    }
+}
+
+/// Read messages while a concurrent ticker reports progress.
+///
+/// The read path only bumps shared byte counters per message; the ticker
+/// snapshots them on the measurement schedule, so building measurements costs
+/// nothing per frame.
+async fn download_loop<T: Transport>(
+    ws: &mut T,
+    ctx: &TestContext<'_>,
+    test_params: TestParams,
+    tx: &mpsc::Sender<Result<Measurement>>,
+) -> Result<()> {
+    let totals = Totals::default();
+    let read = read_messages(ws, ctx, test_params, &totals, tx);
+    let ticker = report_progress(ctx, test_params.measurement_interval, &totals, tx);
+    tokio::select! {
+        result = read => result,
+        never = ticker => match never {},
+    }
+}
 
+async fn read_messages<T: Transport>(
+    ws: &mut T,
+    ctx: &TestContext<'_>,
+    test_params: TestParams,
+    totals: &Totals,
+    tx: &mpsc::Sender<Result<Measurement>>,
+) -> Result<()> {
+    let mut pinger = Pinger::new(test_params.ws_ping_interval, ctx.start);
     loop {
-        let msg = timeout(params::IO_TIMEOUT, ws.next()).await?;
+        let msg = io_timeout(next_message(ws, &mut pinger, ctx)).await?;
         let Some(msg) = msg else { break };
         let msg = msg?;
-        match msg {
-            Message::Binary(data) => {
-                total_bytes += data.len() as i64;
-            }
+        ctx.record(Direction::Received, &msg);
+        let len = match msg {
+            Message::Binary(data) => data.len(),
             Message::Text(text) => {
-                let mut measurement: Measurement = serde_json::from_str(&text)?;
+                ctx.server_measurement(&text, tx).await?;
+                text.len()
+            }
+            Message::Close(frame) => {
+                Ndt7Error::check_close(frame)?;
+                break;
+            }
+            Message::Pong(payload) => {
+                if let Some(m) = ctx.pong(&payload) {
+                    let _ = tx.send(Ok(m)).await;
+                }
+                0
+            }
+            _ => 0, // Pings are answered automatically by tokio-tungstenite
+        };
+        if let Some(framing) = ctx.framing {
+            let stream = framing.stream_bytes(len) as i64;
+            totals.stream.fetch_add(stream, Ordering::Relaxed);
+        }
+        let len = len as i64;
+        let total = totals.payload.fetch_add(len, Ordering::Relaxed) + len;
+        if test_params.max_bytes.is_some_and(|max| total as u64 >= max) {
+            // The final count is always reported, so the summary covers all
+            // data received before the cap.
+            let _ = tx.send(Ok(totals.measurement(ctx))).await;
+            ctx.record(Direction::Sent, &Message::Close(None));
+            let _ = io_timeout(ws.send(Message::Close(None))).await;
+            break;
+        }
+        if let Some(delay) = test_params.download_read_delay {
+            sleep(delay).await;
+        }
+    }
+    Ok(())
+}
+
+/// Next message from `ws`, sending the pings that fall due while waiting.
+async fn next_message<T: Transport>(
+    ws: &mut T,
+    pinger: &mut Pinger,
+    ctx: &TestContext<'_>,
+) -> Option<std::result::Result<Message, WsError>> {
+    loop {
+        tokio::select! {
+            msg = ws.next() => return msg,
+            () = pinger.tick() => {
+                let ping = pinger.ping();
+                ctx.record(Direction::Sent, &ping);
+                if let Err(e) = ws.send(ping).await {
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+/// Send a client measurement of `totals` whenever one is due. Runs until
+/// cancelled.
+async fn report_progress(
+    ctx: &TestContext<'_>,
+    interval: MeasurementInterval,
+    totals: &Totals,
+    tx: &mpsc::Sender<Result<Measurement>>,
+) -> Infallible {
+    let mut updates = UpdateSchedule::new(interval, ctx.start);
+    loop {
+        sleep_until(updates.next_at()).await;
+        if updates.due(totals.payload.load(Ordering::Relaxed)) {
+            let _ = tx.send(Ok(totals.measurement(ctx))).await;
+        }
+    }
+}
+
+/// Relative change of the average throughput between two measurements below
+/// which an adaptive interval grows.
+const STABLE_CHANGE: f64 = 0.05;
+
+/// Decides when client-side measurements are due, shared by the download and
+/// upload loops.
+pub(crate) struct UpdateSchedule {
+    interval: MeasurementInterval,
+    current: Duration,
+    start: Instant,
+    prev: Instant,
+    prev_rate: Option<f64>,
+}
+
+impl UpdateSchedule {
+    pub(crate) fn new(interval: MeasurementInterval, start: Instant) -> Self {
+        let current = match interval {
+            MeasurementInterval::Fixed(interval) => interval,
+            MeasurementInterval::Adaptive { min, .. } => min,
+        };
+        UpdateSchedule {
+            interval,
+            current,
+            start,
+            prev: start,
+            prev_rate: None,
+        }
+    }
+
+    /// Earliest time the next measurement may be due.
+    pub(crate) fn next_at(&self) -> Instant {
+        self.prev + self.current
+    }
+
+    /// Whether a measurement is due after `total_bytes` were transferred.
+    /// An adaptive interval is updated each time one is.
+    pub(crate) fn due(&mut self, total_bytes: i64) -> bool {
+        let now = Instant::now();
+        if now.duration_since(self.prev) < self.current {
+            return false;
+        }
+        self.prev = now;
+        if let MeasurementInterval::Adaptive { min, max } = self.interval {
+            let rate = total_bytes as f64 / now.duration_since(self.start).as_secs_f64();
+            let stable = self
+                .prev_rate
+                .is_some_and(|prev| prev > 0.0 && ((rate - prev) / prev).abs() < STABLE_CHANGE);
+            self.current = if stable {
+                (self.current * 2).min(max.max(min))
+            } else {
+                min
+            };
+            self.prev_rate = Some(rate);
+        }
+        true
+    }
+}
+
+/// Schedules the pings of [`TestParams::ws_ping_interval`], shared by the
+/// download and upload loops.
+///
+/// Each ping carries its send time, which the pong echoes, so round trips are
+/// timed by [`TestContext::pong`] without tracking outstanding pings.
+pub(crate) struct Pinger {
+    interval: Option<Duration>,
+    start: Instant,
+    next: Instant,
+}
+
+impl Pinger {
+    pub(crate) fn new(interval: Option<Duration>, start: Instant) -> Self {
+        Pinger {
+            interval,
+            start,
+            next: start + interval.unwrap_or_default(),
+        }
+    }
+
+    /// Wait until the next ping is due. Never completes if pings are off.
+    pub(crate) async fn tick(&mut self) {
+        let Some(interval) = self.interval else {
+            return std::future::pending().await;
+        };
+        sleep_until(self.next).await;
+        self.next = Instant::now() + interval;
+    }
+
+    /// Whether a ping is due, for loops that cannot wait on [`Pinger::tick`].
+    pub(crate) fn is_due(&mut self) -> bool {
+        let Some(interval) = self.interval else {
+            return false;
+        };
+        let now = Instant::now();
+        if now < self.next {
+            return false;
+        }
+        self.next = now + interval;
+        true
+    }
+
+    /// Ping frame stamped with the current time.
+    pub(crate) fn ping(&self) -> Message {
+        let sent = self.start.elapsed().as_micros() as i64;
+        Message::Ping(Bytes::copy_from_slice(&sent.to_be_bytes()))
+    }
+}
+
+/// Per-test state shared by the download and upload loops.
+pub(crate) struct TestContext<'a> {
+    pub(crate) test: TestKind,
+    pub(crate) start: Instant,
+    pub(crate) tcp_info: Option<&'a TcpInfoSource>,
+    /// Set when overhead on the wire is estimated.
+    pub(crate) framing: Option<Framing>,
+    pub(crate) trace: Option<&'a WireTrace>,
+    /// Fail on a malformed server measurement instead of skipping it.
+    pub(crate) strict_parsing: bool,
+    /// Where skipped server measurements are listed.
+    pub(crate) skipped: Option<&'a SkippedMeasurements>,
+    /// Stamp client measurements with the wall-clock time.
+    pub(crate) utc_timestamps: bool,
+}
+
+impl TestContext<'_> {
+    /// Client-side measurement of `num_bytes` of payload transferred so far,
+    /// carried in `stream_bytes` of TCP payload, with the wire estimate and
+    /// TCP statistics if available.
+    pub(crate) fn measurement(&self, num_bytes: i64, stream_bytes: u64) -> Measurement {
+        let elapsed_time = Micros::from(self.start.elapsed());
+        Measurement {
+            app_info: Some(AppInfo {
+                elapsed_time,
+                num_bytes: ByteCount(num_bytes),
+                wire_bytes: self
+                    .framing
+                    .map(|f| ByteCount(f.wire_bytes(stream_bytes) as i64)),
+                ..Default::default()
+            }),
+            origin: Some(Origin::Client),
+            test: Some(self.test),
+            tcp_info: client_tcp_info(self.tcp_info, elapsed_time),
+            utc_time: self.utc_time(),
+            ..Default::default()
+        }
+    }
+
+    /// Client measurement of the round trip of a pong echoing a [`Pinger`]
+    /// ping, or `None` for other payloads.
+    pub(crate) fn pong(&self, payload: &[u8]) -> Option<Measurement> {
+        let sent = i64::from_be_bytes(payload.try_into().ok()?);
+        let elapsed_time = self.start.elapsed().as_micros() as i64;
+        let rtt = elapsed_time.checked_sub(sent).filter(|&rtt| rtt >= 0)?;
+        Some(Measurement {
+            origin: Some(Origin::Client),
+            test: Some(self.test),
+            ws_ping_info: Some(WSPingInfo {
+                elapsed_time: Micros(elapsed_time),
+                rtt: Micros(rtt),
+                ..Default::default()
+            }),
+            utc_time: self.utc_time(),
+            ..Default::default()
+        })
+    }
+
+    /// Parse a server measurement and send it on `tx`. A malformed one fails
+    /// the test in strict mode; otherwise it is skipped and listed in
+    /// [`TestContext::skipped`].
+    pub(crate) async fn server_measurement(
+        &self,
+        text: &str,
+        tx: &mpsc::Sender<Result<Measurement>>,
+    ) -> Result<()> {
+        match serde_json::from_str::<Measurement>(text) {
+            Ok(mut measurement) => {
                 measurement.origin = Some(Origin::Server);
-                measurement.test = Some(TestKind::Download);
+                measurement.test = Some(self.test);
                 let _ = tx.send(Ok(measurement)).await;
-                total_bytes += text.len() as i64;
             }
-            Message::Close(_) => break,
-            _ => {} // Ping/Pong handled automatically by tokio-tungstenite
-        }
-        if prev_update.elapsed() >= params::UPDATE_INTERVAL {
-            prev_update = Instant::now();
-            let _ = tx
-                .send(Ok(Measurement {
-                    app_info: Some(AppInfo {
-                        elapsed_time: start.elapsed().as_micros() as i64,
-                        num_bytes: total_bytes,
-                    }),
-                    origin: Some(Origin::Client),
-                    test: Some(TestKind::Download),
-                    ..Default::default()
-                }))
-                .await;
+            Err(e) if self.strict_parsing => return Err(e.into()),
+            Err(e) => {
+                if let Some(skipped) = self.skipped {
+                    skipped.push(e.to_string());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The current UTC time, if client measurements are timestamped.
+    fn utc_time(&self) -> Option<String> {
+        self.utc_timestamps
+            .then(|| crate::spec::utc_timestamp(SystemTime::now()))
+    }
+
+    /// Record `msg` to the wire trace, if any.
+    pub(crate) fn record(&self, direction: Direction, msg: &Message) {
+        if let Some(trace) = self.trace {
+            trace.record(self.test, self.start, direction, msg);
         }
     }
-    Ok(())
+}
+
+/// Sample client-side TCP statistics, timestamped like the app-level counters.
+pub(crate) fn client_tcp_info(
+    source: Option<&TcpInfoSource>,
+    elapsed_time: Micros,
+) -> Option<TCPInfo> {
+    let mut info = source?.sample()?;
+    info.elapsed_time = Some(elapsed_time);
+    Some(info)
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::VecDeque;
+    use std::pin::Pin;
+    use std::sync::Mutex;
+    use std::task::{Context, Poll};
 
-    use futures_util::SinkExt;
+    use futures_util::{Sink, SinkExt, Stream};
     use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+    use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
 
     use super::*;
+    use crate::params;
+
+    /// Transport that yields scripted server messages and keeps what the
+    /// client sends.
+    struct Scripted {
+        incoming: VecDeque<Message>,
+        sent: Arc<Mutex<Vec<Message>>>,
+    }
+
+    impl Scripted {
+        fn new(incoming: impl IntoIterator<Item = Message>) -> (Self, Arc<Mutex<Vec<Message>>>) {
+            let sent = Arc::default();
+            let transport = Scripted {
+                incoming: incoming.into_iter().collect(),
+                sent: Arc::clone(&sent),
+            };
+            (transport, sent)
+        }
+    }
+
+    impl Stream for Scripted {
+        type Item = std::result::Result<Message, WsError>;
+
+        fn poll_next(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            Poll::Ready(self.incoming.pop_front().map(Ok))
+        }
+    }
+
+    impl Sink<Message> for Scripted {
+        type Error = WsError;
+
+        fn poll_ready(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<std::result::Result<(), WsError>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(self: Pin<&mut Self>, msg: Message) -> std::result::Result<(), WsError> {
+            self.sent.lock().unwrap().push(msg);
+            Ok(())
+        }
+
+        fn poll_flush(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<std::result::Result<(), WsError>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<std::result::Result<(), WsError>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl Transport for Scripted {}
+
+    #[tokio::test]
+    async fn scripted_server() {
+        let (ws, sent) = Scripted::new([
+            Message::Text(r#"{"AppInfo":{"ElapsedTime":1000,"NumBytes":8192}}"#.into()),
+            Message::Binary(vec![0u8; 4096].into()),
+            Message::Close(Some(CloseFrame {
+                code: CloseCode::Again,
+                reason: "busy".into(),
+            })),
+        ]);
+        let (tx, mut rx) = mpsc::channel(8);
+        run(
+            ws,
+            TestParams::default(),
+            None,
+            SkippedMeasurements::default(),
+            tx,
+        )
+        .await;
+
+        let m = rx.recv().await.unwrap().unwrap();
+        assert_eq!(m.origin, Some(Origin::Server));
+        assert_eq!(m.app_info.unwrap().num_bytes, ByteCount(8192));
+        assert!(matches!(
+            rx.recv().await,
+            Some(Err(Ndt7Error::ServerClosed { code: 1013, .. }))
+        ));
+        assert!(rx.recv().await.is_none());
+        assert!(sent.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn malformed_measurement() {
+        let script = || {
+            Scripted::new([
+                Message::Text(r#"{"AppInfo":{"NumBytes":"many"}}"#.into()),
+                Message::Text(r#"{"AppInfo":{"ElapsedTime":1000,"NumBytes":8192}}"#.into()),
+                Message::Close(None),
+            ])
+            .0
+        };
+
+        // Lenient: skipped and listed, the test goes on.
+        let (tx, mut rx) = mpsc::channel(8);
+        let skipped = SkippedMeasurements::default();
+        run(script(), TestParams::default(), None, skipped.clone(), tx).await;
+        assert_eq!(
+            rx.recv().await.unwrap().unwrap().origin,
+            Some(Origin::Server)
+        );
+        assert!(rx.recv().await.is_none());
+        assert_eq!(skipped.len(), 1);
+        assert!(skipped.errors()[0].contains("invalid type"));
+
+        // Strict: the test fails.
+        let test_params = TestParams {
+            strict_parsing: true,
+            ..Default::default()
+        };
+        let (tx, mut rx) = mpsc::channel(8);
+        run(
+            script(),
+            test_params,
+            None,
+            SkippedMeasurements::default(),
+            tx,
+        )
+        .await;
+        assert!(matches!(
+            rx.recv().await,
+            Some(Err(Ndt7Error::JsonError(_)))
+        ));
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn scripted_max_bytes() {
+        let (ws, sent) = Scripted::new((0..4).map(|_| Message::Binary(vec![0u8; 1024].into())));
+        let test_params = TestParams {
+            max_bytes: Some(2048),
+            ..Default::default()
+        };
+        let (tx, mut rx) = mpsc::channel(8);
+        run(ws, test_params, None, SkippedMeasurements::default(), tx).await;
+
+        let m = rx.recv().await.unwrap().unwrap();
+        assert_eq!(m.app_info.unwrap().num_bytes, ByteCount(2048));
+        assert!(rx.recv().await.is_none());
+        assert_eq!(*sent.lock().unwrap(), [Message::Close(None)]);
+    }
 
     async fn mock_stalling_server() -> std::net::SocketAddr {
         let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
@@ -106,7 +601,16 @@ mod tests {
             .await
             .unwrap();
         let (tx, mut rx) = mpsc::channel(8);
-        tokio::spawn(async move { run(ws_stream, tx).await });
+        tokio::spawn(async move {
+            run(
+                ws_stream,
+                TestParams::default(),
+                None,
+                SkippedMeasurements::default(),
+                tx,
+            )
+            .await
+        });
 
         let mut results = Vec::new();
         while let Some(result) = rx.recv().await {
@@ -118,7 +622,231 @@ mod tests {
         assert!(results[0].is_ok());
         assert!(matches!(
             results.last(),
-            Some(Err(crate::error::Ndt7Error::Timeout(_)))
+            Some(Err(Ndt7Error::StallTimeout { .. }))
         ));
     }
+
+    #[tokio::test]
+    async fn test_max_bytes() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            while ws
+                .send(Message::Binary(vec![0u8; 1024].into()))
+                .await
+                .is_ok()
+            {}
+        });
+
+        let (ws_stream, _response) = tokio_tungstenite::connect_async(format!("ws://{addr}"))
+            .await
+            .unwrap();
+        let test_params = TestParams {
+            max_bytes: Some(10 * 1024),
+            wire_overhead: true,
+            ..Default::default()
+        };
+        let (tx, mut rx) = mpsc::channel(8);
+        tokio::spawn(async move {
+            run(
+                ws_stream,
+                test_params,
+                None,
+                SkippedMeasurements::default(),
+                tx,
+            )
+            .await
+        });
+
+        let mut last = None;
+        while let Some(result) = rx.recv().await {
+            last = Some(result.unwrap());
+        }
+        let app = last.unwrap().app_info.unwrap();
+        assert_eq!(app.num_bytes, ByteCount(10 * 1024));
+        // 10 frames with 4 header bytes each, in 8 IPv4 segments.
+        assert_eq!(app.wire_bytes, Some(ByteCount(10 * 1028 + 8 * 52)));
+    }
+
+    #[tokio::test]
+    async fn test_ws_ping_rtt() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let mut ticker = tokio::time::interval(Duration::from_millis(20));
+            for _ in 0..20 {
+                // Reading drives the automatic pong replies.
+                tokio::select! {
+                    msg = ws.next() => {
+                        if msg.is_none() {
+                            return;
+                        }
+                    }
+                    _ = ticker.tick() => {
+                        let data = Message::Binary(vec![0u8; 1024].into());
+                        ws.send(data).await.unwrap();
+                    }
+                }
+            }
+            let _ = ws.close(None).await;
+        });
+
+        let (ws_stream, _response) = tokio_tungstenite::connect_async(format!("ws://{addr}"))
+            .await
+            .unwrap();
+        let test_params = TestParams {
+            ws_ping_interval: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let (tx, mut rx) = mpsc::channel(8);
+        tokio::spawn(async move {
+            run(
+                ws_stream,
+                test_params,
+                None,
+                SkippedMeasurements::default(),
+                tx,
+            )
+            .await
+        });
+
+        let mut pings = Vec::new();
+        while let Some(result) = rx.recv().await {
+            if let Some(ping) = result.unwrap().ws_ping_info {
+                pings.push(ping);
+            }
+        }
+        assert!(!pings.is_empty());
+        assert!(pings.iter().all(|p| p.rtt.0 >= 0 && p.rtt < p.elapsed_time));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn adaptive_schedule() {
+        let start = Instant::now();
+        let mut updates = UpdateSchedule::new(
+            MeasurementInterval::Adaptive {
+                min: Duration::from_millis(100),
+                max: Duration::from_millis(400),
+            },
+            start,
+        );
+        let mut due_at = Vec::new();
+        for ms in (10..=2000).step_by(10) {
+            tokio::time::advance(Duration::from_millis(10)).await;
+            // Constant rate, with a jump in throughput after 1.5 s.
+            let bytes = if ms <= 1500 { ms * 1000 } else { ms * 3000 };
+            if updates.due(bytes) {
+                due_at.push(start.elapsed().as_millis());
+            }
+        }
+        // Grows to the maximum, drops back to the minimum at the jump.
+        assert_eq!(due_at, [100, 200, 400, 800, 1200, 1600, 1700, 1900]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn progress_on_schedule() {
+        let ctx = TestContext {
+            test: TestKind::Download,
+            start: Instant::now(),
+            tcp_info: None,
+            framing: None,
+            trace: None,
+            strict_parsing: false,
+            skipped: None,
+            utc_timestamps: false,
+        };
+        let totals = Totals::default();
+        totals.payload.store(1 << 20, Ordering::Relaxed);
+        let (tx, mut rx) = mpsc::channel(8);
+        let ticker = report_progress(
+            &ctx,
+            MeasurementInterval::Fixed(params::UPDATE_INTERVAL),
+            &totals,
+            &tx,
+        );
+        tokio::select! {
+            never = ticker => match never {},
+            () = sleep(Duration::from_millis(1100)) => {}
+        }
+        drop(tx);
+
+        let mut elapsed = Vec::new();
+        while let Some(m) = rx.recv().await {
+            elapsed.push(m.unwrap().app_info.unwrap().elapsed_time.0);
+        }
+        assert_eq!(elapsed, [250_000, 500_000, 750_000, 1_000_000]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ping_round_trip() {
+        let ctx = TestContext {
+            test: TestKind::Upload,
+            start: Instant::now(),
+            tcp_info: None,
+            framing: None,
+            trace: None,
+            strict_parsing: false,
+            skipped: None,
+            utc_timestamps: false,
+        };
+        let mut pinger = Pinger::new(Some(Duration::from_millis(100)), ctx.start);
+        assert!(!pinger.is_due());
+        pinger.tick().await;
+        let Message::Ping(payload) = pinger.ping() else {
+            unreachable!()
+        };
+        tokio::time::advance(Duration::from_millis(30)).await;
+        let info = ctx.pong(&payload).unwrap().ws_ping_info.unwrap();
+        assert_eq!(info.elapsed_time, Micros(130_000));
+        assert_eq!(info.rtt, Micros(30_000));
+        assert!(ctx.pong(b"junk").is_none());
+        // The next ping is due an interval after the previous one.
+        tokio::time::advance(Duration::from_millis(70)).await;
+        assert!(pinger.is_due());
+    }
+
+    #[tokio::test]
+    async fn test_server_close_code() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            ws.close(Some(CloseFrame {
+                code: CloseCode::Error,
+                reason: "internal error".into(),
+            }))
+            .await
+            .unwrap();
+            while let Some(Ok(_)) = ws.next().await {}
+        });
+
+        let (ws_stream, _response) = tokio_tungstenite::connect_async(format!("ws://{addr}"))
+            .await
+            .unwrap();
+        let (tx, mut rx) = mpsc::channel(8);
+        tokio::spawn(async move {
+            run(
+                ws_stream,
+                TestParams::default(),
+                None,
+                SkippedMeasurements::default(),
+                tx,
+            )
+            .await
+        });
+
+        let result = rx.recv().await.unwrap();
+        match result {
+            Err(Ndt7Error::ServerClosed { code, reason }) => {
+                assert_eq!(code, 1011);
+                assert_eq!(reason, "internal error");
+            }
+            other => panic!("expected ServerClosed, got {other:?}"),
+        }
+    }
 }
@@ -1,58 +1,245 @@
 //! Output formatting for test events.
 //!
 //! The [`Emitter`] trait defines callbacks for each stage of a test run.
-//! Two implementations are provided:
+//! Four implementations are provided:
 //! - [`HumanReadableEmitter`] — live progress and a formatted summary on a terminal.
 //! - [`JsonEmitter`] — one JSON object per line, suitable for machine consumption.
+//! - [`MarkdownEmitter`] — a report for pasting into issues and tickets.
+//! - [`StatsdEmitter`] — gauges pushed to a statsd server over UDP.
+//!
+//! [`CompositeEmitter`] forwards events to several emitters, e.g. to show live
+//! progress on stderr while writing JSON to stdout. [`FilterEmitter`] passes
+//! a subset of the events on, e.g. to keep slow sinks from being flooded
+//! with measurements.
+//!
+//! [`TestEvent`] is the JSON representation of each callback, for emitters
+//! of other formats to reuse.
 
-use std::io::Write;
+use std::borrow::Cow;
+use std::io::{self, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
 
 use serde::Serialize;
 
+use crate::client::ConnectInfo;
 use crate::error::Result;
-use crate::spec::{Measurement, Origin, TestKind};
-use crate::summary::Summary;
+use crate::metrics::{Metrics, Sample, average_mbps, retransmission_pct};
+use crate::ping::PingResult;
+use crate::spec::{Measurement, Micros, Origin, TestKind};
+use crate::summary::{GoSummary, SubtestSummary, Summary};
+use crate::sweep::SweepReport;
 
-#[derive(Serialize)]
+/// A test event, as [`JsonEmitter`] writes it.
+///
+/// Events serialize to a JSON object with the variant name as `Type` and
+/// the fields in PascalCase, e.g. `{"Type":"Starting","Test":"download"}`.
+/// Emitters of other formats can build one per [`Emitter`] callback to
+/// share this representation.
+#[derive(Debug, Clone, Copy, Serialize)]
 #[serde(tag = "Type")]
-enum Event<'a> {
+pub enum TestEvent<'a> {
+    /// A subtest is about to connect, see [`Emitter::on_starting`].
+    #[serde(rename_all = "PascalCase")]
+    Starting {
+        /// The subtest.
+        test: TestKind,
+    },
+    /// A subtest failed, see [`Emitter::on_error`].
+    #[serde(rename_all = "PascalCase")]
+    Error {
+        /// The subtest.
+        test: TestKind,
+        /// Description of the error.
+        error: &'a str,
+    },
+    /// The server closed the connection abnormally, see
+    /// [`Emitter::on_server_closed`].
     #[serde(rename_all = "PascalCase")]
-    Starting { test: TestKind },
+    ServerClosed {
+        /// The subtest.
+        test: TestKind,
+        /// WebSocket close code.
+        code: u16,
+        /// Reason the server gave.
+        reason: &'a str,
+    },
+    /// A server failed its health check and was skipped, see
+    /// [`Emitter::on_server_unhealthy`].
     #[serde(rename_all = "PascalCase")]
-    Error { test: TestKind, error: &'a str },
+    ServerUnhealthy {
+        /// The subtest.
+        test: TestKind,
+        /// FQDN of the skipped server.
+        #[serde(rename = "FQDN")]
+        fqdn: &'a str,
+        /// Why the server was skipped.
+        reason: &'a str,
+    },
+    /// The WebSocket connection was established, see
+    /// [`Emitter::on_connected`].
     #[serde(rename_all = "PascalCase")]
     Connected {
+        /// The subtest.
         test: TestKind,
+        /// FQDN of the server.
         #[serde(rename = "FQDN")]
         fqdn: &'a str,
+        /// Details of the connection.
+        connect_info: &'a ConnectInfo,
     },
+    /// A client or server measurement.
     #[serde(rename_all = "PascalCase")]
     Measurement {
+        /// The subtest.
         test: TestKind,
+        /// The measurement.
         measurement: &'a Measurement,
     },
+    /// Timing of a running subtest, see [`Emitter::on_progress`].
+    #[serde(rename_all = "PascalCase")]
+    Progress {
+        /// The subtest.
+        test: TestKind,
+        /// Timing of the subtest.
+        #[serde(flatten)]
+        progress: &'a Progress,
+    },
+    /// A subtest ended, see [`Emitter::on_complete`].
+    #[serde(rename_all = "PascalCase")]
+    Complete {
+        /// The subtest.
+        test: TestKind,
+    },
+    /// Results of all subtests, see [`Emitter::on_summary`].
+    #[serde(rename_all = "PascalCase")]
+    Summary {
+        /// The results.
+        summary: &'a Summary,
+    },
+    /// Result of a latency probe, see [`Emitter::on_ping`].
+    #[serde(rename_all = "PascalCase")]
+    Ping {
+        /// The result.
+        ping: &'a PingResult,
+    },
+    /// Outcome of a sweep of several servers, see [`Emitter::on_sweep`].
     #[serde(rename_all = "PascalCase")]
-    Complete { test: TestKind },
+    Sweep {
+        /// The outcome.
+        sweep: &'a SweepReport,
+    },
+    /// A non-fatal problem, see [`Emitter::on_warning`].
     #[serde(rename_all = "PascalCase")]
-    Summary { summary: &'a Summary },
+    Warning {
+        /// Description of the problem.
+        warning: &'a str,
+    },
+}
+
+impl TestEvent<'_> {
+    /// Snake case name of the event, e.g. `server_closed`, as used in MQTT
+    /// topics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            TestEvent::Starting { .. } => "starting",
+            TestEvent::Error { .. } => "error",
+            TestEvent::ServerClosed { .. } => "server_closed",
+            TestEvent::ServerUnhealthy { .. } => "server_unhealthy",
+            TestEvent::Connected { .. } => "connected",
+            TestEvent::Measurement { .. } => "measurement",
+            TestEvent::Progress { .. } => "progress",
+            TestEvent::Complete { .. } => "complete",
+            TestEvent::Summary { .. } => "summary",
+            TestEvent::Ping { .. } => "ping",
+            TestEvent::Sweep { .. } => "sweep",
+            TestEvent::Warning { .. } => "warning",
+        }
+    }
+}
+
+/// Timing of a running subtest, for rendering countdowns and progress bars.
+///
+/// Times are serialized in microseconds, like [`AppInfo::elapsed_time`](crate::spec::AppInfo::elapsed_time).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Progress {
+    /// Time since the subtest started.
+    pub elapsed_time: Micros,
+    /// Configured length of the subtest, see
+    /// [`TestHandle::duration`](crate::client::TestHandle::duration).
+    pub duration: Micros,
+    /// Time left until the subtest ends at the latest.
+    pub remaining_time: Micros,
+}
+
+impl Progress {
+    /// Progress of a subtest of length `duration` after `elapsed_time`.
+    pub fn new(elapsed_time: Micros, duration: Duration) -> Self {
+        let duration = Micros::from(duration);
+        Progress {
+            elapsed_time,
+            duration,
+            remaining_time: Micros((duration.0 - elapsed_time.0).max(0)),
+        }
+    }
+
+    /// Fraction of the subtest completed, between 0.0 and 1.0.
+    pub fn fraction(&self) -> f64 {
+        if self.duration.0 == 0 {
+            return 1.0;
+        }
+        (self.elapsed_time.0 as f64 / self.duration.0 as f64).clamp(0.0, 1.0)
+    }
 }
 
 /// Callbacks for ndt7 test lifecycle events.
+///
+/// Callbacks other than the core lifecycle ones do nothing by default, so
+/// implementors only override the events they report.
 pub trait Emitter {
     /// Called when a subtest is about to begin.
     fn on_starting(&mut self, test: TestKind) -> Result<()>;
     /// Called when a subtest encounters an error.
     fn on_error(&mut self, test: TestKind, err: &str) -> Result<()>;
+    /// Called when the server ends a subtest with a non-normal WebSocket
+    /// close code, in place of [`Emitter::on_error`].
+    fn on_server_closed(&mut self, _test: TestKind, _code: u16, _reason: &str) -> Result<()> {
+        Ok(())
+    }
+    /// Called for each located server skipped because it failed the
+    /// health check, see
+    /// [`ClientBuilder::health_check`](crate::client::ClientBuilder::health_check).
+    fn on_server_unhealthy(&mut self, _test: TestKind, _fqdn: &str, _reason: &str) -> Result<()> {
+        Ok(())
+    }
     /// Called after the WebSocket connection is established.
-    fn on_connected(&mut self, test: TestKind, fqdn: &str) -> Result<()>;
+    fn on_connected(&mut self, test: TestKind, fqdn: &str, info: &ConnectInfo) -> Result<()>;
     /// Called for each measurement received during the download test.
     fn on_download_event(&mut self, m: &Measurement) -> Result<()>;
     /// Called for each measurement received during the upload test.
     fn on_upload_event(&mut self, m: &Measurement) -> Result<()>;
+    /// Called periodically while a subtest runs.
+    fn on_progress(&mut self, _test: TestKind, _progress: &Progress) -> Result<()> {
+        Ok(())
+    }
     /// Called when a subtest finishes.
     fn on_complete(&mut self, test: TestKind) -> Result<()>;
     /// Called after all tests complete, with the final summary.
     fn on_summary(&mut self, s: &Summary) -> Result<()>;
+    /// Called with the result of a latency probe.
+    fn on_ping(&mut self, _p: &PingResult) -> Result<()> {
+        Ok(())
+    }
+    /// Called after the tests against each server of a sweep, see
+    /// [`crate::sweep`].
+    fn on_sweep(&mut self, _report: &SweepReport) -> Result<()> {
+        Ok(())
+    }
+    /// Called with an advisory warning, e.g. about host settings.
+    fn on_warning(&mut self, _warning: &str) -> Result<()> {
+        Ok(())
+    }
 }
 
 /// Emits human-readable progress and results to a writer.
@@ -74,8 +261,26 @@ impl<W: Write> Emitter for HumanReadableEmitter<W> {
         Ok(())
     }
 
-    fn on_connected(&mut self, test: TestKind, fqdn: &str) -> Result<()> {
-        write!(self.out, "\r{:?} in progress with {fqdn}\n", test)?;
+    fn on_server_unhealthy(&mut self, test: TestKind, fqdn: &str, reason: &str) -> Result<()> {
+        write!(self.out, "\r{:?}: skipping {fqdn}: {reason}\n", test)?;
+        Ok(())
+    }
+
+    fn on_connected(&mut self, test: TestKind, fqdn: &str, info: &ConnectInfo) -> Result<()> {
+        let family = match info.peer_addr {
+            Some(SocketAddr::V4(_)) => " over IPv4",
+            Some(SocketAddr::V6(_)) => " over IPv6",
+            None => "",
+        };
+        let from = match info.local_addr {
+            Some(addr) => format!(" from {}", addr.ip()),
+            None => String::new(),
+        };
+        write!(
+            self.out,
+            "\r{:?} in progress with {fqdn}{family}{from}\n",
+            test
+        )?;
         Ok(())
     }
 
@@ -84,17 +289,22 @@ impl<W: Write> Emitter for HumanReadableEmitter<W> {
         Ok(())
     }
 
+    fn on_server_closed(&mut self, test: TestKind, code: u16, reason: &str) -> Result<()> {
+        write!(
+            self.out,
+            "\n{:?} test failed: server closed connection (code {code}): {reason}\n",
+            test
+        )?;
+        Ok(())
+    }
+
     fn on_complete(&mut self, test: TestKind) -> Result<()> {
         write!(self.out, "\n{:?}: complete\n", test)?;
         Ok(())
     }
 
     fn on_download_event(&mut self, m: &Measurement) -> Result<()> {
-        if m.origin == Some(Origin::Client)
-            && let Some(app) = &m.app_info
-            && app.elapsed_time > 0
-        {
-            let speed = 8.0 * app.num_bytes as f64 / app.elapsed_time as f64;
+        if let Some(speed) = average_mbps(TestKind::Download, m) {
             write!(self.out, "\rAvg. speed: {:>7.1} Mbit/s", speed)?;
             self.out.flush()?;
         }
@@ -103,12 +313,7 @@ impl<W: Write> Emitter for HumanReadableEmitter<W> {
     }
 
     fn on_upload_event(&mut self, m: &Measurement) -> Result<()> {
-        if m.origin == Some(Origin::Server)
-            && let Some(tcp) = &m.tcp_info
-            && let (Some(received), Some(elapsed)) = (tcp.bytes_received, tcp.elapsed_time)
-            && elapsed > 0
-        {
-            let speed = 8.0 * received as f64 / elapsed as f64;
+        if let Some(speed) = average_mbps(TestKind::Upload, m) {
             write!(self.out, "\rAvg. speed: {:>7.1} Mbit/s", speed)?;
             self.out.flush()?;
         }
@@ -118,131 +323,1533 @@ impl<W: Write> Emitter for HumanReadableEmitter<W> {
 
     fn on_summary(&mut self, s: &Summary) -> Result<()> {
         writeln!(self.out, "\nTest results\n")?;
-        writeln!(self.out, "{:>10}: {}", "Server", s.server_fqdn)?;
+        match &s.server_location {
+            Some(loc) => writeln!(
+                self.out,
+                "{:>10}: {} ({}, {})",
+                "Server", s.server_fqdn, loc.city, loc.country
+            )?,
+            None => writeln!(self.out, "{:>10}: {}", "Server", s.server_fqdn)?,
+        }
         writeln!(self.out, "{:>10}: {}", "Client", s.client_ip)?;
+        if let Some(dscp) = s.dscp {
+            writeln!(self.out, "{:>10}: {}", "DSCP", dscp)?;
+        }
+        if let Some(idle) = s.idle_latency_ms {
+            writeln!(self.out, "{:>10}: {:>7.1} ms", "Idle RTT", idle)?;
+        }
+        if s.contended {
+            writeln!(
+                self.out,
+                "{:>10}: {:.1} Mbit/s of other traffic, results may be low",
+                "Note",
+                s.background_mbps.unwrap_or_default()
+            )?;
+        }
+        if s.truncated {
+            writeln!(
+                self.out,
+                "{:>10}: deadline exceeded, results are partial",
+                "Note"
+            )?;
+        }
 
         if let Some(dl) = &s.download {
-            writeln!(self.out, "\n{:>22}", "Download")?;
+            writeln!(self.out, "\n{:>22}{}", "Download", partial(dl))?;
             writeln!(
                 self.out,
                 "{:>15}: {:>7.1} Mbit/s",
                 "Throughput", dl.throughput_mbps
             )?;
+            if let Some(wire) = dl.wire_throughput_mbps {
+                writeln!(self.out, "{:>15}: {:>7.1} Mbit/s", "On the wire", wire)?;
+            }
+            write_transfer(&mut self.out, dl)?;
             writeln!(self.out, "{:>15}: {:>7.1} ms", "Latency", dl.latency_ms)?;
-            writeln!(
-                self.out,
-                "{:>15}: {:>7.1} %",
-                "Retransmission", dl.retransmission_pct
-            )?;
+            if let Some(increase) = dl.latency_increase_ms {
+                writeln!(self.out, "{:>15}: {:>+7.1} ms", "Under load", increase)?;
+            }
+            write_latency(&mut self.out, dl)?;
+            if let Some(retrans) = dl.retransmission_pct {
+                writeln!(self.out, "{:>15}: {:>7.1} %", "Retransmission", retrans)?;
+            }
+            if let Some(bw) = dl.max_bandwidth_mbps {
+                writeln!(self.out, "{:>15}: {:>7.1} Mbit/s", "BBR bandwidth", bw)?;
+            }
+            if let Some(uuid) = &dl.uuid {
+                writeln!(self.out, "{:>15}: {}", "UUID", uuid)?;
+            }
         }
 
         if let Some(ul) = &s.upload {
-            writeln!(self.out, "\n{:>20}", "Upload")?;
+            writeln!(self.out, "\n{:>20}{}", "Upload", partial(ul))?;
             writeln!(
                 self.out,
                 "{:>15}: {:>7.1} Mbit/s",
                 "Throughput", ul.throughput_mbps
             )?;
+            if let Some(wire) = ul.wire_throughput_mbps {
+                writeln!(self.out, "{:>15}: {:>7.1} Mbit/s", "On the wire", wire)?;
+            }
+            if let Some(client) = ul.client_throughput_mbps {
+                writeln!(self.out, "{:>15}: {:>7.1} Mbit/s", "Client side", client)?;
+            }
+            write_transfer(&mut self.out, ul)?;
             writeln!(self.out, "{:>15}: {:>7.1} ms", "Latency", ul.latency_ms)?;
+            if let Some(increase) = ul.latency_increase_ms {
+                writeln!(self.out, "{:>15}: {:>+7.1} ms", "Under load", increase)?;
+            }
+            write_latency(&mut self.out, ul)?;
+            if let Some(retrans) = ul.client_retransmission_pct {
+                writeln!(self.out, "{:>15}: {:>7.1} %", "Retransmission", retrans)?;
+            }
+            if let Some(uuid) = &ul.uuid {
+                writeln!(self.out, "{:>15}: {}", "UUID", uuid)?;
+            }
+        }
+
+        if let Some(grade) = &s.grade {
+            writeln!(
+                self.out,
+                "\n{:>15}: {} (streaming {}, gaming {}, video calls {})",
+                "Grade", grade.letter, grade.streaming, grade.gaming, grade.video_call
+            )?;
+        }
+
+        if let Some(cmp) = &s.comparison {
+            writeln!(self.out, "\n{:>27}", "Vs. previous")?;
+            for (name, delta) in [("Download", &cmp.download), ("Upload", &cmp.upload)] {
+                if let Some(d) = delta {
+                    write!(
+                        self.out,
+                        "{:>15}: {:+.1} Mbit/s ({:+.1} %), latency {:+.1} ms",
+                        name,
+                        d.throughput_mbps.change,
+                        d.throughput_mbps.change_pct.unwrap_or_default(),
+                        d.latency_ms.change,
+                    )?;
+                    if let Some(retrans) = &d.retransmission_pct {
+                        write!(self.out, ", retrans. {:+.1} %", retrans.change)?;
+                    }
+                    writeln!(self.out)?;
+                }
+            }
+        }
+
+        if let Some(lat) = &s.latency {
+            writeln!(self.out, "\n{:>25}", "UDP latency")?;
+            writeln!(self.out, "{:>15}: {:>7.1} ms", "Min", lat.min_rtt_ms)?;
+            writeln!(self.out, "{:>15}: {:>7.1} ms", "Median", lat.median_rtt_ms)?;
+            writeln!(self.out, "{:>15}: {:>7.1} ms", "95th pct", lat.p95_rtt_ms)?;
+            writeln!(self.out, "{:>15}: {:>7.1} ms", "Max", lat.max_rtt_ms)?;
+            writeln!(
+                self.out,
+                "{:>15}: {:>7.1} %",
+                "Packet loss", lat.packet_loss_pct
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn on_ping(&mut self, p: &PingResult) -> Result<()> {
+        writeln!(self.out, "Latency probe\n")?;
+        writeln!(self.out, "{:>10}: {}", "Server", p.server_fqdn)?;
+        writeln!(self.out, "{:>10}: {:>7.1} ms", "Connect", p.connect_ms)?;
+        match p.best_rtt_ms() {
+            Some(rtt) => writeln!(self.out, "{:>10}: {:>7.1} ms", "RTT", rtt)?,
+            None => writeln!(self.out, "{:>10}: {:>7}", "RTT", "-")?,
         }
+        match p.min_rtt_ms {
+            Some(rtt) => writeln!(self.out, "{:>10}: {:>7.1} ms", "MinRTT", rtt)?,
+            None => writeln!(self.out, "{:>10}: {:>7}", "MinRTT", "-")?,
+        }
+        Ok(())
+    }
+
+    fn on_sweep(&mut self, report: &SweepReport) -> Result<()> {
+        let mbps = |v: Option<f64>| v.map_or("-".to_string(), |v| format!("{v:.1}"));
+        writeln!(self.out, "\nSweep of {} servers\n", report.servers.len())?;
+        for server in &report.servers {
+            match &server.error {
+                Some(e) => writeln!(self.out, "{:>40}: {e}", server.machine)?,
+                None => writeln!(
+                    self.out,
+                    "{:>40}: {:>7} Mbit/s down, {:>7} Mbit/s up",
+                    server.machine,
+                    mbps(server.download_mbps),
+                    mbps(server.upload_mbps)
+                )?,
+            }
+        }
+        if let Some(fastest) = &report.fastest {
+            writeln!(self.out, "\n{:>40}: {fastest}", "Fastest")?;
+        }
+        Ok(())
+    }
 
+    fn on_warning(&mut self, warning: &str) -> Result<()> {
+        writeln!(self.out, "warning: {warning}")?;
         Ok(())
     }
 }
 
+/// Heading suffix for a subtest that failed mid-run.
+fn partial(s: &SubtestSummary) -> &'static str {
+    if s.complete { "" } else { " (partial)" }
+}
+
+/// Write the bufferbloat, RTT percentiles and jitter of a subtest, if known.
+fn write_latency(out: &mut impl Write, s: &SubtestSummary) -> Result<()> {
+    if let Some(bloat) = s.bufferbloat_ms {
+        writeln!(out, "{:>15}: {:>+7.1} ms", "Bufferbloat", bloat)?;
+    }
+    if let (Some(p50), Some(p95), Some(p99)) = (s.rtt_p50_ms, s.rtt_p95_ms, s.rtt_p99_ms) {
+        writeln!(
+            out,
+            "{:>15}: {:.1} / {:.1} / {:.1} ms",
+            "RTT p50/95/99", p50, p95, p99
+        )?;
+    }
+    if let Some(jitter) = s.jitter_ms {
+        writeln!(out, "{:>15}: {:>7.1} ms", "Jitter", jitter)?;
+    }
+    Ok(())
+}
+
+/// Write the bytes transferred by a subtest and its min/avg/max interval
+/// throughput, if known.
+fn write_transfer(out: &mut impl Write, s: &SubtestSummary) -> Result<()> {
+    writeln!(
+        out,
+        "{:>15}: {:.1} MB in {:.1} s",
+        "Transferred",
+        s.bytes_transferred as f64 / 1e6,
+        s.duration_s
+    )?;
+    if let (Some(min), Some(avg), Some(max)) = (
+        s.min_throughput_mbps,
+        s.avg_throughput_mbps,
+        s.max_throughput_mbps,
+    ) {
+        writeln!(
+            out,
+            "{:>15}: {:.1} / {:.1} / {:.1} Mbit/s",
+            "Min/avg/max", min, avg, max
+        )?;
+    }
+    if !s.throughput_series_mbps.is_empty() {
+        writeln!(
+            out,
+            "{:>15}: {}",
+            "Over time",
+            sparkline(&s.throughput_series_mbps)
+        )?;
+    }
+    Ok(())
+}
+
+/// Render `values` as a bar per value, scaled from 0 to the largest. A
+/// value of 0 is left blank, so stalls stand out.
+fn sparkline(values: &[f64]) -> String {
+    const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+    let max = values.iter().copied().fold(0.0, f64::max);
+    values
+        .iter()
+        .map(|&v| {
+            if v <= 0.0 || max <= 0.0 {
+                return ' ';
+            }
+            let level = (v / max * BARS.len() as f64).ceil() as usize;
+            BARS[level.clamp(1, BARS.len()) - 1]
+        })
+        .collect()
+}
+
 /// Emits one JSON object per line for each event.
 pub struct JsonEmitter<W: Write> {
     out: W,
+    go_summary: bool,
+    go_events: bool,
 }
 
 impl<W: Write> JsonEmitter<W> {
     /// Create a new JSON emitter writing to `out`.
     pub fn new(out: W) -> Self {
-        JsonEmitter { out }
+        JsonEmitter {
+            out,
+            go_summary: false,
+            go_events: false,
+        }
+    }
+
+    /// Write the summary as a bare [`GoSummary`], the structure of
+    /// ndt7-client-go's JSON output, instead of a `Summary` event.
+    pub fn go_summary(mut self) -> Self {
+        self.go_summary = true;
+        self
+    }
+
+    /// Write events in ndt7-client-go's envelopes, e.g.
+    /// `{"Key":"measurement","Value":{...}}`, and the summary as with
+    /// [`JsonEmitter::go_summary`], for pipelines parsing the Go client's
+    /// output.
+    ///
+    /// Events the Go client has no counterpart for are left out, except
+    /// abnormal closes, which become `error` events.
+    pub fn go_events(mut self) -> Self {
+        self.go_summary = true;
+        self.go_events = true;
+        self
     }
 
-    fn emit(&mut self, event: &Event) -> Result<()> {
-        let json = serde_json::to_string(event)?;
+    fn emit(&mut self, event: &TestEvent) -> Result<()> {
+        let json = if self.go_events {
+            match GoEvent::from_event(event) {
+                Some(event) => serde_json::to_string(&event)?,
+                None => return Ok(()),
+            }
+        } else {
+            serde_json::to_string(event)?
+        };
         writeln!(self.out, "{}", json)?;
         Ok(())
     }
 }
 
+/// An event as ndt7-client-go's JSON emitter writes it.
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct GoEvent<'a> {
+    key: &'static str,
+    value: GoValue<'a>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged, rename_all_fields = "PascalCase")]
+enum GoValue<'a> {
+    Test { test: TestKind },
+    Error { failure: String, test: TestKind },
+    Connected { server: &'a str, test: TestKind },
+    Measurement(Box<Cow<'a, Measurement>>),
+}
+
+impl<'a> GoEvent<'a> {
+    fn from_event(event: &TestEvent<'a>) -> Option<GoEvent<'a>> {
+        let (key, value) = match *event {
+            TestEvent::Starting { test } => ("starting", GoValue::Test { test }),
+            TestEvent::Error { test, error } => (
+                "error",
+                GoValue::Error {
+                    failure: error.to_string(),
+                    test,
+                },
+            ),
+            TestEvent::ServerClosed { test, code, reason } => (
+                "error",
+                GoValue::Error {
+                    failure: format!("server closed connection (code {code}): {reason}"),
+                    test,
+                },
+            ),
+            TestEvent::Connected { test, fqdn, .. } => {
+                ("connected", GoValue::Connected { server: fqdn, test })
+            }
+            TestEvent::Measurement { test, measurement } => {
+                // The Go client labels every measurement with its subtest.
+                let measurement = match measurement.test {
+                    Some(_) => Cow::Borrowed(measurement),
+                    None => Cow::Owned(Measurement {
+                        test: Some(test),
+                        ..measurement.clone()
+                    }),
+                };
+                ("measurement", GoValue::Measurement(Box::new(measurement)))
+            }
+            TestEvent::Complete { test } => ("complete", GoValue::Test { test }),
+            TestEvent::ServerUnhealthy { .. }
+            | TestEvent::Progress { .. }
+            | TestEvent::Summary { .. }
+            | TestEvent::Ping { .. }
+            | TestEvent::Sweep { .. }
+            | TestEvent::Warning { .. } => return None,
+        };
+        Some(GoEvent { key, value })
+    }
+}
+
 impl<W: Write> Emitter for JsonEmitter<W> {
     fn on_starting(&mut self, test: TestKind) -> Result<()> {
-        self.emit(&Event::Starting { test })
+        self.emit(&TestEvent::Starting { test })
     }
 
     fn on_error(&mut self, test: TestKind, err: &str) -> Result<()> {
-        self.emit(&Event::Error { test, error: err })
+        self.emit(&TestEvent::Error { test, error: err })
+    }
+
+    fn on_server_closed(&mut self, test: TestKind, code: u16, reason: &str) -> Result<()> {
+        self.emit(&TestEvent::ServerClosed { test, code, reason })
     }
 
-    fn on_connected(&mut self, test: TestKind, fqdn: &str) -> Result<()> {
-        self.emit(&Event::Connected { test, fqdn })
+    fn on_server_unhealthy(&mut self, test: TestKind, fqdn: &str, reason: &str) -> Result<()> {
+        self.emit(&TestEvent::ServerUnhealthy { test, fqdn, reason })
+    }
+
+    fn on_connected(&mut self, test: TestKind, fqdn: &str, info: &ConnectInfo) -> Result<()> {
+        self.emit(&TestEvent::Connected {
+            test,
+            fqdn,
+            connect_info: info,
+        })
     }
 
     fn on_download_event(&mut self, m: &Measurement) -> Result<()> {
-        self.emit(&Event::Measurement {
+        self.emit(&TestEvent::Measurement {
             test: TestKind::Download,
             measurement: m,
         })
     }
 
     fn on_upload_event(&mut self, m: &Measurement) -> Result<()> {
-        self.emit(&Event::Measurement {
+        self.emit(&TestEvent::Measurement {
             test: TestKind::Upload,
             measurement: m,
         })
     }
 
+    fn on_progress(&mut self, test: TestKind, progress: &Progress) -> Result<()> {
+        self.emit(&TestEvent::Progress { test, progress })
+    }
+
     fn on_complete(&mut self, test: TestKind) -> Result<()> {
-        self.emit(&Event::Complete { test })
+        self.emit(&TestEvent::Complete { test })
     }
 
     fn on_summary(&mut self, s: &Summary) -> Result<()> {
-        self.emit(&Event::Summary { summary: s })
+        if self.go_summary {
+            writeln!(self.out, "{}", serde_json::to_string(&GoSummary::from(s))?)?;
+            return Ok(());
+        }
+        self.emit(&TestEvent::Summary { summary: s })
+    }
+
+    fn on_ping(&mut self, p: &PingResult) -> Result<()> {
+        self.emit(&TestEvent::Ping { ping: p })
+    }
+
+    fn on_sweep(&mut self, report: &SweepReport) -> Result<()> {
+        self.emit(&TestEvent::Sweep { sweep: report })
+    }
+
+    fn on_warning(&mut self, warning: &str) -> Result<()> {
+        self.emit(&TestEvent::Warning { warning })
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::spec::AppInfo;
+/// Renders the results as a Markdown report, e.g. for pasting into an issue
+/// or a wiki page.
+///
+/// Nothing is written until the summary arrives. Errors and warnings of the
+/// run are listed below the results. With [`MarkdownEmitter::measurements`],
+/// a collapsible table of the throughput and RTT over time follows.
+pub struct MarkdownEmitter<W: Write> {
+    out: W,
+    measurements: bool,
+    metrics: [Metrics; 2],
+    samples: Vec<(TestKind, Sample)>,
+    notes: Vec<String>,
+}
 
-    use super::*;
+impl<W: Write> MarkdownEmitter<W> {
+    /// Create a new Markdown emitter writing to `out`.
+    pub fn new(out: W) -> Self {
+        MarkdownEmitter {
+            out,
+            measurements: false,
+            metrics: [
+                Metrics::new(TestKind::Download),
+                Metrics::new(TestKind::Upload),
+            ],
+            samples: Vec::new(),
+            notes: Vec::new(),
+        }
+    }
 
-    #[test]
-    fn human_readable_download_event() {
-        let mut buf = Vec::new();
-        let mut emitter = HumanReadableEmitter::new(&mut buf);
+    /// Append a table of the metrics after each measurement.
+    pub fn measurements(mut self) -> Self {
+        self.measurements = true;
+        self
+    }
 
-        let m = Measurement {
-            app_info: Some(AppInfo {
-                num_bytes: 1_000_000,
-                elapsed_time: 1_000_000,
+    fn sample(&mut self, test: TestKind, m: &Measurement) {
+        let metrics = &mut self.metrics[test as usize];
+        if self.measurements && metrics.update(m) {
+            self.samples.push((test, *metrics.sample()));
+        }
+    }
+
+    /// Write a row of the results table, unless neither subtest has a value.
+    fn row(&mut self, name: &str, values: [Option<String>; 2]) -> Result<()> {
+        if values.iter().all(Option::is_none) {
+            return Ok(());
+        }
+        let [dl, ul] = values.map(|v| v.unwrap_or_else(|| "-".into()));
+        writeln!(self.out, "| {name} | {dl} | {ul} |")?;
+        Ok(())
+    }
+
+    fn write_measurements(&mut self) -> Result<()> {
+        writeln!(self.out, "\n<details><summary>Measurements</summary>\n")?;
+        writeln!(
+            self.out,
+            "| Test | Elapsed (s) | Throughput (Mbit/s) | RTT (ms) | Retransmission (%) |"
+        )?;
+        writeln!(self.out, "| --- | ---: | ---: | ---: | ---: |")?;
+        let cell = |v: Option<f64>| v.map_or("-".into(), |v| format!("{v:.1}"));
+        for (test, s) in &self.samples {
+            writeln!(
+                self.out,
+                "| {} | {:.2} | {} | {} | {} |",
+                test_name(*test),
+                s.elapsed_time.as_secs_f64(),
+                cell(s.instant_mbps),
+                cell(s.rtt_ms),
+                cell(s.retransmission_pct),
+            )?;
+        }
+        writeln!(self.out, "\n</details>")?;
+        Ok(())
+    }
+}
+
+impl<W: Write> Emitter for MarkdownEmitter<W> {
+    fn on_starting(&mut self, test: TestKind) -> Result<()> {
+        self.metrics[test as usize] = Metrics::new(test);
+        Ok(())
+    }
+
+    fn on_error(&mut self, test: TestKind, err: &str) -> Result<()> {
+        self.notes.push(format!("{test:?} test failed: {err}"));
+        Ok(())
+    }
+
+    fn on_server_closed(&mut self, test: TestKind, code: u16, reason: &str) -> Result<()> {
+        self.notes.push(format!(
+            "{test:?} test failed: server closed connection (code {code}): {reason}"
+        ));
+        Ok(())
+    }
+
+    fn on_server_unhealthy(&mut self, test: TestKind, fqdn: &str, reason: &str) -> Result<()> {
+        self.notes
+            .push(format!("{test:?}: skipped {fqdn}: {reason}"));
+        Ok(())
+    }
+
+    fn on_connected(&mut self, _test: TestKind, _fqdn: &str, _info: &ConnectInfo) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_download_event(&mut self, m: &Measurement) -> Result<()> {
+        self.sample(TestKind::Download, m);
+        Ok(())
+    }
+
+    fn on_upload_event(&mut self, m: &Measurement) -> Result<()> {
+        self.sample(TestKind::Upload, m);
+        Ok(())
+    }
+
+    fn on_complete(&mut self, _test: TestKind) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_summary(&mut self, s: &Summary) -> Result<()> {
+        writeln!(self.out, "## ndt7 speed test\n")?;
+        match &s.server_location {
+            Some(loc) => writeln!(
+                self.out,
+                "**Server:** {} ({}, {})  ",
+                s.server_fqdn, loc.city, loc.country
+            )?,
+            None => writeln!(self.out, "**Server:** {}  ", s.server_fqdn)?,
+        }
+        writeln!(self.out, "**Client:** {}\n", s.client_ip)?;
+
+        let subtests = [s.download.as_ref(), s.upload.as_ref()];
+        let each = |f: &dyn Fn(&SubtestSummary) -> Option<String>| subtests.map(|t| t.and_then(f));
+        writeln!(self.out, "| | Download | Upload |")?;
+        writeln!(self.out, "| --- | ---: | ---: |")?;
+        self.row(
+            "Throughput",
+            each(&|t| Some(format!("{:.1} Mbit/s{}", t.throughput_mbps, partial(t)))),
+        )?;
+        self.row(
+            "Latency",
+            each(&|t| Some(format!("{:.1} ms", t.latency_ms))),
+        )?;
+        self.row(
+            "Under load",
+            each(&|t| t.latency_increase_ms.map(|ms| format!("{ms:+.1} ms"))),
+        )?;
+        self.row(
+            "Jitter",
+            each(&|t| t.jitter_ms.map(|ms| format!("{ms:.1} ms"))),
+        )?;
+        self.row(
+            "Retransmission",
+            each(&|t| {
+                t.retransmission_pct
+                    .or(t.client_retransmission_pct)
+                    .map(|pct| format!("{pct:.1} %"))
             }),
-            origin: Some(Origin::Client),
-            ..Default::default()
-        };
+        )?;
+        self.row(
+            "UUID",
+            each(&|t| t.uuid.as_ref().map(|uuid| format!("`{uuid}`"))),
+        )?;
 
-        emitter.on_download_event(&m).unwrap();
+        if let Some(grade) = &s.grade {
+            writeln!(
+                self.out,
+                "\n**Grade:** {} (streaming {}, gaming {}, video calls {})",
+                grade.letter, grade.streaming, grade.gaming, grade.video_call
+            )?;
+        }
+        if s.truncated {
+            self.notes
+                .push("Deadline exceeded, results are partial".into());
+        }
+        if !self.notes.is_empty() {
+            writeln!(self.out, "\n**Notes:**\n")?;
+            for note in std::mem::take(&mut self.notes) {
+                writeln!(self.out, "- {note}")?;
+            }
+        }
+        if self.measurements && !self.samples.is_empty() {
+            self.write_measurements()?;
+            self.samples.clear();
+        }
+        Ok(())
+    }
 
-        let out = String::from_utf8(buf).unwrap();
+    fn on_ping(&mut self, p: &PingResult) -> Result<()> {
+        let ms = |v: Option<f64>| v.map_or("-".into(), |v| format!("{v:.1} ms"));
+        writeln!(self.out, "## ndt7 latency probe\n")?;
+        writeln!(self.out, "| Server | Connect | RTT | MinRTT |")?;
+        writeln!(self.out, "| --- | ---: | ---: | ---: |")?;
+        writeln!(
+            self.out,
+            "| {} | {:.1} ms | {} | {} |",
+            p.server_fqdn,
+            p.connect_ms,
+            ms(p.best_rtt_ms()),
+            ms(p.min_rtt_ms)
+        )?;
+        Ok(())
+    }
 
-        assert!(out.contains("8.0 Mbit/s"))
+    fn on_sweep(&mut self, report: &SweepReport) -> Result<()> {
+        let mbps = |v: Option<f64>| v.map_or("-".into(), |v| format!("{v:.1}"));
+        writeln!(self.out, "## ndt7 server sweep\n")?;
+        writeln!(
+            self.out,
+            "| Server | Download (Mbit/s) | Upload (Mbit/s) | Error |"
+        )?;
+        writeln!(self.out, "| --- | ---: | ---: | --- |")?;
+        for server in &report.servers {
+            let fastest = report.fastest.as_ref() == Some(&server.machine);
+            writeln!(
+                self.out,
+                "| {}{} | {} | {} | {} |",
+                server.machine,
+                if fastest { " (fastest)" } else { "" },
+                mbps(server.download_mbps),
+                mbps(server.upload_mbps),
+                server.error.as_deref().unwrap_or("")
+            )?;
+        }
+        Ok(())
     }
 
-    #[test]
-    fn json_emitter_valid() {
-        let mut buf = Vec::new();
-        let mut emitter = JsonEmitter::new(&mut buf);
+    fn on_warning(&mut self, warning: &str) -> Result<()> {
+        self.notes.push(format!("Warning: {warning}"));
+        Ok(())
+    }
+}
 
-        emitter.on_starting(TestKind::Upload).unwrap();
+/// Pushes gauges to a statsd server over UDP as measurements arrive and
+/// when the run ends.
+///
+/// Metrics are named `<prefix>.<test>.<metric>`, e.g.
+/// `ndt7.download.throughput_mbps`:
+/// - `throughput_mbps` for each measurement carrying it, and `rtt_ms` and
+///   `retransmission_pct` for each server measurement carrying them;
+/// - `summary.throughput_mbps`, `summary.latency_ms` and
+///   `summary.retransmission_pct` for the summary of each subtest;
+/// - `errors` and `unhealthy_servers` counters.
+///
+/// Latency probes are sent as `<prefix>.ping.rtt_ms`.
+///
+/// Statsd is fire-and-forget: datagrams that cannot be sent are dropped
+/// rather than failing the test.
+pub struct StatsdEmitter {
+    socket: UdpSocket,
+    prefix: String,
+}
 
-        let out = String::from_utf8(buf).unwrap();
+impl StatsdEmitter {
+    /// Create an emitter sending to the statsd server at `addr`, e.g.
+    /// `"localhost:8125"`, with metrics prefixed by `ndt7`.
+    pub fn new(addr: impl ToSocketAddrs) -> Result<Self> {
+        let addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no statsd address"))?;
+        let local: SocketAddr = if addr.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(addr)?;
+        Ok(StatsdEmitter {
+            socket,
+            prefix: "ndt7".to_string(),
+        })
+    }
 
-        let res = serde_json::from_str::<serde_json::Value>(&out).unwrap();
+    /// Prefix metric names with `prefix` instead of `ndt7`.
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
 
-        assert_eq!(res["Test"], "upload");
-        assert_eq!(res["Type"], "Starting");
+    /// Send `value` of type `kind` as `<prefix>.<name>`.
+    fn send(&self, name: &str, value: impl std::fmt::Display, kind: &str) {
+        let line = format!("{}.{name}:{value}|{kind}", self.prefix);
+        let _ = self.socket.send(line.as_bytes());
+    }
+
+    fn gauge(&self, test: TestKind, metric: &str, value: f64) {
+        let name = format!("{}.{metric}", test_name(test));
+        self.send(&name, format_args!("{value:.3}"), "g");
+    }
+
+    fn count(&self, test: TestKind, metric: &str) {
+        self.send(&format!("{}.{metric}", test_name(test)), 1, "c");
+    }
+
+    fn on_measurement(&mut self, test: TestKind, m: &Measurement) -> Result<()> {
+        if let Some(mbps) = average_mbps(test, m) {
+            self.gauge(test, "throughput_mbps", mbps);
+        }
+        // The server's TCP statistics only, as in the summary; the client's
+        // would alternate with them in the same gauges.
+        if let Some(tcp) = m
+            .tcp_info
+            .as_ref()
+            .filter(|_| m.origin == Some(Origin::Server))
+        {
+            if let Some(rtt) = tcp.rtt {
+                self.gauge(test, "rtt_ms", rtt.as_millis_f64());
+            }
+            if tcp.bytes_sent.is_some() {
+                self.gauge(test, "retransmission_pct", retransmission_pct(tcp));
+            }
+        }
+        Ok(())
+    }
+
+    fn on_subtest_summary(&self, test: TestKind, s: &SubtestSummary) {
+        self.gauge(test, "summary.throughput_mbps", s.throughput_mbps);
+        self.gauge(test, "summary.latency_ms", s.latency_ms);
+        if let Some(pct) = s.retransmission_pct {
+            self.gauge(test, "summary.retransmission_pct", pct);
+        }
+    }
+}
+
+impl Emitter for StatsdEmitter {
+    fn on_starting(&mut self, _test: TestKind) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_error(&mut self, test: TestKind, _err: &str) -> Result<()> {
+        self.count(test, "errors");
+        Ok(())
+    }
+
+    fn on_server_closed(&mut self, test: TestKind, _code: u16, _reason: &str) -> Result<()> {
+        self.count(test, "errors");
+        Ok(())
+    }
+
+    fn on_server_unhealthy(&mut self, test: TestKind, _fqdn: &str, _reason: &str) -> Result<()> {
+        self.count(test, "unhealthy_servers");
+        Ok(())
+    }
+
+    fn on_connected(&mut self, _test: TestKind, _fqdn: &str, _info: &ConnectInfo) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_download_event(&mut self, m: &Measurement) -> Result<()> {
+        self.on_measurement(TestKind::Download, m)
+    }
+
+    fn on_upload_event(&mut self, m: &Measurement) -> Result<()> {
+        self.on_measurement(TestKind::Upload, m)
+    }
+
+    fn on_complete(&mut self, _test: TestKind) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_summary(&mut self, s: &Summary) -> Result<()> {
+        if let Some(download) = &s.download {
+            self.on_subtest_summary(TestKind::Download, download);
+        }
+        if let Some(upload) = &s.upload {
+            self.on_subtest_summary(TestKind::Upload, upload);
+        }
+        Ok(())
+    }
+
+    fn on_ping(&mut self, p: &PingResult) -> Result<()> {
+        if let Some(rtt) = p.best_rtt_ms() {
+            self.send("ping.rtt_ms", format_args!("{rtt:.3}"), "g");
+        }
+        Ok(())
+    }
+}
+
+/// Metric name segment of `test`.
+pub(crate) fn test_name(test: TestKind) -> &'static str {
+    match test {
+        TestKind::Download => "download",
+        TestKind::Upload => "upload",
+    }
+}
+
+/// Forwards every event to each of its emitters, in order.
+///
+/// An emitter that fails does not keep the event from the others, e.g. an
+/// unreachable metrics sink from the terminal output. Its error is logged to
+/// stderr; an error is returned only if every emitter failed.
+#[derive(Default)]
+pub struct CompositeEmitter<'a> {
+    emitters: Vec<Box<dyn Emitter + 'a>>,
+}
+
+impl<'a> CompositeEmitter<'a> {
+    /// Create a composite of `emitters`.
+    pub fn new(emitters: Vec<Box<dyn Emitter + 'a>>) -> Self {
+        CompositeEmitter { emitters }
+    }
+
+    /// Add an emitter after the existing ones.
+    pub fn push(&mut self, emitter: Box<dyn Emitter + 'a>) {
+        self.emitters.push(emitter);
+    }
+
+    fn each(&mut self, mut f: impl FnMut(&mut (dyn Emitter + 'a)) -> Result<()>) -> Result<()> {
+        let mut errors = Vec::new();
+        for (i, emitter) in self.emitters.iter_mut().enumerate() {
+            if let Err(e) = f(emitter.as_mut()) {
+                errors.push((i, e));
+            }
+        }
+        if !errors.is_empty() && errors.len() == self.emitters.len() {
+            // No emitter saw the event; leave it to the caller.
+            return Err(errors.swap_remove(0).1);
+        }
+        for (i, e) in errors {
+            eprintln!("emitter {i} failed: {e}");
+        }
+        Ok(())
+    }
+}
+
+impl Emitter for CompositeEmitter<'_> {
+    fn on_starting(&mut self, test: TestKind) -> Result<()> {
+        self.each(|e| e.on_starting(test))
+    }
+
+    fn on_error(&mut self, test: TestKind, err: &str) -> Result<()> {
+        self.each(|e| e.on_error(test, err))
+    }
+
+    fn on_server_closed(&mut self, test: TestKind, code: u16, reason: &str) -> Result<()> {
+        self.each(|e| e.on_server_closed(test, code, reason))
+    }
+
+    fn on_server_unhealthy(&mut self, test: TestKind, fqdn: &str, reason: &str) -> Result<()> {
+        self.each(|e| e.on_server_unhealthy(test, fqdn, reason))
+    }
+
+    fn on_connected(&mut self, test: TestKind, fqdn: &str, info: &ConnectInfo) -> Result<()> {
+        self.each(|e| e.on_connected(test, fqdn, info))
+    }
+
+    fn on_download_event(&mut self, m: &Measurement) -> Result<()> {
+        self.each(|e| e.on_download_event(m))
+    }
+
+    fn on_upload_event(&mut self, m: &Measurement) -> Result<()> {
+        self.each(|e| e.on_upload_event(m))
+    }
+
+    fn on_progress(&mut self, test: TestKind, progress: &Progress) -> Result<()> {
+        self.each(|e| e.on_progress(test, progress))
+    }
+
+    fn on_complete(&mut self, test: TestKind) -> Result<()> {
+        self.each(|e| e.on_complete(test))
+    }
+
+    fn on_summary(&mut self, s: &Summary) -> Result<()> {
+        self.each(|e| e.on_summary(s))
+    }
+
+    fn on_ping(&mut self, p: &PingResult) -> Result<()> {
+        self.each(|e| e.on_ping(p))
+    }
+
+    fn on_sweep(&mut self, report: &SweepReport) -> Result<()> {
+        self.each(|e| e.on_sweep(report))
+    }
+
+    fn on_warning(&mut self, warning: &str) -> Result<()> {
+        self.each(|e| e.on_warning(warning))
+    }
+}
+
+/// Passes a subset of the events on to another emitter.
+///
+/// Each side reports about four measurements a second during a subtest,
+/// which floods verbose outputs and slow sinks. The filter can keep only the
+/// measurements of one side, sample them at a coarser interval and drop
+/// events by type; every other event is passed on unchanged.
+pub struct FilterEmitter<'a> {
+    inner: Box<dyn Emitter + 'a>,
+    origin: Option<Origin>,
+    interval: Option<Micros>,
+    skip: Vec<String>,
+    /// Elapsed time of the last measurement passed on, by subtest and side.
+    last: Vec<(TestKind, Option<Origin>, Micros)>,
+}
+
+impl<'a> FilterEmitter<'a> {
+    /// Filter the events of `inner`. Everything is passed on until a filter
+    /// is set.
+    pub fn new(inner: Box<dyn Emitter + 'a>) -> Self {
+        FilterEmitter {
+            inner,
+            origin: None,
+            interval: None,
+            skip: Vec::new(),
+            last: Vec::new(),
+        }
+    }
+
+    /// Pass on only the measurements produced by `origin`.
+    pub fn origin(mut self, origin: Origin) -> Self {
+        self.origin = Some(origin);
+        self
+    }
+
+    /// Pass on at most one measurement per `interval` of each subtest and
+    /// side, going by the elapsed time the measurements report.
+    pub fn measurement_interval(mut self, interval: Duration) -> Self {
+        self.interval = Some(Micros(interval.as_micros() as i64));
+        self
+    }
+
+    /// Drop the events of type `name`, as returned by [`TestEvent::name`],
+    /// e.g. `progress`. May be called repeatedly.
+    pub fn skip(mut self, name: impl Into<String>) -> Self {
+        self.skip.push(name.into());
+        self
+    }
+
+    /// Whether `event` is passed on, recording the measurements that are.
+    fn passes(&mut self, event: &TestEvent) -> bool {
+        if self.skip.iter().any(|name| name == event.name()) {
+            return false;
+        }
+        match *event {
+            TestEvent::Starting { test } => {
+                // Elapsed times restart with each subtest.
+                self.last.retain(|(t, _, _)| *t != test);
+                true
+            }
+            TestEvent::Measurement { test, measurement } => {
+                if self.origin.is_some() && measurement.origin != self.origin {
+                    return false;
+                }
+                let (Some(interval), Some(elapsed)) = (self.interval, elapsed(measurement)) else {
+                    return true;
+                };
+                let key = (test, measurement.origin);
+                match self.last.iter_mut().find(|(t, o, _)| (*t, *o) == key) {
+                    Some((_, _, last)) if elapsed.0 < last.0 + interval.0 => false,
+                    Some((_, _, last)) => {
+                        *last = elapsed;
+                        true
+                    }
+                    None => {
+                        self.last.push((test, measurement.origin, elapsed));
+                        true
+                    }
+                }
+            }
+            _ => true,
+        }
+    }
+
+    fn forward(&mut self, event: TestEvent) -> Result<()> {
+        if !self.passes(&event) {
+            return Ok(());
+        }
+        let inner = &mut self.inner;
+        match event {
+            TestEvent::Starting { test } => inner.on_starting(test),
+            TestEvent::Error { test, error } => inner.on_error(test, error),
+            TestEvent::ServerClosed { test, code, reason } => {
+                inner.on_server_closed(test, code, reason)
+            }
+            TestEvent::ServerUnhealthy { test, fqdn, reason } => {
+                inner.on_server_unhealthy(test, fqdn, reason)
+            }
+            TestEvent::Connected {
+                test,
+                fqdn,
+                connect_info,
+            } => inner.on_connected(test, fqdn, connect_info),
+            TestEvent::Measurement {
+                test: TestKind::Download,
+                measurement,
+            } => inner.on_download_event(measurement),
+            TestEvent::Measurement {
+                test: TestKind::Upload,
+                measurement,
+            } => inner.on_upload_event(measurement),
+            TestEvent::Progress { test, progress } => inner.on_progress(test, progress),
+            TestEvent::Complete { test } => inner.on_complete(test),
+            TestEvent::Summary { summary } => inner.on_summary(summary),
+            TestEvent::Ping { ping } => inner.on_ping(ping),
+            TestEvent::Sweep { sweep } => inner.on_sweep(sweep),
+            TestEvent::Warning { warning } => inner.on_warning(warning),
+        }
+    }
+}
+
+/// Time since the start of the subtest a measurement reports, by the
+/// application or else by the kernel.
+fn elapsed(m: &Measurement) -> Option<Micros> {
+    m.app_info
+        .as_ref()
+        .map(|app| app.elapsed_time)
+        .or_else(|| m.tcp_info.as_ref()?.elapsed_time)
+}
+
+impl Emitter for FilterEmitter<'_> {
+    fn on_starting(&mut self, test: TestKind) -> Result<()> {
+        self.forward(TestEvent::Starting { test })
+    }
+
+    fn on_error(&mut self, test: TestKind, err: &str) -> Result<()> {
+        self.forward(TestEvent::Error { test, error: err })
+    }
+
+    fn on_server_closed(&mut self, test: TestKind, code: u16, reason: &str) -> Result<()> {
+        self.forward(TestEvent::ServerClosed { test, code, reason })
+    }
+
+    fn on_server_unhealthy(&mut self, test: TestKind, fqdn: &str, reason: &str) -> Result<()> {
+        self.forward(TestEvent::ServerUnhealthy { test, fqdn, reason })
+    }
+
+    fn on_connected(&mut self, test: TestKind, fqdn: &str, info: &ConnectInfo) -> Result<()> {
+        self.forward(TestEvent::Connected {
+            test,
+            fqdn,
+            connect_info: info,
+        })
+    }
+
+    fn on_download_event(&mut self, m: &Measurement) -> Result<()> {
+        self.forward(TestEvent::Measurement {
+            test: TestKind::Download,
+            measurement: m,
+        })
+    }
+
+    fn on_upload_event(&mut self, m: &Measurement) -> Result<()> {
+        self.forward(TestEvent::Measurement {
+            test: TestKind::Upload,
+            measurement: m,
+        })
+    }
+
+    fn on_progress(&mut self, test: TestKind, progress: &Progress) -> Result<()> {
+        self.forward(TestEvent::Progress { test, progress })
+    }
+
+    fn on_complete(&mut self, test: TestKind) -> Result<()> {
+        self.forward(TestEvent::Complete { test })
+    }
+
+    fn on_summary(&mut self, s: &Summary) -> Result<()> {
+        self.forward(TestEvent::Summary { summary: s })
+    }
+
+    fn on_ping(&mut self, p: &PingResult) -> Result<()> {
+        self.forward(TestEvent::Ping { ping: p })
+    }
+
+    fn on_sweep(&mut self, report: &SweepReport) -> Result<()> {
+        self.forward(TestEvent::Sweep { sweep: report })
+    }
+
+    fn on_warning(&mut self, warning: &str) -> Result<()> {
+        self.forward(TestEvent::Warning { warning })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::grade::Thresholds;
+    use crate::spec::{AppInfo, ByteCount, TCPInfo};
+    use crate::summary::{LatencySummary, ServerLocation};
+
+    use super::*;
+
+    #[test]
+    fn human_readable_download_event() {
+        let mut buf = Vec::new();
+        let mut emitter = HumanReadableEmitter::new(&mut buf);
+
+        let m = Measurement {
+            app_info: Some(AppInfo {
+                num_bytes: ByteCount(1_000_000),
+                elapsed_time: Micros(1_000_000),
+                ..Default::default()
+            }),
+            origin: Some(Origin::Client),
+            ..Default::default()
+        };
+
+        emitter.on_download_event(&m).unwrap();
+
+        let out = String::from_utf8(buf).unwrap();
+
+        assert!(out.contains("8.0 Mbit/s"))
+    }
+
+    #[test]
+    fn human_readable_ping() {
+        let mut buf = Vec::new();
+        let mut emitter = HumanReadableEmitter::new(&mut buf);
+
+        let p = PingResult {
+            server_fqdn: "mlab1-lga06".into(),
+            connect_ms: 12.0,
+            rtt_ms: vec![6.0, 5.0, 7.0],
+            min_rtt_ms: None,
+            bytes_received: 0,
+        };
+        emitter.on_ping(&p).unwrap();
+
+        let out = String::from_utf8(buf).unwrap();
+        assert!(out.contains("RTT:     5.0 ms"));
+        assert!(out.contains("MinRTT:       -"));
+    }
+
+    #[test]
+    fn human_readable_partial_summary() {
+        let mut buf = Vec::new();
+        let mut emitter = HumanReadableEmitter::new(&mut buf);
+
+        let subtest = SubtestSummary {
+            throughput_mbps: 80.0,
+            client_throughput_mbps: None,
+            bytes_transferred: 100_000_000,
+            duration_s: 10.0,
+            wire_throughput_mbps: None,
+            min_throughput_mbps: Some(60.0),
+            avg_throughput_mbps: Some(82.5),
+            max_throughput_mbps: Some(91.0),
+            throughput_series_mbps: vec![60.0, 91.0, 0.0, 75.0],
+            latency_ms: 5.0,
+            loaded_latency_ms: Some(25.0),
+            latency_increase_ms: None,
+            rtt_p50_ms: Some(20.0),
+            rtt_p95_ms: Some(31.0),
+            rtt_p99_ms: Some(42.0),
+            bufferbloat_ms: Some(18.0),
+            jitter_ms: Some(3.5),
+            retransmission_pct: Some(0.0),
+            client_retransmission_pct: None,
+            max_bandwidth_mbps: Some(95.0),
+            uuid: Some("ndt-abc123".into()),
+            connect_info: None,
+            complete: true,
+        };
+        let mut s = Summary {
+            server_fqdn: "mlab1-lga06".into(),
+            server_location: Some(ServerLocation {
+                city: "New York".into(),
+                country: "US".into(),
+            }),
+            client_ip: String::new(),
+            server_ip: String::new(),
+            download: Some(subtest.clone()),
+            upload: Some(SubtestSummary {
+                complete: false,
+                ..subtest.clone()
+            }),
+            idle_latency_ms: None,
+            grade: None,
+            latency: Some(LatencySummary {
+                packet_loss_pct: 0.5,
+                min_rtt_ms: 9.0,
+                median_rtt_ms: 11.0,
+                p95_rtt_ms: 14.0,
+                max_rtt_ms: 30.0,
+            }),
+            dscp: None,
+            host_tuning: None,
+            background_mbps: None,
+            contended: false,
+            truncated: false,
+            comparison: None,
+        };
+        let previous = Summary {
+            download: Some(SubtestSummary {
+                throughput_mbps: 100.0,
+                ..subtest
+            }),
+            upload: None,
+            ..s.clone()
+        };
+        s.set_previous(&previous);
+        s.set_idle_latency(5.0);
+        s.set_grade(&Thresholds::default());
+        emitter.on_summary(&s).unwrap();
+
+        let out = String::from_utf8(buf).unwrap();
+        assert!(out.contains("Server: mlab1-lga06 (New York, US)"));
+        assert!(out.contains("Download\n"));
+        assert!(out.contains("Upload (partial)\n"));
+        assert!(out.contains("Idle RTT:     5.0 ms"));
+        assert!(out.contains("Under load:   +20.0 ms"));
+        assert!(out.contains("BBR bandwidth:    95.0 Mbit/s"));
+        assert!(out.contains("Transferred: 100.0 MB in 10.0 s"));
+        assert!(out.contains("Min/avg/max: 60.0 / 82.5 / 91.0 Mbit/s"));
+        assert!(out.contains("Over time: ▆█ ▇\n"));
+        assert!(out.contains("RTT p50/95/99: 20.0 / 31.0 / 42.0 ms"));
+        assert!(out.contains("Jitter:     3.5 ms"));
+        assert!(out.contains("Bufferbloat:   +18.0 ms"));
+        assert!(out.contains("UUID: ndt-abc123"));
+        assert!(out.contains("UDP latency\n"));
+        assert!(out.contains("Download: -20.0 Mbit/s (-20.0 %), latency +0.0 ms, retrans. +0.0 %"));
+        assert!(out.contains("Grade: A (streaming good, gaming good, video calls good)"));
+        assert!(out.contains("Packet loss:     0.5 %"));
+    }
+
+    #[test]
+    fn json_emitter_valid() {
+        let mut buf = Vec::new();
+        let mut emitter = JsonEmitter::new(&mut buf);
+
+        emitter.on_starting(TestKind::Upload).unwrap();
+
+        let out = String::from_utf8(buf).unwrap();
+
+        let res = serde_json::from_str::<serde_json::Value>(&out).unwrap();
+
+        assert_eq!(res["Test"], "upload");
+        assert_eq!(res["Type"], "Starting");
+    }
+
+    #[test]
+    fn json_go_summary() {
+        let mut buf = Vec::new();
+        let mut emitter = JsonEmitter::new(&mut buf).go_summary();
+
+        let client = Measurement {
+            origin: Some(Origin::Client),
+            app_info: Some(AppInfo {
+                elapsed_time: Micros(1_000_000),
+                num_bytes: ByteCount(12_500_000),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let s =
+            Summary::from_measurements("mlab1-lga06".into(), Some(&client), Some(&client), None);
+        emitter.on_summary(&s).unwrap();
+
+        let out = String::from_utf8(buf).unwrap();
+        let res = serde_json::from_str::<serde_json::Value>(&out).unwrap();
+        assert_eq!(res["ServerFQDN"], "mlab1-lga06");
+        assert_eq!(res["Download"]["Throughput"]["Value"], 100.0);
+        assert_eq!(res["Download"]["Throughput"]["Unit"], "Mbit/s");
+        assert_eq!(res["Download"]["Latency"]["Unit"], "ms");
+        assert!(res.get("Upload").is_none());
+        assert!(res.get("Type").is_none());
+    }
+
+    #[test]
+    fn json_go_events() {
+        let mut buf = Vec::new();
+        let mut emitter = JsonEmitter::new(&mut buf).go_events();
+
+        emitter.on_starting(TestKind::Download).unwrap();
+        emitter
+            .on_connected(TestKind::Download, "mlab1-lga06", &ConnectInfo::default())
+            .unwrap();
+        emitter
+            .on_download_event(&Measurement {
+                origin: Some(Origin::Client),
+                ..Default::default()
+            })
+            .unwrap();
+        emitter.on_warning("low buffers").unwrap();
+        emitter.on_error(TestKind::Download, "stalled").unwrap();
+        emitter.on_complete(TestKind::Download).unwrap();
+
+        let out = String::from_utf8(buf).unwrap();
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                r#"{"Key":"starting","Value":{"Test":"download"}}"#,
+                r#"{"Key":"connected","Value":{"Server":"mlab1-lga06","Test":"download"}}"#,
+                r#"{"Key":"measurement","Value":{"Origin":"client","Test":"download"}}"#,
+                r#"{"Key":"error","Value":{"Failure":"stalled","Test":"download"}}"#,
+                r#"{"Key":"complete","Value":{"Test":"download"}}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn markdown_report() {
+        let mut buf = Vec::new();
+        let mut emitter = MarkdownEmitter::new(&mut buf).measurements();
+
+        let client = Measurement {
+            origin: Some(Origin::Client),
+            app_info: Some(AppInfo {
+                elapsed_time: Micros(1_000_000),
+                num_bytes: ByteCount(12_500_000),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        emitter.on_starting(TestKind::Download).unwrap();
+        emitter.on_download_event(&client).unwrap();
+        emitter.on_warning("low buffers").unwrap();
+        let s =
+            Summary::from_measurements("mlab1-lga06".into(), Some(&client), Some(&client), None);
+        emitter.on_summary(&s).unwrap();
+
+        let out = String::from_utf8(buf).unwrap();
+        assert!(out.starts_with("## ndt7 speed test\n\n**Server:** mlab1-lga06  \n"));
+        assert!(out.contains("| Throughput | 100.0 Mbit/s | - |\n"));
+        assert!(!out.contains("| UUID |"));
+        assert!(out.contains("- Warning: low buffers\n"));
+        assert!(out.contains("| download | 1.00 | 100.0 | - | - |\n"));
+        assert!(out.ends_with("</details>\n"));
+    }
+
+    #[test]
+    fn json_server_closed() {
+        let mut buf = Vec::new();
+        let mut emitter = JsonEmitter::new(&mut buf);
+
+        emitter
+            .on_server_closed(TestKind::Download, 1011, "internal error")
+            .unwrap();
+
+        let out = String::from_utf8(buf).unwrap();
+        let res = serde_json::from_str::<serde_json::Value>(&out).unwrap();
+
+        assert_eq!(res["Type"], "ServerClosed");
+        assert_eq!(res["Code"], 1011);
+        assert_eq!(res["Reason"], "internal error");
+    }
+
+    #[test]
+    fn json_server_unhealthy() {
+        let mut buf = Vec::new();
+        let mut emitter = JsonEmitter::new(&mut buf);
+
+        emitter
+            .on_server_unhealthy(TestKind::Download, "mlab1-lga06", "connection refused")
+            .unwrap();
+
+        let out = String::from_utf8(buf).unwrap();
+        let res = serde_json::from_str::<serde_json::Value>(&out).unwrap();
+
+        assert_eq!(res["Type"], "ServerUnhealthy");
+        assert_eq!(res["FQDN"], "mlab1-lga06");
+        assert_eq!(res["Reason"], "connection refused");
+    }
+
+    #[test]
+    fn json_progress() {
+        let mut buf = Vec::new();
+        let mut emitter = JsonEmitter::new(&mut buf);
+
+        let progress = Progress::new(Micros(2_500_000), Duration::from_secs(10));
+        assert_eq!(progress.fraction(), 0.25);
+        assert_eq!(progress.remaining_time, Micros(7_500_000));
+        emitter.on_progress(TestKind::Upload, &progress).unwrap();
+
+        let out = String::from_utf8(buf).unwrap();
+        let res = serde_json::from_str::<serde_json::Value>(&out).unwrap();
+
+        assert_eq!(res["Type"], "Progress");
+        assert_eq!(res["Test"], "upload");
+        assert_eq!(res["Duration"], 10_000_000);
+        assert_eq!(res["RemainingTime"], 7_500_000);
+    }
+
+    #[test]
+    fn statsd_gauges() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut emitter = StatsdEmitter::new(server.local_addr().unwrap())
+            .unwrap()
+            .prefix("home");
+
+        let m = Measurement {
+            app_info: Some(AppInfo {
+                num_bytes: ByteCount(1_000_000),
+                elapsed_time: Micros(1_000_000),
+                ..Default::default()
+            }),
+            origin: Some(Origin::Client),
+            tcp_info: Some(TCPInfo {
+                rtt: Some(Micros(20_000)),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        emitter.on_download_event(&m).unwrap();
+        // Only the server's RTT is reported.
+        let server_m = Measurement {
+            origin: Some(Origin::Server),
+            tcp_info: Some(TCPInfo {
+                rtt: Some(Micros(30_000)),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        emitter.on_download_event(&server_m).unwrap();
+        emitter.on_error(TestKind::Upload, "oops").unwrap();
+
+        let mut buf = [0u8; 512];
+        let mut recv = || {
+            let n = server.recv(&mut buf).unwrap();
+            String::from_utf8(buf[..n].to_vec()).unwrap()
+        };
+        assert_eq!(recv(), "home.download.throughput_mbps:8.000|g");
+        assert_eq!(recv(), "home.download.rtt_ms:30.000|g");
+        assert_eq!(recv(), "home.upload.errors:1|c");
+    }
+
+    #[test]
+    fn composite_forwards_to_all() {
+        let mut human = Vec::new();
+        let mut json = Vec::new();
+        let mut emitter =
+            CompositeEmitter::new(vec![Box::new(HumanReadableEmitter::new(&mut human))]);
+        emitter.push(Box::new(JsonEmitter::new(&mut json)));
+
+        emitter.on_warning("low buffers").unwrap();
+        drop(emitter);
+
+        assert_eq!(String::from_utf8(human).unwrap(), "warning: low buffers\n");
+        let res = serde_json::from_slice::<serde_json::Value>(&json).unwrap();
+        assert_eq!(res["Type"], "Warning");
+    }
+
+    struct BrokenPipe;
+
+    impl Write for BrokenPipe {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn composite_isolates_errors() {
+        let mut json = Vec::new();
+        let mut emitter = CompositeEmitter::new(vec![
+            Box::new(JsonEmitter::new(BrokenPipe)),
+            Box::new(JsonEmitter::new(&mut json)),
+        ]);
+
+        // The event reached the working emitter.
+        emitter.on_warning("low buffers").unwrap();
+        drop(emitter);
+
+        let res = serde_json::from_slice::<serde_json::Value>(&json).unwrap();
+        assert_eq!(res["Type"], "Warning");
+
+        let mut emitter = CompositeEmitter::new(vec![
+            Box::new(JsonEmitter::new(BrokenPipe)),
+            Box::new(JsonEmitter::new(BrokenPipe)),
+        ]);
+        assert!(emitter.on_warning("low buffers").is_err());
+    }
+
+    #[test]
+    fn filter_samples_measurements() {
+        let mut json = Vec::new();
+        let mut emitter = FilterEmitter::new(Box::new(JsonEmitter::new(&mut json)))
+            .origin(Origin::Client)
+            .measurement_interval(Duration::from_secs(1))
+            .skip("progress");
+
+        emitter.on_starting(TestKind::Download).unwrap();
+        for ms in [250, 500, 1000, 1250, 2300] {
+            let m = Measurement {
+                app_info: Some(AppInfo {
+                    elapsed_time: Micros(ms * 1000),
+                    num_bytes: ByteCount(ms),
+                    ..Default::default()
+                }),
+                origin: Some(Origin::Client),
+                ..Default::default()
+            };
+            emitter.on_download_event(&m).unwrap();
+            let server = Measurement {
+                origin: Some(Origin::Server),
+                ..m.clone()
+            };
+            emitter.on_download_event(&server).unwrap();
+            let progress = Progress::new(Micros(ms * 1000), Duration::from_secs(10));
+            emitter.on_progress(TestKind::Download, &progress).unwrap();
+        }
+        drop(emitter);
+
+        let events: Vec<serde_json::Value> = serde_json::Deserializer::from_slice(&json)
+            .into_iter()
+            .map(|v| v.unwrap())
+            .collect();
+        let elapsed: Vec<_> = events[1..]
+            .iter()
+            .map(|e| e["Measurement"]["AppInfo"]["ElapsedTime"].as_i64().unwrap())
+            .collect();
+        assert_eq!(events[0]["Type"], "Starting");
+        assert_eq!(elapsed, [250_000, 1_250_000, 2_300_000]);
     }
 }
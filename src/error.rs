@@ -1,7 +1,13 @@
 //! Error types for the ndt7 client.
 
+use std::time::Duration;
+
 use crate::client::AddressFamily;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
+use tokio_tungstenite::tungstenite::Error as WsError;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
 
 /// Errors that can occur during ndt7 operations.
 #[derive(Debug, Error)]
@@ -15,12 +21,29 @@ pub enum Ndt7Error {
     /// The Locate API returned 204: M-Lab is out of capacity.
     #[error("server at capacity; try again later")]
     NoCapacity,
+    /// The Locate API returned 429: too many requests from this client.
+    #[error("rate limited by the Locate API")]
+    RateLimited {
+        /// How long to wait before the next request, from the response's
+        /// `Retry-After` header, if it gave a number of seconds.
+        retry_after: Option<Duration>,
+    },
     /// JSON serialization or deserialization failed.
     #[error("serialize/deserialize error: {0}")]
     JsonError(#[from] serde_json::Error),
-    /// A test exceeded its time limit.
-    #[error("timeout occured")]
-    Timeout(#[from] tokio::time::error::Elapsed),
+    /// Connection setup (TCP, TLS and WebSocket handshake) did not finish in
+    /// time.
+    #[error("connect timed out after {elapsed:?}")]
+    ConnectTimeout {
+        /// Time spent waiting for the connection.
+        elapsed: Duration,
+    },
+    /// The peer stopped sending or accepting data mid-test.
+    #[error("connection stalled: no progress for {elapsed:?}")]
+    StallTimeout {
+        /// Time without progress before giving up.
+        elapsed: Duration,
+    },
     /// A WebSocket-level error occurred.
     #[error("websocket error: {0}")]
     WebSocket(Box<tokio_tungstenite::tungstenite::Error>),
@@ -39,14 +62,207 @@ pub enum Ndt7Error {
     /// No addresses of the requested IP family were found for the host.
     #[error("no {0} address found")]
     NoAddressFound(AddressFamily),
+    /// The client's overall deadline expired.
+    #[error("deadline exceeded after {elapsed:?}")]
+    TestDeadline {
+        /// Time since the deadline clock started.
+        elapsed: Duration,
+    },
+    /// The server closed the WebSocket with a code other than normal closure.
+    #[error("server closed connection: {code} {reason}")]
+    ServerClosed {
+        /// WebSocket close code (RFC 6455, section 7.4).
+        code: u16,
+        /// Close reason sent by the server, possibly empty.
+        reason: String,
+    },
+    /// The M-Lab archive query failed or returned an unexpected result.
+    #[error("archive query failed: {0}")]
+    ArchiveQuery(String),
+    /// TLS certificate verification is enabled but there are no root
+    /// certificates to verify servers against.
+    #[error(
+        "no trusted root certificates: enable the `webpki-roots` or `native-roots` feature, \
+         install system CA certificates or supply roots with \
+         ClientBuilder::root_certificates_pem"
+    )]
+    NoTrustAnchors,
+    /// The application cancelled the test.
+    #[error("cancelled")]
+    Cancelled,
+    /// An error read back from a recording, see [`crate::replay`].
+    #[error("{message}")]
+    Replayed {
+        /// Classification of the original error.
+        kind: ErrorKind,
+        /// Message of the original error.
+        message: String,
+    },
+    /// A [webhook](crate::webhook) request failed after all retries.
+    #[error("webhook delivery failed: {0}")]
+    WebhookFailed(String),
+    /// A [proxy](crate::proxy) refused or failed to open a tunnel to the
+    /// server.
+    #[error("proxy failed: {0}")]
+    ProxyFailed(String),
+    /// The client configuration is invalid.
+    #[error("invalid configuration: {0}")]
+    Config(#[from] ConfigError),
+}
+
+/// Invalid [`ClientBuilder`](crate::client::ClientBuilder) settings, reported
+/// by [`ClientBuilder::try_build`](crate::client::ClientBuilder::try_build).
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ConfigError {
+    /// A string option was set to an empty value.
+    #[error("{0} must not be empty")]
+    Empty(&'static str),
+    /// A size or duration option was set to zero.
+    #[error("{0} must not be zero")]
+    Zero(&'static str),
+    /// A size option exceeds its limit.
+    #[error("{0} must not exceed {1}")]
+    TooLarge(&'static str, usize),
+    /// The lower bound of a range option exceeds its upper bound.
+    #[error("{0} minimum exceeds its maximum")]
+    InvalidRange(&'static str),
+    /// The DSCP class does not fit in six bits.
+    #[error("DSCP class {0} out of range (0-63)")]
+    DscpOutOfRange(u8),
+    /// Two options were set that cannot be used together.
+    #[error("{0} cannot be combined with {1}")]
+    Conflict(&'static str, &'static str),
+    /// An option was set without another one it depends on.
+    #[error("{0} requires {1}")]
+    Requires(&'static str, &'static str),
+    /// A root certificate bundle is not valid PEM or holds no certificates.
+    #[error("invalid root certificates: {0}")]
+    InvalidRootCertificates(String),
+    /// A fallback server is neither a hostname nor a URL.
+    #[error("invalid fallback server: {0}")]
+    InvalidServer(String),
+    /// A webhook header name or value is not valid in HTTP.
+    #[error("invalid webhook header: {0}")]
+    InvalidHeader(String),
+    /// A proxy URL does not parse or has an unsupported scheme.
+    #[error("invalid proxy: {0}")]
+    InvalidProxy(String),
+    /// The HTTP client for the Locate API could not be built from the
+    /// settings.
+    #[error("cannot build HTTP client: {0}")]
+    HttpClient(String),
 }
 
 // Reducing size of Ndt7Error by boxing the large tungstenite::Error variant.
-impl From<tokio_tungstenite::tungstenite::Error> for Ndt7Error {
-    fn from(e: tokio_tungstenite::tungstenite::Error) -> Self {
+impl From<WsError> for Ndt7Error {
+    fn from(e: WsError) -> Self {
         Ndt7Error::WebSocket(Box::new(e))
     }
 }
 
+/// Broad classification of an [`Ndt7Error`], for deciding whether to retry
+/// or alert without matching on error messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// Transient network failure: connection refused or reset, I/O timeout.
+    Network,
+    /// The server or Locate API refused the request, e.g. no capacity, an
+    /// HTTP error on upgrade or an abnormal close.
+    ServerRejected,
+    /// The client was configured or invoked incorrectly.
+    Misconfiguration,
+    /// The peer did not follow the ndt7 or WebSocket protocol.
+    Protocol,
+    /// The client's overall deadline expired, or the test was cancelled.
+    Deadline,
+}
+
+impl Ndt7Error {
+    /// Classify the error.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Ndt7Error::LocateFailed(e) if e.is_builder() => ErrorKind::Misconfiguration,
+            Ndt7Error::LocateFailed(e) if e.status().is_some() => ErrorKind::ServerRejected,
+            Ndt7Error::LocateFailed(_)
+            | Ndt7Error::ConnectTimeout { .. }
+            | Ndt7Error::StallTimeout { .. }
+            | Ndt7Error::ProxyFailed(_)
+            | Ndt7Error::IoError(_) => ErrorKind::Network,
+            Ndt7Error::NoTargets
+            | Ndt7Error::NoCapacity
+            | Ndt7Error::RateLimited { .. }
+            | Ndt7Error::ServerClosed { .. }
+            | Ndt7Error::ArchiveQuery(_)
+            | Ndt7Error::WebhookFailed(_) => ErrorKind::ServerRejected,
+            Ndt7Error::JsonError(_) | Ndt7Error::ProtocolViolation(_) => ErrorKind::Protocol,
+            Ndt7Error::WebSocket(e) => match e.as_ref() {
+                WsError::ConnectionClosed
+                | WsError::AlreadyClosed
+                | WsError::Io(_)
+                | WsError::Tls(_)
+                | WsError::WriteBufferFull(_) => ErrorKind::Network,
+                WsError::Http(_) => ErrorKind::ServerRejected,
+                WsError::Url(_) => ErrorKind::Misconfiguration,
+                _ => ErrorKind::Protocol,
+            },
+            Ndt7Error::ServiceUnsupported(_)
+            | Ndt7Error::UrlParse(_)
+            | Ndt7Error::NoAddressFound(_)
+            | Ndt7Error::NoTrustAnchors
+            | Ndt7Error::Config(_) => ErrorKind::Misconfiguration,
+            Ndt7Error::TestDeadline { .. } | Ndt7Error::Cancelled => ErrorKind::Deadline,
+            Ndt7Error::Replayed { kind, .. } => *kind,
+        }
+    }
+
+    /// Whether retrying the operation later, possibly against another
+    /// server, may succeed. True for network and server-side failures.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self.kind(), ErrorKind::Network | ErrorKind::ServerRejected)
+    }
+
+    /// Map a received close frame to an error, unless it signals normal
+    /// closure. A close without a frame is treated as normal.
+    pub(crate) fn check_close(frame: Option<CloseFrame>) -> Result<()> {
+        match frame {
+            Some(frame) if frame.code != CloseCode::Normal => Err(Ndt7Error::ServerClosed {
+                code: frame.code.into(),
+                reason: frame.reason.to_string(),
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
 /// A `Result` type alias using [`Ndt7Error`].
 pub type Result<T> = std::result::Result<T, Ndt7Error>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_errors() {
+        let reset = std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+        assert_eq!(Ndt7Error::from(reset).kind(), ErrorKind::Network);
+        assert!(Ndt7Error::NoCapacity.is_retryable());
+
+        let closed = Ndt7Error::ServerClosed {
+            code: 1011,
+            reason: String::new(),
+        };
+        assert_eq!(closed.kind(), ErrorKind::ServerRejected);
+
+        let config = Ndt7Error::from(ConfigError::Zero("deadline"));
+        assert_eq!(config.kind(), ErrorKind::Misconfiguration);
+        assert!(!config.is_retryable());
+
+        let violation = Ndt7Error::from(WsError::AttackAttempt);
+        assert_eq!(violation.kind(), ErrorKind::Protocol);
+        let deadline = Ndt7Error::TestDeadline {
+            elapsed: Duration::from_secs(30),
+        };
+        assert!(!deadline.is_retryable());
+    }
+}
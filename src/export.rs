@@ -0,0 +1,222 @@
+//! Test results in M-Lab's archival format.
+//!
+//! ndt-server archives every test as an `NDT7Result` JSON document holding
+//! the connection endpoints, the start and end times, and the complete client
+//! and server measurement series of each subtest. [`ArchivalResult`] builds
+//! the same document from a [`MeasurementLog`], so results of this client can
+//! be fed to pipelines built around M-Lab's data.
+//!
+//! Times are the client's wall clock, unless the server reported a start
+//! time in its [`ConnectionInfo`](crate::spec::ConnectionInfo).
+
+use std::io::Write;
+use std::net::SocketAddr;
+
+use serde::Serialize;
+
+use crate::error::Result;
+use crate::metrics::{MeasurementLog, TestLog};
+use crate::spec::{Measurement, utc_timestamp};
+
+/// A metadata entry, as archived for the client's query parameters.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct NameValue {
+    /// Parameter name, e.g. `client_name`.
+    pub name: String,
+    /// Parameter value.
+    pub value: String,
+}
+
+/// Archived data of one subtest.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ArchivalData {
+    /// Test UUID assigned by the server, or empty if it reported none.
+    #[serde(rename = "UUID")]
+    pub uuid: String,
+    /// Start of the subtest in RFC 3339 format.
+    pub start_time: String,
+    /// End of the subtest in RFC 3339 format.
+    pub end_time: String,
+    /// Every measurement the server sent.
+    pub server_measurements: Vec<Measurement>,
+    /// Every measurement the client took.
+    pub client_measurements: Vec<Measurement>,
+    /// Metadata the client sent with the request.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub client_metadata: Vec<NameValue>,
+}
+
+impl ArchivalData {
+    /// The archived data of `log`, or `None` if it holds no measurements.
+    pub fn from_log(log: &TestLog) -> Option<ArchivalData> {
+        if log.client.is_empty() && log.server.is_empty() {
+            return None;
+        }
+        let info = log.connection_info();
+        let start_time = info
+            .and_then(|info| info.start_time.clone())
+            .or_else(|| log.start_time.map(utc_timestamp))
+            .unwrap_or_default();
+        Some(ArchivalData {
+            uuid: info.and_then(|info| info.uuid.clone()).unwrap_or_default(),
+            start_time,
+            end_time: log.end_time.map(utc_timestamp).unwrap_or_default(),
+            server_measurements: log.server.clone(),
+            client_measurements: log.client.clone(),
+            client_metadata: Vec::new(),
+        })
+    }
+}
+
+/// A complete test result in ndt-server's `NDT7Result` schema.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ArchivalResult {
+    /// Commit of the software that produced the result. Always empty, as
+    /// this crate is not built from a git checkout.
+    pub git_short_commit: String,
+    /// Version of the software that produced the result.
+    pub version: String,
+    /// Server IP address.
+    #[serde(rename = "ServerIP")]
+    pub server_ip: String,
+    /// Server port.
+    pub server_port: u16,
+    /// Client IP address.
+    #[serde(rename = "ClientIP")]
+    pub client_ip: String,
+    /// Client port.
+    pub client_port: u16,
+    /// Start of the first subtest in RFC 3339 format.
+    pub start_time: String,
+    /// End of the last subtest in RFC 3339 format.
+    pub end_time: String,
+    /// The download subtest, if it ran.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub download: Option<ArchivalData>,
+    /// The upload subtest, if it ran.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upload: Option<ArchivalData>,
+}
+
+impl ArchivalResult {
+    /// The archived result of the tests in `log`.
+    ///
+    /// Endpoints come from the first subtest whose server reported them.
+    pub fn from_log(log: &MeasurementLog) -> ArchivalResult {
+        let download = ArchivalData::from_log(&log.download);
+        let upload = ArchivalData::from_log(&log.upload);
+        let info = log
+            .download
+            .connection_info()
+            .or_else(|| log.upload.connection_info());
+        let (client_ip, client_port) = endpoint(info.map(|i| i.client.as_str()));
+        let (server_ip, server_port) = endpoint(info.map(|i| i.server.as_str()));
+        let first = download.as_ref().or(upload.as_ref());
+        let last = upload.as_ref().or(download.as_ref());
+        let start_time = first.map(|d| d.start_time.clone());
+        let end_time = last.map(|d| d.end_time.clone());
+        ArchivalResult {
+            git_short_commit: String::new(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            server_ip,
+            server_port,
+            client_ip,
+            client_port,
+            start_time: start_time.unwrap_or_default(),
+            end_time: end_time.unwrap_or_default(),
+            download,
+            upload,
+        }
+    }
+
+    /// Attach the metadata the client sent with its requests, e.g. from
+    /// [`Client::client_metadata`](crate::client::Client::client_metadata),
+    /// to every subtest.
+    pub fn client_metadata<N, V>(mut self, metadata: impl IntoIterator<Item = (N, V)>) -> Self
+    where
+        N: Into<String>,
+        V: Into<String>,
+    {
+        let metadata: Vec<_> = metadata
+            .into_iter()
+            .map(|(name, value)| NameValue {
+                name: name.into(),
+                value: value.into(),
+            })
+            .collect();
+        for data in self.download.iter_mut().chain(&mut self.upload) {
+            data.client_metadata = metadata.clone();
+        }
+        self
+    }
+
+    /// Write the result to `out` as one line of JSON.
+    pub fn write(&self, mut out: impl Write) -> Result<()> {
+        serde_json::to_writer(&mut out, self)?;
+        writeln!(out)?;
+        Ok(())
+    }
+}
+
+/// Split an `ip:port` address, or return an empty IP and port 0 if it is
+/// missing or malformed.
+fn endpoint(addr: Option<&str>) -> (String, u16) {
+    match addr.and_then(|a| a.parse::<SocketAddr>().ok()) {
+        Some(addr) => (addr.ip().to_string(), addr.port()),
+        None => (String::new(), 0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spec::{ConnectionInfo, Origin, TestKind};
+
+    #[test]
+    fn archival_schema() {
+        let mut log = MeasurementLog::new();
+        log.push(
+            TestKind::Download,
+            Measurement {
+                origin: Some(Origin::Server),
+                connection_info: Some(ConnectionInfo {
+                    client: "[2001:db8::1]:54321".into(),
+                    server: "192.0.2.1:443".into(),
+                    uuid: Some("ndt-abc".into()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        );
+        log.push(
+            TestKind::Download,
+            Measurement {
+                origin: Some(Origin::Client),
+                ..Default::default()
+            },
+        );
+
+        let result = ArchivalResult::from_log(&log).client_metadata([("client_name", "test")]);
+        let mut out = Vec::new();
+        result.write(&mut out).unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(value["ClientIP"], "2001:db8::1");
+        assert_eq!(value["ClientPort"], 54321);
+        assert_eq!(value["ServerIP"], "192.0.2.1");
+        assert_eq!(value["ServerPort"], 443);
+        assert_eq!(value["StartTime"], value["Download"]["StartTime"]);
+        assert!(value.get("Upload").is_none());
+
+        let download = &value["Download"];
+        assert_eq!(download["UUID"], "ndt-abc");
+        assert_eq!(download["ServerMeasurements"].as_array().unwrap().len(), 1);
+        assert_eq!(download["ClientMeasurements"].as_array().unwrap().len(), 1);
+        assert_eq!(
+            download["ClientMetadata"],
+            serde_json::json!([{"Name": "client_name", "Value": "test"}])
+        );
+    }
+}
@@ -0,0 +1,236 @@
+//! Connection quality grades for non-expert users.
+//!
+//! A [`Grade`] rates a [`Summary`] for streaming, gaming and video calls
+//! and condenses the ratings into a letter. Each use case has a
+//! [`Requirement`] of throughput and latency under load; a connection that
+//! meets it is [`Rating::Good`], one within a factor of two of it is
+//! [`Rating::Fair`]. Retransmissions above
+//! [`Thresholds::max_retransmission_pct`] lower every rating by one step.
+//! Thresholds other than the defaults can be read from JSON, e.g.
+//! `{"Gaming":{"DownloadMbps":20,"UploadMbps":5,"LatencyMs":30}}`; omitted
+//! use cases keep their defaults.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::summary::Summary;
+
+/// How well a connection suits a use case.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Rating {
+    /// Falls well short of the requirement.
+    Poor,
+    /// Within a factor of two of the requirement.
+    Fair,
+    /// Meets the requirement.
+    Good,
+}
+
+impl Rating {
+    fn points(self) -> u32 {
+        self as u32
+    }
+
+    fn lower(self) -> Rating {
+        match self {
+            Rating::Good => Rating::Fair,
+            Rating::Fair | Rating::Poor => Rating::Poor,
+        }
+    }
+}
+
+impl fmt::Display for Rating {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Rating::Poor => "poor",
+            Rating::Fair => "fair",
+            Rating::Good => "good",
+        })
+    }
+}
+
+/// What a use case needs from a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Requirement {
+    /// Download throughput in Mbit/s.
+    pub download_mbps: f64,
+    /// Upload throughput in Mbit/s. Ignored if no upload test ran.
+    pub upload_mbps: f64,
+    /// Largest acceptable round-trip time under load, in milliseconds.
+    pub latency_ms: f64,
+}
+
+impl Requirement {
+    fn rate(&self, download_mbps: f64, upload_mbps: Option<f64>, latency_ms: f64) -> Rating {
+        let meets = |factor: f64| {
+            download_mbps * factor >= self.download_mbps
+                && upload_mbps.is_none_or(|up| up * factor >= self.upload_mbps)
+                && latency_ms <= self.latency_ms * factor
+        };
+        if meets(1.0) {
+            Rating::Good
+        } else if meets(2.0) {
+            Rating::Fair
+        } else {
+            Rating::Poor
+        }
+    }
+}
+
+/// Requirements of each use case.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+pub struct Thresholds {
+    /// HD/4K video streaming.
+    pub streaming: Requirement,
+    /// Online gaming.
+    pub gaming: Requirement,
+    /// Video calls.
+    pub video_call: Requirement,
+    /// Percentage of retransmitted bytes above which every rating is
+    /// lowered by one step.
+    pub max_retransmission_pct: f64,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Thresholds {
+            streaming: Requirement {
+                download_mbps: 25.0,
+                upload_mbps: 1.0,
+                latency_ms: 200.0,
+            },
+            gaming: Requirement {
+                download_mbps: 10.0,
+                upload_mbps: 3.0,
+                latency_ms: 50.0,
+            },
+            video_call: Requirement {
+                download_mbps: 5.0,
+                upload_mbps: 3.0,
+                latency_ms: 100.0,
+            },
+            max_retransmission_pct: 2.0,
+        }
+    }
+}
+
+/// Overall grade and per use case ratings of a connection.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Grade {
+    /// Letter grade from `A` (every use case good) to `F`.
+    pub letter: char,
+    /// Suitability for video streaming.
+    pub streaming: Rating,
+    /// Suitability for online gaming.
+    pub gaming: Rating,
+    /// Suitability for video calls.
+    pub video_call: Rating,
+}
+
+impl Grade {
+    /// Grade `summary` against `thresholds`, or `None` if it has no
+    /// download results.
+    ///
+    /// Latency is the larger loaded RTT of the subtests, or their minimum
+    /// RTT where no loaded RTT was reported.
+    pub fn from_summary(summary: &Summary, thresholds: &Thresholds) -> Option<Grade> {
+        let download = summary.download.as_ref()?;
+        let upload = summary.upload.as_ref();
+        let latency_ms = [Some(download), upload]
+            .into_iter()
+            .flatten()
+            .map(|s| s.loaded_latency_ms.unwrap_or(s.latency_ms))
+            .fold(0.0, f64::max);
+        let retransmission_pct = [Some(download), upload]
+            .into_iter()
+            .flatten()
+            .filter_map(|s| s.data_retransmission_pct())
+            .fold(0.0, f64::max);
+
+        let upload_mbps = upload.map(|s| s.throughput_mbps);
+        let rate = |req: &Requirement| {
+            let rating = req.rate(download.throughput_mbps, upload_mbps, latency_ms);
+            if retransmission_pct > thresholds.max_retransmission_pct {
+                rating.lower()
+            } else {
+                rating
+            }
+        };
+        let streaming = rate(&thresholds.streaming);
+        let gaming = rate(&thresholds.gaming);
+        let video_call = rate(&thresholds.video_call);
+        let letter = match streaming.points() + gaming.points() + video_call.points() {
+            6 => 'A',
+            5 => 'B',
+            4 => 'C',
+            2 | 3 => 'D',
+            _ => 'F',
+        };
+        Some(Grade {
+            letter,
+            streaming,
+            gaming,
+            video_call,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spec::{AppInfo, ByteCount, Measurement, Micros, Origin, TCPInfo};
+
+    fn summary(download_mbps: i64, rtt_ms: i64) -> Summary {
+        let client = Measurement {
+            origin: Some(Origin::Client),
+            app_info: Some(AppInfo {
+                elapsed_time: Micros(1_000_000),
+                num_bytes: ByteCount(download_mbps * 125_000),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let server = Measurement {
+            origin: Some(Origin::Server),
+            tcp_info: Some(TCPInfo {
+                rtt: Some(Micros(rtt_ms * 1000)),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        Summary::from_measurements(String::new(), Some(&client), Some(&server), None)
+    }
+
+    #[test]
+    fn grades() {
+        let thresholds = Thresholds::default();
+        let fast = Grade::from_summary(&summary(100, 20), &thresholds).unwrap();
+        assert_eq!(fast.letter, 'A');
+
+        // Enough for streaming, too laggy for gaming.
+        let laggy = Grade::from_summary(&summary(100, 120), &thresholds).unwrap();
+        assert_eq!(
+            (laggy.streaming, laggy.gaming, laggy.video_call),
+            (Rating::Good, Rating::Poor, Rating::Fair)
+        );
+        assert_eq!(laggy.letter, 'D');
+    }
+
+    #[test]
+    fn thresholds_from_json() {
+        let json = r#"{"Gaming":{"DownloadMbps":20,"UploadMbps":5,"LatencyMs":150},"MaxRetransmissionPct":5}"#;
+        let thresholds: Thresholds = serde_json::from_str(json).unwrap();
+        assert_eq!(thresholds.gaming.latency_ms, 150.0);
+        assert_eq!(thresholds.max_retransmission_pct, 5.0);
+        assert_eq!(thresholds.streaming, Thresholds::default().streaming);
+
+        // Laggy for the default gaming requirement, fine for this one.
+        let laggy = Grade::from_summary(&summary(100, 120), &thresholds).unwrap();
+        assert_eq!(laggy.gaming, Rating::Good);
+    }
+}
@@ -0,0 +1,216 @@
+//! Host TCP settings relevant to measurement quality.
+//!
+//! On high bandwidth-delay-product paths, small kernel buffer limits or a
+//! disabled window scale cap throughput well below the link capacity.
+//! [`HostTuning::inspect`] reads the relevant settings (currently Linux only)
+//! and produces advisory warnings to help interpret surprisingly low results.
+//!
+//! Other transfers running on the host compete with the test for the link.
+//! [`background_traffic_mbps`] samples the interface counters to detect them.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// Buffer size needed to fill 1 Gbit/s at 100 ms RTT (16 MiB, rounded up).
+pub const RECOMMENDED_BUFFER_MAX: u64 = 16 << 20;
+
+/// Background throughput above which a test is considered contended.
+pub const CONTENTION_THRESHOLD_MBPS: f64 = 10.0;
+
+/// How long interface counters are sampled before a test.
+pub const CONTENTION_SAMPLE: Duration = Duration::from_millis(250);
+
+/// Snapshot of host TCP settings. Fields are `None` where not readable.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct HostTuning {
+    /// Maximum receive buffer an application may request (`net.core.rmem_max`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rmem_max: Option<u64>,
+    /// Maximum send buffer an application may request (`net.core.wmem_max`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wmem_max: Option<u64>,
+    /// Receive buffer autotuning limit (`net.ipv4.tcp_rmem`, max value).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tcp_rmem_max: Option<u64>,
+    /// Send buffer autotuning limit (`net.ipv4.tcp_wmem`, max value).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tcp_wmem_max: Option<u64>,
+    /// Whether TCP window scaling is enabled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub window_scaling: Option<bool>,
+    /// Default congestion control algorithm.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub congestion_control: Option<String>,
+    /// Advisory warnings derived from the settings above.
+    #[serde(default)]
+    pub warnings: Vec<String>,
+}
+
+impl HostTuning {
+    /// Read the host settings and derive warnings.
+    ///
+    /// Returns an empty snapshot on platforms where settings are not readable.
+    pub fn inspect() -> HostTuning {
+        let mut tuning = read_settings();
+        tuning.warnings = tuning.check();
+        tuning
+    }
+
+    fn check(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if let Some(max) = self.tcp_rmem_max.filter(|&m| m < RECOMMENDED_BUFFER_MAX) {
+            warnings.push(format!(
+                "net.ipv4.tcp_rmem max is {} KiB; download throughput may be limited on high-latency paths",
+                max / 1024
+            ));
+        }
+        if let Some(max) = self.tcp_wmem_max.filter(|&m| m < RECOMMENDED_BUFFER_MAX) {
+            warnings.push(format!(
+                "net.ipv4.tcp_wmem max is {} KiB; upload throughput may be limited on high-latency paths",
+                max / 1024
+            ));
+        }
+        if let Some(max) = self.rmem_max.filter(|&m| m < RECOMMENDED_BUFFER_MAX) {
+            warnings.push(format!(
+                "net.core.rmem_max is {} KiB; receive buffers set with SO_RCVBUF are capped at it",
+                max / 1024
+            ));
+        }
+        if let Some(max) = self.wmem_max.filter(|&m| m < RECOMMENDED_BUFFER_MAX) {
+            warnings.push(format!(
+                "net.core.wmem_max is {} KiB; send buffers set with SO_SNDBUF are capped at it",
+                max / 1024
+            ));
+        }
+        if self.window_scaling == Some(false) {
+            warnings.push(
+                "TCP window scaling is disabled; throughput is capped at 64 KiB per RTT".into(),
+            );
+        }
+        if self.congestion_control.as_deref() == Some("reno") {
+            warnings.push(
+                "congestion control is reno; cubic or bbr perform better on lossy or long paths"
+                    .into(),
+            );
+        }
+        warnings
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn read_settings() -> HostTuning {
+    let read = |name: &str| std::fs::read_to_string(format!("/proc/sys/net/{name}")).ok();
+    HostTuning {
+        rmem_max: read("core/rmem_max").and_then(|s| s.trim().parse().ok()),
+        wmem_max: read("core/wmem_max").and_then(|s| s.trim().parse().ok()),
+        tcp_rmem_max: read("ipv4/tcp_rmem").and_then(|s| triplet_max(&s)),
+        tcp_wmem_max: read("ipv4/tcp_wmem").and_then(|s| triplet_max(&s)),
+        window_scaling: read("ipv4/tcp_window_scaling").map(|s| s.trim() != "0"),
+        congestion_control: read("ipv4/tcp_congestion_control").map(|s| s.trim().to_string()),
+        warnings: Vec::new(),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_settings() -> HostTuning {
+    HostTuning::default()
+}
+
+/// Measure the host's network throughput over `window`, in Mbit/s, summed
+/// over both directions of the physical interfaces. Virtual interfaces
+/// (loopback, bridges, tunnels, container veths) have no device behind them
+/// in `/sys/class/net` and are skipped, as their traffic is either local or
+/// already counted on a physical interface.
+///
+/// Returns `None` where interface counters are not readable (non-Linux).
+pub async fn background_traffic_mbps(window: Duration) -> Option<f64> {
+    let before = interface_bytes()?;
+    tokio::time::sleep(window).await;
+    let after = interface_bytes()?;
+    Some(8.0 * after.saturating_sub(before) as f64 / window.as_micros() as f64)
+}
+
+#[cfg(target_os = "linux")]
+fn interface_bytes() -> Option<u64> {
+    let dev = std::fs::read_to_string("/proc/net/dev").ok()?;
+    let net = std::path::Path::new("/sys/class/net");
+    Some(net_dev_bytes(&dev, |name| {
+        net.join(name).join("device").exists()
+    }))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn interface_bytes() -> Option<u64> {
+    None
+}
+
+/// Sum received and sent bytes in `/proc/net/dev` of the interfaces for
+/// which `physical` returns true.
+#[cfg_attr(not(any(target_os = "linux", test)), allow(dead_code))]
+fn net_dev_bytes(s: &str, physical: impl Fn(&str) -> bool) -> u64 {
+    s.lines()
+        .filter_map(|line| line.split_once(':'))
+        .filter(|(name, _)| physical(name.trim()))
+        .map(|(_, counters)| {
+            let fields: Vec<u64> = counters
+                .split_whitespace()
+                .filter_map(|f| f.parse().ok())
+                .collect();
+            // Field 0 is received bytes, field 8 transmitted bytes.
+            fields.first().unwrap_or(&0) + fields.get(8).unwrap_or(&0)
+        })
+        .sum()
+}
+
+/// Parse the max value of a `min default max` sysctl triplet.
+#[cfg_attr(not(any(target_os = "linux", test)), allow(dead_code))]
+fn triplet_max(s: &str) -> Option<u64> {
+    s.split_whitespace().nth(2)?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_triplet() {
+        assert_eq!(triplet_max("4096\t131072\t6291456\n"), Some(6291456));
+        assert_eq!(triplet_max("4096 131072"), None);
+    }
+
+    #[test]
+    fn warns_on_small_buffers() {
+        let tuning = HostTuning {
+            rmem_max: Some(208 << 10),
+            wmem_max: Some(16 << 20),
+            tcp_rmem_max: Some(6 << 20),
+            tcp_wmem_max: Some(32 << 20),
+            window_scaling: Some(true),
+            congestion_control: Some("cubic".into()),
+            ..Default::default()
+        };
+        let warnings = tuning.check();
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].contains("tcp_rmem max is 6144 KiB"));
+        assert!(warnings[1].contains("rmem_max is 208 KiB"));
+    }
+
+    #[test]
+    fn parse_net_dev() {
+        let dev = "Inter-|   Receive                            |  Transmit
+ face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs drop fifo colls carrier compressed
+    lo: 5000      10    0    0    0     0          0         0     5000      10    0    0    0     0       0          0
+  eth0: 1000      10    0    0    0     0          0         0      200       2    0    0    0     0       0          0
+ wlan0:   30       1    0    0    0     0          0         0        4       1    0    0    0     0       0          0
+";
+        assert_eq!(net_dev_bytes(dev, |name| name != "lo"), 1234);
+        assert_eq!(net_dev_bytes(dev, |name| name == "eth0"), 1200);
+    }
+
+    #[test]
+    fn no_warnings_when_unreadable() {
+        assert!(HostTuning::default().check().is_empty());
+    }
+}
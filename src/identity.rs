@@ -0,0 +1,115 @@
+//! Probe identity metadata.
+//!
+//! M-Lab archives every query parameter of the ndt7 request URL as client
+//! metadata. A [`ProbeIdentity`] encodes a stable probe id and deployment id
+//! into those parameters, so runs from a fleet of probes can later be found
+//! in the public archive with [`ProbeIdentity::archive_query`].
+
+/// Query parameter carrying the probe identifier.
+pub const PROBE_ID_PARAM: &str = "probe_id";
+
+/// Query parameter carrying the deployment identifier.
+pub const DEPLOYMENT_ID_PARAM: &str = "deployment_id";
+
+/// BigQuery table holding archived ndt7 results.
+pub const NDT7_ARCHIVE_TABLE: &str = "measurement-lab.ndt.ndt7";
+
+/// Identifies the probe running a test and the deployment it belongs to.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProbeIdentity {
+    /// Identifier of a single probe (e.g. a device serial or hostname).
+    pub probe_id: Option<String>,
+    /// Identifier of the deployment or campaign the probe belongs to.
+    pub deployment_id: Option<String>,
+}
+
+impl ProbeIdentity {
+    /// Create an identity for the given probe.
+    pub fn new(probe_id: impl Into<String>) -> Self {
+        ProbeIdentity {
+            probe_id: Some(probe_id.into()),
+            deployment_id: None,
+        }
+    }
+
+    /// Set the deployment identifier.
+    pub fn with_deployment(mut self, deployment_id: impl Into<String>) -> Self {
+        self.deployment_id = Some(deployment_id.into());
+        self
+    }
+
+    /// Metadata query parameters to append to the service URL.
+    pub fn query_pairs(&self) -> Vec<(&'static str, &str)> {
+        let mut pairs = Vec::new();
+        if let Some(probe_id) = &self.probe_id {
+            pairs.push((PROBE_ID_PARAM, probe_id.as_str()));
+        }
+        if let Some(deployment_id) = &self.deployment_id {
+            pairs.push((DEPLOYMENT_ID_PARAM, deployment_id.as_str()));
+        }
+        pairs
+    }
+
+    /// Build a BigQuery SQL query selecting archived ndt7 runs tagged with
+    /// this identity, on or after `since` (a `YYYY-MM-DD` date).
+    ///
+    /// Both download and upload rows are matched on their client metadata.
+    pub fn archive_query(&self, since: &str) -> String {
+        let mut query = format!(
+            "SELECT id, date, a.TestTime, a.MeanThroughputMbps, a.MinRTT, a.LossRate\n\
+             FROM `{NDT7_ARCHIVE_TABLE}`\n\
+             WHERE date >= '{}'",
+            sql_escape(since)
+        );
+        for (name, value) in self.query_pairs() {
+            query.push_str(&format!(
+                "\n  AND EXISTS (SELECT 1 FROM UNNEST(IFNULL(raw.Download.ClientMetadata, raw.Upload.ClientMetadata)) md \
+                 WHERE md.Name = '{name}' AND md.Value = '{}')",
+                sql_escape(value)
+            ));
+        }
+        query.push_str("\nORDER BY a.TestTime");
+        query
+    }
+}
+
+fn sql_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('\'', "\\'")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_pairs_skip_unset_fields() {
+        assert!(ProbeIdentity::default().query_pairs().is_empty());
+
+        let id = ProbeIdentity::new("probe-1");
+        assert_eq!(id.query_pairs(), vec![("probe_id", "probe-1")]);
+
+        let id = id.with_deployment("fleet-a");
+        assert_eq!(
+            id.query_pairs(),
+            vec![("probe_id", "probe-1"), ("deployment_id", "fleet-a")]
+        );
+    }
+
+    #[test]
+    fn archive_query_filters_on_metadata() {
+        let id = ProbeIdentity::new("probe-1").with_deployment("fleet-a");
+        let query = id.archive_query("2026-01-01");
+
+        assert!(query.contains("`measurement-lab.ndt.ndt7`"));
+        assert!(query.contains("date >= '2026-01-01'"));
+        assert!(query.contains("md.Name = 'probe_id' AND md.Value = 'probe-1'"));
+        assert!(query.contains("md.Name = 'deployment_id' AND md.Value = 'fleet-a'"));
+    }
+
+    #[test]
+    fn archive_query_escapes_values() {
+        let id = ProbeIdentity::new("o'brien");
+        let query = id.archive_query("2026-01-01");
+        assert!(query.contains(r"md.Value = 'o\'brien'"));
+    }
+}
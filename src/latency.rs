@@ -0,0 +1,211 @@
+//! msak latency1 UDP latency test.
+//!
+//! latency1 measures round-trip times and packet loss with small UDP
+//! packets rather than over a TCP connection, so the figures are free of
+//! retransmissions and head-of-line blocking. The client first fetches a
+//! session from the server's authorize endpoint, using the access token
+//! from the Locate API; the response is a kickoff [`LatencyPacket`] that the
+//! client sends to the server's [`UDP_PORT`]. The server then sends a probe
+//! every few milliseconds for the duration of the test. The client echoes
+//! each probe back, and the server reports the round trip it measured in
+//! [`LatencyPacket::last_rtt`] of the next probe.
+//!
+//! Run the test with [`Client::latency1`](crate::client::Client::latency1).
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::net::UdpSocket;
+use tokio::time::{Instant, timeout, timeout_at};
+
+use crate::error::{Ndt7Error, Result};
+use crate::{metrics, params};
+
+/// URL path of the authorize endpoint.
+pub const AUTHORIZE_URL_PATH: &str = "/latency/v1/authorize";
+
+/// UDP port the server exchanges probes on.
+pub const UDP_PORT: u16 = 1053;
+
+/// Default test duration.
+pub const DEFAULT_DURATION: Duration = Duration::from_secs(5);
+
+/// Interval between kickoff retransmissions until the first probe arrives.
+const KICKOFF_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Largest packet accepted from the server.
+const MAX_PACKET_SIZE: usize = 1500;
+
+/// A latency1 packet, sent as a JSON datagram.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct LatencyPacket {
+    /// `"c2s"` for the client's kickoff packet, `"s2c"` for the server's
+    /// probes.
+    #[serde(rename = "Type")]
+    pub kind: String,
+    /// Session ID assigned by the authorize endpoint.
+    #[serde(rename = "ID")]
+    pub id: String,
+    /// Sequence number of a probe, from 0.
+    #[serde(default)]
+    pub seq: u64,
+    /// Round-trip time of the previous probe in microseconds as measured by
+    /// the server, or 0 if it has none.
+    #[serde(rename = "LastRTT", default)]
+    pub last_rtt: i64,
+}
+
+/// Result of a latency1 test.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct LatencyResult {
+    /// FQDN of the server.
+    #[serde(rename = "ServerFQDN")]
+    pub server_fqdn: String,
+    /// Probes the server sent before the last one the client received.
+    pub packets_sent: u64,
+    /// Distinct probes the client received.
+    pub packets_received: u64,
+    /// Round-trip times reported by the server, in milliseconds, in the
+    /// order they were measured.
+    #[serde(rename = "RTTMs")]
+    pub rtt_ms: Vec<f64>,
+}
+
+impl LatencyResult {
+    /// Percentage of the server's probes lost on the way to the client.
+    pub fn loss_pct(&self) -> f64 {
+        if self.packets_sent == 0 {
+            return 0.0;
+        }
+        let lost = self.packets_sent.saturating_sub(self.packets_received);
+        lost as f64 / self.packets_sent as f64 * 100.0
+    }
+
+    /// The `p`th percentile (0-100) of the round-trip times by nearest rank,
+    /// if any was measured.
+    pub fn percentile_ms(&self, p: f64) -> Option<f64> {
+        metrics::percentile(&self.rtt_ms, p)
+    }
+}
+
+/// Run the test on `socket`, connected to the server's UDP port: send
+/// `kickoff` until the first probe arrives, then echo probes for `duration`.
+///
+/// Fails with [`Ndt7Error::StallTimeout`] if no probe arrives within
+/// [`params::IO_TIMEOUT`].
+pub(crate) async fn run(
+    socket: &UdpSocket,
+    kickoff: &LatencyPacket,
+    duration: Duration,
+) -> Result<LatencyResult> {
+    let kickoff = serde_json::to_vec(kickoff)?;
+    let mut buf = vec![0u8; MAX_PACKET_SIZE];
+    let start = Instant::now();
+    let len = loop {
+        socket.send(&kickoff).await?;
+        if let Ok(len) = timeout(KICKOFF_INTERVAL, socket.recv(&mut buf)).await {
+            break len?;
+        }
+        if start.elapsed() >= params::IO_TIMEOUT {
+            return Err(Ndt7Error::StallTimeout {
+                elapsed: start.elapsed(),
+            });
+        }
+    };
+
+    let mut result = LatencyResult::default();
+    let mut seen = std::collections::HashSet::new();
+    let end = Instant::now() + duration;
+    let mut len = len;
+    loop {
+        // A datagram that is not a probe, e.g. stray traffic to the port, is
+        // dropped rather than ending the test.
+        if let Ok(packet) = serde_json::from_slice::<LatencyPacket>(&buf[..len]) {
+            if packet.kind != "s2c" {
+                return Err(Ndt7Error::ProtocolViolation(format!(
+                    "unexpected latency1 packet type {:?}",
+                    packet.kind
+                )));
+            }
+            socket.send(&buf[..len]).await?;
+            if seen.insert(packet.seq) {
+                result.packets_received += 1;
+                result.packets_sent = result.packets_sent.max(packet.seq + 1);
+                if packet.last_rtt > 0 {
+                    result.rtt_ms.push(packet.last_rtt as f64 / 1000.0);
+                }
+            }
+        }
+
+        let recv = timeout(params::IO_TIMEOUT, socket.recv(&mut buf));
+        len = match timeout_at(end, recv).await {
+            Ok(Ok(len)) => len?,
+            Ok(Err(_)) => {
+                return Err(Ndt7Error::StallTimeout {
+                    elapsed: params::IO_TIMEOUT,
+                });
+            }
+            // The test ran its full duration.
+            Err(_) => return Ok(result),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Server that waits for a kickoff, then sends `count` probes, with
+    /// garbage in place of probe `skip`, and reports 2 ms round trips for
+    /// echoed probes.
+    async fn mock_server(count: u64, skip: u64) -> std::net::SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_PACKET_SIZE];
+            let (len, client) = socket.recv_from(&mut buf).await.unwrap();
+            let kickoff: LatencyPacket = serde_json::from_slice(&buf[..len]).unwrap();
+            let mut last_rtt = 0;
+            for seq in 0..count {
+                if seq != skip {
+                    let probe = LatencyPacket {
+                        kind: "s2c".into(),
+                        id: kickoff.id.clone(),
+                        seq,
+                        last_rtt,
+                    };
+                    let data = serde_json::to_vec(&probe).unwrap();
+                    socket.send_to(&data, client).await.unwrap();
+                    socket.recv_from(&mut buf).await.unwrap();
+                    last_rtt = 2000;
+                } else {
+                    socket.send_to(b"not a probe", client).await.unwrap();
+                }
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn echoes_probes() {
+        let addr = mock_server(5, 2).await;
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(addr).await.unwrap();
+        let kickoff = LatencyPacket {
+            kind: "c2s".into(),
+            id: "session".into(),
+            ..Default::default()
+        };
+
+        let result = run(&socket, &kickoff, Duration::from_millis(300))
+            .await
+            .unwrap();
+        assert_eq!(result.packets_sent, 5);
+        assert_eq!(result.packets_received, 4);
+        assert_eq!(result.loss_pct(), 20.0);
+        assert_eq!(result.rtt_ms, [2.0, 2.0, 2.0]);
+        assert_eq!(result.percentile_ms(50.0), Some(2.0));
+    }
+}
@@ -12,7 +12,7 @@
 //!
 //! # #[tokio::main]
 //! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
-//! let mut client = ClientBuilder::new("my-app", "0.1.0").build();
+//! let client = ClientBuilder::new("my-app", "0.1.0").build();
 //! let handle = client.start_download(None).await?;
 //! println!("connected to {}", handle.server_fqdn);
 //! let mut rx = handle.rx;
@@ -28,12 +28,44 @@
 
 #![warn(missing_docs)]
 
+#[cfg(feature = "archive")]
+pub mod archive;
 pub mod client;
 pub mod download;
 pub mod emitter;
 pub mod error;
+pub mod export;
+pub mod grade;
+pub mod host;
+pub mod identity;
+pub mod latency;
 pub mod locate;
+pub mod metrics;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+pub mod msak;
+#[cfg(feature = "otel")]
+pub mod otel;
+pub mod overhead;
 pub mod params;
+pub mod ping;
+pub mod proxy;
+pub mod relay;
+pub mod replay;
+pub mod rotate;
+#[cfg(feature = "server")]
+pub mod server;
+pub mod session;
 pub mod spec;
 pub mod summary;
+pub mod sweep;
+pub mod tcpinfo;
+#[cfg(test)]
+mod test_util;
+#[cfg(feature = "test-server")]
+pub mod testing;
+pub mod trace;
+#[cfg(feature = "tui")]
+pub mod tui;
 pub mod upload;
+pub mod webhook;
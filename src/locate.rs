@@ -1,24 +1,101 @@
 //! M-Lab Locate API client.
 //!
 //! The Locate API returns the nearest M-Lab servers with signed WebSocket
-//! URLs for running ndt7 tests.
+//! URLs for running ndt7 tests, or msak throughput1 tests with
+//! [`nearest_at`] and [`MSAK_THROUGHPUT1_URL`] or [`MSAK_LATENCY1_URL`].
+//! A [`LocateQuery`] restricts the servers to a country, region, site or
+//! organization instead of the nearest ones, and carries the API key or
+//! priority token of registered integrations.
+//!
+//! Authorized continuous testers check the health of a specific machine
+//! with [`monitoring`], which returns service URLs for the machine named in
+//! their monitoring token.
 
-use crate::error::Result;
+use crate::error::{Ndt7Error, Result};
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 /// Base URL for the M-Lab Locate v2 API.
 pub const LOCATE_URL: &str = "https://locate.measurementlab.net/v2/nearest/ndt/ndt7";
 
+/// Locate v2 API URL for msak throughput1 servers.
+pub const MSAK_THROUGHPUT1_URL: &str =
+    "https://locate.measurementlab.net/v2/nearest/msak/throughput1";
+
+/// Locate v2 API URL for msak latency1 servers.
+pub const MSAK_LATENCY1_URL: &str = "https://locate.measurementlab.net/v2/nearest/msak/latency1";
+
+/// Longest `Retry-After` of a rate-limited Locate request that is waited
+/// out before retrying. Longer ones fail with [`Ndt7Error::RateLimited`].
+pub const MAX_RETRY_AFTER: Duration = Duration::from_secs(10);
+
+/// How often a rate-limited Locate request is retried.
+const RATE_LIMIT_RETRIES: u32 = 2;
+
+/// Locate v2 API URL for monitoring ndt7 on a specific machine.
+pub const MONITORING_URL: &str =
+    "https://locate.measurementlab.net/v2/platform/monitoring/ndt/ndt7";
+
+/// Path prefix of the Locate v2 nearest endpoints.
+const NEAREST_PATH: &str = "/v2/nearest/";
+
+/// Path prefix of the Locate v2 priority endpoints, which require a
+/// [`LocateQuery::priority_token`].
+const PRIORITY_NEAREST_PATH: &str = "/v2/priority/nearest/";
+
 /// A single M-Lab server returned by the Locate API.
-#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
 pub struct Target {
     /// FQDN of the server machine.
     pub machine: String,
+    /// FQDN of the service on the machine, e.g.
+    /// `"ndt-mlab1-lga06.mlab-oss.measurement-lab.org"`, if provided by the
+    /// API.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
     /// Map of service key (e.g. `"wss:///ndt/v7/download"`) to full URL with access token.
     pub urls: HashMap<String, String>,
     /// Geographic location of the server, if provided by the API.
     pub location: Option<Location>,
+    /// Fields not known to this version of the crate, such as network
+    /// details, kept so that consumers can read them and re-serializing the
+    /// struct preserves them.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// Parts of an M-Lab machine name such as
+/// `mlab1-lga06.mlab-oss.measurement-lab.org`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MachineName {
+    /// Machine within the site, e.g. `"mlab1"`.
+    pub machine: String,
+    /// Site, named after the nearest airport and a number, e.g. `"lga06"`.
+    pub site: String,
+    /// Project the machine belongs to, e.g. `"mlab-oss"`.
+    pub project: String,
+}
+
+impl MachineName {
+    /// Split `name`, or return `None` if it does not follow M-Lab's
+    /// `<machine>-<site>.<project>.<domain>` naming.
+    pub fn parse(name: &str) -> Option<MachineName> {
+        let mut labels = name.split('.');
+        let (machine, site) = labels.next()?.split_once('-')?;
+        let project = labels.next()?;
+        if machine.is_empty() || site.is_empty() || site.contains('-') || labels.next().is_none() {
+            return None;
+        }
+        Some(MachineName {
+            machine: machine.to_string(),
+            site: site.to_string(),
+            project: project.to_string(),
+        })
+    }
 }
 
 /// Download and upload URLs extracted from a [`Target`] for a specific scheme.
@@ -30,22 +107,124 @@ pub struct ServiceUrls {
 }
 
 impl Target {
-    /// Extract the download and upload URLs for the given scheme (`"wss"` or `"ws"`).
-    pub fn service_urls(&self, scheme: &str) -> ServiceUrls {
-        let mut dl = None;
-        let mut ul = None;
-        for (key, url) in &self.urls {
-            if key.starts_with(scheme) && key.contains(crate::params::DOWNLOAD_URL_PATH) {
-                dl = Some(url.clone());
-            } else if key.starts_with(scheme) && key.contains(crate::params::UPLOAD_URL_PATH) {
-                ul = Some(url.clone());
+    /// A target for an ndt7 server not found through the Locate API.
+    ///
+    /// `server` is either a hostname, optionally with a port, offering both
+    /// tests over `ws` and `wss`, or a URL. A URL whose path is
+    /// [`crate::params::DOWNLOAD_URL_PATH`] or
+    /// [`crate::params::UPLOAD_URL_PATH`] offers that test only, with its
+    /// query such as an access token kept; other URLs offer both tests over
+    /// their scheme.
+    pub fn from_server(server: &str) -> std::result::Result<Target, url::ParseError> {
+        let paths = [
+            crate::params::DOWNLOAD_URL_PATH,
+            crate::params::UPLOAD_URL_PATH,
+        ];
+        let (url, schemes) = if server.contains("://") {
+            let url = url::Url::parse(server)?;
+            let scheme = url.scheme().to_string();
+            (url, vec![scheme])
+        } else {
+            let url = url::Url::parse(&format!("ws://{server}"))?;
+            (url, vec!["ws".to_string(), "wss".to_string()])
+        };
+        let machine = url
+            .host_str()
+            .ok_or(url::ParseError::EmptyHost)?
+            .to_string();
+
+        let mut urls = HashMap::new();
+        if paths.contains(&url.path()) {
+            urls.insert(
+                format!("{}://{}", schemes[0], url.path()),
+                server.to_string(),
+            );
+        } else {
+            let authority = &url[url::Position::BeforeHost..url::Position::AfterPort];
+            for scheme in &schemes {
+                for path in paths {
+                    urls.insert(
+                        format!("{scheme}://{path}"),
+                        format!("{scheme}://{authority}{path}"),
+                    );
+                }
             }
         }
+        Ok(Target {
+            machine,
+            urls,
+            ..Default::default()
+        })
+    }
+
+    /// Extract the download and upload URLs for the given scheme (`"wss"` or `"ws"`).
+    pub fn service_urls(&self, scheme: &str) -> ServiceUrls {
+        let url = |path| self.service_url(scheme, path).map(str::to_string);
         ServiceUrls {
-            download: dl,
-            upload: ul,
+            download: url(crate::params::DOWNLOAD_URL_PATH),
+            upload: url(crate::params::UPLOAD_URL_PATH),
         }
     }
+
+    /// The URL for `path` (e.g. [`crate::msak::DOWNLOAD_URL_PATH`]) with the
+    /// given scheme, if the server offers it.
+    pub fn service_url(&self, scheme: &str, path: &str) -> Option<&str> {
+        self.urls
+            .get(&format!("{scheme}://{path}"))
+            .map(String::as_str)
+    }
+
+    /// The parts of the machine's name, for labelling results by site.
+    pub fn machine_name(&self) -> Option<MachineName> {
+        MachineName::parse(&self.machine)
+    }
+
+    /// When the first of the access tokens in the target's URLs expires,
+    /// if any carries an expiry.
+    ///
+    /// Access tokens are JWTs; the expiry is their `exp` claim. Their
+    /// signatures are not checked, the test servers do that.
+    pub fn token_expiry(&self) -> Option<SystemTime> {
+        self.urls.values().filter_map(|url| token_expiry(url)).min()
+    }
+}
+
+/// The `exp` claim of the `access_token` query parameter of `url`.
+fn token_expiry(url: &str) -> Option<SystemTime> {
+    #[derive(Deserialize)]
+    struct Claims {
+        exp: u64,
+    }
+
+    let url = url::Url::parse(url).ok()?;
+    let (_, token) = url.query_pairs().find(|(name, _)| name == "access_token")?;
+    let payload = base64url_decode(token.split('.').nth(1)?)?;
+    let claims: Claims = serde_json::from_slice(&payload).ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(claims.exp))
+}
+
+/// Decode unpadded base64url, as used in JWTs.
+fn base64url_decode(input: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(input.len() * 3 / 4);
+    let mut bits = 0u32;
+    let mut nbits = 0;
+    for c in input.bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'-' => 62,
+            b'_' => 63,
+            _ => return None,
+        };
+        bits = (bits << 6) | u32::from(value);
+        nbits += 6;
+        if nbits >= 8 {
+            nbits -= 8;
+            out.push((bits >> nbits) as u8);
+        }
+    }
+    Some(out)
 }
 
 /// Geographic location of an M-Lab server.
@@ -57,6 +236,227 @@ pub struct Location {
     pub country: String,
 }
 
+/// Which of the located servers a client connects to.
+///
+/// The Locate API lists servers nearest first. Whatever the policy, the
+/// remaining servers are tried in that order if the chosen one fails.
+#[derive(Clone, Default)]
+pub enum TargetPolicy {
+    /// The nearest server.
+    #[default]
+    First,
+    /// A random server for every run.
+    Random,
+    /// The next server in the list for every run, wrapping around, so a
+    /// long-running monitor spreads its tests over a site's machines.
+    RoundRobin,
+    /// The server with the shortest TCP connect time among the given
+    /// number of nearest servers, probed before every run. The Locate API
+    /// orders servers geographically, which is not always the fastest path.
+    Fastest(usize),
+    /// Only servers the predicate accepts, nearest first.
+    Custom(Arc<dyn Fn(&Target) -> bool + Send + Sync>),
+}
+
+impl TargetPolicy {
+    /// A [`TargetPolicy::Custom`] policy accepting the servers for which
+    /// `predicate` returns true, e.g. those in a given country.
+    pub fn custom(predicate: impl Fn(&Target) -> bool + Send + Sync + 'static) -> Self {
+        TargetPolicy::Custom(Arc::new(predicate))
+    }
+
+    /// The index of the server a new run starts at, given the number of
+    /// runs started before it.
+    pub(crate) fn start(&self, runs: usize, len: usize) -> usize {
+        match self {
+            TargetPolicy::Random if len > 0 => rand::random_range(0..len),
+            TargetPolicy::RoundRobin if len > 0 => runs % len,
+            _ => 0,
+        }
+    }
+
+    /// The servers of `targets` to try, in order, starting at `start`.
+    pub(crate) fn order<'a>(&self, targets: &'a [Target], start: usize) -> Vec<&'a Target> {
+        let (head, tail) = targets.split_at(start.min(targets.len()));
+        let rotated = tail.iter().chain(head);
+        match self {
+            TargetPolicy::Custom(predicate) => rotated.filter(|t| predicate(t)).collect(),
+            _ => rotated.collect(),
+        }
+    }
+}
+
+impl fmt::Debug for TargetPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TargetPolicy::First => f.write_str("First"),
+            TargetPolicy::Random => f.write_str("Random"),
+            TargetPolicy::RoundRobin => f.write_str("RoundRobin"),
+            TargetPolicy::Fastest(count) => write!(f, "Fastest({count})"),
+            TargetPolicy::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
+
+/// Servers to exclude from, or restrict tests to, among those returned by
+/// the Locate API.
+///
+/// Each entry names a machine by its full name
+/// (`mlab1-lga06.mlab-oss.measurement-lab.org`), its short name
+/// (`mlab1-lga06`) or its site (`lga06`), ignoring case.
+///
+/// ```
+/// # use ndt7_client::locate::ServerFilter;
+/// let filter = ServerFilter::new().block("lga06").block("mlab2-ams08");
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServerFilter {
+    /// If not empty, only servers matching one of these entries.
+    pub allow: Vec<String>,
+    /// Never servers matching one of these entries.
+    pub block: Vec<String>,
+}
+
+impl ServerFilter {
+    /// A filter accepting every server.
+    pub fn new() -> Self {
+        ServerFilter::default()
+    }
+
+    /// Only servers matching `entry`, or any of the other allowed entries.
+    pub fn allow(mut self, entry: impl Into<String>) -> Self {
+        self.allow.push(entry.into());
+        self
+    }
+
+    /// No servers matching `entry`.
+    pub fn block(mut self, entry: impl Into<String>) -> Self {
+        self.block.push(entry.into());
+        self
+    }
+
+    /// Whether the filter accepts every server.
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.block.is_empty()
+    }
+
+    /// Whether tests may run against `target`.
+    pub fn accepts(&self, target: &Target) -> bool {
+        let name = target.machine_name();
+        let matches = |entry: &String| {
+            entry.eq_ignore_ascii_case(&target.machine)
+                || name.as_ref().is_some_and(|n| {
+                    entry.eq_ignore_ascii_case(&n.site)
+                        || entry.eq_ignore_ascii_case(&format!("{}-{}", n.machine, n.site))
+                })
+        };
+        (self.allow.is_empty() || self.allow.iter().any(matches)) && !self.block.iter().any(matches)
+    }
+
+    /// The servers of `targets` the filter accepts, in order.
+    pub fn apply(&self, mut targets: Vec<Target>) -> Vec<Target> {
+        targets.retain(|t| self.accepts(t));
+        targets
+    }
+}
+
+/// Constraints on the servers returned by the Locate API, and credentials
+/// for it.
+///
+/// ```
+/// # use ndt7_client::locate::LocateQuery;
+/// let query = LocateQuery::new().country("DE").api_key("my-key");
+/// ```
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct LocateQuery {
+    /// ISO 3166-1 alpha-2 country code, e.g. `"DE"`.
+    pub country: Option<String>,
+    /// ISO 3166-2 region code, e.g. `"US-NY"`.
+    pub region: Option<String>,
+    /// M-Lab site name, e.g. `"lga06"`.
+    pub site: Option<String>,
+    /// Organization operating the servers, e.g. `"mlab"`.
+    pub org: Option<String>,
+    /// API key M-Lab issues to registered integrations, sent as the `key`
+    /// query parameter.
+    pub api_key: Option<String>,
+    /// Signed token granting priority access, sent as a bearer token to
+    /// the Locate API's priority endpoint. The access tokens in the returned
+    /// service URLs then carry the priority on to the test servers.
+    pub priority_token: Option<String>,
+}
+
+impl LocateQuery {
+    /// A query without constraints, returning the nearest servers.
+    pub fn new() -> Self {
+        LocateQuery::default()
+    }
+
+    /// Only servers in `country`.
+    pub fn country(mut self, country: impl Into<String>) -> Self {
+        self.country = Some(country.into());
+        self
+    }
+
+    /// Only servers in `region`.
+    pub fn region(mut self, region: impl Into<String>) -> Self {
+        self.region = Some(region.into());
+        self
+    }
+
+    /// Only servers at `site`.
+    pub fn site(mut self, site: impl Into<String>) -> Self {
+        self.site = Some(site.into());
+        self
+    }
+
+    /// Only servers operated by `org`.
+    pub fn org(mut self, org: impl Into<String>) -> Self {
+        self.org = Some(org.into());
+        self
+    }
+
+    /// Identify as a registered integration with `key`.
+    pub fn api_key(mut self, key: impl Into<String>) -> Self {
+        self.api_key = Some(key.into());
+        self
+    }
+
+    /// Request priority access with `token`.
+    pub fn priority_token(mut self, token: impl Into<String>) -> Self {
+        self.priority_token = Some(token.into());
+        self
+    }
+
+    /// The constraints and API key as query parameters of the Locate API.
+    pub fn query_pairs(&self) -> Vec<(&'static str, &str)> {
+        [
+            ("country", &self.country),
+            ("region", &self.region),
+            ("site", &self.site),
+            ("org", &self.org),
+            ("key", &self.api_key),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| Some((name, value.as_deref()?)))
+        .collect()
+    }
+}
+
+impl fmt::Debug for LocateQuery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let redacted = |secret: &Option<String>| secret.as_ref().map(|_| "<redacted>");
+        f.debug_struct("LocateQuery")
+            .field("country", &self.country)
+            .field("region", &self.region)
+            .field("site", &self.site)
+            .field("org", &self.org)
+            .field("api_key", &redacted(&self.api_key))
+            .field("priority_token", &redacted(&self.priority_token))
+            .finish()
+    }
+}
+
 /// Top-level response from the Locate API.
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct LocateResponse {
@@ -64,20 +464,146 @@ pub struct LocateResponse {
     pub results: Vec<Target>,
 }
 
+/// Response from the Locate API's monitoring endpoint.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct MonitoringResponse {
+    /// Access token for the machine, also embedded in the target's URLs.
+    #[serde(default)]
+    pub access_token: String,
+    /// The machine named in the monitoring token.
+    pub target: Option<Target>,
+}
+
 /// Query the Locate API for the nearest M-Lab servers.
 ///
-/// Returns [`crate::error::Ndt7Error::NoCapacity`] when the Locate API responds with
-/// 204 (M-Lab is out of capacity).
+/// Returns [`Ndt7Error::NoCapacity`] when the Locate API responds with
+/// 204 (M-Lab is out of capacity), and [`Ndt7Error::RateLimited`] when it
+/// keeps responding with 429, see [`MAX_RETRY_AFTER`].
 pub async fn nearest(user_agent: &str) -> Result<Vec<Target>> {
+    nearest_with_query(user_agent, &LocateQuery::default()).await
+}
+
+/// Like [`nearest`], keeping to the servers matching `query`.
+pub async fn nearest_with_query(user_agent: &str, query: &LocateQuery) -> Result<Vec<Target>> {
+    nearest_at(LOCATE_URL, user_agent, query).await
+}
+
+/// Query the Locate API endpoint at `url` for the nearest M-Lab servers of
+/// its service matching `query`, e.g. [`MSAK_THROUGHPUT1_URL`].
+///
+/// With a [`LocateQuery::priority_token`], the request goes to the
+/// endpoint's priority counterpart under `/v2/priority/nearest/`.
+///
+/// Fails like [`nearest`].
+pub async fn nearest_at(url: &str, user_agent: &str, query: &LocateQuery) -> Result<Vec<Target>> {
     let client = reqwest::Client::builder().user_agent(user_agent).build()?;
-    let response = client.get(LOCATE_URL).send().await?.error_for_status()?;
+    nearest_with(&client, url, query).await
+}
+
+/// Like [`nearest_at`], sending the request with `client`, e.g. to share
+/// its TLS and proxy settings.
+pub async fn nearest_with(
+    client: &reqwest::Client,
+    url: &str,
+    query: &LocateQuery,
+) -> Result<Vec<Target>> {
+    let (locate, _) = nearest_response(client, url, query).await?;
+    Ok(locate.results)
+}
+
+/// Like [`nearest_with`], returning the whole response both parsed and as
+/// the JSON the Locate API sent, e.g. to read fields this crate does not
+/// know about or to archive the response.
+pub async fn nearest_response(
+    client: &reqwest::Client,
+    url: &str,
+    query: &LocateQuery,
+) -> Result<(LocateResponse, Value)> {
+    let mut url = url::Url::parse(url)?;
+    if !query.query_pairs().is_empty() {
+        url.query_pairs_mut().extend_pairs(query.query_pairs());
+    }
+    if query.priority_token.is_some() {
+        let path = url.path().replacen(NEAREST_PATH, PRIORITY_NEAREST_PATH, 1);
+        url.set_path(&path);
+    }
+    let response = send(|| {
+        let request = client.get(url.clone());
+        match &query.priority_token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    })
+    .await?;
 
     if response.status() == reqwest::StatusCode::NO_CONTENT {
-        return Err(crate::error::Ndt7Error::NoCapacity);
+        return Err(Ndt7Error::NoCapacity);
     }
 
-    let locate: LocateResponse = response.json().await?;
-    Ok(locate.results)
+    let raw: Value = response.json().await?;
+    let locate = LocateResponse::deserialize(&raw)?;
+    Ok((locate, raw))
+}
+
+/// Query the monitoring endpoint for service URLs of `machine`, using a
+/// monitoring `token` issued by M-Lab for it.
+///
+/// The token, sent as a bearer token, names the machine; `machine` guards
+/// against testing another one than intended. Returns
+/// [`Ndt7Error::NoTargets`] if the response has no target or a target
+/// other than `machine`, and fails like [`nearest`] otherwise.
+pub async fn monitoring(user_agent: &str, machine: &str, token: &str) -> Result<Target> {
+    monitoring_at(MONITORING_URL, user_agent, machine, token).await
+}
+
+/// Like [`monitoring`], querying the monitoring endpoint at `url`.
+pub async fn monitoring_at(
+    url: &str,
+    user_agent: &str,
+    machine: &str,
+    token: &str,
+) -> Result<Target> {
+    let client = reqwest::Client::builder().user_agent(user_agent).build()?;
+    monitoring_with(&client, url, machine, token).await
+}
+
+/// Like [`monitoring_at`], sending the request with `client`.
+pub async fn monitoring_with(
+    client: &reqwest::Client,
+    url: &str,
+    machine: &str,
+    token: &str,
+) -> Result<Target> {
+    let response = send(|| client.get(url).bearer_auth(token)).await?;
+    let monitoring: MonitoringResponse = response.json().await?;
+    monitoring
+        .target
+        .filter(|t| t.machine == machine)
+        .ok_or(Ndt7Error::NoTargets)
+}
+
+/// Send the request built by `request`, waiting out and retrying 429
+/// responses whose `Retry-After` is at most [`MAX_RETRY_AFTER`].
+async fn send(request: impl Fn() -> reqwest::RequestBuilder) -> Result<reqwest::Response> {
+    let mut retries = 0;
+    loop {
+        let response = request().send().await?;
+        if response.status() != reqwest::StatusCode::TOO_MANY_REQUESTS {
+            return Ok(response.error_for_status()?);
+        }
+        let retry_after = response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|v| v.to_str().ok()?.trim().parse().ok())
+            .map(Duration::from_secs);
+        match retry_after {
+            Some(wait) if wait <= MAX_RETRY_AFTER && retries < RATE_LIMIT_RETRIES => {
+                retries += 1;
+                tokio::time::sleep(wait).await;
+            }
+            _ => return Err(Ndt7Error::RateLimited { retry_after }),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -120,9 +646,13 @@ mod tests {
                        "wss:///ndt/v7/download": "wss://mlab1-lga06:4443/ndt/v7/download?access_token=abc",
                        "wss:///ndt/v7/upload": "wss://mlab1-lga06:4443/ndt/v7/upload?access_token=def"
                    },
+                   "hostname": "ndt-mlab1-lga06.mlab-oss.measurement-lab.org",
                    "location": {
                        "city": "Tokyo",
                        "country": "JP"
+                   },
+                   "network": {
+                       "asn": 15169
                    }
                }
            ]
@@ -140,6 +670,215 @@ mod tests {
         let location = results[0].location.as_ref().unwrap();
         assert_eq!(location.city, "Tokyo");
         assert_eq!(location.country, "JP");
+        assert_eq!(
+            results[0].hostname.as_deref(),
+            Some("ndt-mlab1-lga06.mlab-oss.measurement-lab.org")
+        );
+        assert_eq!(results[0].extra["network"]["asn"], 15169);
+
+        let name = results[0].machine_name().unwrap();
+        assert_eq!(
+            (
+                name.machine.as_str(),
+                name.site.as_str(),
+                name.project.as_str()
+            ),
+            ("mlab1", "lga06", "mlab-oss")
+        );
+        assert_eq!(MachineName::parse("localhost"), None);
+    }
+
+    #[test]
+    fn deserialize_monitoring_response() {
+        let json = r#"{
+           "access_token": "token",
+           "target": {
+               "machine": "mlab1-lga06.mlab-oss.measurement-lab.org",
+               "urls": {
+                   "wss:///ndt/v7/download": "wss://mlab1-lga06:4443/ndt/v7/download?access_token=token"
+               }
+           }
+        }"#;
+
+        let response: MonitoringResponse = serde_json::from_str(json).unwrap();
+        assert_eq!(response.access_token, "token");
+        let target = response.target.unwrap();
+        assert_eq!(target.machine, "mlab1-lga06.mlab-oss.measurement-lab.org");
+        assert!(target.service_urls("wss").download.is_some());
+    }
+
+    /// HTTP server answering one request per connection with the next of
+    /// `responses`.
+    async fn mock_locate(responses: Vec<&'static str>) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            for response in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 1024];
+                let _ = stream.read(&mut buf).await.unwrap();
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        format!("http://{addr}/v2/nearest/ndt/ndt7")
+    }
+
+    #[tokio::test]
+    async fn rate_limited() {
+        const LIMITED: &str = "HTTP/1.1 429 Too Many Requests\r\nRetry-After: 0\r\n\
+                               Content-Length: 0\r\nConnection: close\r\n\r\n";
+        const OK: &str = "HTTP/1.1 200 OK\r\nContent-Length: 14\r\n\
+                          Connection: close\r\n\r\n{\"results\":[]}";
+        let query = LocateQuery::new();
+
+        // Short waits are retried.
+        let url = mock_locate(vec![LIMITED, OK]).await;
+        let client = reqwest::Client::new();
+        let (locate, raw) = nearest_response(&client, &url, &query).await.unwrap();
+        assert!(locate.results.is_empty());
+        assert_eq!(raw, serde_json::json!({"results": []}));
+
+        let url = mock_locate(vec![LIMITED, LIMITED, LIMITED]).await;
+        let err = nearest_at(&url, "test", &query).await.unwrap_err();
+        assert!(matches!(
+            err,
+            Ndt7Error::RateLimited {
+                retry_after: Some(Duration::ZERO)
+            }
+        ));
+
+        // Long waits are left to the caller.
+        let url = mock_locate(vec![
+            "HTTP/1.1 429 Too Many Requests\r\nRetry-After: 3600\r\n\
+             Content-Length: 0\r\nConnection: close\r\n\r\n",
+        ])
+        .await;
+        let err = nearest_at(&url, "test", &query).await.unwrap_err();
+        assert!(matches!(
+            err,
+            Ndt7Error::RateLimited {
+                retry_after: Some(d)
+            } if d == Duration::from_secs(3600)
+        ));
+    }
+
+    #[test]
+    fn static_servers() {
+        let target = Target::from_server("localhost:8080").unwrap();
+        assert_eq!(target.machine, "localhost");
+        let urls = target.service_urls("ws");
+        assert_eq!(
+            urls.download.as_deref(),
+            Some("ws://localhost:8080/ndt/v7/download")
+        );
+        let urls = target.service_urls("wss");
+        assert_eq!(
+            urls.upload.as_deref(),
+            Some("wss://localhost:8080/ndt/v7/upload")
+        );
+
+        let url = "wss://ndt.example.com/ndt/v7/download?access_token=abc";
+        let target = Target::from_server(url).unwrap();
+        let urls = target.service_urls("wss");
+        assert_eq!(urls.download.as_deref(), Some(url));
+        assert_eq!(urls.upload, None);
+
+        assert!(Target::from_server("").is_err());
+    }
+
+    #[test]
+    fn access_token_expiry() {
+        // Header and payload of a JWT with `"exp":1700000000`, unsigned.
+        let token = "eyJhbGciOiJFZERTQSJ9.eyJleHAiOjE3MDAwMDAwMDB9.sig";
+        let target = Target {
+            machine: "mlab1-lga06".into(),
+            urls: HashMap::from([
+                (
+                    "wss:///ndt/v7/download".into(),
+                    format!("wss://mlab1-lga06/ndt/v7/download?access_token={token}"),
+                ),
+                (
+                    "wss:///ndt/v7/upload".into(),
+                    "wss://mlab1-lga06/ndt/v7/upload".into(),
+                ),
+            ]),
+            ..Default::default()
+        };
+        assert_eq!(
+            target.token_expiry(),
+            Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+        );
+    }
+
+    #[test]
+    fn target_policy_order() {
+        let targets: Vec<_> = ["a", "b", "c"]
+            .into_iter()
+            .map(|machine| Target {
+                machine: machine.into(),
+                urls: HashMap::new(),
+                ..Default::default()
+            })
+            .collect();
+        let machines = |order: Vec<&Target>| -> Vec<String> {
+            order.iter().map(|t| t.machine.clone()).collect()
+        };
+
+        let first = TargetPolicy::First;
+        assert_eq!(first.start(5, 3), 0);
+        assert_eq!(machines(first.order(&targets, 0)), ["a", "b", "c"]);
+
+        let round_robin = TargetPolicy::RoundRobin;
+        assert_eq!(round_robin.start(4, 3), 1);
+        assert_eq!(machines(round_robin.order(&targets, 1)), ["b", "c", "a"]);
+        assert!(TargetPolicy::Random.start(0, 3) < 3);
+
+        let custom = TargetPolicy::custom(|t| t.machine != "b");
+        assert_eq!(machines(custom.order(&targets, 0)), ["a", "c"]);
+    }
+
+    #[test]
+    fn server_filter() {
+        let target = |machine: &str| Target {
+            machine: machine.into(),
+            ..Default::default()
+        };
+        let lga = target("mlab1-lga06.mlab-oss.measurement-lab.org");
+        let ams = target("mlab2-ams08.mlab-oss.measurement-lab.org");
+        let custom = target("ndt.example.com");
+
+        let filter = ServerFilter::new();
+        assert!(filter.accepts(&lga) && filter.accepts(&custom));
+
+        let blocked = ServerFilter::new().block("LGA06").block("ndt.example.com");
+        assert!(!blocked.accepts(&lga));
+        assert!(blocked.accepts(&ams));
+        assert!(!blocked.accepts(&custom));
+
+        let allowed = ServerFilter::new().allow("mlab2-ams08");
+        let machines: Vec<_> = allowed
+            .apply(vec![lga.clone(), ams.clone(), custom])
+            .into_iter()
+            .map(|t| t.machine)
+            .collect();
+        assert_eq!(machines, ["mlab2-ams08.mlab-oss.measurement-lab.org"]);
+        assert!(!allowed.clone().block("ams08").accepts(&ams));
+    }
+
+    #[test]
+    fn locate_query_pairs() {
+        assert!(LocateQuery::new().query_pairs().is_empty());
+        let query = LocateQuery::new()
+            .region("US-NY")
+            .site("lga06")
+            .api_key("secret");
+        assert_eq!(
+            query.query_pairs(),
+            vec![("region", "US-NY"), ("site", "lga06"), ("key", "secret")]
+        );
+        assert!(!format!("{query:?}").contains("secret"));
     }
 
     #[tokio::test]
@@ -0,0 +1,565 @@
+//! Metrics derived from the measurement stream of a test.
+//!
+//! The throughput of a test is read from the receiving side's counters: the
+//! client's [`AppInfo`](crate::spec::AppInfo) during the download and the
+//! server's [`TCPInfo`] during the upload. Latency and retransmissions come
+//! from the server's `TCPInfo`. [`average_mbps`] and [`retransmission_pct`]
+//! are the definitions shared by the emitters, the session and the summary;
+//! [`Metrics`] follows a whole stream and derives per-interval and smoothed
+//! series from it, and [`MeasurementLog`] keeps the streams of both tests
+//! for use after they end.
+
+use std::time::SystemTime;
+
+use serde::Serialize;
+
+use crate::spec::{ByteCount, ConnectionInfo, Measurement, Micros, Origin, TCPInfo, TestKind};
+
+/// Default weight of the newest value in exponentially weighted moving
+/// averages.
+pub const DEFAULT_ALPHA: f64 = 0.3;
+
+/// Payload bytes received and elapsed time in `m`, if it is the kind of
+/// measurement that carries the throughput of `test`.
+fn progress(test: TestKind, m: &Measurement) -> Option<(ByteCount, Micros)> {
+    match (test, m.origin) {
+        (TestKind::Download, Some(Origin::Client)) => {
+            let app = m.app_info.as_ref()?;
+            Some((app.num_bytes, app.elapsed_time))
+        }
+        (TestKind::Upload, Some(Origin::Server)) => {
+            let tcp = m.tcp_info.as_ref()?;
+            Some((tcp.bytes_received?, tcp.elapsed_time?))
+        }
+        _ => None,
+    }
+}
+
+/// Average throughput of `test` since its start in Mbit/s, or `None` if `m`
+/// does not carry it.
+pub fn average_mbps(test: TestKind, m: &Measurement) -> Option<f64> {
+    let (bytes, elapsed) = progress(test, m)?;
+    bytes.mbps_over(elapsed)
+}
+
+/// Percentage of the bytes sent that were retransmitted, or 0 if nothing
+/// was sent.
+pub fn retransmission_pct(tcp: &TCPInfo) -> f64 {
+    let sent = tcp.bytes_sent.unwrap_or_default().0;
+    if sent <= 0 {
+        return 0.0;
+    }
+    tcp.bytes_retrans.unwrap_or_default().0 as f64 / sent as f64 * 100.0
+}
+
+/// The `p`th percentile (0-100) of `values` by nearest rank, or `None` if
+/// there are none.
+pub fn percentile(values: &[f64], p: f64) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let rank = (p.clamp(0.0, 100.0) / 100.0 * sorted.len() as f64).ceil() as usize;
+    Some(sorted[rank.saturating_sub(1)])
+}
+
+/// Mean absolute difference between consecutive `values`, a measure of
+/// jitter, or `None` with fewer than two values.
+pub fn jitter(values: &[f64]) -> Option<f64> {
+    let diffs = values.windows(2).map(|w| (w[1] - w[0]).abs());
+    let n = values.len().checked_sub(1).filter(|&n| n > 0)?;
+    Some(diffs.sum::<f64>() / n as f64)
+}
+
+/// The metrics of a test after its latest measurement.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Sample {
+    /// Time since the start of the test of the latest throughput
+    /// measurement.
+    pub elapsed_time: Micros,
+    /// Throughput since the start of the test, in Mbit/s.
+    pub average_mbps: Option<f64>,
+    /// Throughput between the two latest throughput measurements, in Mbit/s.
+    pub instant_mbps: Option<f64>,
+    /// Moving average of the interval throughput, in Mbit/s.
+    pub smoothed_mbps: Option<f64>,
+    /// Latest round-trip time reported by the server, in milliseconds.
+    #[serde(rename = "RTTMs")]
+    pub rtt_ms: Option<f64>,
+    /// Moving average of the reported round-trip times, in milliseconds.
+    #[serde(rename = "SmoothedRTTMs")]
+    pub smoothed_rtt_ms: Option<f64>,
+    /// Percentage of the server's bytes retransmitted so far.
+    pub retransmission_pct: Option<f64>,
+}
+
+/// Derives the metrics of one test from its measurements.
+///
+/// ```
+/// use ndt7_client::metrics::Metrics;
+/// use ndt7_client::spec::TestKind;
+///
+/// let mut metrics = Metrics::new(TestKind::Download);
+/// # let stream: Vec<ndt7_client::spec::Measurement> = Vec::new();
+/// for m in &stream {
+///     if metrics.update(m) {
+///         println!("{:?}", metrics.sample().smoothed_mbps);
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    test: TestKind,
+    alpha: f64,
+    last: Option<(ByteCount, Micros)>,
+    sample: Sample,
+}
+
+impl Metrics {
+    /// Follow a `test`, smoothing with [`DEFAULT_ALPHA`].
+    pub fn new(test: TestKind) -> Self {
+        Metrics::with_alpha(test, DEFAULT_ALPHA)
+    }
+
+    /// Follow a `test`, giving the newest value a weight of `alpha`
+    /// (clamped to 0-1) in moving averages.
+    pub fn with_alpha(test: TestKind, alpha: f64) -> Self {
+        Metrics {
+            test,
+            alpha: alpha.clamp(0.0, 1.0),
+            last: None,
+            sample: Sample::default(),
+        }
+    }
+
+    /// Account for `m`. Returns whether it changed any metric.
+    pub fn update(&mut self, m: &Measurement) -> bool {
+        let mut changed = false;
+        if let Some((bytes, elapsed)) = progress(self.test, m) {
+            let instant = match self.last {
+                Some(last) => interval_mbps(last, (bytes, elapsed)),
+                None => bytes.mbps_over(elapsed),
+            };
+            self.last = Some((bytes, elapsed));
+            self.sample.elapsed_time = elapsed;
+            self.sample.average_mbps = bytes.mbps_over(elapsed);
+            if instant.is_some() {
+                self.sample.instant_mbps = instant;
+                self.sample.smoothed_mbps = self.ewma(self.sample.smoothed_mbps, instant);
+            }
+            changed = true;
+        }
+        if m.origin == Some(Origin::Server)
+            && let Some(tcp) = &m.tcp_info
+        {
+            if let Some(rtt) = tcp.rtt {
+                let rtt_ms = rtt.as_millis_f64();
+                self.sample.rtt_ms = Some(rtt_ms);
+                self.sample.smoothed_rtt_ms = self.ewma(self.sample.smoothed_rtt_ms, Some(rtt_ms));
+                changed = true;
+            }
+            if tcp.bytes_sent.is_some() {
+                self.sample.retransmission_pct = Some(retransmission_pct(tcp));
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// The metrics so far.
+    pub fn sample(&self) -> &Sample {
+        &self.sample
+    }
+
+    fn ewma(&self, average: Option<f64>, value: Option<f64>) -> Option<f64> {
+        match (average, value) {
+            (Some(average), Some(value)) => Some(average + self.alpha * (value - average)),
+            (None, value) => value,
+            (average, None) => average,
+        }
+    }
+}
+
+/// Throughput statistics over the intervals between the measurements of a
+/// test.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct IntervalStats {
+    /// Lowest throughput of an interval, in Mbit/s.
+    pub min_mbps: f64,
+    /// Throughput between the first and last measurements, in Mbit/s.
+    pub avg_mbps: f64,
+    /// Highest throughput of an interval, in Mbit/s.
+    pub max_mbps: f64,
+}
+
+/// All measurements of one test, by origin, in the order received.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct TestLog {
+    /// Client measurements, including ping round trips.
+    pub client: Vec<Measurement>,
+    /// Server measurements.
+    pub server: Vec<Measurement>,
+    /// Wall-clock time the first measurement was logged.
+    #[serde(skip)]
+    pub start_time: Option<SystemTime>,
+    /// Wall-clock time the last measurement was logged.
+    #[serde(skip)]
+    pub end_time: Option<SystemTime>,
+}
+
+impl TestLog {
+    /// The last client measurement with byte counts, skipping ping round
+    /// trips.
+    pub fn last_client(&self) -> Option<&Measurement> {
+        self.client.iter().rev().find(|m| m.app_info.is_some())
+    }
+
+    /// The last server measurement.
+    pub fn last_server(&self) -> Option<&Measurement> {
+        self.server.last()
+    }
+
+    /// The test's connection endpoints and UUID. ndt-server reports them in
+    /// its first measurement only.
+    pub fn connection_info(&self) -> Option<&ConnectionInfo> {
+        self.server.iter().find_map(|m| m.connection_info.as_ref())
+    }
+
+    /// Average throughput of `test` since its start in Mbit/s, up to the
+    /// valid measurement taken last, whichever order the measurements
+    /// arrived in.
+    pub fn throughput_mbps(&self, test: TestKind) -> Option<f64> {
+        let (bytes, elapsed) = self.transferred(test)?;
+        bytes.mbps_over(elapsed)
+    }
+
+    /// Payload bytes of `test` transferred and time elapsed up to the valid
+    /// measurement taken last.
+    pub fn transferred(&self, test: TestKind) -> Option<(ByteCount, Micros)> {
+        self.progress(test).last().copied()
+    }
+
+    /// Throughput statistics of `test` over the intervals between its
+    /// valid measurements, or `None` with fewer than two of them.
+    pub fn interval_stats(&self, test: TestKind) -> Option<IntervalStats> {
+        let series = self.progress(test);
+        let intervals: Vec<f64> = series
+            .windows(2)
+            .filter_map(|w| interval_mbps(w[0], w[1]))
+            .collect();
+        Some(IntervalStats {
+            min_mbps: intervals.iter().copied().reduce(f64::min)?,
+            avg_mbps: interval_mbps(*series.first()?, *series.last()?)?,
+            max_mbps: intervals.iter().copied().reduce(f64::max)?,
+        })
+    }
+
+    /// Throughput of `test` over consecutive intervals of at least `step`
+    /// from its start, in Mbit/s, to show how it developed over time. The
+    /// last interval may be shorter.
+    pub fn throughput_series(&self, test: TestKind, step: Micros) -> Vec<f64> {
+        let progress = self.progress(test);
+        let mut series = Vec::new();
+        let mut from = (ByteCount(0), Micros(0));
+        for (i, &to) in progress.iter().enumerate() {
+            if to.1.0 - from.1.0 >= step.0 || i == progress.len() - 1 {
+                series.extend(interval_mbps(from, to));
+                from = to;
+            }
+        }
+        series
+    }
+
+    /// Smoothed round-trip times reported in the server measurements, in
+    /// milliseconds.
+    pub fn rtt_ms(&self) -> Vec<f64> {
+        self.server
+            .iter()
+            .filter_map(|m| m.tcp_info.as_ref()?.rtt)
+            .map(Micros::as_millis_f64)
+            .collect()
+    }
+
+    /// Growth of the RTT under load in milliseconds: the smoothed RTT
+    /// reported nearest the end of the interval of peak throughput of
+    /// `test`, minus the first one reported, while the connection was still
+    /// nearly idle.
+    pub fn bufferbloat_ms(&self, test: TestKind) -> Option<f64> {
+        let mut rtts: Vec<(Micros, Micros)> = self
+            .server
+            .iter()
+            .filter_map(|m| {
+                let tcp = m.tcp_info.as_ref()?;
+                Some((tcp.elapsed_time?, tcp.rtt?))
+            })
+            .collect();
+        rtts.sort_by_key(|&(elapsed, _)| elapsed);
+        let (_, first) = *rtts.first()?;
+
+        let series = self.progress(test);
+        let (_, peak_at) = series
+            .windows(2)
+            .filter_map(|w| Some((interval_mbps(w[0], w[1])?, w[1].1)))
+            .max_by(|a, b| a.0.total_cmp(&b.0))?;
+        let (_, at_peak) = rtts
+            .iter()
+            .min_by_key(|(elapsed, _)| (elapsed.0 - peak_at.0).abs())?;
+        Some(at_peak.as_millis_f64() - first.as_millis_f64())
+    }
+
+    /// Bytes and elapsed time of the measurements carrying the throughput
+    /// of `test`, in order of elapsed time. Measurements taken at the start
+    /// of the test are left out.
+    fn progress(&self, test: TestKind) -> Vec<(ByteCount, Micros)> {
+        let mut series: Vec<_> = self
+            .client
+            .iter()
+            .chain(&self.server)
+            .filter_map(|m| progress(test, m))
+            .filter(|(_, elapsed)| elapsed.0 > 0)
+            .collect();
+        series.sort_by_key(|&(_, elapsed)| elapsed);
+        series
+    }
+
+    /// The metrics of `test` after each measurement that changed them.
+    pub fn samples(&self, test: TestKind) -> Vec<Sample> {
+        let mut all: Vec<&Measurement> = self.client.iter().chain(&self.server).collect();
+        // Both sides time their measurements from the start of the test.
+        all.sort_by_key(|m| elapsed_time(m));
+        let mut metrics = Metrics::new(test);
+        let mut samples = Vec::new();
+        for m in all {
+            if metrics.update(m) {
+                samples.push(*metrics.sample());
+            }
+        }
+        samples
+    }
+}
+
+/// Throughput between two measurements in Mbit/s, or `None` if no time
+/// passed between them.
+fn interval_mbps(from: (ByteCount, Micros), to: (ByteCount, Micros)) -> Option<f64> {
+    ByteCount(to.0.0 - from.0.0).mbps_over(Micros(to.1.0 - from.1.0))
+}
+
+/// Time since the start of the test at which `m` was taken, if known.
+fn elapsed_time(m: &Measurement) -> Option<Micros> {
+    m.app_info
+        .as_ref()
+        .map(|app| app.elapsed_time)
+        .or_else(|| m.tcp_info.as_ref()?.elapsed_time)
+        .or_else(|| m.ws_ping_info.as_ref().map(|ping| ping.elapsed_time))
+}
+
+/// Collects every measurement of a download and an upload test, keeping the
+/// full series for summaries, exports and plotting once the tests end.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct MeasurementLog {
+    /// Measurements of the download test.
+    pub download: TestLog,
+    /// Measurements of the upload test.
+    pub upload: TestLog,
+}
+
+impl MeasurementLog {
+    /// An empty log.
+    pub fn new() -> Self {
+        MeasurementLog::default()
+    }
+
+    /// Append a measurement of `test`, updating its start and end times.
+    /// Measurements without an origin are dropped.
+    pub fn push(&mut self, test: TestKind, m: Measurement) {
+        let log = self.test_mut(test);
+        match m.origin {
+            Some(Origin::Client) => log.client.push(m),
+            Some(Origin::Server) => log.server.push(m),
+            None => return,
+        }
+        let now = SystemTime::now();
+        log.start_time.get_or_insert(now);
+        log.end_time = Some(now);
+    }
+
+    /// The measurements of `test`.
+    pub fn test(&self, test: TestKind) -> &TestLog {
+        match test {
+            TestKind::Download => &self.download,
+            TestKind::Upload => &self.upload,
+        }
+    }
+
+    fn test_mut(&mut self, test: TestKind) -> &mut TestLog {
+        match test {
+            TestKind::Download => &mut self.download,
+            TestKind::Upload => &mut self.upload,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spec::AppInfo;
+
+    fn client(elapsed_ms: i64, bytes: i64) -> Measurement {
+        Measurement {
+            origin: Some(Origin::Client),
+            app_info: Some(AppInfo {
+                elapsed_time: Micros(elapsed_ms * 1000),
+                num_bytes: ByteCount(bytes),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn server(rtt_ms: i64, sent: i64, retrans: i64) -> Measurement {
+        Measurement {
+            origin: Some(Origin::Server),
+            tcp_info: Some(TCPInfo {
+                rtt: Some(Micros(rtt_ms * 1000)),
+                bytes_sent: Some(ByteCount(sent)),
+                bytes_retrans: Some(ByteCount(retrans)),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn download_series() {
+        let mut metrics = Metrics::with_alpha(TestKind::Download, 0.5);
+        // 10 Mbit/s for the first second, then 30 Mbit/s.
+        assert!(metrics.update(&client(1000, 1_250_000)));
+        assert!(metrics.update(&server(20, 1_000_000, 10_000)));
+        assert!(metrics.update(&client(2000, 5_000_000)));
+        assert!(metrics.update(&server(40, 2_000_000, 10_000)));
+
+        let s = metrics.sample();
+        assert_eq!(s.elapsed_time, Micros(2_000_000));
+        assert_eq!(s.average_mbps, Some(20.0));
+        assert_eq!(s.instant_mbps, Some(30.0));
+        assert_eq!(s.smoothed_mbps, Some(20.0));
+        assert_eq!(s.rtt_ms, Some(40.0));
+        assert_eq!(s.smoothed_rtt_ms, Some(30.0));
+        assert_eq!(s.retransmission_pct, Some(0.5));
+
+        // Client measurements do not carry the upload's throughput.
+        let mut upload = Metrics::new(TestKind::Upload);
+        assert!(!upload.update(&client(1000, 1_250_000)));
+        assert_eq!(average_mbps(TestKind::Upload, &client(1000, 1)), None);
+    }
+
+    #[test]
+    fn log_keeps_series() {
+        let mut log = MeasurementLog::new();
+        log.push(TestKind::Download, client(1000, 1_250_000));
+        log.push(TestKind::Download, server(20, 1_000_000, 0));
+        log.push(TestKind::Download, client(2000, 5_000_000));
+        log.push(
+            TestKind::Download,
+            Measurement {
+                origin: Some(Origin::Client),
+                ..Default::default()
+            },
+        );
+
+        let download = log.test(TestKind::Download);
+        assert_eq!(download.client.len(), 3);
+        assert_eq!(download.server.len(), 1);
+        assert_eq!(download.last_client(), Some(&client(2000, 5_000_000)));
+        assert_eq!(log.test(TestKind::Upload), &TestLog::default());
+
+        let samples = download.samples(TestKind::Download);
+        let speeds: Vec<_> = samples.iter().map(|s| s.instant_mbps).collect();
+        // The server measurement has no elapsed time and sorts first.
+        assert_eq!(speeds, [None, Some(10.0), Some(30.0)]);
+
+        // Out of order, the last sample received is not the latest.
+        let mut log = TestLog::default();
+        for (elapsed_ms, bytes) in [(1000, 1_250_000), (3000, 6_250_000), (2000, 5_000_000)] {
+            log.client.push(client(elapsed_ms, bytes));
+        }
+        let mbps = log.throughput_mbps(TestKind::Download).unwrap();
+        assert!((mbps - 16.666).abs() < 0.001);
+        let stats = log.interval_stats(TestKind::Download).unwrap();
+        assert_eq!((stats.min_mbps, stats.max_mbps), (10.0, 30.0));
+        assert_eq!(stats.avg_mbps, 20.0);
+        let series = log.throughput_series(TestKind::Download, Micros(1_000_000));
+        assert_eq!(series, [10.0, 30.0, 10.0]);
+        let series = log.throughput_series(TestKind::Download, Micros(1_500_000));
+        assert_eq!(series, [20.0, 10.0]);
+
+        assert_eq!(jitter(&[10.0, 14.0, 12.0]), Some(3.0));
+        assert_eq!(jitter(&[10.0]), None);
+
+        // Throughput peaks in the second interval, when the RTT is 35 ms.
+        let mut log = TestLog::default();
+        for (elapsed_ms, bytes) in [(1000, 1_250_000), (2000, 5_000_000), (3000, 6_250_000)] {
+            log.client.push(client(elapsed_ms, bytes));
+        }
+        for (elapsed_ms, rtt_ms) in [(500, 10), (1900, 35), (2900, 30)] {
+            let mut m = server(rtt_ms, 0, 0);
+            m.tcp_info.as_mut().unwrap().elapsed_time = Some(Micros(elapsed_ms * 1000));
+            log.server.push(m);
+        }
+        assert_eq!(log.bufferbloat_ms(TestKind::Download), Some(25.0));
+    }
+
+    #[test]
+    fn interval_stats() {
+        assert_eq!(TestLog::default().interval_stats(TestKind::Download), None);
+
+        // A single interval has no spread; one measurement has no interval.
+        let mut log = TestLog::default();
+        log.client.push(client(1000, 1_250_000));
+        assert_eq!(log.interval_stats(TestKind::Download), None);
+        log.client.push(client(2000, 5_000_000));
+        let stats = log.interval_stats(TestKind::Download).unwrap();
+        assert_eq!(
+            (stats.min_mbps, stats.avg_mbps, stats.max_mbps),
+            (30.0, 30.0, 30.0)
+        );
+
+        // 30 Mbit/s, then 10 Mbit/s: 5 MB in 2 s is 20 Mbit/s overall. The
+        // measurement at the start of the test is left out.
+        log.client.push(client(0, 0));
+        log.client.push(client(3000, 6_250_000));
+        let stats = log.interval_stats(TestKind::Download).unwrap();
+        assert_eq!(
+            (stats.min_mbps, stats.avg_mbps, stats.max_mbps),
+            (10.0, 20.0, 30.0)
+        );
+        assert_eq!(log.interval_stats(TestKind::Upload), None);
+    }
+
+    #[test]
+    fn rtt_percentiles() {
+        assert_eq!(percentile(&[], 50.0), None);
+        assert_eq!(percentile(&[7.0], 99.0), Some(7.0));
+
+        // Nearest rank of 10 values: p50 is the 5th, p95 and p99 the 10th.
+        let values = [40.0, 10.0, 100.0, 30.0, 20.0, 90.0, 60.0, 50.0, 80.0, 70.0];
+        assert_eq!(percentile(&values, 0.0), Some(10.0));
+        assert_eq!(percentile(&values, 50.0), Some(50.0));
+        assert_eq!(percentile(&values, 90.0), Some(90.0));
+        assert_eq!(percentile(&values, 95.0), Some(100.0));
+        assert_eq!(percentile(&values, 99.0), Some(100.0));
+        assert_eq!(percentile(&values, 150.0), Some(100.0));
+
+        let mut log = TestLog::default();
+        for rtt_ms in [20, 40, 30] {
+            log.server.push(server(rtt_ms, 0, 0));
+        }
+        log.client.push(client(1000, 1));
+        assert_eq!(log.rtt_ms(), [20.0, 40.0, 30.0]);
+    }
+}
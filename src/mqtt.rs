@@ -0,0 +1,377 @@
+//! Test events published to an MQTT broker.
+//!
+//! [`MqttEmitter`] publishes every event as the JSON object
+//! [`JsonEmitter`](crate::emitter::JsonEmitter) would write, to a topic
+//! named after the event under a configurable prefix, e.g.
+//! `probes/<id>/ndt7/summary`, for fleets of devices that report telemetry
+//! over MQTT. Packets are written by a background task in the order of the
+//! events, so a slow broker does not hold up the tests;
+//! [`MqttDelivery::finish`] waits for the outstanding ones.
+//!
+//! Only what publishing needs of MQTT 3.1.1 is implemented: a connection
+//! without keep-alive, optionally over TLS and with a username and
+//! password, and QoS 0 messages.
+
+use std::io;
+use std::sync::Arc;
+
+use rustls::pki_types::ServerName;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_rustls::TlsConnector;
+
+use crate::client::{ConnectInfo, io_timeout, tls_config};
+use crate::emitter::{Emitter, Progress, TestEvent};
+use crate::error::{ConfigError, Ndt7Error, Result};
+use crate::ping::PingResult;
+use crate::spec::{Measurement, TestKind};
+use crate::summary::Summary;
+use crate::sweep::SweepReport;
+
+/// Default prefix of the topics events are published to.
+pub const DEFAULT_TOPIC_PREFIX: &str = "ndt7";
+
+/// Settings of a broker connection, turned into an emitter with
+/// [`Mqtt::connect`].
+#[derive(Debug, Clone)]
+pub struct Mqtt {
+    addr: String,
+    client_id: String,
+    prefix: String,
+    credentials: Option<(String, String)>,
+    tls: bool,
+}
+
+impl Mqtt {
+    /// Publish to the broker at `addr`, e.g. `"localhost:1883"`, as
+    /// `client_id`, under [`DEFAULT_TOPIC_PREFIX`].
+    pub fn new(addr: impl Into<String>, client_id: impl Into<String>) -> Self {
+        Mqtt {
+            addr: addr.into(),
+            client_id: client_id.into(),
+            prefix: DEFAULT_TOPIC_PREFIX.to_string(),
+            credentials: None,
+            tls: false,
+        }
+    }
+
+    /// Publish under `prefix` instead of [`DEFAULT_TOPIC_PREFIX`], e.g.
+    /// `probes/router-17/ndt7`.
+    pub fn topic_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// Log in with `username` and `password`.
+    pub fn credentials(mut self, username: impl Into<String>, password: impl Into<String>) -> Self {
+        self.credentials = Some((username.into(), password.into()));
+        self
+    }
+
+    /// Connect over TLS, usually to port 8883, verifying the broker with
+    /// the root certificates of the `webpki-roots` or `native-roots`
+    /// feature.
+    pub fn tls(mut self) -> Self {
+        self.tls = true;
+        self
+    }
+
+    /// Connect to the broker and start the background task publishing the
+    /// events, returning the emitter feeding it and the handle to wait for
+    /// it with.
+    ///
+    /// Fails if the broker cannot be reached or refuses the connection, or
+    /// the client identifier or credentials are longer than MQTT allows.
+    pub async fn connect(self) -> Result<(MqttEmitter, MqttDelivery)> {
+        let mut body = Vec::new();
+        put_str(&mut body, "MQTT")?;
+        // Protocol level 4 (3.1.1), clean session, keep-alive disabled.
+        let flags = if self.credentials.is_some() {
+            0xc2
+        } else {
+            0x02
+        };
+        body.extend_from_slice(&[4, flags, 0, 0]);
+        put_str(&mut body, &self.client_id)?;
+        if let Some((username, password)) = &self.credentials {
+            put_str(&mut body, username)?;
+            put_str(&mut body, password)?;
+        }
+
+        let tcp = io_timeout(TcpStream::connect(&self.addr)).await??;
+        let mut stream: Box<dyn Stream> = if self.tls {
+            let tls = tls_config(false, []).ok_or(ConfigError::Requires(
+                "MQTT over TLS",
+                "the webpki-roots or native-roots feature",
+            ))?;
+            let host = self.addr.rsplit_once(':').map_or(&*self.addr, |(h, _)| h);
+            let host = host.trim_start_matches('[').trim_end_matches(']');
+            let name = ServerName::try_from(host.to_string())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            let connector = TlsConnector::from(Arc::clone(&tls));
+            Box::new(io_timeout(connector.connect(name, tcp)).await??)
+        } else {
+            Box::new(tcp)
+        };
+        io_timeout(handshake(&mut stream, &packet(0x10, &body))).await??;
+
+        let (tx, mut rx) = mpsc::unbounded_channel::<Vec<u8>>();
+        let task = tokio::spawn(async move {
+            while let Some(packet) = rx.recv().await {
+                io_timeout(stream.write_all(&packet)).await??;
+            }
+            // DISCONNECT, so the broker does not treat the close as a
+            // failure.
+            io_timeout(stream.write_all(&[0xe0, 0])).await??;
+            io_timeout(stream.shutdown()).await??;
+            Ok(())
+        });
+        let emitter = MqttEmitter {
+            tx,
+            prefix: self.prefix,
+        };
+        Ok((emitter, MqttDelivery { task }))
+    }
+}
+
+/// A connection to the broker, in plain text or over TLS.
+trait Stream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Stream for T {}
+
+/// Send the CONNECT packet `connect` and check the broker's CONNACK.
+async fn handshake(stream: &mut Box<dyn Stream>, connect: &[u8]) -> Result<()> {
+    stream.write_all(connect).await?;
+    let mut connack = [0u8; 4];
+    stream.read_exact(&mut connack).await?;
+    if connack[0] != 0x20 || connack[1] != 2 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "expected MQTT CONNACK").into());
+    }
+    if connack[3] != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("MQTT broker refused the connection (code {})", connack[3]),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Publishes test events as JSON to an MQTT broker, see [`Mqtt`].
+///
+/// Each event goes to `<prefix>/<event>`, where `<event>` is the snake
+/// case event type, e.g. `measurement` or `summary`. Summaries are
+/// retained, so subscribers joining later see the last result of each
+/// probe.
+///
+/// Events are queued and never block; delivery errors are reported by
+/// [`MqttDelivery::finish`].
+pub struct MqttEmitter {
+    tx: mpsc::UnboundedSender<Vec<u8>>,
+    prefix: String,
+}
+
+impl MqttEmitter {
+    fn publish(&mut self, event: &TestEvent) -> Result<()> {
+        let retain = matches!(event, TestEvent::Summary { .. });
+        let mut body = Vec::new();
+        put_str(&mut body, &format!("{}/{}", self.prefix, event.name()))?;
+        serde_json::to_writer(&mut body, event)?;
+        // The task only stops once the emitter is dropped, or after a write
+        // failed, which finish reports.
+        let _ = self.tx.send(packet(0x30 | u8::from(retain), &body));
+        Ok(())
+    }
+}
+
+/// Background publishing of an [`MqttEmitter`]'s events.
+pub struct MqttDelivery {
+    task: JoinHandle<Result<()>>,
+}
+
+impl MqttDelivery {
+    /// Wait until every queued event was published and the connection
+    /// closed, after the emitter has been dropped. Returns the write that
+    /// failed, if any.
+    pub async fn finish(self) -> Result<()> {
+        match self.task.await {
+            Ok(result) => result,
+            Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+            Err(_) => Err(Ndt7Error::Cancelled),
+        }
+    }
+}
+
+/// A control packet of type and flags `header` with `body`.
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![header];
+    // Remaining length, 7 bits per byte, least significant first.
+    let mut len = body.len();
+    loop {
+        let byte = (len % 128) as u8;
+        len /= 128;
+        packet.push(if len > 0 { byte | 0x80 } else { byte });
+        if len == 0 {
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+/// Append `s` as a length-prefixed UTF-8 string, failing if it is longer
+/// than the 65535 bytes MQTT allows.
+fn put_str(buf: &mut Vec<u8>, s: &str) -> Result<()> {
+    let len = u16::try_from(s.len()).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("MQTT string of {} bytes exceeds 65535", s.len()),
+        )
+    })?;
+    buf.extend_from_slice(&len.to_be_bytes());
+    buf.extend_from_slice(s.as_bytes());
+    Ok(())
+}
+
+impl Emitter for MqttEmitter {
+    fn on_starting(&mut self, test: TestKind) -> Result<()> {
+        self.publish(&TestEvent::Starting { test })
+    }
+
+    fn on_error(&mut self, test: TestKind, err: &str) -> Result<()> {
+        self.publish(&TestEvent::Error { test, error: err })
+    }
+
+    fn on_server_closed(&mut self, test: TestKind, code: u16, reason: &str) -> Result<()> {
+        self.publish(&TestEvent::ServerClosed { test, code, reason })
+    }
+
+    fn on_server_unhealthy(&mut self, test: TestKind, fqdn: &str, reason: &str) -> Result<()> {
+        self.publish(&TestEvent::ServerUnhealthy { test, fqdn, reason })
+    }
+
+    fn on_connected(&mut self, test: TestKind, fqdn: &str, info: &ConnectInfo) -> Result<()> {
+        self.publish(&TestEvent::Connected {
+            test,
+            fqdn,
+            connect_info: info,
+        })
+    }
+
+    fn on_download_event(&mut self, m: &Measurement) -> Result<()> {
+        self.publish(&TestEvent::Measurement {
+            test: TestKind::Download,
+            measurement: m,
+        })
+    }
+
+    fn on_upload_event(&mut self, m: &Measurement) -> Result<()> {
+        self.publish(&TestEvent::Measurement {
+            test: TestKind::Upload,
+            measurement: m,
+        })
+    }
+
+    fn on_progress(&mut self, test: TestKind, progress: &Progress) -> Result<()> {
+        self.publish(&TestEvent::Progress { test, progress })
+    }
+
+    fn on_complete(&mut self, test: TestKind) -> Result<()> {
+        self.publish(&TestEvent::Complete { test })
+    }
+
+    fn on_summary(&mut self, s: &Summary) -> Result<()> {
+        self.publish(&TestEvent::Summary { summary: s })
+    }
+
+    fn on_ping(&mut self, p: &PingResult) -> Result<()> {
+        self.publish(&TestEvent::Ping { ping: p })
+    }
+
+    fn on_sweep(&mut self, report: &SweepReport) -> Result<()> {
+        self.publish(&TestEvent::Sweep { sweep: report })
+    }
+
+    fn on_warning(&mut self, warning: &str) -> Result<()> {
+        self.publish(&TestEvent::Warning { warning })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    /// Read one control packet, returning its header and body.
+    fn read_packet(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut byte = [0u8];
+        stream.read_exact(&mut byte).unwrap();
+        let header = byte[0];
+        let (mut len, mut shift) = (0usize, 0);
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            len |= ((byte[0] & 0x7f) as usize) << shift;
+            shift += 7;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = vec![0u8; len];
+        stream.read_exact(&mut body).unwrap();
+        (header, body)
+    }
+
+    #[tokio::test]
+    async fn publish_summary() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let broker = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let (header, connect) = read_packet(&mut stream);
+            assert_eq!(header, 0x10);
+            assert_eq!(connect[7], 0xc2, "username and password flags");
+            assert!(connect.ends_with(b"\x00\x05probe\x00\x04user\x00\x06secret"));
+            stream.write_all(&[0x20, 2, 0, 0]).unwrap();
+            let packets: Vec<_> = (0..3).map(|_| read_packet(&mut stream)).collect();
+            packets
+        });
+
+        let (mut emitter, delivery) = Mqtt::new(addr.to_string(), "probe")
+            .topic_prefix("probes/17/ndt7")
+            .credentials("user", "secret")
+            .connect()
+            .await
+            .unwrap();
+        emitter.on_starting(TestKind::Download).unwrap();
+        emitter
+            .on_summary(&Summary::from_measurements(
+                "mlab1".into(),
+                None,
+                None,
+                None,
+            ))
+            .unwrap();
+        drop(emitter);
+        delivery.finish().await.unwrap();
+
+        let packets = broker.join().unwrap();
+        let (header, body) = &packets[1];
+        assert_eq!(*header, 0x31, "summaries are retained");
+        let topic = b"probes/17/ndt7/summary";
+        assert_eq!(&body[2..2 + topic.len()], topic);
+        let event: serde_json::Value = serde_json::from_slice(&body[2 + topic.len()..]).unwrap();
+        assert_eq!(event["Type"], "Summary");
+        assert_eq!(packets[2], (0xe0, Vec::new()));
+    }
+
+    #[test]
+    fn rejects_long_strings() {
+        let mut buf = Vec::new();
+        assert!(put_str(&mut buf, &"x".repeat(65535)).is_ok());
+        assert!(put_str(&mut buf, &"x".repeat(65536)).is_err());
+    }
+}
@@ -0,0 +1,545 @@
+//! msak throughput1 protocol.
+//!
+//! msak is M-Lab's successor to ndt7. Its throughput1 test measures one
+//! direction over several concurrent TCP connections ("streams") to the same
+//! server, grouped by a measurement ID, and reports their aggregate. Each
+//! stream is a WebSocket connection using the [`PROTOCOL`] subprotocol: the
+//! server sends binary messages on download streams and receives them on
+//! upload streams, and both peers send [`WireMeasurement`]s of their side as
+//! text messages during the test.
+//!
+//! Start a test with
+//! [`Client::start_throughput`](crate::client::Client::start_throughput) and
+//! sum up its streams with [`Aggregate`].
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, StreamExt};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tokio::time::{Instant, sleep_until, timeout};
+use tokio_tungstenite::tungstenite::Message;
+use url::Url;
+
+use crate::client::{ConnectInfo, SkippedMeasurements, Transport, io_timeout};
+use crate::download::client_tcp_info;
+use crate::error::{Ndt7Error, Result};
+use crate::params;
+use crate::spec::{Micros, Origin, TCPInfo, TestKind};
+use crate::tcpinfo::TcpInfoSource;
+
+/// Value of the Sec-WebSocket-Protocol header.
+pub const PROTOCOL: &str = "net.measurementlab.throughput.v1";
+
+/// URL path for the download test.
+pub const DOWNLOAD_URL_PATH: &str = "/throughput/v1/download";
+
+/// URL path for the upload test.
+pub const UPLOAD_URL_PATH: &str = "/throughput/v1/upload";
+
+/// Default number of streams, as in M-Lab's reference client.
+pub const DEFAULT_STREAMS: usize = 2;
+
+/// Default test duration, as in M-Lab's reference client.
+pub const DEFAULT_DURATION: Duration = Duration::from_secs(5);
+
+/// URL path of `test`.
+pub fn url_path(test: TestKind) -> &'static str {
+    match test {
+        TestKind::Download => DOWNLOAD_URL_PATH,
+        TestKind::Upload => UPLOAD_URL_PATH,
+    }
+}
+
+/// Parameters of a throughput1 test.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ThroughputConfig {
+    /// Number of concurrent streams, at least one.
+    pub streams: usize,
+    /// How long the test runs. Both peers end their streams after it.
+    pub duration: Duration,
+    /// Congestion control algorithm the server should use, e.g. `"bbr"`.
+    /// The server's default if `None`.
+    pub cc: Option<String>,
+    /// End each stream after this many bytes of payload.
+    pub bytes: Option<u64>,
+}
+
+impl Default for ThroughputConfig {
+    fn default() -> Self {
+        ThroughputConfig {
+            streams: DEFAULT_STREAMS,
+            duration: DEFAULT_DURATION,
+            cc: None,
+            bytes: None,
+        }
+    }
+}
+
+/// Bytes sent and received by one side of a stream.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ByteCounters {
+    /// Bytes sent.
+    #[serde(default)]
+    pub bytes_sent: i64,
+    /// Bytes received.
+    #[serde(default)]
+    pub bytes_received: i64,
+}
+
+/// A measurement of one stream, exchanged as a text message.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct WireMeasurement {
+    /// Congestion control algorithm of the sender.
+    #[serde(rename = "CC", default, skip_serializing_if = "Option::is_none")]
+    pub cc: Option<String>,
+    /// Unique identifier of the stream assigned by the server.
+    #[serde(rename = "UUID", default, skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<String>,
+    /// Address of the measuring side as `ip:port`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub local_addr: Option<String>,
+    /// Address of the other side as `ip:port`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remote_addr: Option<String>,
+    /// WebSocket message payload bytes.
+    #[serde(default)]
+    pub application: ByteCounters,
+    /// TCP payload bytes, where the kernel reports them.
+    #[serde(default)]
+    pub network: ByteCounters,
+    /// Microseconds since the start of the stream.
+    #[serde(default)]
+    pub elapsed_time: i64,
+    /// TCP-level metrics from the kernel.
+    #[serde(rename = "TCPInfo", default, skip_serializing_if = "Option::is_none")]
+    pub tcp_info: Option<TCPInfo>,
+}
+
+/// A [`WireMeasurement`] of one stream of a running test.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamMeasurement {
+    /// Index of the stream, from 0.
+    pub stream: usize,
+    /// Which side produced the measurement.
+    pub origin: Origin,
+    /// The measurement.
+    pub measurement: WireMeasurement,
+}
+
+/// Handle to a running throughput1 test, returned by
+/// [`Client::start_throughput`](crate::client::Client::start_throughput).
+pub struct ThroughputHandle {
+    /// Fully qualified domain name of the server running the test.
+    pub server_fqdn: String,
+    /// Details of the server's WebSocket upgrade response of the first
+    /// stream.
+    pub connect_info: ConnectInfo,
+    /// Measurement ID shared by the streams.
+    pub mid: String,
+    /// How long the test runs.
+    pub duration: Duration,
+    /// Measurements of all streams. A stream that fails sends its error and
+    /// stops; the channel closes when all streams have ended. Dropping it
+    /// stops the test.
+    pub rx: mpsc::Receiver<Result<StreamMeasurement>>,
+    /// Server measurements of any stream skipped because they could not be
+    /// parsed, see [`TestParams::strict_parsing`](crate::params::TestParams::strict_parsing).
+    pub skipped: SkippedMeasurements,
+}
+
+/// Aggregate of the client measurements of all streams of a test.
+#[derive(Debug, Clone)]
+pub struct Aggregate {
+    test: TestKind,
+    /// Latest application bytes and elapsed time of each stream.
+    latest: BTreeMap<usize, (i64, i64)>,
+}
+
+impl Aggregate {
+    /// Create an empty aggregate of a `test`.
+    pub fn new(test: TestKind) -> Self {
+        Aggregate {
+            test,
+            latest: BTreeMap::new(),
+        }
+    }
+
+    /// Account for `m`. Server measurements are ignored.
+    pub fn update(&mut self, m: &StreamMeasurement) {
+        if m.origin != Origin::Client {
+            return;
+        }
+        let app = &m.measurement.application;
+        let bytes = match self.test {
+            TestKind::Download => app.bytes_received,
+            TestKind::Upload => app.bytes_sent,
+        };
+        self.latest
+            .insert(m.stream, (bytes, m.measurement.elapsed_time));
+    }
+
+    /// Payload bytes transferred over all streams.
+    pub fn num_bytes(&self) -> i64 {
+        self.latest.values().map(|(bytes, _)| bytes).sum()
+    }
+
+    /// Throughput over all streams in Mbit/s, or `None` before the first
+    /// measurement.
+    pub fn throughput_mbps(&self) -> Option<f64> {
+        let elapsed = self.latest.values().map(|&(_, elapsed)| elapsed).max()?;
+        (elapsed > 0).then(|| 8.0 * self.num_bytes() as f64 / elapsed as f64)
+    }
+}
+
+/// A new random measurement ID.
+pub(crate) fn measurement_id() -> String {
+    let mut id = [0u8; 16];
+    rand::rng().fill_bytes(&mut id);
+    id.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// `url` with the test parameters appended.
+pub(crate) fn stream_url(mut url: Url, mid: &str, config: &ThroughputConfig) -> Url {
+    {
+        let mut pairs = url.query_pairs_mut();
+        pairs
+            .append_pair("mid", mid)
+            .append_pair("streams", &config.streams.to_string())
+            .append_pair("duration", &config.duration.as_millis().to_string());
+        if let Some(cc) = &config.cc {
+            pairs.append_pair("cc", cc);
+        }
+        if let Some(bytes) = config.bytes {
+            pairs.append_pair("bytes", &bytes.to_string());
+        }
+    }
+    url
+}
+
+/// One stream of a test, for [`run_stream`].
+pub(crate) struct Stream {
+    pub(crate) test: TestKind,
+    /// Index of the stream, from 0.
+    pub(crate) index: usize,
+    /// Fail on a malformed server measurement instead of skipping it.
+    pub(crate) strict_parsing: bool,
+    /// Where skipped server measurements are listed.
+    pub(crate) skipped: SkippedMeasurements,
+}
+
+/// Run one stream of a test on an established connection, sending prefixes
+/// of `corpus` on upload streams.
+///
+/// Measurements are sent on `tx`. The stream ends after the test duration,
+/// when the server closes the connection or when the byte limit is reached.
+/// An error is sent as the stream's last item.
+pub(crate) async fn run_stream<T: Transport>(
+    ws: T,
+    stream: Stream,
+    corpus: Bytes,
+    config: ThroughputConfig,
+    tx: mpsc::Sender<Result<StreamMeasurement>>,
+) {
+    let tcp = ws.tcp_stream();
+    let ctx = StreamContext {
+        test: stream.test,
+        index: stream.index,
+        strict_parsing: stream.strict_parsing,
+        skipped: stream.skipped,
+        start: Instant::now(),
+        tcp_info: TcpInfoSource::new(&ws),
+        local_addr: tcp.and_then(|t| t.local_addr().ok()).map(|a| a.to_string()),
+        remote_addr: tcp.and_then(|t| t.peer_addr().ok()).map(|a| a.to_string()),
+        sent: AtomicI64::new(0),
+        received: AtomicI64::new(0),
+        tx,
+    };
+    let (mut sink, stream) = ws.split();
+    let run = async {
+        tokio::select! {
+            result = read_stream(stream, &ctx) => result,
+            result = write_stream(&mut sink, &ctx, corpus, config.bytes) => result,
+        }
+    };
+    let result = match timeout(config.duration, run).await {
+        Ok(result) => result,
+        // The test ran its full duration.
+        Err(_) => Ok(()),
+    };
+    if let Err(e) = result {
+        let _ = ctx.tx.send(Err(e)).await;
+    }
+    let _ = io_timeout(sink.send(Message::Close(None))).await;
+}
+
+/// State of one stream.
+struct StreamContext {
+    test: TestKind,
+    index: usize,
+    strict_parsing: bool,
+    skipped: SkippedMeasurements,
+    start: Instant,
+    tcp_info: Option<TcpInfoSource>,
+    local_addr: Option<String>,
+    remote_addr: Option<String>,
+    /// Application bytes sent so far.
+    sent: AtomicI64,
+    /// Application bytes received so far.
+    received: AtomicI64,
+    tx: mpsc::Sender<Result<StreamMeasurement>>,
+}
+
+impl StreamContext {
+    /// The client's measurement of the stream so far.
+    fn measurement(&self) -> WireMeasurement {
+        let elapsed = Micros::from(self.start.elapsed());
+        let tcp_info = client_tcp_info(self.tcp_info.as_ref(), elapsed);
+        let network = tcp_info
+            .as_ref()
+            .map(|t| ByteCounters {
+                bytes_sent: t.bytes_acked.unwrap_or_default().0,
+                bytes_received: t.bytes_received.unwrap_or_default().0,
+            })
+            .unwrap_or_default();
+        WireMeasurement {
+            local_addr: self.local_addr.clone(),
+            remote_addr: self.remote_addr.clone(),
+            application: ByteCounters {
+                bytes_sent: self.sent.load(Ordering::Relaxed),
+                bytes_received: self.received.load(Ordering::Relaxed),
+            },
+            network,
+            elapsed_time: elapsed.0,
+            tcp_info,
+            ..Default::default()
+        }
+    }
+
+    async fn emit(&self, origin: Origin, measurement: WireMeasurement) {
+        let m = StreamMeasurement {
+            stream: self.index,
+            origin,
+            measurement,
+        };
+        let _ = self.tx.send(Ok(m)).await;
+    }
+}
+
+/// Read the server's messages until it closes the stream.
+async fn read_stream<T: Transport>(mut stream: SplitStream<T>, ctx: &StreamContext) -> Result<()> {
+    loop {
+        let Some(msg) = io_timeout(stream.next()).await? else {
+            return Ok(());
+        };
+        match msg? {
+            Message::Binary(data) => {
+                if ctx.test == TestKind::Upload {
+                    return Err(Ndt7Error::ProtocolViolation(
+                        "server sent unexpected binary message during upload".into(),
+                    ));
+                }
+                ctx.received.fetch_add(data.len() as i64, Ordering::Relaxed);
+            }
+            Message::Text(text) => {
+                ctx.received.fetch_add(text.len() as i64, Ordering::Relaxed);
+                match serde_json::from_str(&text) {
+                    Ok(m) => ctx.emit(Origin::Server, m).await,
+                    Err(e) if ctx.strict_parsing => return Err(e.into()),
+                    Err(e) => ctx.skipped.push(e.to_string()),
+                }
+            }
+            Message::Close(frame) => return Ndt7Error::check_close(frame),
+            _ => {} // Pings are answered automatically by tokio-tungstenite
+        }
+    }
+}
+
+/// Send the client's measurements and, on upload streams, the payload.
+/// Returns once `max_bytes` of payload were sent, or never on download
+/// streams.
+async fn write_stream<T: Transport>(
+    sink: &mut SplitSink<T, Message>,
+    ctx: &StreamContext,
+    corpus: Bytes,
+    max_bytes: Option<u64>,
+) -> Result<()> {
+    let mut next_report = ctx.start + params::UPDATE_INTERVAL;
+    let max_size = corpus.len();
+    let mut size = params::INITIAL_MESSAGE_SIZE.min(max_size);
+    loop {
+        if ctx.test == TestKind::Download {
+            sleep_until(next_report).await;
+        }
+        if Instant::now() >= next_report {
+            let m = ctx.measurement();
+            let text = serde_json::to_string(&m)?;
+            io_timeout(sink.send(Message::Text(text.into()))).await??;
+            ctx.emit(Origin::Client, m).await;
+            next_report += params::UPDATE_INTERVAL;
+        }
+        if ctx.test == TestKind::Download {
+            continue;
+        }
+
+        let sent = ctx.sent.load(Ordering::Relaxed) as u64;
+        if max_bytes.is_some_and(|max| sent >= max) {
+            ctx.emit(Origin::Client, ctx.measurement()).await;
+            return Ok(());
+        }
+        let len = max_bytes.map_or(size, |max| size.min((max - sent) as usize));
+        io_timeout(sink.send(Message::Binary(corpus.slice(..len)))).await??;
+        let total = ctx.sent.fetch_add(len as i64, Ordering::Relaxed) + len as i64;
+        if size < max_size && size <= total as usize / params::SCALING_FRACTION {
+            size = (size * 2).min(max_size);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+
+    use super::*;
+    use crate::client::ClientBuilder;
+    use crate::error::ConfigError;
+
+    /// Download server that checks the test parameters, sends some data and
+    /// a measurement on each stream, and records the client's measurements.
+    async fn mock_download_server(streams: usize) -> (String, mpsc::Receiver<WireMeasurement>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::channel(64);
+        tokio::spawn(async move {
+            for _ in 0..streams {
+                let (stream, _) = listener.accept().await.unwrap();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    #[allow(clippy::result_large_err)]
+                    let callback = |req: &Request, mut resp: Response| {
+                        let query: HashMap<_, _> =
+                            url::form_urlencoded::parse(req.uri().query().unwrap().as_bytes())
+                                .collect();
+                        assert_eq!(query["streams"], "2");
+                        assert_eq!(query["duration"], "400");
+                        assert_eq!(query["mid"].len(), 32);
+                        let proto = req.headers()["Sec-WebSocket-Protocol"].clone();
+                        resp.headers_mut().insert("Sec-WebSocket-Protocol", proto);
+                        Ok(resp)
+                    };
+                    let mut ws = tokio_tungstenite::accept_hdr_async(stream, callback)
+                        .await
+                        .unwrap();
+                    let m = WireMeasurement {
+                        cc: Some("bbr".into()),
+                        elapsed_time: 1000,
+                        ..Default::default()
+                    };
+                    let text = serde_json::to_string(&m).unwrap();
+                    ws.send(Message::Text(text.into())).await.unwrap();
+                    for _ in 0..4 {
+                        ws.send(Message::Binary(vec![0u8; 1000].into()))
+                            .await
+                            .unwrap();
+                    }
+                    while let Some(Ok(msg)) = ws.next().await {
+                        if let Message::Text(text) = msg {
+                            let _ = tx.send(serde_json::from_str(&text).unwrap()).await;
+                        }
+                    }
+                });
+            }
+        });
+        (format!("ws://{addr}{DOWNLOAD_URL_PATH}"), rx)
+    }
+
+    #[tokio::test]
+    async fn multi_stream_download() {
+        let (url, mut server_rx) = mock_download_server(2).await;
+        let client = ClientBuilder::new("test", "test").no_tls().build();
+        let config = ThroughputConfig {
+            duration: Duration::from_millis(400),
+            ..Default::default()
+        };
+        let mut handle = client
+            .start_throughput(TestKind::Download, Some(&url), &config)
+            .await
+            .unwrap();
+        assert_eq!(handle.connect_info.subprotocol.as_deref(), Some(PROTOCOL));
+
+        let mut aggregate = Aggregate::new(TestKind::Download);
+        let mut server_streams = Vec::new();
+        while let Some(result) = handle.rx.recv().await {
+            let m = result.unwrap();
+            aggregate.update(&m);
+            if m.origin == Origin::Server {
+                assert_eq!(m.measurement.cc.as_deref(), Some("bbr"));
+                server_streams.push(m.stream);
+            }
+        }
+        server_streams.sort();
+        assert_eq!(server_streams, [0, 1]);
+        // Data and measurement of both streams.
+        let text_len = serde_json::to_string(&WireMeasurement {
+            cc: Some("bbr".into()),
+            elapsed_time: 1000,
+            ..Default::default()
+        })
+        .unwrap()
+        .len() as i64;
+        assert_eq!(aggregate.num_bytes(), 2 * (4000 + text_len));
+        assert!(aggregate.throughput_mbps().unwrap() > 0.0);
+
+        // The client reported its side to the server.
+        let reported = server_rx.recv().await.unwrap();
+        assert!(reported.elapsed_time > 0);
+    }
+
+    #[tokio::test]
+    async fn zero_streams_rejected() {
+        let client = ClientBuilder::new("test", "test").no_tls().build();
+        let config = ThroughputConfig {
+            streams: 0,
+            ..Default::default()
+        };
+        let result = client
+            .start_throughput(TestKind::Download, Some("ws://127.0.0.1:1"), &config)
+            .await;
+        assert!(matches!(
+            result,
+            Err(Ndt7Error::Config(ConfigError::Zero("streams")))
+        ));
+    }
+
+    #[test]
+    fn aggregate_streams() {
+        let mut aggregate = Aggregate::new(TestKind::Upload);
+        assert_eq!(aggregate.throughput_mbps(), None);
+        for (stream, bytes, elapsed) in [(0, 500_000, 1_000_000), (1, 750_000, 2_000_000)] {
+            aggregate.update(&StreamMeasurement {
+                stream,
+                origin: Origin::Client,
+                measurement: WireMeasurement {
+                    application: ByteCounters {
+                        bytes_sent: bytes,
+                        bytes_received: 0,
+                    },
+                    elapsed_time: elapsed,
+                    ..Default::default()
+                },
+            });
+        }
+        assert_eq!(aggregate.num_bytes(), 1_250_000);
+        assert_eq!(aggregate.throughput_mbps(), Some(5.0));
+    }
+}
@@ -0,0 +1,232 @@
+//! OpenTelemetry traces and metrics of test runs.
+//!
+//! [`OtelEmitter`] records each subtest as a span named `ndt7.download` or
+//! `ndt7.upload`, with a `connect` child span covering the time until the
+//! WebSocket connection was established, and exports the values of the
+//! final summary as histograms. Spans and instruments come from the
+//! globally installed providers unless others are passed in, so ndt7 runs
+//! show up in the application's existing backends.
+
+use opentelemetry::global::{BoxedSpan, BoxedTracer};
+use opentelemetry::metrics::{Histogram, Meter};
+use opentelemetry::trace::{Span, Status, TraceContextExt, Tracer};
+use opentelemetry::{Context, KeyValue, global};
+
+use crate::client::ConnectInfo;
+use crate::emitter::{Emitter, test_name};
+use crate::error::Result;
+use crate::ping::PingResult;
+use crate::spec::{Measurement, TestKind};
+use crate::summary::{SubtestSummary, Summary};
+
+/// Instrumentation scope of the spans and metrics.
+const SCOPE: &str = "ndt7-client";
+
+/// Spans of a running subtest.
+struct Subtest {
+    /// Context holding the subtest's span.
+    cx: Context,
+    /// The connect phase, until the connection is established.
+    connect: Option<BoxedSpan>,
+}
+
+/// Records subtests as OpenTelemetry spans and summaries as metrics.
+///
+/// Metrics are the histograms `ndt7.throughput` (Mbit/s), `ndt7.latency`
+/// (ms) and `ndt7.retransmission` (%), recorded once per subtest with a
+/// `test` attribute of `download` or `upload`, and `ndt7.ping.rtt` (ms) for
+/// latency probes.
+pub struct OtelEmitter {
+    tracer: BoxedTracer,
+    throughput: Histogram<f64>,
+    latency: Histogram<f64>,
+    retransmission: Histogram<f64>,
+    ping_rtt: Histogram<f64>,
+    subtests: [Option<Subtest>; 2],
+}
+
+impl OtelEmitter {
+    /// Create an emitter using the global tracer and meter providers.
+    pub fn new() -> Self {
+        OtelEmitter::with_providers(global::tracer(SCOPE), &global::meter(SCOPE))
+    }
+
+    /// Create an emitter recording spans with `tracer` and metrics with
+    /// `meter`.
+    pub fn with_providers(tracer: BoxedTracer, meter: &Meter) -> Self {
+        let histogram = |name: &'static str, unit: &'static str, description: &'static str| {
+            meter
+                .f64_histogram(name)
+                .with_unit(unit)
+                .with_description(description)
+                .build()
+        };
+        OtelEmitter {
+            tracer,
+            throughput: histogram("ndt7.throughput", "Mbit/s", "Throughput of a subtest"),
+            latency: histogram("ndt7.latency", "ms", "Minimum round-trip time of a subtest"),
+            retransmission: histogram(
+                "ndt7.retransmission",
+                "%",
+                "Percentage of bytes retransmitted in a subtest",
+            ),
+            ping_rtt: histogram("ndt7.ping.rtt", "ms", "Round-trip time of a latency probe"),
+            subtests: [None, None],
+        }
+    }
+
+    fn subtest(&mut self, test: TestKind) -> &mut Option<Subtest> {
+        &mut self.subtests[test as usize]
+    }
+
+    /// End the connect span of `test`, if still open, and its span.
+    fn end(&mut self, test: TestKind) {
+        if let Some(mut subtest) = self.subtest(test).take() {
+            if let Some(mut connect) = subtest.connect.take() {
+                connect.end();
+            }
+            subtest.cx.span().end();
+        }
+    }
+
+    fn record(&self, test: TestKind, s: &SubtestSummary) {
+        let attributes = [KeyValue::new("test", test_name(test))];
+        self.throughput.record(s.throughput_mbps, &attributes);
+        self.latency.record(s.latency_ms, &attributes);
+        if let Some(pct) = s.retransmission_pct {
+            self.retransmission.record(pct, &attributes);
+        }
+    }
+}
+
+impl Default for OtelEmitter {
+    fn default() -> Self {
+        OtelEmitter::new()
+    }
+}
+
+impl Emitter for OtelEmitter {
+    fn on_starting(&mut self, test: TestKind) -> Result<()> {
+        // A subtest that never completed is closed by its successor.
+        self.end(test);
+        let span = self.tracer.start(format!("ndt7.{}", test_name(test)));
+        let cx = Context::current_with_span(span);
+        let connect = self.tracer.start_with_context("connect", &cx);
+        *self.subtest(test) = Some(Subtest {
+            cx,
+            connect: Some(connect),
+        });
+        Ok(())
+    }
+
+    fn on_error(&mut self, test: TestKind, err: &str) -> Result<()> {
+        if let Some(subtest) = self.subtest(test) {
+            let status = Status::error(err.to_string());
+            if let Some(connect) = &mut subtest.connect {
+                connect.set_status(status.clone());
+            }
+            subtest.cx.span().set_status(status);
+        }
+        Ok(())
+    }
+
+    fn on_server_closed(&mut self, test: TestKind, code: u16, reason: &str) -> Result<()> {
+        self.on_error(
+            test,
+            &format!("server closed connection (code {code}): {reason}"),
+        )
+    }
+
+    fn on_server_unhealthy(&mut self, test: TestKind, fqdn: &str, reason: &str) -> Result<()> {
+        if let Some(Subtest {
+            connect: Some(connect),
+            ..
+        }) = self.subtest(test)
+        {
+            connect.add_event(
+                "server unhealthy",
+                vec![
+                    KeyValue::new("server.address", fqdn.to_string()),
+                    KeyValue::new("reason", reason.to_string()),
+                ],
+            );
+        }
+        Ok(())
+    }
+
+    fn on_connected(&mut self, test: TestKind, fqdn: &str, info: &ConnectInfo) -> Result<()> {
+        if let Some(subtest) = self.subtest(test) {
+            if let Some(mut connect) = subtest.connect.take() {
+                connect.end();
+            }
+            let span = subtest.cx.span();
+            span.set_attribute(KeyValue::new("server.address", fqdn.to_string()));
+            span.set_attribute(KeyValue::new(
+                "http.response.status_code",
+                info.status as i64,
+            ));
+        }
+        Ok(())
+    }
+
+    fn on_download_event(&mut self, _m: &Measurement) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_upload_event(&mut self, _m: &Measurement) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_complete(&mut self, test: TestKind) -> Result<()> {
+        self.end(test);
+        Ok(())
+    }
+
+    fn on_summary(&mut self, s: &Summary) -> Result<()> {
+        if let Some(download) = &s.download {
+            self.record(TestKind::Download, download);
+        }
+        if let Some(upload) = &s.upload {
+            self.record(TestKind::Upload, upload);
+        }
+        Ok(())
+    }
+
+    fn on_ping(&mut self, p: &PingResult) -> Result<()> {
+        if let Some(rtt) = p.best_rtt_ms() {
+            self.ping_rtt.record(rtt, &[]);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_sdk::trace::{InMemorySpanExporter, SdkTracerProvider};
+
+    use super::*;
+
+    #[test]
+    fn subtest_spans() {
+        let exporter = InMemorySpanExporter::default();
+        let provider = SdkTracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let tracer = BoxedTracer::new(Box::new(provider.tracer(SCOPE)));
+        let mut emitter = OtelEmitter::with_providers(tracer, &global::meter(SCOPE));
+
+        emitter.on_starting(TestKind::Download).unwrap();
+        emitter
+            .on_connected(TestKind::Download, "mlab1-lga06", &ConnectInfo::default())
+            .unwrap();
+        emitter.on_error(TestKind::Download, "stalled").unwrap();
+        emitter.on_complete(TestKind::Download).unwrap();
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let names: Vec<_> = spans.iter().map(|s| s.name.as_ref()).collect();
+        assert_eq!(names, ["connect", "ndt7.download"]);
+        assert_eq!(spans[0].parent_span_id, spans[1].span_context.span_id());
+        assert_eq!(spans[1].status, Status::error("stalled"));
+    }
+}
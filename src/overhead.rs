@@ -0,0 +1,119 @@
+//! Estimates of protocol overhead on the wire.
+//!
+//! The ndt7 `NumBytes` counters cover WebSocket message payloads only, so
+//! they report goodput. Each message also costs a WebSocket frame header,
+//! TLS record headers and authentication tags on `wss` connections, and
+//! TCP/IP headers for every segment. [`Framing`] estimates these from the
+//! message sizes, which gives the throughput seen on the link.
+//!
+//! The estimate assumes TLS 1.3 records and full-sized segments on a
+//! 1500-byte MTU with TCP timestamps. It ignores link-layer framing,
+//! acknowledgements and retransmissions.
+
+use crate::client::Transport;
+use crate::spec::TestKind;
+
+/// Largest TLS record plaintext (16 KiB).
+pub const TLS_RECORD_SIZE: usize = 1 << 14;
+
+/// Bytes added to each TLS 1.3 record: 5 header bytes, the inner content
+/// type and a 16-byte AEAD tag.
+pub const TLS_RECORD_OVERHEAD: usize = 22;
+
+/// Assumed path MTU.
+pub const MTU: usize = 1500;
+
+/// IPv4 and TCP headers, including the timestamp option.
+pub const IPV4_TCP_HEADER: usize = 52;
+
+/// IPv6 and TCP headers, including the timestamp option.
+pub const IPV6_TCP_HEADER: usize = 72;
+
+/// How messages of one test connection are carried.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Framing {
+    /// Whether the connection uses TLS.
+    pub tls: bool,
+    /// Whether frames are masked, which is the case for client-to-server
+    /// frames.
+    pub masked: bool,
+    /// Whether the connection runs over IPv6.
+    pub ipv6: bool,
+}
+
+impl Framing {
+    /// Framing of the messages `test` sends over `ws`: the download test
+    /// receives unmasked frames, the upload test sends masked ones.
+    pub fn new(ws: &impl Transport, test: TestKind) -> Framing {
+        Framing {
+            tls: ws.is_tls(),
+            masked: test == TestKind::Upload,
+            ipv6: ws
+                .tcp_stream()
+                .and_then(|tcp| tcp.peer_addr().ok())
+                .is_some_and(|addr| addr.is_ipv6()),
+        }
+    }
+
+    /// Bytes a message with `payload` bytes occupies in the TCP stream.
+    pub fn stream_bytes(&self, payload: usize) -> u64 {
+        let header = match payload {
+            0..126 => 2,
+            126..=0xffff => 4,
+            _ => 10,
+        } + if self.masked { 4 } else { 0 };
+        let frame = payload + header;
+        let records = if self.tls {
+            frame.div_ceil(TLS_RECORD_SIZE) * TLS_RECORD_OVERHEAD
+        } else {
+            0
+        };
+        (frame + records) as u64
+    }
+
+    /// Bytes on the wire, including TCP/IP headers, for `stream_bytes` bytes
+    /// of TCP payload.
+    pub fn wire_bytes(&self, stream_bytes: u64) -> u64 {
+        let header = if self.ipv6 {
+            IPV6_TCP_HEADER
+        } else {
+            IPV4_TCP_HEADER
+        };
+        let mss = (MTU - header) as u64;
+        stream_bytes + stream_bytes.div_ceil(mss) * header as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_overhead() {
+        let plain = Framing::default();
+        assert_eq!(plain.stream_bytes(100), 102);
+        assert_eq!(plain.stream_bytes(1 << 13), (1 << 13) + 4);
+        assert_eq!(plain.stream_bytes(1 << 20), (1 << 20) + 10);
+
+        let tls_masked = Framing {
+            tls: true,
+            masked: true,
+            ipv6: false,
+        };
+        // 1 MiB + 14 header bytes spans 65 records.
+        assert_eq!(tls_masked.stream_bytes(1 << 20), (1 << 20) + 14 + 65 * 22);
+    }
+
+    #[test]
+    fn tcp_ip_overhead() {
+        let v4 = Framing::default();
+        assert_eq!(v4.wire_bytes(0), 0);
+        assert_eq!(v4.wire_bytes(1448), 1500);
+        assert_eq!(v4.wire_bytes(1449), 1449 + 2 * 52);
+        let v6 = Framing {
+            ipv6: true,
+            ..Default::default()
+        };
+        assert_eq!(v6.wire_bytes(1428 * 10), 15000);
+    }
+}
@@ -1,4 +1,8 @@
 //! Protocol constants and tuning parameters.
+//!
+//! Every timer and timestamp in the test loops goes through [`tokio::time`],
+//! so tests can pause the clock (`#[tokio::test(start_paused = true)]`) and
+//! step through the intervals and timeouts below deterministically.
 
 use std::time::Duration;
 
@@ -11,24 +15,137 @@ pub const DOWNLOAD_URL_PATH: &str = "/ndt/v7/download";
 /// URL path for the upload test.
 pub const UPLOAD_URL_PATH: &str = "/ndt/v7/upload";
 
-/// Initial size of uploaded messages (8 KiB).
+/// Default initial size of uploaded messages (8 KiB).
 pub const INITIAL_MESSAGE_SIZE: usize = 1 << 13;
 
-/// Maximum accepted message size (1 MiB).
+/// Maximum accepted message size (1 MiB), and the default limit on
+/// uploaded messages.
 pub const MAX_MESSAGE_SIZE: usize = 1 << 20;
 
-/// Threshold for scaling binary messages. When the current message size is
-/// <= 1/SCALING_FRACTION of the total bytes sent, the message size doubles.
+/// Default threshold for scaling binary messages. When the current message
+/// size is <= 1/SCALING_FRACTION of the total bytes sent, the message size
+/// doubles.
 pub const SCALING_FRACTION: usize = 16;
 
-/// Time after which the download test must stop.
+/// Default time after which the download test must stop.
 pub const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(15);
 
-/// Time after which the upload test must stop.
+/// Default time after which the upload test must stop.
 pub const UPLOAD_TIMEOUT: Duration = Duration::from_secs(10);
 
-/// Timeout for individual I/O operations.
+/// Timeout for individual I/O operations, and the default for establishing
+/// the connection of a test.
 pub const IO_TIMEOUT: Duration = Duration::from_secs(7);
 
-/// Interval between client-side measurement updates.
+/// Default interval between client-side measurement updates.
 pub const UPDATE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Time after which the latency probe must stop.
+pub const PING_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Time after which a server that did not accept a TCP connection is
+/// skipped when probing for the fastest server or checking its health.
+pub const TARGET_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Number of WebSocket ping/pong round trips sampled by the latency probe.
+pub const PING_COUNT: usize = 3;
+
+/// Receive buffer size used by the latency probe (16 KiB). Keeps the amount
+/// of download data the server can push during the probe small.
+pub const PING_RECV_BUFFER_SIZE: u32 = 1 << 14;
+
+/// Per-test settings: limits applied on top of the protocol and the upload
+/// message sizing.
+///
+/// The default imposes no limits and sizes messages like the reference
+/// client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TestParams {
+    /// Stop the test once this many bytes of payload have been transferred.
+    pub max_bytes: Option<u64>,
+    /// Pace the upload to at most this many bytes per second.
+    pub upload_rate: Option<u64>,
+    /// Sleep this long after each message read during the download, which
+    /// throttles the transfer through TCP flow control.
+    pub download_read_delay: Option<Duration>,
+    /// Size of the first uploaded message, capped at `max_message_size`.
+    /// Defaults to [`INITIAL_MESSAGE_SIZE`].
+    pub initial_message_size: usize,
+    /// Largest uploaded message, at most [`MAX_MESSAGE_SIZE`], which is the
+    /// default. The upload corpus is allocated at this size.
+    pub max_message_size: usize,
+    /// Uploaded messages double in size while they are at most
+    /// 1/`scaling_fraction` of the bytes sent so far. Defaults to
+    /// [`SCALING_FRACTION`].
+    pub scaling_fraction: usize,
+    /// When client-side measurements are generated. Defaults to every
+    /// [`UPDATE_INTERVAL`].
+    pub measurement_interval: MeasurementInterval,
+    /// Also count estimated framing and TCP/IP overhead in client
+    /// measurements, reported as [`AppInfo::wire_bytes`](crate::spec::AppInfo::wire_bytes).
+    pub wire_overhead: bool,
+    /// Send a WebSocket ping this often during a test and report each round
+    /// trip as a client measurement with
+    /// [`WSPingInfo`](crate::spec::WSPingInfo), which samples latency under
+    /// load. Off by default.
+    pub ws_ping_interval: Option<Duration>,
+    /// Fail the test on a server measurement that is not valid JSON of the
+    /// expected shape. By default such measurements are skipped and listed
+    /// in [`TestHandle::skipped`](crate::client::TestHandle::skipped);
+    /// strict parsing is meant for conformance testing.
+    pub strict_parsing: bool,
+    /// Stamp client measurements with the wall-clock time in
+    /// [`Measurement::utc_time`](crate::spec::Measurement::utc_time), for
+    /// correlating them with server logs or packet captures. Off by default,
+    /// which leaves the field out of serialized measurements.
+    pub utc_timestamps: bool,
+    /// Time after which the download test stops. Defaults to
+    /// [`DOWNLOAD_TIMEOUT`]. Servers end the download on their own after
+    /// about 10 seconds, so a longer duration only pays off with servers
+    /// configured for longer tests.
+    pub download_duration: Duration,
+    /// Time after which the upload test stops. Defaults to
+    /// [`UPLOAD_TIMEOUT`].
+    pub upload_duration: Duration,
+    /// Time allowed to establish the WebSocket connection of a test,
+    /// including the TLS and HTTP upgrade handshakes. Defaults to
+    /// [`IO_TIMEOUT`].
+    pub connect_timeout: Duration,
+}
+
+impl Default for TestParams {
+    fn default() -> Self {
+        TestParams {
+            max_bytes: None,
+            upload_rate: None,
+            download_read_delay: None,
+            initial_message_size: INITIAL_MESSAGE_SIZE,
+            max_message_size: MAX_MESSAGE_SIZE,
+            scaling_fraction: SCALING_FRACTION,
+            measurement_interval: MeasurementInterval::Fixed(UPDATE_INTERVAL),
+            wire_overhead: false,
+            ws_ping_interval: None,
+            strict_parsing: false,
+            utc_timestamps: false,
+            download_duration: DOWNLOAD_TIMEOUT,
+            upload_duration: UPLOAD_TIMEOUT,
+            connect_timeout: IO_TIMEOUT,
+        }
+    }
+}
+
+/// Spacing of client-side measurements during a test.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MeasurementInterval {
+    /// One measurement per interval.
+    Fixed(Duration),
+    /// Start at `min` while throughput ramps up, then double the interval
+    /// up to `max` for as long as the average throughput stays stable. Any
+    /// change in throughput falls back to `min`.
+    Adaptive {
+        /// Interval at the start of the test and after changes.
+        min: Duration,
+        /// Interval once throughput has stabilized.
+        max: Duration,
+    },
+}
@@ -0,0 +1,135 @@
+//! ndt7 latency probe.
+//!
+//! Connects to the download endpoint but, instead of measuring throughput,
+//! samples WebSocket ping/pong round trips and the server's kernel `MinRTT`
+//! for at most [`params::PING_TIMEOUT`], then closes the connection. It is a
+//! cheap reachability and latency check to run between full tests.
+
+use bytes::Bytes;
+use futures_util::{SinkExt, StreamExt};
+use serde::Serialize;
+use tokio::time::{Instant, timeout};
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::client::{WsStream, io_timeout};
+use crate::error::{Ndt7Error, Result};
+use crate::params;
+use crate::spec::Measurement;
+
+/// Result of a latency probe.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct PingResult {
+    /// FQDN of the server that was probed.
+    #[serde(rename = "ServerFQDN")]
+    pub server_fqdn: String,
+    /// Time to establish the TCP, TLS and WebSocket connection, in milliseconds.
+    pub connect_ms: f64,
+    /// WebSocket ping/pong round-trip times in milliseconds.
+    #[serde(rename = "RTTMs")]
+    pub rtt_ms: Vec<f64>,
+    /// Minimum RTT in milliseconds reported by the server's TCPInfo, if the
+    /// server sent a measurement before the probe ended.
+    #[serde(rename = "MinRTTMs")]
+    pub min_rtt_ms: Option<f64>,
+    /// Bytes received from the server during the probe.
+    pub bytes_received: i64,
+}
+
+impl PingResult {
+    /// Smallest ping/pong round-trip time, if any sample was taken.
+    pub fn best_rtt_ms(&self) -> Option<f64> {
+        self.rtt_ms.iter().copied().reduce(f64::min)
+    }
+}
+
+/// Run the latency probe on an established download WebSocket connection.
+///
+/// Reaching [`params::PING_TIMEOUT`] is not an error: whatever samples were
+/// collected so far are returned.
+pub async fn run(mut ws: WsStream) -> Result<PingResult> {
+    let mut result = PingResult::default();
+    // Overall timeout (Err) is normal completion.
+    if let Ok(Err(e)) = timeout(params::PING_TIMEOUT, ping_loop(&mut ws, &mut result)).await {
+        return Err(e);
+    }
+    let _ = timeout(params::IO_TIMEOUT, ws.close(None)).await;
+    Ok(result)
+}
+
+async fn ping_loop(ws: &mut WsStream, result: &mut PingResult) -> Result<()> {
+    let mut seq: u64 = 0;
+    let mut sent_at = Instant::now();
+    ws.send(Message::Ping(Bytes::from(seq.to_be_bytes().to_vec())))
+        .await?;
+
+    loop {
+        let msg = io_timeout(ws.next()).await?;
+        let Some(msg) = msg else { break };
+        match msg? {
+            Message::Binary(data) => result.bytes_received += data.len() as i64,
+            Message::Text(text) => {
+                result.bytes_received += text.len() as i64;
+                let measurement: Measurement = serde_json::from_str(&text)?;
+                if let Some(min_rtt) = measurement.tcp_info.and_then(|t| t.min_rtt) {
+                    result.min_rtt_ms = Some(min_rtt.as_millis_f64());
+                }
+            }
+            Message::Pong(payload) if payload.as_ref() == seq.to_be_bytes() => {
+                result.rtt_ms.push(sent_at.elapsed().as_secs_f64() * 1000.0);
+                if result.rtt_ms.len() < params::PING_COUNT {
+                    seq += 1;
+                    sent_at = Instant::now();
+                    ws.send(Message::Ping(Bytes::from(seq.to_be_bytes().to_vec())))
+                        .await?;
+                }
+            }
+            Message::Close(frame) => {
+                Ndt7Error::check_close(frame)?;
+                break;
+            }
+            _ => {}
+        }
+        if result.rtt_ms.len() >= params::PING_COUNT && result.min_rtt_ms.is_some() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    async fn mock_server() -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            ws.send(Message::Text(r#"{"TCPInfo":{"MinRTT":4000}}"#.into()))
+                .await
+                .unwrap();
+            // Reading drives the automatic pong replies.
+            while let Some(Ok(_)) = ws.next().await {}
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_ping_samples() {
+        let addr = mock_server().await;
+        let (ws_stream, _response) = tokio_tungstenite::connect_async(format!("ws://{addr}"))
+            .await
+            .unwrap();
+
+        let result = run(ws_stream).await.unwrap();
+
+        assert_eq!(result.rtt_ms.len(), params::PING_COUNT);
+        assert_eq!(result.min_rtt_ms, Some(4.0));
+        assert!(result.best_rtt_ms().is_some());
+    }
+}
@@ -0,0 +1,255 @@
+//! Test events streamed live over WebSocket.
+//!
+//! A browser dashboard cannot read the output of a probe running headless.
+//! [`serve`] accepts WebSocket connections and streams every event to each
+//! connected client as it happens, as the JSON text messages
+//! [`JsonEmitter`](crate::emitter::JsonEmitter) would print; [`connect`]
+//! pushes the same messages to a WebSocket server instead, e.g. a dashboard
+//! collecting from many probes.
+//!
+//! Messages are sent by background tasks, so a slow client does not hold up
+//! the tests. A client that falls more than [`BUFFERED_EVENTS`] behind skips
+//! the events it missed.
+
+use std::net::SocketAddr;
+
+use futures_util::SinkExt;
+use tokio::net::{TcpListener, ToSocketAddrs};
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::client::ConnectInfo;
+use crate::emitter::{Emitter, Progress, TestEvent};
+use crate::error::Result;
+use crate::ping::PingResult;
+use crate::spec::{Measurement, TestKind};
+use crate::summary::Summary;
+use crate::sweep::SweepReport;
+
+/// Events buffered for each client.
+pub const BUFFERED_EVENTS: usize = 1024;
+
+/// Accept WebSocket connections on `addr` and stream events to every
+/// connected client. Clients receive the events from the time they
+/// connected.
+pub async fn serve(addr: impl ToSocketAddrs) -> Result<(RelayEmitter, Relay)> {
+    let listener = TcpListener::bind(addr).await?;
+    let local_addr = listener.local_addr()?;
+    let (tx, _) = broadcast::channel(BUFFERED_EVENTS);
+    let (done, finished) = mpsc::channel(1);
+    let accept = tokio::spawn({
+        let tx = tx.clone();
+        async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                // Subscribe before the handshake, so a client sees every
+                // event sent after it connected.
+                let rx = tx.subscribe();
+                let done = done.clone();
+                tokio::spawn(async move {
+                    if let Ok(ws) = tokio_tungstenite::accept_async(stream).await {
+                        forward(ws, rx).await;
+                    }
+                    drop(done);
+                });
+            }
+        }
+    });
+    let relay = Relay {
+        accept: Some(accept),
+        local_addr: Some(local_addr),
+        finished,
+    };
+    Ok((RelayEmitter { tx }, relay))
+}
+
+/// Connect to the WebSocket server at `url`, e.g. `ws://dashboard:9000/probe`,
+/// and stream events to it.
+pub async fn connect(url: &str) -> Result<(RelayEmitter, Relay)> {
+    let (ws, _) = tokio_tungstenite::connect_async(url).await?;
+    let (tx, rx) = broadcast::channel(BUFFERED_EVENTS);
+    let (done, finished) = mpsc::channel(1);
+    tokio::spawn(async move {
+        forward(ws, rx).await;
+        drop(done);
+    });
+    let relay = Relay {
+        accept: None,
+        local_addr: None,
+        finished,
+    };
+    Ok((RelayEmitter { tx }, relay))
+}
+
+/// Send the events from `rx` to `ws` until the emitter is dropped or the
+/// peer goes away.
+async fn forward<S>(
+    mut ws: tokio_tungstenite::WebSocketStream<S>,
+    mut rx: broadcast::Receiver<String>,
+) where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    loop {
+        let json = match rx.recv().await {
+            Ok(json) => json,
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        if ws.send(Message::Text(json.into())).await.is_err() {
+            return;
+        }
+    }
+    let _ = ws.close(None).await;
+}
+
+/// Streams test events to WebSocket clients, see [`serve`] and [`connect`].
+///
+/// Events are queued and never block.
+pub struct RelayEmitter {
+    tx: broadcast::Sender<String>,
+}
+
+impl RelayEmitter {
+    fn send(&self, event: &TestEvent) -> Result<()> {
+        // Without connected clients the event is dropped.
+        let _ = self.tx.send(serde_json::to_string(event)?);
+        Ok(())
+    }
+}
+
+/// Background tasks of a [`RelayEmitter`].
+pub struct Relay {
+    accept: Option<JoinHandle<()>>,
+    local_addr: Option<SocketAddr>,
+    finished: mpsc::Receiver<()>,
+}
+
+impl Relay {
+    /// Address clients connect to, for a relay started with [`serve`].
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    /// Stop accepting clients and wait until the events queued for the
+    /// connected ones were sent, after the emitter has been dropped.
+    pub async fn finish(mut self) {
+        if let Some(accept) = self.accept.take() {
+            accept.abort();
+            let _ = accept.await;
+        }
+        // Completes once every task holding a sender ended.
+        self.finished.recv().await;
+    }
+}
+
+impl Emitter for RelayEmitter {
+    fn on_starting(&mut self, test: TestKind) -> Result<()> {
+        self.send(&TestEvent::Starting { test })
+    }
+
+    fn on_error(&mut self, test: TestKind, error: &str) -> Result<()> {
+        self.send(&TestEvent::Error { test, error })
+    }
+
+    fn on_server_closed(&mut self, test: TestKind, code: u16, reason: &str) -> Result<()> {
+        self.send(&TestEvent::ServerClosed { test, code, reason })
+    }
+
+    fn on_server_unhealthy(&mut self, test: TestKind, fqdn: &str, reason: &str) -> Result<()> {
+        self.send(&TestEvent::ServerUnhealthy { test, fqdn, reason })
+    }
+
+    fn on_connected(&mut self, test: TestKind, fqdn: &str, info: &ConnectInfo) -> Result<()> {
+        self.send(&TestEvent::Connected {
+            test,
+            fqdn,
+            connect_info: info,
+        })
+    }
+
+    fn on_download_event(&mut self, m: &Measurement) -> Result<()> {
+        self.send(&TestEvent::Measurement {
+            test: TestKind::Download,
+            measurement: m,
+        })
+    }
+
+    fn on_upload_event(&mut self, m: &Measurement) -> Result<()> {
+        self.send(&TestEvent::Measurement {
+            test: TestKind::Upload,
+            measurement: m,
+        })
+    }
+
+    fn on_progress(&mut self, test: TestKind, progress: &Progress) -> Result<()> {
+        self.send(&TestEvent::Progress { test, progress })
+    }
+
+    fn on_complete(&mut self, test: TestKind) -> Result<()> {
+        self.send(&TestEvent::Complete { test })
+    }
+
+    fn on_summary(&mut self, summary: &Summary) -> Result<()> {
+        self.send(&TestEvent::Summary { summary })
+    }
+
+    fn on_ping(&mut self, ping: &PingResult) -> Result<()> {
+        self.send(&TestEvent::Ping { ping })
+    }
+
+    fn on_sweep(&mut self, report: &SweepReport) -> Result<()> {
+        self.send(&TestEvent::Sweep { sweep: report })
+    }
+
+    fn on_warning(&mut self, warning: &str) -> Result<()> {
+        self.send(&TestEvent::Warning { warning })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn serve_streams_events() {
+        let (mut emitter, relay) = serve("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", relay.local_addr().unwrap());
+        let (mut ws, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+
+        emitter.on_starting(TestKind::Download).unwrap();
+        emitter.on_warning("low buffers").unwrap();
+        drop(emitter);
+        relay.finish().await;
+
+        let mut types = Vec::new();
+        while let Some(Ok(Message::Text(text))) = ws.next().await {
+            let value: serde_json::Value = serde_json::from_str(&text).unwrap();
+            types.push(value["Type"].as_str().unwrap().to_string());
+        }
+        assert_eq!(types, ["Starting", "Warning"]);
+    }
+
+    #[tokio::test]
+    async fn connect_streams_events() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            ws.next().await.unwrap().unwrap()
+        });
+
+        let (mut emitter, relay) = connect(&url).await.unwrap();
+        emitter.on_complete(TestKind::Upload).unwrap();
+        drop(emitter);
+        relay.finish().await;
+
+        let message = server.await.unwrap();
+        assert_eq!(
+            message.into_text().unwrap().as_str(),
+            r#"{"Type":"Complete","Test":"upload"}"#
+        );
+    }
+}
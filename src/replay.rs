@@ -0,0 +1,396 @@
+//! Record and replay of test event streams.
+//!
+//! [`Recorder::tee`] wraps a [`TestHandle`] and writes every item of its
+//! channel to a JSONL file as it passes through. [`Recording`] loads such a
+//! file and produces handles that yield the same items again, so emitters
+//! and summary computation can be developed and regression-tested without
+//! connecting to a server.
+//!
+//! Errors are replayed with their message and [`ErrorKind`];
+//! [`Ndt7Error::ServerClosed`] and [`Ndt7Error::TestDeadline`] keep their
+//! variant since consumers act on them. Skipped server measurements are
+//! recorded like errors and replayed into [`TestHandle::skipped`].
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+use crate::client::{ConnectInfo, SkippedMeasurements, TestHandle};
+use crate::error::{ErrorKind, Ndt7Error, Result};
+use crate::locate::Location;
+use crate::spec::{Measurement, TestKind};
+
+/// One line of a recording.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "Type", rename_all_fields = "PascalCase")]
+pub enum Record {
+    /// A test started.
+    Start {
+        /// The test.
+        test: TestKind,
+        /// FQDN of the server.
+        #[serde(rename = "ServerFQDN")]
+        server_fqdn: String,
+        /// Location of the server, if known.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        server_location: Option<Location>,
+        /// The server's upgrade response.
+        connect_info: ConnectInfo,
+        /// Planned test duration in microseconds.
+        duration: u64,
+    },
+    /// A measurement arrived.
+    Measurement {
+        /// The test.
+        test: TestKind,
+        /// Microseconds since the start of the test.
+        offset: u64,
+        /// The measurement.
+        measurement: Box<Measurement>,
+    },
+    /// The test failed.
+    Error {
+        /// The test.
+        test: TestKind,
+        /// Microseconds since the start of the test.
+        offset: u64,
+        /// The error.
+        error: RecordedError,
+    },
+}
+
+/// An [`Ndt7Error`] in a recording.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all_fields = "PascalCase")]
+pub enum RecordedError {
+    /// [`Ndt7Error::ServerClosed`].
+    ServerClosed {
+        /// WebSocket close code.
+        code: u16,
+        /// Close reason.
+        reason: String,
+    },
+    /// [`Ndt7Error::TestDeadline`].
+    Deadline {
+        /// Time since the deadline clock started, in microseconds.
+        elapsed: u64,
+    },
+    /// A server measurement that was skipped, see
+    /// [`TestHandle::skipped`].
+    MalformedMeasurement {
+        /// Parse error message.
+        message: String,
+    },
+    /// Any other error.
+    Other {
+        /// Classification of the error.
+        kind: ErrorKind,
+        /// Error message.
+        message: String,
+    },
+}
+
+impl From<&Ndt7Error> for RecordedError {
+    fn from(e: &Ndt7Error) -> Self {
+        match e {
+            Ndt7Error::ServerClosed { code, reason } => RecordedError::ServerClosed {
+                code: *code,
+                reason: reason.clone(),
+            },
+            Ndt7Error::TestDeadline { elapsed } => RecordedError::Deadline {
+                elapsed: elapsed.as_micros() as u64,
+            },
+            e => RecordedError::Other {
+                kind: e.kind(),
+                message: e.to_string(),
+            },
+        }
+    }
+}
+
+impl From<RecordedError> for Ndt7Error {
+    fn from(e: RecordedError) -> Self {
+        match e {
+            RecordedError::ServerClosed { code, reason } => {
+                Ndt7Error::ServerClosed { code, reason }
+            }
+            RecordedError::Deadline { elapsed } => Ndt7Error::TestDeadline {
+                elapsed: Duration::from_micros(elapsed),
+            },
+            RecordedError::MalformedMeasurement { message } => Ndt7Error::Replayed {
+                kind: ErrorKind::Protocol,
+                message,
+            },
+            RecordedError::Other { kind, message } => Ndt7Error::Replayed { kind, message },
+        }
+    }
+}
+
+/// Writes the event streams of tests to a JSONL file.
+///
+/// Cheap to clone; clones write to the same output.
+#[derive(Clone)]
+pub struct Recorder {
+    out: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl Recorder {
+    /// Record to a new file at `path`, replacing an existing one.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Recorder> {
+        Ok(Recorder::new(io::BufWriter::new(File::create(path)?)))
+    }
+
+    /// Record to `out`.
+    pub fn new(out: impl Write + Send + 'static) -> Recorder {
+        Recorder {
+            out: Arc::new(Mutex::new(Box::new(out))),
+        }
+    }
+
+    /// Record the test behind `handle`, returning a handle that yields the
+    /// same items. The recording is flushed when the test ends.
+    pub fn tee(&self, mut handle: TestHandle, test: TestKind) -> TestHandle {
+        self.write(&Record::Start {
+            test,
+            server_fqdn: handle.server_fqdn.clone(),
+            server_location: handle.server_location.clone(),
+            connect_info: handle.connect_info.clone(),
+            duration: handle.duration.as_micros() as u64,
+        });
+        let (tx, rx) = mpsc::channel(handle.rx.max_capacity());
+        let recorder = self.clone();
+        let skipped = handle.skipped.clone();
+        let start = Instant::now();
+        tokio::spawn(async move {
+            let mut recorded = 0;
+            while let Some(item) = handle.rx.recv().await {
+                let offset = start.elapsed().as_micros() as u64;
+                recorder.write_skipped(test, offset, &skipped, &mut recorded);
+                recorder.write(&match &item {
+                    Ok(m) => Record::Measurement {
+                        test,
+                        offset,
+                        measurement: Box::new(m.clone()),
+                    },
+                    Err(e) => Record::Error {
+                        test,
+                        offset,
+                        error: e.into(),
+                    },
+                });
+                if tx.send(item).await.is_err() {
+                    break;
+                }
+            }
+            let offset = start.elapsed().as_micros() as u64;
+            recorder.write_skipped(test, offset, &skipped, &mut recorded);
+            recorder.flush();
+        });
+        TestHandle { rx, ..handle }
+    }
+
+    /// Record the measurements in `skipped` after the first `recorded`,
+    /// counting them in `recorded`.
+    fn write_skipped(
+        &self,
+        test: TestKind,
+        offset: u64,
+        skipped: &SkippedMeasurements,
+        recorded: &mut usize,
+    ) {
+        for message in skipped.errors().into_iter().skip(*recorded) {
+            *recorded += 1;
+            self.write(&Record::Error {
+                test,
+                offset,
+                error: RecordedError::MalformedMeasurement { message },
+            });
+        }
+    }
+
+    fn write(&self, record: &Record) {
+        let mut out = self.out.lock().unwrap_or_else(|e| e.into_inner());
+        if serde_json::to_writer(&mut *out, record).is_ok() {
+            let _ = out.write_all(b"\n");
+        }
+    }
+
+    fn flush(&self) {
+        let _ = self.out.lock().unwrap_or_else(|e| e.into_inner()).flush();
+    }
+}
+
+impl std::fmt::Debug for Recorder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Recorder").finish_non_exhaustive()
+    }
+}
+
+/// A loaded recording.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Recording {
+    /// The records, in the order they were written.
+    pub records: Vec<Record>,
+}
+
+impl Recording {
+    /// Load a recording from the file at `path`.
+    pub fn open(path: impl AsRef<Path>) -> Result<Recording> {
+        Recording::load(BufReader::new(File::open(path)?))
+    }
+
+    /// Load a recording, one JSON record per line. Blank lines are skipped.
+    pub fn load(reader: impl BufRead) -> Result<Recording> {
+        let mut records = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                records.push(serde_json::from_str(&line)?);
+            }
+        }
+        Ok(Recording { records })
+    }
+
+    /// Replay the first recorded run of `test`, or `None` if the recording
+    /// has none. Items are delivered as fast as they are consumed.
+    pub fn handle(&self, test: TestKind) -> Option<TestHandle> {
+        let (start, server_fqdn, server_location, connect_info, duration) =
+            self.records.iter().enumerate().find_map(|(i, r)| match r {
+                Record::Start {
+                    test: t,
+                    server_fqdn,
+                    server_location,
+                    connect_info,
+                    duration,
+                } if *t == test => Some((
+                    i,
+                    server_fqdn.clone(),
+                    server_location.clone(),
+                    connect_info.clone(),
+                    *duration,
+                )),
+                _ => None,
+            })?;
+        let skipped = SkippedMeasurements::default();
+        let items: Vec<Result<Measurement>> = self.records[start + 1..]
+            .iter()
+            .take_while(|r| !matches!(r, Record::Start { test: t, .. } if *t == test))
+            .filter_map(|r| match r {
+                Record::Measurement {
+                    test: t,
+                    measurement,
+                    ..
+                } if *t == test => Some(Ok(Measurement::clone(measurement))),
+                Record::Error {
+                    test: t,
+                    error: RecordedError::MalformedMeasurement { message },
+                    ..
+                } if *t == test => {
+                    skipped.push(message.clone());
+                    None
+                }
+                Record::Error { test: t, error, .. } if *t == test => {
+                    Some(Err(error.clone().into()))
+                }
+                _ => None,
+            })
+            .collect();
+
+        let (tx, rx) = mpsc::channel(64);
+        tokio::spawn(async move {
+            for item in items {
+                if tx.send(item).await.is_err() {
+                    break;
+                }
+            }
+        });
+        Some(TestHandle {
+            server_fqdn,
+            server_location,
+            connect_info,
+            unhealthy: Vec::new(),
+            duration: Duration::from_micros(duration),
+            rx,
+            skipped,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spec::{AppInfo, ByteCount, Micros, Origin};
+    use crate::test_util::SharedBuf;
+
+    #[tokio::test]
+    async fn record_and_replay() {
+        let measurement = Measurement {
+            app_info: Some(AppInfo {
+                elapsed_time: Micros(250_000),
+                num_bytes: ByteCount(1 << 20),
+                ..Default::default()
+            }),
+            origin: Some(Origin::Client),
+            test: Some(TestKind::Download),
+            ..Default::default()
+        };
+        let (tx, rx) = mpsc::channel(8);
+        tx.send(Ok(measurement.clone())).await.unwrap();
+        tx.send(Err(Ndt7Error::ServerClosed {
+            code: 1011,
+            reason: "oops".into(),
+        }))
+        .await
+        .unwrap();
+        tx.send(Err(Ndt7Error::StallTimeout {
+            elapsed: Duration::from_secs(7),
+        }))
+        .await
+        .unwrap();
+        drop(tx);
+        let handle = TestHandle {
+            server_fqdn: "mlab1-lga06".into(),
+            server_location: None,
+            connect_info: ConnectInfo::default(),
+            unhealthy: Vec::new(),
+            duration: Duration::from_secs(10),
+            rx,
+            skipped: SkippedMeasurements::default(),
+        };
+        handle.skipped.push("invalid type".into());
+
+        let buf = SharedBuf::default();
+        let recorder = Recorder::new(buf.clone());
+        let mut tee = recorder.tee(handle, TestKind::Download);
+        let mut passed = Vec::new();
+        while let Some(item) = tee.rx.recv().await {
+            passed.push(item);
+        }
+        assert_eq!(passed.len(), 3);
+
+        let data = buf.0.lock().unwrap().clone();
+        let recording = Recording::load(&data[..]).unwrap();
+        assert!(recording.handle(TestKind::Upload).is_none());
+        let mut replay = recording.handle(TestKind::Download).unwrap();
+        assert_eq!(replay.server_fqdn, "mlab1-lga06");
+        assert_eq!(replay.duration, Duration::from_secs(10));
+        assert_eq!(replay.skipped.errors(), ["invalid type"]);
+        assert_eq!(replay.rx.recv().await.unwrap().unwrap(), measurement);
+        assert!(matches!(
+            replay.rx.recv().await,
+            Some(Err(Ndt7Error::ServerClosed { code: 1011, .. }))
+        ));
+        let Some(Err(e)) = replay.rx.recv().await else {
+            panic!("expected an error");
+        };
+        assert_eq!(e.kind(), ErrorKind::Network);
+        assert!(e.to_string().contains("stalled"));
+        assert!(replay.rx.recv().await.is_none());
+    }
+}
@@ -0,0 +1,231 @@
+//! JSON events appended to a file that is rotated by size or date.
+//!
+//! [`FileEmitter`] writes every event as the line
+//! [`JsonEmitter`](crate::emitter::JsonEmitter) would print, to a file that
+//! survives restarts. Once the file grows past a size limit or a UTC day
+//! ends, it is renamed next to itself with a timestamp suffix and a new one
+//! started, so long-running monitors need no external log rotation. The
+//! rename is atomic: readers see either the old file complete or the new
+//! one, never a truncated file.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::client::ConnectInfo;
+use crate::emitter::{Emitter, Progress, TestEvent};
+use crate::error::Result;
+use crate::ping::PingResult;
+use crate::spec::{Measurement, TestKind, utc_timestamp};
+use crate::summary::Summary;
+use crate::sweep::SweepReport;
+
+/// Appends JSON events to a file, rotating it by size, date or both.
+///
+/// Events are never split across files. Rotated files are named
+/// `<path>.<date>` for daily rotation, with the day their events were
+/// written, and `<path>.<timestamp>` for rotation by size, with the time of
+/// the rotation in UTC.
+pub struct FileEmitter {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    /// UTC day, counted from the Unix epoch, of the events in the file.
+    day: u64,
+    max_size: Option<u64>,
+    daily: bool,
+}
+
+impl FileEmitter {
+    /// Append to `path`, creating it if needed. The file is not rotated
+    /// until [`FileEmitter::max_size`] or [`FileEmitter::daily`] is set.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let file = append(&path)?;
+        let metadata = file.metadata()?;
+        // A file kept from a previous run holds events of the day it was
+        // last written.
+        let modified = match metadata.len() {
+            0 => SystemTime::now(),
+            _ => metadata.modified()?,
+        };
+        Ok(FileEmitter {
+            path,
+            file,
+            size: metadata.len(),
+            day: day(modified),
+            max_size: None,
+            daily: false,
+        })
+    }
+
+    /// Rotate before an event would grow the file past `bytes`. A single
+    /// event larger than that still goes into a file of its own.
+    pub fn max_size(mut self, bytes: u64) -> Self {
+        self.max_size = Some(bytes);
+        self
+    }
+
+    /// Rotate at the first event of each UTC day.
+    pub fn daily(mut self) -> Self {
+        self.daily = true;
+        self
+    }
+
+    fn emit(&mut self, event: &TestEvent) -> Result<()> {
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+        let now = SystemTime::now();
+        let full = self
+            .max_size
+            .is_some_and(|max| self.size + line.len() as u64 > max);
+        let new_day = self.daily && day(now) != self.day;
+        if self.size > 0 && (full || new_day) {
+            let suffix = if new_day {
+                let start = UNIX_EPOCH + Duration::from_secs(self.day * 86_400);
+                utc_timestamp(start)[..10].to_string()
+            } else {
+                utc_timestamp(now).replace(':', "-")
+            };
+            self.rotate(&suffix)?;
+        }
+        if self.size == 0 {
+            self.day = day(now);
+        }
+        self.file.write_all(&line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Rename the file to `<path>.<suffix>` and start a new one.
+    fn rotate(&mut self, suffix: &str) -> Result<()> {
+        self.file.flush()?;
+        let mut name = self.path.as_os_str().to_owned();
+        name.push(format!(".{suffix}"));
+        let mut rotated = PathBuf::from(&name);
+        // Never overwrite an earlier rotation, e.g. after a clock change.
+        let mut n = 1;
+        while rotated.exists() {
+            rotated = PathBuf::from(format!("{}.{n}", name.to_string_lossy()));
+            n += 1;
+        }
+        fs::rename(&self.path, &rotated)?;
+        self.file = append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn append(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Days from the Unix epoch to `time` in UTC.
+fn day(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / 86_400
+}
+
+impl Emitter for FileEmitter {
+    fn on_starting(&mut self, test: TestKind) -> Result<()> {
+        self.emit(&TestEvent::Starting { test })
+    }
+
+    fn on_error(&mut self, test: TestKind, error: &str) -> Result<()> {
+        self.emit(&TestEvent::Error { test, error })
+    }
+
+    fn on_server_closed(&mut self, test: TestKind, code: u16, reason: &str) -> Result<()> {
+        self.emit(&TestEvent::ServerClosed { test, code, reason })
+    }
+
+    fn on_server_unhealthy(&mut self, test: TestKind, fqdn: &str, reason: &str) -> Result<()> {
+        self.emit(&TestEvent::ServerUnhealthy { test, fqdn, reason })
+    }
+
+    fn on_connected(&mut self, test: TestKind, fqdn: &str, info: &ConnectInfo) -> Result<()> {
+        self.emit(&TestEvent::Connected {
+            test,
+            fqdn,
+            connect_info: info,
+        })
+    }
+
+    fn on_download_event(&mut self, m: &Measurement) -> Result<()> {
+        self.emit(&TestEvent::Measurement {
+            test: TestKind::Download,
+            measurement: m,
+        })
+    }
+
+    fn on_upload_event(&mut self, m: &Measurement) -> Result<()> {
+        self.emit(&TestEvent::Measurement {
+            test: TestKind::Upload,
+            measurement: m,
+        })
+    }
+
+    fn on_progress(&mut self, test: TestKind, progress: &Progress) -> Result<()> {
+        self.emit(&TestEvent::Progress { test, progress })
+    }
+
+    fn on_complete(&mut self, test: TestKind) -> Result<()> {
+        self.emit(&TestEvent::Complete { test })
+    }
+
+    fn on_summary(&mut self, summary: &Summary) -> Result<()> {
+        self.emit(&TestEvent::Summary { summary })
+    }
+
+    fn on_ping(&mut self, ping: &PingResult) -> Result<()> {
+        self.emit(&TestEvent::Ping { ping })
+    }
+
+    fn on_sweep(&mut self, report: &SweepReport) -> Result<()> {
+        self.emit(&TestEvent::Sweep { sweep: report })
+    }
+
+    fn on_warning(&mut self, warning: &str) -> Result<()> {
+        self.emit(&TestEvent::Warning { warning })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotate_by_size() {
+        let dir = std::env::temp_dir().join(format!("ndt7-rotate-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("events.jsonl");
+
+        // Each warning is 42 bytes; two fit.
+        let mut emitter = FileEmitter::open(&path).unwrap().max_size(90);
+        for warning in ["first line", "2nd line!!", "third line"] {
+            emitter.on_warning(warning).unwrap();
+        }
+        drop(emitter);
+        // Reopening appends to the current file.
+        let mut emitter = FileEmitter::open(&path).unwrap().max_size(90);
+        emitter.on_warning("fourth one").unwrap();
+
+        let current = fs::read_to_string(&path).unwrap();
+        let rotated: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| *p != path)
+            .collect();
+        let old = fs::read_to_string(&rotated[0]).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(rotated.len(), 1);
+        assert!(old.contains("first line") && old.contains("2nd line!!"));
+        assert_eq!(old.lines().count(), 2);
+        assert!(current.contains("third line") && current.contains("fourth one"));
+    }
+}
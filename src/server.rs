@@ -0,0 +1,529 @@
+//! Server side of the ndt7 protocol.
+//!
+//! A [`Server`] accepts ndt7 tests on plain WebSocket connections. The
+//! download test sends random binary messages whose size grows with the
+//! bytes sent, like the M-Lab server, and the upload test receives them.
+//! Both send a measurement with the kernel's statistics of the connection on
+//! a fixed interval and close the connection normally after the test
+//! duration. Two machines running this crate can so measure the path between
+//! them, without M-Lab infrastructure:
+//!
+//! ```no_run
+//! use ndt7_client::server::ServerBuilder;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> std::io::Result<()> {
+//! let server = ServerBuilder::new().bind("0.0.0.0:8080").await?;
+//! // Clients connect to ws://<host>:8080/ndt/v7/download and /ndt/v7/upload.
+//! server.serve().await;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! A test that does not finish its handshake within [`params::IO_TIMEOUT`],
+//! or its transfer within that grace after the test duration, is dropped, so
+//! a stalled client cannot hold on to the server.
+//! [`ServerBuilder::max_concurrent_tests`] rejects tests beyond a limit with
+//! `503 Service Unavailable`.
+//!
+//! TLS is not terminated by the server; put it behind a proxy for `wss`.
+//! [`TCPInfo`] is included where [`crate::tcpinfo`] has a backend for the
+//! platform.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures_util::{SinkExt, StreamExt};
+use rand::RngCore;
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio::time::{Instant, interval, sleep, timeout, timeout_at};
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, WebSocketConfig};
+
+use crate::client::Transport;
+use crate::download::client_tcp_info;
+use crate::params;
+use crate::spec::{AppInfo, ByteCount, ConnectionInfo, Measurement, Micros, TCPInfo, TestKind};
+use crate::tcpinfo::TcpInfoSource;
+use crate::upload::PayloadConfig;
+
+/// Default duration of each test, as on M-Lab servers.
+pub const DEFAULT_TEST_DURATION: Duration = Duration::from_secs(10);
+
+/// First pause after failing to accept a connection, doubled on each
+/// further failure up to [`MAX_ACCEPT_BACKOFF`].
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(5);
+
+/// Longest pause after failing to accept a connection.
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Configures and binds a [`Server`].
+#[derive(Debug, Clone)]
+pub struct ServerBuilder {
+    duration: Duration,
+    measurement_interval: Duration,
+    initial_message_size: usize,
+    max_message_size: usize,
+    max_concurrent_tests: Option<usize>,
+    /// Report TCP statistics made up from this round-trip time instead of
+    /// the kernel's, see [`crate::testing`].
+    pub(crate) synthetic_rtt: Option<Duration>,
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        ServerBuilder {
+            duration: DEFAULT_TEST_DURATION,
+            measurement_interval: params::UPDATE_INTERVAL,
+            initial_message_size: params::INITIAL_MESSAGE_SIZE,
+            max_message_size: params::MAX_MESSAGE_SIZE,
+            max_concurrent_tests: None,
+            synthetic_rtt: None,
+        }
+    }
+}
+
+impl ServerBuilder {
+    /// Create a builder with the defaults of M-Lab servers.
+    pub fn new() -> Self {
+        ServerBuilder::default()
+    }
+
+    /// How long each test runs before the server closes the connection.
+    /// Default: [`DEFAULT_TEST_DURATION`].
+    pub fn duration(mut self, duration: Duration) -> Self {
+        self.duration = duration;
+        self
+    }
+
+    /// Interval between server measurements.
+    /// Default: [`params::UPDATE_INTERVAL`].
+    pub fn measurement_interval(mut self, interval: Duration) -> Self {
+        self.measurement_interval = interval;
+        self
+    }
+
+    /// Size of the first download message, doubled whenever it is at most
+    /// 1/[`params::SCALING_FRACTION`] of the bytes sent.
+    /// Default: [`params::INITIAL_MESSAGE_SIZE`].
+    pub fn initial_message_size(mut self, size: usize) -> Self {
+        self.initial_message_size = size;
+        self
+    }
+
+    /// Largest download message. Uploaded messages are accepted up to
+    /// [`params::MAX_MESSAGE_SIZE`] regardless. Default: the same.
+    pub fn max_message_size(mut self, size: usize) -> Self {
+        self.max_message_size = size;
+        self
+    }
+
+    /// Most tests served at once; further tests are rejected with
+    /// `503 Service Unavailable`. Default: unlimited.
+    pub fn max_concurrent_tests(mut self, limit: usize) -> Self {
+        self.max_concurrent_tests = Some(limit);
+        self
+    }
+
+    /// Listen on `addr`.
+    ///
+    /// The download corpus is generated here, once, and shared by all tests.
+    pub async fn bind(self, addr: impl ToSocketAddrs) -> std::io::Result<Server> {
+        let corpus = PayloadConfig::default()
+            .corpus_of_size(self.max_message_size)
+            .map_err(std::io::Error::other)?;
+        Ok(Server {
+            listener: TcpListener::bind(addr).await?,
+            slots: self
+                .max_concurrent_tests
+                .map(|limit| Arc::new(Semaphore::new(limit))),
+            config: Arc::new(self),
+            corpus,
+        })
+    }
+}
+
+/// An ndt7 server bound to a local address, see the
+/// [module documentation](self).
+#[derive(Debug)]
+pub struct Server {
+    listener: TcpListener,
+    config: Arc<ServerBuilder>,
+    corpus: Bytes,
+    slots: Option<Arc<Semaphore>>,
+}
+
+impl Server {
+    /// Address the server listens on.
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Serve tests, concurrently, until the future is dropped, which stops
+    /// the server and aborts running tests.
+    ///
+    /// Failing to accept a connection does not stop the server. The error
+    /// is logged to stderr and, unless it only concerns that connection,
+    /// e.g. one the client reset, accepting pauses with a growing backoff,
+    /// so running out of file descriptors does not spin.
+    pub async fn serve(self) {
+        let mut tests = JoinSet::new();
+        let mut backoff = MIN_ACCEPT_BACKOFF;
+        loop {
+            let stream = match self.listener.accept().await {
+                Ok((stream, _)) => {
+                    backoff = MIN_ACCEPT_BACKOFF;
+                    stream
+                }
+                Err(e) if is_connection_error(&e) => {
+                    eprintln!("ndt7 server: failed to accept a connection: {e}");
+                    continue;
+                }
+                Err(e) => {
+                    eprintln!(
+                        "ndt7 server: failed to accept a connection, retrying in {backoff:?}: {e}"
+                    );
+                    sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                    continue;
+                }
+            };
+            // The permit is held until the test ends.
+            let permit = self
+                .slots
+                .as_ref()
+                .map(|slots| Arc::clone(slots).try_acquire_owned().ok());
+            let busy = matches!(permit, Some(None));
+            let test = serve_test(stream, Arc::clone(&self.config), self.corpus.clone(), busy);
+            tests.spawn(async move {
+                test.await;
+                drop(permit);
+            });
+            // Reap finished tests so the set does not grow unbounded.
+            while tests.try_join_next().is_some() {}
+        }
+    }
+}
+
+/// Whether accepting failed because of the connection being accepted
+/// rather than the listener.
+fn is_connection_error(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+    matches!(
+        e.kind(),
+        ErrorKind::ConnectionAborted
+            | ErrorKind::ConnectionRefused
+            | ErrorKind::ConnectionReset
+            | ErrorKind::Interrupted
+    )
+}
+
+impl Transport for WebSocketStream<TcpStream> {
+    fn tcp_stream(&self) -> Option<&TcpStream> {
+        Some(self.get_ref())
+    }
+}
+
+/// Run the test requested on `stream`, or reject it if the server is `busy`.
+async fn serve_test(stream: TcpStream, config: Arc<ServerBuilder>, corpus: Bytes, busy: bool) {
+    let (Ok(client), Ok(server)) = (stream.peer_addr(), stream.local_addr()) else {
+        return;
+    };
+    let mut test = None;
+    #[allow(clippy::result_large_err)]
+    let callback = |req: &Request, mut resp: Response| -> Result<Response, ErrorResponse> {
+        test = match req.uri().path() {
+            params::DOWNLOAD_URL_PATH => Some(TestKind::Download),
+            params::UPLOAD_URL_PATH => Some(TestKind::Upload),
+            _ => return Err(error_response(StatusCode::NOT_FOUND)),
+        };
+        if busy {
+            return Err(error_response(StatusCode::SERVICE_UNAVAILABLE));
+        }
+        let proto = req.headers().get("Sec-WebSocket-Protocol");
+        if proto.is_none_or(|p| p != params::SEC_WEBSOCKET_PROTOCOL) {
+            return Err(error_response(StatusCode::BAD_REQUEST));
+        }
+        resp.headers_mut().insert(
+            "Sec-WebSocket-Protocol",
+            params::SEC_WEBSOCKET_PROTOCOL.parse().unwrap(),
+        );
+        Ok(resp)
+    };
+    let ws_config = WebSocketConfig::default()
+        .max_message_size(Some(params::MAX_MESSAGE_SIZE))
+        .max_frame_size(Some(params::MAX_MESSAGE_SIZE));
+    let accepted = timeout(
+        params::IO_TIMEOUT,
+        tokio_tungstenite::accept_hdr_async_with_config(stream, callback, Some(ws_config)),
+    )
+    .await;
+    let (Ok(Ok(ws)), Some(test)) = (accepted, test) else {
+        return;
+    };
+
+    let mut uuid = [0u8; 16];
+    rand::rng().fill_bytes(&mut uuid);
+    let reporter = Reporter {
+        test,
+        start: Instant::now(),
+        tcp_info: TcpInfoSource::new(&ws),
+        synthetic_rtt: config.synthetic_rtt.map(Micros::from),
+        connection_info: ConnectionInfo {
+            client: client.to_string(),
+            server: server.to_string(),
+            uuid: Some(uuid.iter().map(|b| format!("{b:02x}")).collect()),
+            ..Default::default()
+        },
+    };
+    // Bound every send and receive, including the closing handshake.
+    let deadline = reporter.start + config.duration + params::IO_TIMEOUT;
+    let run = async {
+        match test {
+            TestKind::Download => send_download(ws, &config, &corpus, &reporter).await,
+            TestKind::Upload => receive_upload(ws, &config, &reporter).await,
+        }
+    };
+    let _ = timeout_at(deadline, run).await;
+}
+
+fn error_response(status: StatusCode) -> ErrorResponse {
+    let mut resp = ErrorResponse::new(None);
+    *resp.status_mut() = status;
+    resp
+}
+
+/// Builds the server's measurements of one test.
+struct Reporter {
+    test: TestKind,
+    start: Instant,
+    tcp_info: Option<TcpInfoSource>,
+    synthetic_rtt: Option<Micros>,
+    connection_info: ConnectionInfo,
+}
+
+impl Reporter {
+    /// Measurement after `num_bytes` were sent or received, as a text
+    /// message.
+    fn measurement(&self, num_bytes: i64) -> Message {
+        let elapsed_time = Micros::from(self.start.elapsed());
+        let num_bytes = ByteCount(num_bytes);
+        let tcp_info = match self.synthetic_rtt {
+            Some(rtt) => Some(self.synthetic_tcp_info(elapsed_time, num_bytes, rtt)),
+            None => client_tcp_info(self.tcp_info.as_ref(), elapsed_time),
+        };
+        let measurement = Measurement {
+            app_info: Some(AppInfo {
+                elapsed_time,
+                num_bytes,
+                ..Default::default()
+            }),
+            connection_info: Some(self.connection_info.clone()),
+            tcp_info,
+            ..Default::default()
+        };
+        Message::Text(serde_json::to_string(&measurement).unwrap().into())
+    }
+
+    fn synthetic_tcp_info(
+        &self,
+        elapsed_time: Micros,
+        num_bytes: ByteCount,
+        rtt: Micros,
+    ) -> TCPInfo {
+        let mut info = TCPInfo {
+            elapsed_time: Some(elapsed_time),
+            min_rtt: Some(rtt),
+            rtt: Some(rtt),
+            rtt_var: Some(Micros(0)),
+            ..Default::default()
+        };
+        match self.test {
+            TestKind::Download => {
+                info.bytes_sent = Some(num_bytes);
+                info.bytes_acked = Some(num_bytes);
+            }
+            TestKind::Upload => info.bytes_received = Some(num_bytes),
+        }
+        info
+    }
+}
+
+/// Send binary messages until the test duration elapses or the client goes
+/// away.
+async fn send_download(
+    ws: WebSocketStream<TcpStream>,
+    config: &ServerBuilder,
+    corpus: &Bytes,
+    reporter: &Reporter,
+) {
+    let (mut sink, mut stream) = ws.split();
+    // Reading answers the client's pings and notices when it closes. The set
+    // aborts the reader if the test is dropped at its deadline.
+    let mut reader = JoinSet::new();
+    reader.spawn(async move {
+        while let Some(Ok(msg)) = stream.next().await {
+            if msg.is_close() {
+                break;
+            }
+        }
+    });
+    let max_size = config.max_message_size;
+    let mut size = config.initial_message_size.min(max_size);
+    let mut num_bytes = 0;
+    let mut next_report = reporter.start + config.measurement_interval;
+    while reporter.start.elapsed() < config.duration && reader.try_join_next().is_none() {
+        if Instant::now() >= next_report {
+            if sink.send(reporter.measurement(num_bytes)).await.is_err() {
+                return;
+            }
+            next_report += config.measurement_interval;
+        }
+        if sink
+            .send(Message::Binary(corpus.slice(..size)))
+            .await
+            .is_err()
+        {
+            return;
+        }
+        num_bytes += size as i64;
+        if size < max_size && size <= num_bytes as usize / params::SCALING_FRACTION {
+            size = (size * 2).min(max_size);
+        }
+    }
+    let _ = sink.send(close_normal()).await;
+    let _ = reader.join_next().await;
+}
+
+/// Receive messages until the test duration elapses or the client goes away.
+async fn receive_upload(
+    mut ws: WebSocketStream<TcpStream>,
+    config: &ServerBuilder,
+    reporter: &Reporter,
+) {
+    let mut num_bytes = 0;
+    let mut ticker = interval(config.measurement_interval);
+    ticker.reset();
+    let end = sleep(config.duration);
+    tokio::pin!(end);
+    loop {
+        tokio::select! {
+            _ = &mut end => break,
+            _ = ticker.tick() => {
+                if ws.send(reporter.measurement(num_bytes)).await.is_err() {
+                    return;
+                }
+            }
+            msg = ws.next() => match msg {
+                Some(Ok(Message::Binary(data))) => num_bytes += data.len() as i64,
+                Some(Ok(Message::Text(text))) => num_bytes += text.len() as i64,
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+    let _ = ws.send(close_normal()).await;
+    // Wait for the client to acknowledge the close.
+    while let Some(Ok(_)) = ws.next().await {}
+}
+
+fn close_normal() -> Message {
+    Message::Close(Some(CloseFrame {
+        code: CloseCode::Normal,
+        reason: "".into(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ClientBuilder;
+    use crate::error::ErrorKind;
+    use crate::spec::Origin;
+
+    #[tokio::test]
+    async fn point_to_point() {
+        let server = ServerBuilder::new()
+            .duration(Duration::from_millis(600))
+            .measurement_interval(Duration::from_millis(100))
+            .bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let task = tokio::spawn(server.serve());
+        let client = ClientBuilder::new("test", "test").no_tls().build();
+
+        let url = format!("ws://{addr}{}", params::DOWNLOAD_URL_PATH);
+        let mut handle = client.start_download(Some(&url)).await.unwrap();
+        let mut last = None;
+        while let Some(result) = handle.rx.recv().await {
+            let m = result.unwrap();
+            if m.origin == Some(Origin::Server) {
+                last = Some(m);
+            }
+        }
+        let last = last.unwrap();
+        assert!(last.app_info.unwrap().num_bytes.0 > 0);
+        assert_eq!(last.connection_info.unwrap().uuid.unwrap().len(), 32);
+        #[cfg(target_os = "linux")]
+        assert!(last.tcp_info.unwrap().bytes_acked.unwrap().0 > 0);
+
+        let url = format!("ws://{addr}/ndt/v7/other");
+        let Err(e) = client.start_upload(Some(&url)).await else {
+            panic!("unknown path accepted");
+        };
+        assert_eq!(e.kind(), ErrorKind::ServerRejected);
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn rejects_tests_over_the_limit() {
+        let server = ServerBuilder::new()
+            .duration(Duration::from_millis(200))
+            .max_concurrent_tests(1)
+            .bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let task = tokio::spawn(server.serve());
+        let client = ClientBuilder::new("test", "test").no_tls().build();
+        let url = format!("ws://{addr}{}", params::DOWNLOAD_URL_PATH);
+
+        // A connection in its handshake takes the only slot.
+        let idle = TcpStream::connect(addr).await.unwrap();
+        let Err(e) = client.start_download(Some(&url)).await else {
+            panic!("test over the limit accepted");
+        };
+        assert_eq!(e.kind(), ErrorKind::ServerRejected);
+
+        drop(idle);
+        sleep(Duration::from_millis(100)).await;
+        let mut handle = client.start_download(Some(&url)).await.unwrap();
+        while handle.rx.recv().await.is_some() {}
+        task.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn drops_stalled_handshake() {
+        use tokio::io::AsyncReadExt;
+
+        let server = ServerBuilder::new().bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let task = tokio::spawn(server.serve());
+
+        let mut idle = TcpStream::connect(addr).await.unwrap();
+        let start = Instant::now();
+        let read = idle.read(&mut [0u8; 16]).await;
+        assert!(matches!(read, Ok(0) | Err(_)));
+        assert!(start.elapsed() >= params::IO_TIMEOUT);
+        task.abort();
+    }
+}
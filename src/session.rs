@@ -0,0 +1,387 @@
+//! Speed tests driven from a non-async event loop.
+//!
+//! GUI toolkits run their own event loop and cannot await the client's
+//! futures. [`Session::spawn`] runs a download and an upload test in the
+//! background on a tokio runtime, and the returned [`Session`] is polled from
+//! the event loop without blocking: [`Session::progress`] for live progress,
+//! [`Session::try_summary`] for the final result, and [`Session::cancel`] to
+//! stop early.
+//!
+//! See `examples/gui.rs` for an egui application built on it.
+
+use std::sync::Arc;
+
+use futures_util::FutureExt;
+use tokio::runtime::Handle;
+use tokio::sync::{Notify, watch};
+use tokio::task::JoinHandle;
+
+use crate::client::{Client, TestHandle};
+use crate::emitter::Progress;
+use crate::error::{Ndt7Error, Result};
+use crate::grade::Thresholds;
+use crate::metrics::{MeasurementLog, average_mbps};
+use crate::spec::{Measurement, Origin, TestKind};
+use crate::summary::{ServerLocation, SubtestOutcome, Summary};
+
+/// Stage of a session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Locating a server and connecting for the subtest.
+    Connecting(TestKind),
+    /// The subtest is transferring data.
+    Running(TestKind),
+    /// All subtests ended, or the session failed or was cancelled.
+    Finished,
+}
+
+/// Live state of a session, published after every measurement.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestProgress {
+    /// What the session is doing.
+    pub phase: Phase,
+    /// FQDN of the server of the current or last subtest.
+    pub server_fqdn: Option<String>,
+    /// Average download throughput so far, in Mbit/s.
+    pub download_mbps: Option<f64>,
+    /// Average upload throughput so far, in Mbit/s.
+    pub upload_mbps: Option<f64>,
+    /// Timing of the running subtest.
+    pub progress: Option<Progress>,
+    /// Error that ended the last subtest or the session early, if any.
+    pub error: Option<String>,
+}
+
+impl Default for TestProgress {
+    fn default() -> Self {
+        TestProgress {
+            phase: Phase::Connecting(TestKind::Download),
+            server_fqdn: None,
+            download_mbps: None,
+            upload_mbps: None,
+            progress: None,
+            error: None,
+        }
+    }
+}
+
+/// A download and upload test running in the background.
+///
+/// Dropping the session does not stop the tests; call [`Session::cancel`].
+pub struct Session {
+    progress: watch::Receiver<TestProgress>,
+    task: JoinHandle<Result<Summary>>,
+    cancel: Arc<Notify>,
+    done: bool,
+}
+
+impl Session {
+    /// Run a download test followed by an upload test with `client` on
+    /// `runtime`.
+    ///
+    /// May be called from any thread, including ones without a tokio
+    /// context such as a GUI main thread.
+    pub fn spawn(client: &Client, runtime: &Handle) -> Session {
+        Session::spawn_with_thresholds(client, runtime, Thresholds::default())
+    }
+
+    /// Like [`Session::spawn`], grading the summary against `thresholds`.
+    pub fn spawn_with_thresholds(
+        client: &Client,
+        runtime: &Handle,
+        thresholds: Thresholds,
+    ) -> Session {
+        let (tx, progress) = watch::channel(TestProgress::default());
+        let cancel = Arc::new(Notify::new());
+        let task = runtime.spawn(run(client.clone(), thresholds, tx, Arc::clone(&cancel)));
+        Session {
+            progress,
+            task,
+            cancel,
+            done: false,
+        }
+    }
+
+    /// Latest progress. Never blocks.
+    pub fn progress(&mut self) -> TestProgress {
+        self.progress.borrow_and_update().clone()
+    }
+
+    /// Whether the progress changed since the last [`Session::progress`]
+    /// call, e.g. to decide whether to redraw.
+    pub fn has_changed(&self) -> bool {
+        self.progress.has_changed().unwrap_or(true)
+    }
+
+    /// Stop the running tests. The result becomes
+    /// [`Ndt7Error::Cancelled`] unless the session already finished, and
+    /// the progress moves to [`Phase::Finished`] with that error.
+    pub fn cancel(&self) {
+        // Stored if the task is not waiting yet, so it is never missed.
+        self.cancel.notify_one();
+    }
+
+    /// Whether the session finished, successfully or not.
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+
+    /// Take the result if the session finished. Never blocks.
+    ///
+    /// Returns `None` while tests are running and after the result has
+    /// been taken.
+    ///
+    /// The summary covers the subtests that produced results. Subtests that
+    /// failed mid-run are marked incomplete, see
+    /// [`SubtestSummary::complete`](crate::summary::SubtestSummary::complete).
+    /// An error is returned if a subtest could not start or the session was
+    /// cancelled.
+    pub fn try_summary(&mut self) -> Option<Result<Summary>> {
+        if self.done || !self.task.is_finished() {
+            return None;
+        }
+        self.done = true;
+        (&mut self.task).now_or_never().map(join_result)
+    }
+
+    /// Wait for the session to finish and return its result, like
+    /// [`Session::try_summary`].
+    pub async fn summary(self) -> Result<Summary> {
+        join_result(self.task.await)
+    }
+}
+
+fn join_result(
+    result: std::result::Result<Result<Summary>, tokio::task::JoinError>,
+) -> Result<Summary> {
+    match result {
+        Ok(result) => result,
+        Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+        Err(_) => Err(Ndt7Error::Cancelled),
+    }
+}
+
+/// How a subtest ended.
+struct Outcome {
+    complete: bool,
+    truncated: bool,
+}
+
+async fn run(
+    client: Client,
+    thresholds: Thresholds,
+    tx: watch::Sender<TestProgress>,
+    cancel: Arc<Notify>,
+) -> Result<Summary> {
+    let result = tokio::select! {
+        result = run_tests(&client, &thresholds, &tx) => result,
+        _ = cancel.notified() => Err(Ndt7Error::Cancelled),
+    };
+    tx.send_modify(|p| {
+        p.phase = Phase::Finished;
+        p.progress = None;
+        if let Err(e) = &result {
+            p.error = Some(e.to_string());
+        }
+    });
+    result
+}
+
+async fn run_tests(
+    client: &Client,
+    thresholds: &Thresholds,
+    tx: &watch::Sender<TestProgress>,
+) -> Result<Summary> {
+    let mut outcomes = Vec::new();
+    let mut log = MeasurementLog::new();
+    let mut server_fqdn = String::new();
+    let mut server_location = None;
+    for kind in [TestKind::Download, TestKind::Upload] {
+        tx.send_modify(|p| p.phase = Phase::Connecting(kind));
+        let handle = match kind {
+            TestKind::Download => client.start_download(None).await?,
+            TestKind::Upload => client.start_upload(None).await?,
+        };
+        server_fqdn = handle.server_fqdn.clone();
+        server_location = handle.server_location.clone();
+        tx.send_modify(|p| {
+            p.phase = Phase::Running(kind);
+            p.server_fqdn = Some(handle.server_fqdn.clone());
+        });
+        let connect_info = handle.connect_info.clone();
+        let outcome = collect(handle, kind, tx, &mut log).await;
+        let truncated = outcome.truncated;
+        outcomes.push((outcome, connect_info));
+        if truncated {
+            break;
+        }
+    }
+
+    let truncated = outcomes.iter().any(|(o, _)| o.truncated);
+    let mut outcomes = outcomes
+        .into_iter()
+        .map(|(outcome, connect_info)| SubtestOutcome {
+            connect_info,
+            complete: outcome.complete,
+        });
+    let download = outcomes.next();
+    let upload = outcomes.next();
+    let mut summary = Summary::from_run(server_fqdn, &log, download, upload);
+    summary.server_location = server_location.map(ServerLocation::from);
+    summary.dscp = client.dscp();
+    summary.truncated = truncated;
+    summary.set_grade(thresholds);
+    Ok(summary)
+}
+
+/// Drain a subtest's channel into `log`, publishing progress along the way.
+async fn collect(
+    mut handle: TestHandle,
+    kind: TestKind,
+    tx: &watch::Sender<TestProgress>,
+    log: &mut MeasurementLog,
+) -> Outcome {
+    let mut outcome = Outcome {
+        complete: true,
+        truncated: false,
+    };
+    while let Some(result) = handle.rx.recv().await {
+        let m = match result {
+            Ok(m) => m,
+            Err(e) => {
+                outcome.complete = false;
+                outcome.truncated = matches!(e, Ndt7Error::TestDeadline { .. });
+                tx.send_modify(|p| p.error = Some(e.to_string()));
+                continue;
+            }
+        };
+        tx.send_modify(|p| update(p, kind, handle.duration, &m));
+        log.push(kind, m);
+    }
+    outcome
+}
+
+/// Fold a measurement into the progress, computing throughput like
+/// [`Summary`] with [`average_mbps`].
+fn update(p: &mut TestProgress, kind: TestKind, duration: std::time::Duration, m: &Measurement) {
+    if let Some(mbps) = average_mbps(kind, m) {
+        match kind {
+            TestKind::Download => p.download_mbps = Some(mbps),
+            TestKind::Upload => p.upload_mbps = Some(mbps),
+        }
+    }
+    if m.origin == Some(Origin::Client)
+        && let Some(app) = &m.app_info
+    {
+        p.progress = Some(Progress::new(app.elapsed_time, duration));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::Message;
+
+    use super::*;
+    use crate::client::ClientBuilder;
+    use crate::locate::Target;
+    use crate::test_util::accept_ndt7;
+
+    /// Client for a local server handling both subtests. With `stall`, the
+    /// download never ends.
+    async fn local_client(stall: bool) -> Client {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            // Download: one measurement with TCPInfo, then close.
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = accept_ndt7(stream).await;
+            ws.send(Message::Text(
+                r#"{"TCPInfo":{"MinRTT":4000,"BytesSent":1000,"ElapsedTime":1000}}"#.into(),
+            ))
+            .await
+            .unwrap();
+            if stall {
+                futures_util::future::pending::<()>().await;
+            }
+            // Outlast the client's measurement interval.
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            ws.send(Message::Binary(vec![0u8; 1024].into()))
+                .await
+                .unwrap();
+            ws.close(None).await.unwrap();
+            while let Some(Ok(_)) = ws.next().await {}
+
+            // Upload: read a few messages, report and close.
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = accept_ndt7(stream).await;
+            for _ in 0..4 {
+                ws.next().await;
+            }
+            ws.send(Message::Text(
+                r#"{"TCPInfo":{"BytesReceived":125000,"ElapsedTime":100000}}"#.into(),
+            ))
+            .await
+            .unwrap();
+            ws.close(None).await.unwrap();
+            while let Some(Ok(_)) = ws.next().await {}
+        });
+
+        let urls = HashMap::from([
+            (
+                "ws:///ndt/v7/download".into(),
+                format!("ws://{addr}/ndt/v7/download"),
+            ),
+            (
+                "ws:///ndt/v7/upload".into(),
+                format!("ws://{addr}/ndt/v7/upload"),
+            ),
+        ]);
+        let mut client = ClientBuilder::new("test", "test").no_tls().build();
+        client.set_targets(vec![Target {
+            machine: addr.ip().to_string(),
+            urls,
+            ..Default::default()
+        }]);
+        client
+    }
+
+    #[tokio::test]
+    async fn test_session_summary() {
+        let client = local_client(false).await;
+        let mut session = Session::spawn(&client, &Handle::current());
+        assert!(session.try_summary().is_none());
+
+        let summary = session.summary().await.unwrap();
+        let upload = summary.upload.unwrap();
+        assert_eq!(upload.throughput_mbps, 10.0);
+        assert!(upload.complete);
+        assert_eq!(summary.download.unwrap().latency_ms, 4.0);
+    }
+
+    #[tokio::test]
+    async fn test_session_cancel() {
+        let client = local_client(true).await;
+        let mut session = Session::spawn(&client, &Handle::current());
+        while session.progress().phase != Phase::Running(TestKind::Download) {
+            tokio::task::yield_now().await;
+        }
+
+        session.cancel();
+        while !session.is_finished() {
+            tokio::task::yield_now().await;
+        }
+        let progress = session.progress();
+        assert_eq!(progress.phase, Phase::Finished);
+        assert_eq!(progress.error, Some(Ndt7Error::Cancelled.to_string()));
+        assert!(matches!(
+            session.try_summary(),
+            Some(Err(Ndt7Error::Cancelled))
+        ));
+        assert!(session.try_summary().is_none());
+    }
+}
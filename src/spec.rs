@@ -2,8 +2,13 @@
 //!
 //! These structs match the JSON measurement messages defined in the
 //! [ndt7 specification](https://github.com/m-lab/ndt-server/blob/master/spec/ndt7-protocol.md).
+//! Fields a server sends that are not part of them are kept in each
+//! struct's `extra` map and written back out on serialization.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 
 /// Which side produced a measurement.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -25,15 +30,130 @@ pub enum TestKind {
     Upload,
 }
 
+/// A time or duration in microseconds, the unit of all ndt7 timestamps and
+/// round-trip times. Serialized as a plain integer.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct Micros(pub i64);
+
+impl Micros {
+    /// The value as a [`Duration`]; negative values become zero.
+    pub fn as_duration(self) -> Duration {
+        Duration::from_micros(self.0.max(0) as u64)
+    }
+
+    /// The value in milliseconds.
+    pub fn as_millis_f64(self) -> f64 {
+        self.0 as f64 / 1000.0
+    }
+
+    /// The value in seconds.
+    pub fn as_secs_f64(self) -> f64 {
+        self.0 as f64 / 1e6
+    }
+}
+
+impl From<Duration> for Micros {
+    fn from(d: Duration) -> Self {
+        Micros(d.as_micros().min(i64::MAX as u128) as i64)
+    }
+}
+
+impl From<i64> for Micros {
+    fn from(us: i64) -> Self {
+        Micros(us)
+    }
+}
+
+impl From<Micros> for Duration {
+    fn from(m: Micros) -> Self {
+        m.as_duration()
+    }
+}
+
+/// A number of bytes. Serialized as a plain integer.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct ByteCount(pub i64);
+
+impl From<i64> for ByteCount {
+    fn from(bytes: i64) -> Self {
+        ByteCount(bytes)
+    }
+}
+
+impl ByteCount {
+    /// Throughput in megabits per second of transferring these bytes in
+    /// `elapsed`, or `None` if no time elapsed.
+    pub fn mbps_over(self, elapsed: Micros) -> Option<f64> {
+        (elapsed.0 > 0).then(|| 8.0 * self.0 as f64 / elapsed.0 as f64)
+    }
+}
+
+/// Format `time` as an RFC 3339 UTC timestamp with microsecond precision,
+/// e.g. `2024-05-01T12:00:00.250000Z`. Times before the Unix epoch are
+/// clamped to it.
+pub fn utc_timestamp(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs();
+    // Civil date from days since the epoch, after Howard Hinnant's
+    // `civil_from_days`.
+    let z = (secs / 86_400) as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    let rem = secs % 86_400;
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:06}Z",
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60,
+        since.subsec_micros()
+    )
+}
+
 /// Application-level throughput counters.
 #[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct AppInfo {
     /// Microseconds elapsed since the start of the test.
     #[serde(rename = "ElapsedTime")]
-    pub elapsed_time: i64,
+    pub elapsed_time: Micros,
     /// Total bytes transferred so far.
     #[serde(rename = "NumBytes")]
-    pub num_bytes: i64,
+    pub num_bytes: ByteCount,
+    /// Estimated bytes on the wire, including WebSocket, TLS and TCP/IP
+    /// overhead. Only set by the client when overhead accounting is enabled,
+    /// see [`crate::overhead`].
+    #[serde(rename = "WireBytes", default, skip_serializing_if = "Option::is_none")]
+    pub wire_bytes: Option<ByteCount>,
+    /// Fields not known to this version of the crate, kept so that
+    /// re-serializing the struct preserves them.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// Round trip of a WebSocket ping sent by the client during a test.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WSPingInfo {
+    /// Microseconds elapsed since the start of the test when the pong arrived.
+    #[serde(rename = "ElapsedTime")]
+    pub elapsed_time: Micros,
+    /// Round-trip time (microseconds).
+    #[serde(rename = "RTT")]
+    pub rtt: Micros,
+    /// Fields not known to this version of the crate, kept so that
+    /// re-serializing the struct preserves them.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
 /// Endpoint addresses and connection metadata.
@@ -51,56 +171,230 @@ pub struct ConnectionInfo {
     /// Start time of the test in RFC 3339 format.
     #[serde(rename = "StartTime", skip_serializing_if = "Option::is_none")]
     pub start_time: Option<String>,
+    /// Fields not known to this version of the crate, kept so that
+    /// re-serializing the struct preserves them.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
-/// TCP connection metrics from the kernel (server-side).
+/// TCP connection metrics from the kernel.
+///
+/// Covers the `tcp_info` fields reported by ndt-server, which are those of
+/// Linux's `struct tcp_info`. Reported by the server, and by the client
+/// where [`crate::tcpinfo`] has a backend for the platform.
 #[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct TCPInfo {
+    /// Delayed ACK timeout (microseconds).
+    #[serde(rename = "ATO", skip_serializing_if = "Option::is_none")]
+    pub ato: Option<Micros>,
+    /// Advertised maximum segment size.
+    #[serde(rename = "AdvMSS", skip_serializing_if = "Option::is_none")]
+    pub adv_mss: Option<i64>,
+    /// Whether the last delivery rate sample was limited by the application.
+    #[serde(rename = "AppLimited", skip_serializing_if = "Option::is_none")]
+    pub app_limited: Option<i64>,
+    /// Exponential backoff count of the retransmission timer.
+    #[serde(rename = "Backoff", skip_serializing_if = "Option::is_none")]
+    pub backoff: Option<i64>,
     /// Time (microseconds) the connection has been actively sending data.
     #[serde(rename = "BusyTime", skip_serializing_if = "Option::is_none")]
-    pub busy_time: Option<i64>,
+    pub busy_time: Option<Micros>,
     /// Bytes acknowledged by the peer.
     #[serde(rename = "BytesAcked", skip_serializing_if = "Option::is_none")]
-    pub bytes_acked: Option<i64>,
+    pub bytes_acked: Option<ByteCount>,
     /// Bytes received from the peer.
     #[serde(rename = "BytesReceived", skip_serializing_if = "Option::is_none")]
-    pub bytes_received: Option<i64>,
+    pub bytes_received: Option<ByteCount>,
     /// Bytes sent to the peer.
     #[serde(rename = "BytesSent", skip_serializing_if = "Option::is_none")]
-    pub bytes_sent: Option<i64>,
+    pub bytes_sent: Option<ByteCount>,
     /// Bytes retransmitted.
     #[serde(rename = "BytesRetrans", skip_serializing_if = "Option::is_none")]
-    pub bytes_retrans: Option<i64>,
+    pub bytes_retrans: Option<ByteCount>,
+    /// Congestion avoidance state (open, disorder, CWR, recovery or loss).
+    #[serde(rename = "CAState", skip_serializing_if = "Option::is_none")]
+    pub ca_state: Option<i64>,
+    /// Duplicate segments reported by DSACK.
+    #[serde(rename = "DSackDups", skip_serializing_if = "Option::is_none")]
+    pub dsack_dups: Option<i64>,
+    /// Segments received that carried data.
+    #[serde(rename = "DataSegsIn", skip_serializing_if = "Option::is_none")]
+    pub data_segs_in: Option<i64>,
+    /// Segments sent that carried data.
+    #[serde(rename = "DataSegsOut", skip_serializing_if = "Option::is_none")]
+    pub data_segs_out: Option<i64>,
+    /// Segments delivered to the peer, including retransmissions.
+    #[serde(rename = "Delivered", skip_serializing_if = "Option::is_none")]
+    pub delivered: Option<i64>,
+    /// Delivered segments that were marked with ECN congestion experienced.
+    #[serde(rename = "DeliveredCE", skip_serializing_if = "Option::is_none")]
+    pub delivered_ce: Option<i64>,
+    /// Most recent delivery rate sample (bytes per second).
+    #[serde(rename = "DeliveryRate", skip_serializing_if = "Option::is_none")]
+    pub delivery_rate: Option<i64>,
     /// Microseconds elapsed since the TCP connection was established.
     #[serde(rename = "ElapsedTime", skip_serializing_if = "Option::is_none")]
-    pub elapsed_time: Option<i64>,
+    pub elapsed_time: Option<Micros>,
+    /// Forward-acknowledged segments.
+    #[serde(rename = "Fackets", skip_serializing_if = "Option::is_none")]
+    pub fackets: Option<i64>,
+    /// Time (milliseconds) since the last ACK was received.
+    #[serde(rename = "LastAckRecv", skip_serializing_if = "Option::is_none")]
+    pub last_ack_recv: Option<i64>,
+    /// Time (milliseconds) since the last ACK was sent.
+    #[serde(rename = "LastAckSent", skip_serializing_if = "Option::is_none")]
+    pub last_ack_sent: Option<i64>,
+    /// Time (milliseconds) since data was last received.
+    #[serde(rename = "LastDataRecv", skip_serializing_if = "Option::is_none")]
+    pub last_data_recv: Option<i64>,
+    /// Time (milliseconds) since data was last sent.
+    #[serde(rename = "LastDataSent", skip_serializing_if = "Option::is_none")]
+    pub last_data_sent: Option<i64>,
+    /// Segments currently considered lost.
+    #[serde(rename = "Lost", skip_serializing_if = "Option::is_none")]
+    pub lost: Option<i64>,
+    /// Upper limit of the pacing rate (bytes per second).
+    #[serde(rename = "MaxPacingRate", skip_serializing_if = "Option::is_none")]
+    pub max_pacing_rate: Option<i64>,
     /// Minimum round-trip time observed (microseconds).
     #[serde(rename = "MinRTT", skip_serializing_if = "Option::is_none")]
-    pub min_rtt: Option<i64>,
+    pub min_rtt: Option<Micros>,
+    /// Bytes queued in the send buffer but not yet sent.
+    #[serde(rename = "NotsentBytes", skip_serializing_if = "Option::is_none")]
+    pub notsent_bytes: Option<ByteCount>,
+    /// TCP options negotiated for the connection, as a bitmask.
+    #[serde(rename = "Options", skip_serializing_if = "Option::is_none")]
+    pub options: Option<i64>,
+    /// Path MTU.
+    #[serde(rename = "PMTU", skip_serializing_if = "Option::is_none")]
+    pub pmtu: Option<i64>,
+    /// Current pacing rate (bytes per second).
+    #[serde(rename = "PacingRate", skip_serializing_if = "Option::is_none")]
+    pub pacing_rate: Option<i64>,
+    /// Unanswered zero window probes.
+    #[serde(rename = "Probes", skip_serializing_if = "Option::is_none")]
+    pub probes: Option<i64>,
+    /// Retransmission timeout (microseconds).
+    #[serde(rename = "RTO", skip_serializing_if = "Option::is_none")]
+    pub rto: Option<Micros>,
     /// Smoothed round-trip time (microseconds).
     #[serde(rename = "RTT", skip_serializing_if = "Option::is_none")]
-    pub rtt: Option<i64>,
+    pub rtt: Option<Micros>,
     /// Round-trip time variance (microseconds).
     #[serde(rename = "RTTVar", skip_serializing_if = "Option::is_none")]
-    pub rtt_var: Option<i64>,
+    pub rtt_var: Option<Micros>,
     /// Time (microseconds) limited by the receive window.
     #[serde(rename = "RWndLimited", skip_serializing_if = "Option::is_none")]
-    pub rwnd_limited: Option<i64>,
+    pub rwnd_limited: Option<Micros>,
+    /// Maximum segment size estimated for received data.
+    #[serde(rename = "RcvMSS", skip_serializing_if = "Option::is_none")]
+    pub rcv_mss: Option<i64>,
+    /// Out-of-order packets received.
+    #[serde(rename = "RcvOooPack", skip_serializing_if = "Option::is_none")]
+    pub rcv_ooopack: Option<i64>,
+    /// Round-trip time estimated by the receiver (microseconds).
+    #[serde(rename = "RcvRTT", skip_serializing_if = "Option::is_none")]
+    pub rcv_rtt: Option<Micros>,
+    /// Receive buffer space the receiver auto-tuning aims for.
+    #[serde(rename = "RcvSpace", skip_serializing_if = "Option::is_none")]
+    pub rcv_space: Option<i64>,
+    /// Current receive window clamp.
+    #[serde(rename = "RcvSsThresh", skip_serializing_if = "Option::is_none")]
+    pub rcv_ssthresh: Option<i64>,
+    /// Reordering events seen.
+    #[serde(rename = "ReordSeen", skip_serializing_if = "Option::is_none")]
+    pub reord_seen: Option<i64>,
+    /// Reordering degree, in segments.
+    #[serde(rename = "Reordering", skip_serializing_if = "Option::is_none")]
+    pub reordering: Option<i64>,
+    /// Segments currently retransmitted and not yet acknowledged.
+    #[serde(rename = "Retrans", skip_serializing_if = "Option::is_none")]
+    pub retrans: Option<i64>,
+    /// Consecutive retransmission timeouts of the current segment.
+    #[serde(rename = "Retransmits", skip_serializing_if = "Option::is_none")]
+    pub retransmits: Option<i64>,
+    /// Segments received.
+    #[serde(rename = "SegsIn", skip_serializing_if = "Option::is_none")]
+    pub segs_in: Option<i64>,
+    /// Segments sent.
+    #[serde(rename = "SegsOut", skip_serializing_if = "Option::is_none")]
+    pub segs_out: Option<i64>,
     /// Time (microseconds) limited by the send buffer.
     #[serde(rename = "SndBufLimited", skip_serializing_if = "Option::is_none")]
-    pub snd_buf_limited: Option<i64>,
+    pub snd_buf_limited: Option<Micros>,
+    /// Congestion window, in segments.
+    #[serde(rename = "SndCwnd", skip_serializing_if = "Option::is_none")]
+    pub snd_cwnd: Option<i64>,
+    /// Maximum segment size for sending.
+    #[serde(rename = "SndMSS", skip_serializing_if = "Option::is_none")]
+    pub snd_mss: Option<i64>,
+    /// Slow start threshold, in segments.
+    #[serde(rename = "SndSsThresh", skip_serializing_if = "Option::is_none")]
+    pub snd_ssthresh: Option<i64>,
+    /// Send window advertised by the peer.
+    #[serde(rename = "SndWnd", skip_serializing_if = "Option::is_none")]
+    pub snd_wnd: Option<i64>,
+    /// Segments selectively acknowledged by the peer.
+    #[serde(rename = "Sacked", skip_serializing_if = "Option::is_none")]
+    pub sacked: Option<i64>,
+    /// TCP connection state, as in the kernel's `TCP_ESTABLISHED` etc.
+    #[serde(rename = "State", skip_serializing_if = "Option::is_none")]
+    pub state: Option<i64>,
+    /// Total segments retransmitted over the connection.
+    #[serde(rename = "TotalRetrans", skip_serializing_if = "Option::is_none")]
+    pub total_retrans: Option<i64>,
+    /// Segments sent but not yet acknowledged.
+    #[serde(rename = "Unacked", skip_serializing_if = "Option::is_none")]
+    pub unacked: Option<i64>,
+    /// Window scale factors: send in the low, receive in the high 4 bits.
+    #[serde(rename = "WScale", skip_serializing_if = "Option::is_none")]
+    pub wscale: Option<i64>,
+    /// Fields not known to this version of the crate, kept so that
+    /// re-serializing the struct preserves them.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// State of the BBR congestion control algorithm, reported by servers whose
+/// connection uses BBR.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BBRInfo {
+    /// Bandwidth estimate (max-filtered delivery rate) in bytes per second.
+    #[serde(rename = "BW", skip_serializing_if = "Option::is_none")]
+    pub bw: Option<i64>,
+    /// BBR's minimum round-trip time estimate (microseconds).
+    #[serde(rename = "MinRTT", skip_serializing_if = "Option::is_none")]
+    pub min_rtt: Option<Micros>,
+    /// Pacing gain, shifted left by 8 bits.
+    #[serde(rename = "PacingGain", skip_serializing_if = "Option::is_none")]
+    pub pacing_gain: Option<i64>,
+    /// Congestion window gain, shifted left by 8 bits.
+    #[serde(rename = "CwndGain", skip_serializing_if = "Option::is_none")]
+    pub cwnd_gain: Option<i64>,
+    /// Microseconds elapsed since the start of the test.
+    #[serde(rename = "ElapsedTime", skip_serializing_if = "Option::is_none")]
+    pub elapsed_time: Option<Micros>,
+    /// Fields not known to this version of the crate, kept so that
+    /// re-serializing the struct preserves them.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
 /// A single measurement message exchanged during an ndt7 test.
 ///
 /// Both the server and client produce measurements. Server measurements
 /// include [`TCPInfo`] from the kernel; client measurements include
-/// [`AppInfo`] with application-level byte counts.
+/// [`AppInfo`] with application-level byte counts, plus the client kernel's
+/// [`TCPInfo`] on supported platforms.
 #[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct Measurement {
     /// Application-level throughput counters.
     #[serde(rename = "AppInfo", skip_serializing_if = "Option::is_none")]
     pub app_info: Option<AppInfo>,
+    /// BBR congestion control state, in server measurements of connections
+    /// using BBR.
+    #[serde(rename = "BBRInfo", skip_serializing_if = "Option::is_none")]
+    pub bbr_info: Option<BBRInfo>,
     /// Connection endpoint addresses.
     #[serde(rename = "ConnectionInfo", skip_serializing_if = "Option::is_none")]
     pub connection_info: Option<ConnectionInfo>,
@@ -113,6 +407,19 @@ pub struct Measurement {
     /// TCP-level metrics from the kernel.
     #[serde(rename = "TCPInfo", skip_serializing_if = "Option::is_none")]
     pub tcp_info: Option<TCPInfo>,
+    /// Wall-clock time the client took this measurement, as an RFC 3339 UTC
+    /// timestamp. Only set when enabled with
+    /// [`TestParams::utc_timestamps`](crate::params::TestParams::utc_timestamps).
+    #[serde(rename = "UTCTime", skip_serializing_if = "Option::is_none")]
+    pub utc_time: Option<String>,
+    /// Round trip of a client WebSocket ping, in client measurements that
+    /// report one.
+    #[serde(rename = "WSPingInfo", skip_serializing_if = "Option::is_none")]
+    pub ws_ping_info: Option<WSPingInfo>,
+    /// Fields not known to this version of the crate, kept so that
+    /// re-serializing the struct preserves them.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
 #[cfg(test)]
@@ -150,13 +457,14 @@ mod tests {
             "ConnectionInfo": {"Client": "1.2.3.4:5678", "Server": "[::1]:2345", "UUID": "abc-1234"},
             "Origin": "server",
             "Test": "download",
-            "TCPInfo": {"RTT": 6000, "MinRTT": 5000}
+            "TCPInfo": {"RTT": 6000, "MinRTT": 5000},
+            "BBRInfo": {"BW": 12500000, "MinRTT": 4800, "PacingGain": 256, "CwndGain": 512}
         }"#;
         let m: Measurement = serde_json::from_str(json).unwrap();
 
         let app = m.app_info.unwrap();
-        assert_eq!(app.elapsed_time, 1234);
-        assert_eq!(app.num_bytes, 5678);
+        assert_eq!(app.elapsed_time, Micros(1234));
+        assert_eq!(app.num_bytes, ByteCount(5678));
 
         let con_info = m.connection_info.unwrap();
         let uuid = con_info.uuid.unwrap();
@@ -173,32 +481,113 @@ mod tests {
         let tcp_info = m.tcp_info.unwrap();
         let rtt = tcp_info.rtt.unwrap();
         let min_rtt = tcp_info.min_rtt.unwrap();
-        assert_eq!(rtt, 6000);
-        assert_eq!(min_rtt, 5000);
+        assert_eq!(rtt.as_duration(), Duration::from_millis(6));
+        assert_eq!(min_rtt.as_millis_f64(), 5.0);
+
+        let bbr_info = m.bbr_info.unwrap();
+        assert_eq!(bbr_info.bw, Some(12_500_000));
+        assert_eq!(bbr_info.min_rtt, Some(Micros(4800)));
+        assert_eq!(bbr_info.pacing_gain, Some(256));
+        assert_eq!(bbr_info.cwnd_gain, Some(512));
+    }
+
+    #[test]
+    fn deserialize_extended_tcp_info() {
+        let json = r#"{
+            "TCPInfo": {
+                "State": 1, "CAState": 0, "Retransmits": 0, "WScale": 119,
+                "AdvMSS": 1448, "SndCwnd": 42, "Lost": 3, "PacingRate": 15000000,
+                "Delivered": 10000, "DeliveredCE": 12, "SndWnd": 65535
+            }
+        }"#;
+        let tcp_info = serde_json::from_str::<Measurement>(json)
+            .unwrap()
+            .tcp_info
+            .unwrap();
+        assert_eq!(tcp_info.state, Some(1));
+        assert_eq!(tcp_info.ca_state, Some(0));
+        assert_eq!(tcp_info.wscale, Some(119));
+        assert_eq!(tcp_info.adv_mss, Some(1448));
+        assert_eq!(tcp_info.snd_cwnd, Some(42));
+        assert_eq!(tcp_info.lost, Some(3));
+        assert_eq!(tcp_info.pacing_rate, Some(15_000_000));
+        assert_eq!(tcp_info.delivered, Some(10_000));
+        assert_eq!(tcp_info.delivered_ce, Some(12));
+        assert_eq!(tcp_info.snd_wnd, Some(65_535));
+        assert_eq!(tcp_info.rtt, None);
+    }
+
+    #[test]
+    fn preserves_unknown_fields() {
+        let json = r#"{"AppInfo":{"ElapsedTime":1,"NumBytes":2,"Extra":[3]},"TCPInfo":{"RTT":4,"NewField":5},"Future":{"A":"b"}}"#;
+        let m: Measurement = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            m.app_info.as_ref().unwrap().extra["Extra"],
+            serde_json::json!([3])
+        );
+        assert_eq!(m.tcp_info.as_ref().unwrap().extra["NewField"], 5);
+        assert_eq!(m.extra["Future"]["A"], "b");
+
+        let reserialized: Value = serde_json::to_value(&m).unwrap();
+        assert_eq!(reserialized, serde_json::from_str::<Value>(json).unwrap());
+    }
+
+    #[test]
+    fn unit_conversions() {
+        let app: AppInfo =
+            serde_json::from_str(r#"{"ElapsedTime":2000000,"NumBytes":25000000}"#).unwrap();
+        assert_eq!(app.elapsed_time.as_duration(), Duration::from_secs(2));
+        assert_eq!(app.num_bytes.mbps_over(app.elapsed_time), Some(100.0));
+        assert_eq!(app.num_bytes.mbps_over(Micros(0)), None);
+        assert_eq!(Micros::from(Duration::from_millis(3)), Micros(3000));
+        assert_eq!(Micros(-5).as_duration(), Duration::ZERO);
+        // Serialized as plain integers.
+        assert_eq!(
+            serde_json::to_string(&app).unwrap(),
+            r#"{"ElapsedTime":2000000,"NumBytes":25000000}"#
+        );
     }
 
     #[test]
     fn round_trip() {
         let m = Measurement {
             app_info: Some(AppInfo {
-                elapsed_time: 500_000,
-                num_bytes: 1_048_576,
+                elapsed_time: Micros(500_000),
+                num_bytes: ByteCount(1_048_576),
+                wire_bytes: Some(ByteCount(1_090_000)),
+                ..Default::default()
+            }),
+            bbr_info: Some(BBRInfo {
+                bw: Some(12_500_000),
+                min_rtt: Some(Micros(7_900)),
+                ..Default::default()
             }),
             connection_info: Some(ConnectionInfo {
                 client: "10.0.0.1:12345".into(),
                 server: "10.0.0.2:443".into(),
                 uuid: Some("test-uuid".into()),
                 start_time: Some("2026-02-23T13:05:00.000000000Z".into()),
+                ..Default::default()
             }),
             origin: Some(Origin::Server),
             test: Some(TestKind::Upload),
             tcp_info: Some(TCPInfo {
-                rtt: Some(10_000),
-                min_rtt: Some(8_000),
+                rtt: Some(Micros(10_000)),
+                min_rtt: Some(Micros(8_000)),
+                ..Default::default()
+            }),
+            utc_time: Some(utc_timestamp(
+                UNIX_EPOCH + Duration::from_micros(1_709_210_096_250_000),
+            )),
+            ws_ping_info: Some(WSPingInfo {
+                elapsed_time: Micros(250_000),
+                rtt: Micros(12_000),
                 ..Default::default()
             }),
+            extra: Map::from_iter([("Future".to_string(), Value::from(1))]),
         };
 
+        assert_eq!(m.utc_time.as_deref(), Some("2024-02-29T12:34:56.250000Z"));
         let json = serde_json::to_string(&m).unwrap();
         let deserialized: Measurement = serde_json::from_str(&json).unwrap();
         assert_eq!(m, deserialized);
@@ -1,28 +1,192 @@
 //! Post-test summary computation.
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-use crate::spec::Measurement;
+use crate::client::ConnectInfo;
+use crate::error::{Ndt7Error, Result};
+use crate::grade::{Grade, Thresholds};
+use crate::host::HostTuning;
+use crate::latency::LatencyResult;
+use crate::locate::Location;
+use crate::metrics::{
+    MeasurementLog, TestLog, average_mbps, jitter, percentile, retransmission_pct,
+};
+use crate::spec::{Measurement, Micros, TestKind};
+
+/// Width of the intervals of [`SubtestSummary::throughput_series_mbps`].
+pub const SERIES_STEP: Micros = Micros(500_000);
+
+/// How a subtest that started ended, for [`Summary::from_run`].
+#[derive(Debug, Clone)]
+pub struct SubtestOutcome {
+    /// Details of the connection, see [`SubtestSummary::connect_info`].
+    pub connect_info: ConnectInfo,
+    /// Whether the subtest ended without an error, see
+    /// [`SubtestSummary::complete`].
+    pub complete: bool,
+}
 
 /// Results for a single subtest (download or upload).
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct SubtestSummary {
     /// Throughput in megabits per second.
     pub throughput_mbps: f64,
+    /// Payload bytes transferred.
+    #[serde(default)]
+    pub bytes_transferred: i64,
+    /// Time the subtest transferred data for, in seconds.
+    #[serde(rename = "DurationS", default)]
+    pub duration_s: f64,
+    /// Upload throughput from the client's own counts of bytes sent, in
+    /// megabits per second. The server's counts are authoritative; this is
+    /// what the client saw, and the fallback for [`Self::throughput_mbps`]
+    /// if no server measurement arrived.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_throughput_mbps: Option<f64>,
+    /// Estimated throughput on the wire, including framing and TCP/IP
+    /// overhead, if the client accounted for it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wire_throughput_mbps: Option<f64>,
+    /// Lowest throughput between consecutive measurements, in megabits per
+    /// second, if the full series was available. See [`Summary::from_log`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_throughput_mbps: Option<f64>,
+    /// Throughput between the first and last measurements, in megabits per
+    /// second, if the full series was available.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avg_throughput_mbps: Option<f64>,
+    /// Highest throughput between consecutive measurements, in megabits per
+    /// second, if the full series was available.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_throughput_mbps: Option<f64>,
+    /// Throughput over consecutive intervals of about [`SERIES_STEP`], in
+    /// megabits per second, if the full series was available.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub throughput_series_mbps: Vec<f64>,
     /// Minimum RTT in milliseconds (from server TCPInfo).
     pub latency_ms: f64,
-    /// Percentage of bytes retransmitted.
-    pub retransmission_pct: f64,
+    /// Smoothed RTT in milliseconds at the end of the subtest, with the
+    /// queues the transfer built up (from server TCPInfo).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub loaded_latency_ms: Option<f64>,
+    /// Loaded minus idle latency in milliseconds, a measure of bufferbloat,
+    /// if an idle baseline was taken. See [`Summary::set_idle_latency`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_increase_ms: Option<f64>,
+    /// Median of the smoothed RTTs reported during the subtest, in
+    /// milliseconds, if the full series was available.
+    #[serde(rename = "RTTp50Ms", skip_serializing_if = "Option::is_none")]
+    pub rtt_p50_ms: Option<f64>,
+    /// 95th percentile of the smoothed RTTs, in milliseconds.
+    #[serde(rename = "RTTp95Ms", skip_serializing_if = "Option::is_none")]
+    pub rtt_p95_ms: Option<f64>,
+    /// 99th percentile of the smoothed RTTs, in milliseconds.
+    #[serde(rename = "RTTp99Ms", skip_serializing_if = "Option::is_none")]
+    pub rtt_p99_ms: Option<f64>,
+    /// Smoothed RTT at peak throughput minus the first RTT reported in the
+    /// subtest, in milliseconds, a measure of bufferbloat that needs no idle
+    /// baseline. Only available from the full series.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bufferbloat_ms: Option<f64>,
+    /// Variation of the RTT in milliseconds: the mean difference between
+    /// consecutive smoothed RTTs reported during the subtest, or the
+    /// kernel's RTTVar of the final server measurement if the full series
+    /// was not available.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jitter_ms: Option<f64>,
+    /// Percentage of the server's bytes retransmitted (from server TCPInfo).
+    /// Only set for downloads: during an upload the server sends nothing
+    /// but acknowledgements and its counters say nothing about the data
+    /// path.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retransmission_pct: Option<f64>,
+    /// Percentage of the client's bytes retransmitted during an upload
+    /// (from client TCPInfo), an estimate of loss on the upload path. Only
+    /// available where the client can sample its own TCP statistics.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_retransmission_pct: Option<f64>,
+    /// Highest BBR bandwidth estimate in megabits per second over the
+    /// server's measurements (from BBRInfo), if the server's connection used
+    /// BBR. Only the final measurement is seen when summarizing final
+    /// measurements.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_bandwidth_mbps: Option<f64>,
+    /// UUID the server assigned to the subtest, for looking it up in the
+    /// M-Lab archive.
+    #[serde(rename = "UUID", skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<String>,
+    /// Details of the server's WebSocket upgrade response.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connect_info: Option<ConnectInfo>,
+    /// Whether the subtest ran to completion. If `false`, it failed mid-run
+    /// and the figures cover only the measurements taken before the error.
+    pub complete: bool,
+}
+
+/// Results of an msak latency1 UDP test.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct LatencySummary {
+    /// Percentage of the server's probes lost on the way to the client.
+    pub packet_loss_pct: f64,
+    /// Smallest round-trip time in milliseconds.
+    #[serde(rename = "MinRTTMs")]
+    pub min_rtt_ms: f64,
+    /// Median round-trip time in milliseconds.
+    #[serde(rename = "MedianRTTMs")]
+    pub median_rtt_ms: f64,
+    /// 95th percentile round-trip time in milliseconds.
+    #[serde(rename = "P95RTTMs")]
+    pub p95_rtt_ms: f64,
+    /// Largest round-trip time in milliseconds.
+    #[serde(rename = "MaxRTTMs")]
+    pub max_rtt_ms: f64,
+}
+
+impl LatencySummary {
+    /// Summarize a latency1 test, or `None` if it measured no round trips.
+    pub fn from_result(result: &LatencyResult) -> Option<LatencySummary> {
+        Some(LatencySummary {
+            packet_loss_pct: result.loss_pct(),
+            min_rtt_ms: result.percentile_ms(0.0)?,
+            median_rtt_ms: result.percentile_ms(50.0)?,
+            p95_rtt_ms: result.percentile_ms(95.0)?,
+            max_rtt_ms: result.percentile_ms(100.0)?,
+        })
+    }
+}
+
+/// Geographic location of the server of a [`Summary`]: a [`Location`] with
+/// the summary's PascalCase keys.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ServerLocation {
+    /// City where the server is located (e.g. "Tokyo").
+    pub city: String,
+    /// Country where the server is located (e.g. "JP").
+    pub country: String,
+}
+
+impl From<Location> for ServerLocation {
+    fn from(location: Location) -> Self {
+        ServerLocation {
+            city: location.city,
+            country: location.country,
+        }
+    }
 }
 
 /// Aggregated results for an entire speed test session.
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct Summary {
     /// FQDN of the M-Lab server used.
     #[serde(rename = "ServerFQDN")]
     pub server_fqdn: String,
+    /// Location of the server, if it was found through the Locate API.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_location: Option<ServerLocation>,
     /// Client IP address as seen by the server.
     #[serde(rename = "ClientIP")]
     pub client_ip: String,
@@ -33,64 +197,220 @@ pub struct Summary {
     pub download: Option<SubtestSummary>,
     /// Upload subtest results, if an upload test was run.
     pub upload: Option<SubtestSummary>,
+    /// Round-trip time in milliseconds measured before the tests, while the
+    /// connection was idle.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub idle_latency_ms: Option<f64>,
+    /// Suitability of the connection for common uses, if graded. See
+    /// [`Summary::set_grade`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub grade: Option<Grade>,
+    /// UDP latency and packet loss, if a latency1 test was run. See
+    /// [`Summary::set_latency`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency: Option<LatencySummary>,
+    /// DSCP class the test traffic was marked with, if any.
+    #[serde(rename = "DSCP", skip_serializing_if = "Option::is_none")]
+    pub dscp: Option<u8>,
+    /// Host TCP settings, if they were inspected.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host_tuning: Option<HostTuning>,
+    /// Throughput of other traffic on the host measured just before the
+    /// run, in Mbit/s, if it was sampled.
+    #[serde(rename = "BackgroundMbps", skip_serializing_if = "Option::is_none")]
+    pub background_mbps: Option<f64>,
+    /// Whether other traffic above [`crate::host::CONTENTION_THRESHOLD_MBPS`]
+    /// competed with the run, so results may understate the link capacity.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub contended: bool,
+    /// Whether the run was cut short by a deadline, leaving results partial.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+    /// Changes from a previous result, if compared with one. See
+    /// [`Summary::set_previous`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comparison: Option<Comparison>,
 }
 
 impl SubtestSummary {
     /// Build download summary: throughput from client AppInfo, latency/retransmission from server TCPInfo.
     pub fn from_download(client: &Measurement, server: &Measurement) -> Option<SubtestSummary> {
         let app = client.app_info.as_ref()?;
-        if app.elapsed_time <= 0 {
-            return None;
-        }
-        let throughput_mbps = 8.0 * app.num_bytes as f64 / app.elapsed_time as f64;
+        let throughput_mbps = average_mbps(TestKind::Download, client)?;
+        let wire_throughput_mbps = app
+            .wire_bytes
+            .and_then(|wire| wire.mbps_over(app.elapsed_time));
 
         let tcp = server.tcp_info.as_ref();
-        let latency_ms = tcp.and_then(|t| t.min_rtt).unwrap_or(0) as f64 / 1000.0;
-
-        let bytes_sent = tcp.and_then(|t| t.bytes_sent).unwrap_or(0) as f64;
-        let bytes_retrans = tcp.and_then(|t| t.bytes_retrans).unwrap_or(0) as f64;
-        let retransmission_pct = if bytes_sent > 0.0 {
-            bytes_retrans / bytes_sent * 100.0
-        } else {
-            0.0
-        };
+        let latency_ms = tcp
+            .and_then(|t| t.min_rtt)
+            .unwrap_or_default()
+            .as_millis_f64();
+        let loaded_latency_ms = tcp.and_then(|t| t.rtt).map(Micros::as_millis_f64);
+        let jitter_ms = tcp.and_then(|t| t.rtt_var).map(Micros::as_millis_f64);
+        let retransmission_pct = tcp.map(retransmission_pct);
 
         Some(SubtestSummary {
             throughput_mbps,
+            client_throughput_mbps: None,
+            bytes_transferred: app.num_bytes.0,
+            duration_s: app.elapsed_time.as_secs_f64(),
+            wire_throughput_mbps,
+            min_throughput_mbps: None,
+            avg_throughput_mbps: None,
+            max_throughput_mbps: None,
+            throughput_series_mbps: Vec::new(),
             latency_ms,
+            loaded_latency_ms,
+            latency_increase_ms: None,
+            rtt_p50_ms: None,
+            rtt_p95_ms: None,
+            rtt_p99_ms: None,
+            bufferbloat_ms: None,
+            jitter_ms,
             retransmission_pct,
+            client_retransmission_pct: None,
+            max_bandwidth_mbps: bbr_bandwidth_mbps(server),
+            uuid: uuid(server),
+            connect_info: None,
+            complete: true,
         })
     }
 
     /// Build upload summary: throughput/latency/retransmission all from server TCPInfo.
     pub fn from_upload(server: &Measurement) -> Option<SubtestSummary> {
         let tcp = server.tcp_info.as_ref()?;
-        let elapsed = tcp.elapsed_time? as f64;
-        if elapsed <= 0.0 {
-            return None;
-        }
+        let throughput_mbps = average_mbps(TestKind::Upload, server)?;
+        let latency_ms = tcp.min_rtt.unwrap_or_default().as_millis_f64();
+        let loaded_latency_ms = tcp.rtt.map(Micros::as_millis_f64);
+        let jitter_ms = tcp.rtt_var.map(Micros::as_millis_f64);
 
-        let throughput_mbps = 8.0 * tcp.bytes_received.unwrap_or(0) as f64 / elapsed;
-        let latency_ms = tcp.min_rtt.unwrap_or(0) as f64 / 1000.0;
+        Some(SubtestSummary {
+            throughput_mbps,
+            client_throughput_mbps: None,
+            bytes_transferred: tcp.bytes_received.unwrap_or_default().0,
+            duration_s: tcp.elapsed_time.unwrap_or_default().as_secs_f64(),
+            wire_throughput_mbps: None,
+            min_throughput_mbps: None,
+            avg_throughput_mbps: None,
+            max_throughput_mbps: None,
+            throughput_series_mbps: Vec::new(),
+            latency_ms,
+            loaded_latency_ms,
+            latency_increase_ms: None,
+            rtt_p50_ms: None,
+            rtt_p95_ms: None,
+            rtt_p99_ms: None,
+            bufferbloat_ms: None,
+            jitter_ms,
+            retransmission_pct: None,
+            client_retransmission_pct: None,
+            max_bandwidth_mbps: bbr_bandwidth_mbps(server),
+            uuid: uuid(server),
+            connect_info: None,
+            complete: true,
+        })
+    }
 
-        let bytes_sent = tcp.bytes_sent.unwrap_or(0) as f64;
-        let bytes_retrans = tcp.bytes_retrans.unwrap_or(0) as f64;
-        let retransmission_pct = if bytes_sent > 0.0 {
-            bytes_retrans / bytes_sent * 100.0
-        } else {
-            0.0
-        };
+    /// Build upload summary from the client's measurement alone, for when no
+    /// server measurement arrived: throughput from client AppInfo,
+    /// latency/retransmission from the client's TCPInfo where available.
+    pub fn from_upload_client(client: &Measurement) -> Option<SubtestSummary> {
+        let app = client.app_info.as_ref()?;
+        let throughput_mbps = app.num_bytes.mbps_over(app.elapsed_time)?;
+        let tcp = client.tcp_info.as_ref();
 
         Some(SubtestSummary {
             throughput_mbps,
-            latency_ms,
-            retransmission_pct,
+            client_throughput_mbps: Some(throughput_mbps),
+            bytes_transferred: app.num_bytes.0,
+            duration_s: app.elapsed_time.as_secs_f64(),
+            wire_throughput_mbps: None,
+            min_throughput_mbps: None,
+            avg_throughput_mbps: None,
+            max_throughput_mbps: None,
+            throughput_series_mbps: Vec::new(),
+            latency_ms: tcp
+                .and_then(|t| t.min_rtt)
+                .unwrap_or_default()
+                .as_millis_f64(),
+            loaded_latency_ms: tcp.and_then(|t| t.rtt).map(Micros::as_millis_f64),
+            latency_increase_ms: None,
+            rtt_p50_ms: None,
+            rtt_p95_ms: None,
+            rtt_p99_ms: None,
+            bufferbloat_ms: None,
+            jitter_ms: tcp.and_then(|t| t.rtt_var).map(Micros::as_millis_f64),
+            retransmission_pct: None,
+            client_retransmission_pct: client_retransmission_pct(client),
+            max_bandwidth_mbps: None,
+            uuid: None,
+            connect_info: None,
+            complete: true,
         })
     }
+
+    /// Take the throughput of `test` from its full series in `log` rather
+    /// than from the final measurements, and add its interval statistics
+    /// RTT percentiles and peak BBR bandwidth.
+    fn set_series(&mut self, test: TestKind, log: &TestLog) {
+        if let Some((bytes, elapsed)) = log.transferred(test) {
+            self.bytes_transferred = bytes.0;
+            self.duration_s = elapsed.as_secs_f64();
+            self.throughput_mbps = bytes.mbps_over(elapsed).unwrap_or_default();
+        }
+        if let Some(stats) = log.interval_stats(test) {
+            self.min_throughput_mbps = Some(stats.min_mbps);
+            self.avg_throughput_mbps = Some(stats.avg_mbps);
+            self.max_throughput_mbps = Some(stats.max_mbps);
+        }
+        self.throughput_series_mbps = log.throughput_series(test, SERIES_STEP);
+        let rtt_ms = log.rtt_ms();
+        self.rtt_p50_ms = percentile(&rtt_ms, 50.0);
+        self.rtt_p95_ms = percentile(&rtt_ms, 95.0);
+        self.rtt_p99_ms = percentile(&rtt_ms, 99.0);
+        self.bufferbloat_ms = log.bufferbloat_ms(test);
+        if let Some(uuid) = log.connection_info().and_then(|c| c.uuid.clone()) {
+            self.uuid = Some(uuid);
+        }
+        if let Some(jitter_ms) = jitter(&rtt_ms) {
+            self.jitter_ms = Some(jitter_ms);
+        }
+        if let Some(max) = log
+            .server
+            .iter()
+            .filter_map(bbr_bandwidth_mbps)
+            .reduce(f64::max)
+        {
+            self.max_bandwidth_mbps = Some(max);
+        }
+    }
+
+    /// Retransmissions on the subtest's data path: the server's for a
+    /// download, the client's for an upload.
+    pub fn data_retransmission_pct(&self) -> Option<f64> {
+        self.retransmission_pct.or(self.client_retransmission_pct)
+    }
+
+    /// Set the wire throughput of an upload from the client's final
+    /// measurement, scaling the throughput by its ratio of wire to payload
+    /// bytes. Does nothing if the client did not account for overhead.
+    pub fn set_upload_wire(&mut self, client: &Measurement) {
+        let Some(app) = client.app_info.as_ref() else {
+            return;
+        };
+        if let Some(wire) = app.wire_bytes.filter(|_| app.num_bytes.0 > 0) {
+            self.wire_throughput_mbps =
+                Some(self.throughput_mbps * wire.0 as f64 / app.num_bytes.0 as f64);
+        }
+    }
 }
 
 impl Summary {
     /// Compute a summary from the final measurements of each subtest.
+    ///
+    /// The measurements may be the last ones received before a subtest
+    /// failed; mark such subtests with [`SubtestSummary::complete`].
     pub fn from_measurements(
         server_fqdn: String,
         dl_client: Option<&Measurement>,
@@ -110,12 +430,324 @@ impl Summary {
 
         Summary {
             server_fqdn,
+            server_location: None,
             client_ip,
             server_ip,
             download,
             upload: ul_server.and_then(SubtestSummary::from_upload),
+            idle_latency_ms: None,
+            grade: None,
+            latency: None,
+            dscp: None,
+            host_tuning: None,
+            background_mbps: None,
+            contended: false,
+            truncated: false,
+            comparison: None,
+        }
+    }
+
+    /// Compute a summary from the full series of each subtest in `log`.
+    ///
+    /// Throughput is taken from the valid measurement with the latest
+    /// elapsed time, so a server measurement that arrived out of order does
+    /// not skew it, and each subtest gets min/avg/max throughput over the
+    /// intervals between measurements. Latency and retransmissions come from
+    /// the last server measurement, like [`Summary::from_measurements`]. An
+    /// upload without server measurements is summarized from the client's,
+    /// see [`SubtestSummary::from_upload_client`].
+    pub fn from_log(server_fqdn: String, log: &MeasurementLog) -> Summary {
+        Summary::from_run(server_fqdn, log, None, None)
+    }
+
+    /// Compute a summary from the measurements of a run, like
+    /// [`Summary::from_log`], recording how the `download` and `upload`
+    /// subtests that started ended.
+    ///
+    /// The summary is not graded; call [`Summary::set_grade`] once the other
+    /// figures are set.
+    pub fn from_run(
+        server_fqdn: String,
+        log: &MeasurementLog,
+        download: Option<SubtestOutcome>,
+        upload: Option<SubtestOutcome>,
+    ) -> Summary {
+        let mut summary = Summary::from_measurements(
+            server_fqdn,
+            log.download.last_client(),
+            log.download.last_server(),
+            log.upload.last_server(),
+        );
+        if summary.client_ip.is_empty()
+            && let Some(conn) = log
+                .download
+                .connection_info()
+                .or(log.upload.connection_info())
+        {
+            summary.client_ip = strip_port(&conn.client);
+            summary.server_ip = strip_port(&conn.server);
+        }
+        if let Some(dl) = summary.download.as_mut() {
+            dl.set_series(TestKind::Download, &log.download);
+        }
+        if summary.upload.is_none() {
+            summary.upload = log
+                .upload
+                .last_client()
+                .and_then(SubtestSummary::from_upload_client);
+        }
+        if let Some(ul) = summary.upload.as_mut() {
+            ul.set_series(TestKind::Upload, &log.upload);
+            if let Some(client) = log.upload.last_client() {
+                ul.client_throughput_mbps = client
+                    .app_info
+                    .as_ref()
+                    .and_then(|app| app.num_bytes.mbps_over(app.elapsed_time));
+                ul.client_retransmission_pct = client_retransmission_pct(client);
+                ul.set_upload_wire(client);
+            }
+        }
+        for (subtest, outcome) in [
+            (summary.download.as_mut(), download),
+            (summary.upload.as_mut(), upload),
+        ] {
+            if let (Some(subtest), Some(outcome)) = (subtest, outcome) {
+                subtest.connect_info = Some(outcome.connect_info);
+                subtest.complete = outcome.complete;
+            }
         }
+        summary
     }
+
+    /// Grade the results against `thresholds`. Call once the other figures
+    /// are final.
+    pub fn set_grade(&mut self, thresholds: &Thresholds) {
+        self.grade = Grade::from_summary(self, thresholds);
+    }
+
+    /// Compare the results with a `previous` summary and record the
+    /// changes in [`Summary::comparison`].
+    pub fn set_previous(&mut self, previous: &Summary) {
+        self.comparison = Some(Comparison::new(self, previous));
+    }
+
+    /// Read a summary written by the JSON emitter: a bare summary, a
+    /// `Summary` event, or JSON Lines of events, of which the last summary
+    /// is taken.
+    pub fn from_json(text: &str) -> Result<Summary> {
+        #[derive(Deserialize)]
+        struct Event {
+            #[serde(rename = "Summary")]
+            summary: Summary,
+        }
+        let parse = |line: &str| {
+            serde_json::from_str::<Event>(line)
+                .map(|e| e.summary)
+                .or_else(|_| serde_json::from_str::<Summary>(line))
+        };
+        if let Ok(summary) = parse(text) {
+            return Ok(summary);
+        }
+        text.lines()
+            .rev()
+            .find_map(|line| parse(line).ok())
+            .ok_or_else(|| serde::de::Error::custom("no summary found"))
+            .map_err(Ndt7Error::JsonError)
+    }
+
+    /// Record the idle round-trip time measured before the tests and derive
+    /// each subtest's [`SubtestSummary::latency_increase_ms`] from it.
+    pub fn set_idle_latency(&mut self, idle_ms: f64) {
+        self.idle_latency_ms = Some(idle_ms);
+        for subtest in [&mut self.download, &mut self.upload].into_iter().flatten() {
+            subtest.latency_increase_ms = subtest.loaded_latency_ms.map(|loaded| loaded - idle_ms);
+        }
+    }
+
+    /// Record the results of a latency1 test run alongside the throughput
+    /// tests.
+    pub fn set_latency(&mut self, result: &LatencyResult) {
+        self.latency = LatencySummary::from_result(result);
+    }
+}
+
+/// Change of a figure from a previous result.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Delta {
+    /// The previous value.
+    pub previous: f64,
+    /// The current value.
+    pub current: f64,
+    /// Current minus previous value.
+    pub change: f64,
+    /// Change relative to the previous value, in percent, or `None` if the
+    /// previous value was zero.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub change_pct: Option<f64>,
+}
+
+impl Delta {
+    /// The change from `previous` to `current`.
+    pub fn new(previous: f64, current: f64) -> Self {
+        let change = current - previous;
+        Delta {
+            previous,
+            current,
+            change,
+            change_pct: (previous != 0.0).then(|| change / previous * 100.0),
+        }
+    }
+}
+
+/// Changes of a subtest's results from a previous run.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct SubtestDelta {
+    /// Change of [`SubtestSummary::throughput_mbps`].
+    pub throughput_mbps: Delta,
+    /// Change of [`SubtestSummary::latency_ms`].
+    pub latency_ms: Delta,
+    /// Change of [`SubtestSummary::data_retransmission_pct`], if both runs
+    /// reported it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retransmission_pct: Option<Delta>,
+}
+
+impl SubtestDelta {
+    fn new(current: &SubtestSummary, previous: &SubtestSummary) -> Self {
+        SubtestDelta {
+            throughput_mbps: Delta::new(previous.throughput_mbps, current.throughput_mbps),
+            latency_ms: Delta::new(previous.latency_ms, current.latency_ms),
+            retransmission_pct: previous
+                .data_retransmission_pct()
+                .zip(current.data_retransmission_pct())
+                .map(|(p, c)| Delta::new(p, c)),
+        }
+    }
+}
+
+/// Changes of the results from a previous run, for subtests present in
+/// both.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Comparison {
+    /// Download changes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub download: Option<SubtestDelta>,
+    /// Upload changes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upload: Option<SubtestDelta>,
+}
+
+impl Comparison {
+    /// Compare `current` with `previous`.
+    pub fn new(current: &Summary, previous: &Summary) -> Self {
+        let delta = |c: &Option<SubtestSummary>, p: &Option<SubtestSummary>| {
+            Some(SubtestDelta::new(c.as_ref()?, p.as_ref()?))
+        };
+        Comparison {
+            download: delta(&current.download, &previous.download),
+            upload: delta(&current.upload, &previous.upload),
+        }
+    }
+}
+
+/// A value with its unit, as in ndt7-client-go's summary.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ValueUnitPair {
+    /// The value.
+    pub value: f64,
+    /// Unit of the value, e.g. `Mbit/s`.
+    pub unit: &'static str,
+}
+
+impl ValueUnitPair {
+    fn new(value: f64, unit: &'static str) -> Self {
+        ValueUnitPair { value, unit }
+    }
+}
+
+/// Subtest results in ndt7-client-go's summary structure.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct GoSubtestSummary {
+    /// Test UUID assigned by the server, or empty if unknown.
+    #[serde(rename = "UUID")]
+    pub uuid: String,
+    /// Throughput in Mbit/s.
+    pub throughput: ValueUnitPair,
+    /// Minimum RTT in ms.
+    pub latency: ValueUnitPair,
+    /// Percentage of bytes retransmitted.
+    pub retransmission: ValueUnitPair,
+}
+
+impl From<&SubtestSummary> for GoSubtestSummary {
+    fn from(s: &SubtestSummary) -> Self {
+        GoSubtestSummary {
+            uuid: s.uuid.clone().unwrap_or_default(),
+            throughput: ValueUnitPair::new(s.throughput_mbps, "Mbit/s"),
+            latency: ValueUnitPair::new(s.latency_ms, "ms"),
+            retransmission: ValueUnitPair::new(
+                s.data_retransmission_pct().unwrap_or_default(),
+                "%",
+            ),
+        }
+    }
+}
+
+/// A summary in the structure of ndt7-client-go's `-format=json` output,
+/// for tools that already parse it. Figures this crate adds are left out.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct GoSummary {
+    /// Client IP address as seen by the server.
+    #[serde(rename = "ClientIP")]
+    pub client_ip: String,
+    /// FQDN of the M-Lab server used.
+    #[serde(rename = "ServerFQDN")]
+    pub server_fqdn: String,
+    /// Server IP address.
+    #[serde(rename = "ServerIP")]
+    pub server_ip: String,
+    /// Download results, if a download test was run.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub download: Option<GoSubtestSummary>,
+    /// Upload results, if an upload test was run.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upload: Option<GoSubtestSummary>,
+}
+
+impl From<&Summary> for GoSummary {
+    fn from(s: &Summary) -> Self {
+        GoSummary {
+            client_ip: s.client_ip.clone(),
+            server_fqdn: s.server_fqdn.clone(),
+            server_ip: s.server_ip.clone(),
+            download: s.download.as_ref().map(GoSubtestSummary::from),
+            upload: s.upload.as_ref().map(GoSubtestSummary::from),
+        }
+    }
+}
+
+/// Retransmissions in the client's own TCPInfo in `m`, if it reports bytes
+/// sent.
+fn client_retransmission_pct(m: &Measurement) -> Option<f64> {
+    let tcp = m.tcp_info.as_ref().filter(|t| t.bytes_sent.is_some())?;
+    Some(retransmission_pct(tcp))
+}
+
+/// The test UUID reported in `m`, if any.
+fn uuid(m: &Measurement) -> Option<String> {
+    m.connection_info.as_ref()?.uuid.clone()
+}
+
+/// BBR's bandwidth estimate in `m` in Mbit/s.
+fn bbr_bandwidth_mbps(m: &Measurement) -> Option<f64> {
+    let bw = m.bbr_info.as_ref()?.bw?;
+    Some(bw as f64 * 8.0 / 1e6)
 }
 
 fn strip_port(addr: &str) -> String {
@@ -123,3 +755,211 @@ fn strip_port(addr: &str) -> String {
         .map(|a| a.ip().to_string())
         .unwrap_or_else(|_| addr.to_string())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spec::{AppInfo, BBRInfo, ByteCount, ConnectionInfo, Origin, TCPInfo};
+
+    #[test]
+    fn compare_with_json() {
+        let previous = r#"{"Type":"Starting","Test":"download"}
+{"Type":"Summary","Summary":{"ServerFQDN":"a","ClientIP":"","ServerIP":"","Download":{"ThroughputMbps":50.0,"LatencyMs":10.0,"RetransmissionPct":1.0,"Complete":true},"Upload":null}}
+"#;
+        let previous = Summary::from_json(previous).unwrap();
+        assert!(Summary::from_json("{}").is_err());
+
+        let mut current = previous.clone();
+        if let Some(dl) = current.download.as_mut() {
+            dl.throughput_mbps = 75.0;
+        }
+        current.set_previous(&previous);
+        let dl = current.comparison.unwrap().download.unwrap();
+        assert_eq!(dl.throughput_mbps.change, 25.0);
+        assert_eq!(dl.throughput_mbps.change_pct, Some(50.0));
+        assert_eq!(dl.latency_ms.change, 0.0);
+    }
+
+    #[test]
+    fn upload_falls_back_to_client() {
+        let mut log = MeasurementLog::new();
+        log.push(
+            TestKind::Upload,
+            Measurement {
+                origin: Some(Origin::Client),
+                app_info: Some(AppInfo {
+                    elapsed_time: Micros(2_000_000),
+                    num_bytes: ByteCount(5_000_000),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        );
+        let summary = Summary::from_log("a".into(), &log);
+        let ul = summary.upload.unwrap();
+        assert_eq!(ul.throughput_mbps, 20.0);
+        assert_eq!(ul.client_throughput_mbps, Some(20.0));
+    }
+
+    fn client(elapsed_ms: i64, bytes: i64) -> Measurement {
+        Measurement {
+            origin: Some(Origin::Client),
+            app_info: Some(AppInfo {
+                elapsed_time: Micros(elapsed_ms * 1000),
+                num_bytes: ByteCount(bytes),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn server(elapsed_ms: i64, rtt_ms: i64) -> Measurement {
+        Measurement {
+            origin: Some(Origin::Server),
+            tcp_info: Some(TCPInfo {
+                elapsed_time: Some(Micros(elapsed_ms * 1000)),
+                rtt: Some(Micros(rtt_ms * 1000)),
+                min_rtt: Some(Micros(10_000)),
+                bytes_sent: Some(ByteCount(4_000_000)),
+                bytes_retrans: Some(ByteCount(40_000)),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn from_log_takes_series() {
+        let mut log = MeasurementLog::new();
+        let mut first = server(500, 20);
+        first.connection_info = Some(ConnectionInfo {
+            client: "192.0.2.1:40000".into(),
+            server: "198.51.100.1:443".into(),
+            uuid: Some("ndt-abc_123".into()),
+            ..Default::default()
+        });
+        log.push(TestKind::Download, first);
+        log.push(TestKind::Download, client(1000, 1_250_000));
+        log.push(TestKind::Download, server(1500, 40));
+        log.push(TestKind::Download, client(3000, 6_250_000));
+        // Arrives last but was taken before the latest one.
+        log.push(TestKind::Download, client(2000, 5_000_000));
+        log.push(TestKind::Download, server(2500, 30));
+
+        let summary = Summary::from_log("a".into(), &log);
+        assert_eq!(summary.client_ip, "192.0.2.1");
+        assert_eq!(summary.server_ip, "198.51.100.1");
+        let dl = summary.download.unwrap();
+        assert_eq!(dl.bytes_transferred, 6_250_000);
+        assert_eq!(dl.duration_s, 3.0);
+        // 6.25 MB in 3 s, and 30 then 10 Mbit/s after the first second.
+        assert!((dl.throughput_mbps - 16.666).abs() < 0.001);
+        assert_eq!(dl.min_throughput_mbps, Some(10.0));
+        assert_eq!(dl.avg_throughput_mbps, Some(20.0));
+        assert_eq!(dl.max_throughput_mbps, Some(30.0));
+        assert_eq!(dl.rtt_p50_ms, Some(30.0));
+        assert_eq!(dl.rtt_p95_ms, Some(40.0));
+        assert_eq!(dl.rtt_p99_ms, Some(40.0));
+        assert_eq!(dl.jitter_ms, Some(15.0));
+        assert_eq!(dl.latency_ms, 10.0);
+        assert_eq!(dl.retransmission_pct, Some(1.0));
+        // Only the first server measurement carries the UUID.
+        assert_eq!(dl.uuid.as_deref(), Some("ndt-abc_123"));
+        assert!(summary.upload.is_none());
+    }
+
+    #[test]
+    fn max_bandwidth_over_series() {
+        let mut log = MeasurementLog::new();
+        for bw in [1_000_000, 5_000_000, 2_500_000] {
+            let mut m = server(1000, 20);
+            m.bbr_info = Some(BBRInfo {
+                bw: Some(bw),
+                ..Default::default()
+            });
+            log.push(TestKind::Download, m);
+        }
+        log.push(TestKind::Download, client(1000, 1_250_000));
+
+        let dl = Summary::from_log("a".into(), &log).download.unwrap();
+        // 5 MB/s, not the final 2.5 MB/s.
+        assert_eq!(dl.max_bandwidth_mbps, Some(40.0));
+    }
+
+    #[test]
+    fn upload_retransmissions_from_client() {
+        let mut log = MeasurementLog::new();
+        let mut ul = server(2000, 20);
+        let tcp = ul.tcp_info.as_mut().unwrap();
+        tcp.bytes_received = Some(ByteCount(5_000_000));
+        log.push(TestKind::Upload, ul);
+        let mut sent = client(2000, 5_000_000);
+        sent.tcp_info = Some(TCPInfo {
+            bytes_sent: Some(ByteCount(5_000_000)),
+            bytes_retrans: Some(ByteCount(100_000)),
+            ..Default::default()
+        });
+        log.push(TestKind::Upload, sent);
+
+        let ul = Summary::from_log("a".into(), &log).upload.unwrap();
+        assert_eq!(ul.throughput_mbps, 20.0);
+        assert_eq!(ul.bytes_transferred, 5_000_000);
+        assert_eq!(ul.duration_s, 2.0);
+        // The server's counters of an upload are left out.
+        assert_eq!(ul.retransmission_pct, None);
+        assert_eq!(ul.client_retransmission_pct, Some(2.0));
+        assert_eq!(ul.data_retransmission_pct(), Some(2.0));
+        assert_eq!(ul.uuid, None);
+    }
+
+    #[test]
+    fn server_location_round_trips() {
+        let mut log = MeasurementLog::new();
+        log.push(TestKind::Download, client(1000, 1_250_000));
+        log.push(TestKind::Download, server(1000, 20));
+        let mut summary = Summary::from_log("a".into(), &log);
+        assert_eq!(summary.server_location, None);
+        let json = serde_json::to_string(&summary).unwrap();
+        assert!(!json.contains("ServerLocation"));
+
+        summary.server_location = Some(ServerLocation::from(Location {
+            city: "Tokyo".into(),
+            country: "JP".into(),
+        }));
+        let json = serde_json::to_string(&summary).unwrap();
+        assert!(json.contains(r#""ServerLocation":{"City":"Tokyo","Country":"JP"}"#));
+        let parsed = Summary::from_json(&json).unwrap();
+        assert_eq!(parsed.server_location, summary.server_location);
+    }
+
+    #[test]
+    fn latency_from_latency1() {
+        let mut log = MeasurementLog::new();
+        log.push(TestKind::Download, client(1000, 1_250_000));
+        log.push(TestKind::Download, server(1000, 20));
+        let mut summary = Summary::from_log("a".into(), &log);
+        summary.set_latency(&LatencyResult {
+            server_fqdn: "b".into(),
+            packets_sent: 4,
+            packets_received: 3,
+            rtt_ms: vec![12.0, 10.0, 30.0],
+        });
+        let latency = summary.latency.as_ref().unwrap();
+        assert_eq!(latency.packet_loss_pct, 25.0);
+        assert_eq!(latency.min_rtt_ms, 10.0);
+        assert_eq!(latency.median_rtt_ms, 12.0);
+        assert_eq!(latency.p95_rtt_ms, 30.0);
+        assert_eq!(latency.max_rtt_ms, 30.0);
+        let json = serde_json::to_string(&summary).unwrap();
+        assert!(json.contains(r#""Latency":{"PacketLossPct":25.0,"MinRTTMs":10.0"#));
+
+        // Without round trips there is nothing to summarize.
+        summary.set_latency(&LatencyResult {
+            server_fqdn: "b".into(),
+            packets_sent: 0,
+            packets_received: 0,
+            rtt_ms: Vec::new(),
+        });
+        assert_eq!(summary.latency, None);
+    }
+}
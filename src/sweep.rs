@@ -0,0 +1,214 @@
+//! Tests against several servers in turn.
+//!
+//! A problem on one network path, e.g. a congested peering link, shows up
+//! against some servers only. [`sweep`] runs the download and upload tests
+//! against each of the nearest located servers, one after the other, and
+//! [`best`] picks the fastest result, so results can be compared across
+//! paths. [`SweepReport`] sums the sweep up for
+//! [`Emitter::on_sweep`](crate::emitter::Emitter::on_sweep).
+
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+use tokio::runtime::Handle;
+
+use crate::client::Client;
+use crate::error::{Ndt7Error, Result};
+use crate::locate::Target;
+use crate::session::Session;
+use crate::summary::Summary;
+
+/// Result of the tests against one server of a sweep.
+#[derive(Debug)]
+pub struct ServerResult<E = Ndt7Error> {
+    /// The server tested against.
+    pub target: Target,
+    /// Summary of the tests, or the error that kept them from running.
+    pub result: std::result::Result<Summary, E>,
+}
+
+/// Run the download and upload tests against each of the first `count`
+/// servers of [`Client::targets`], in order.
+///
+/// A server whose tests fail does not end the sweep; its error is kept in
+/// its [`ServerResult`]. An error is returned only if no servers could be
+/// located.
+pub async fn sweep(client: &Client, count: usize) -> Result<Vec<ServerResult>> {
+    sweep_with(client, count, async |client: &mut Client| {
+        Session::spawn(client, &Handle::current()).summary().await
+    })
+    .await
+}
+
+/// Like [`sweep`], with `run` running the tests against each server, e.g.
+/// to report their progress.
+///
+/// `run` gets a client of [`Client::with_targets`] for the server. Its URLs
+/// come from a fresh [`Client::targets`], so servers reached after the
+/// access tokens of the first lookup expired are located again; the servers
+/// swept stay those of the first lookup.
+pub async fn sweep_with<E>(
+    client: &Client,
+    count: usize,
+    mut run: impl AsyncFnMut(&mut Client) -> std::result::Result<Summary, E>,
+) -> Result<Vec<ServerResult<E>>> {
+    let targets = client.targets().await?;
+    let mut results = Vec::new();
+    for target in targets.iter().take(count) {
+        // Keep the first lookup's target if the server is gone or the
+        // lookup fails; its test then reports what went wrong.
+        let target = match client.targets().await {
+            Ok(fresh) => fresh.iter().find(|t| t.machine == target.machine).cloned(),
+            Err(_) => None,
+        }
+        .unwrap_or_else(|| target.clone());
+        let mut server = client.with_targets(vec![target.clone()]);
+        let result = run(&mut server).await;
+        results.push(ServerResult { target, result });
+    }
+    Ok(results)
+}
+
+/// Outcome of a sweep, one entry per server in the order tested.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct SweepReport {
+    /// The servers tested against.
+    pub servers: Vec<SweepServer>,
+    /// Machine of the fastest server, see [`best`], or `None` if no server
+    /// completed a download test.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fastest: Option<String>,
+}
+
+/// Outcome of the tests against one server of a sweep.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct SweepServer {
+    /// FQDN of the server machine.
+    pub machine: String,
+    /// Download throughput, if the download test ran.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub download_mbps: Option<f64>,
+    /// Upload throughput, if the upload test ran.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upload_mbps: Option<f64>,
+    /// The error that kept the tests from running.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl SweepReport {
+    /// Report of the `results` of a sweep.
+    pub fn new<E: Display>(results: &[ServerResult<E>]) -> Self {
+        let summaries = results.iter().filter_map(|r| r.result.as_ref().ok());
+        let fastest = best(summaries).and_then(|best| {
+            results
+                .iter()
+                .find(|r| r.result.as_ref().is_ok_and(|s| std::ptr::eq(s, best)))
+        });
+        SweepReport {
+            servers: results
+                .iter()
+                .map(|r| match &r.result {
+                    Ok(s) => SweepServer {
+                        machine: r.target.machine.clone(),
+                        download_mbps: s.download.as_ref().map(|d| d.throughput_mbps),
+                        upload_mbps: s.upload.as_ref().map(|u| u.throughput_mbps),
+                        error: None,
+                    },
+                    Err(e) => SweepServer {
+                        machine: r.target.machine.clone(),
+                        error: Some(e.to_string()),
+                        ..Default::default()
+                    },
+                })
+                .collect(),
+            fastest: fastest.map(|r| r.target.machine.clone()),
+        }
+    }
+}
+
+/// The summary with the highest download throughput, ties broken by
+/// upload throughput, or `None` if no summary has download results.
+pub fn best<'a>(summaries: impl IntoIterator<Item = &'a Summary>) -> Option<&'a Summary> {
+    let mbps = |s: &Summary| {
+        (
+            s.download.as_ref().map_or(0.0, |d| d.throughput_mbps),
+            s.upload.as_ref().map_or(0.0, |u| u.throughput_mbps),
+        )
+    };
+    summaries
+        .into_iter()
+        .filter(|s| s.download.is_some())
+        .max_by(|a, b| {
+            let (a, b) = (mbps(a), mbps(b));
+            a.0.total_cmp(&b.0).then(a.1.total_cmp(&b.1))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spec::{AppInfo, ByteCount, Measurement, Micros, Origin, TCPInfo};
+
+    fn summary(fqdn: &str, download_mbps: Option<i64>) -> Summary {
+        let client = download_mbps.map(|mbps| Measurement {
+            origin: Some(Origin::Client),
+            app_info: Some(AppInfo {
+                elapsed_time: Micros(1_000_000),
+                num_bytes: ByteCount(mbps * 125_000),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        let server = Measurement {
+            origin: Some(Origin::Server),
+            tcp_info: Some(TCPInfo::default()),
+            ..Default::default()
+        };
+        Summary::from_measurements(fqdn.into(), client.as_ref(), Some(&server), None)
+    }
+
+    #[test]
+    fn best_by_download() {
+        let summaries = [
+            summary("a", Some(50)),
+            summary("b", Some(80)),
+            summary("c", None),
+        ];
+        assert_eq!(best(&summaries).unwrap().server_fqdn, "b");
+        assert!(best(&summaries[2..]).is_none());
+    }
+
+    #[test]
+    fn report_names_fastest_machine() {
+        let target = |machine: &str| Target {
+            machine: machine.into(),
+            hostname: None,
+            urls: Default::default(),
+            location: None,
+            extra: Default::default(),
+        };
+        let results = [
+            ServerResult {
+                target: target("mlab1-aaa01"),
+                result: Ok(summary("a", Some(50))),
+            },
+            ServerResult {
+                target: target("mlab1-bbb01"),
+                result: Err("refused"),
+            },
+            ServerResult {
+                target: target("mlab1-ccc01"),
+                result: Ok(summary("c", Some(80))),
+            },
+        ];
+        let report = SweepReport::new(&results);
+        assert_eq!(report.fastest.as_deref(), Some("mlab1-ccc01"));
+        assert_eq!(report.servers.len(), 3);
+        assert_eq!(report.servers[0].download_mbps, Some(50.0));
+        assert_eq!(report.servers[1].error.as_deref(), Some("refused"));
+        assert_eq!(report.servers[1].download_mbps, None);
+    }
+}
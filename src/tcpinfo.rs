@@ -0,0 +1,477 @@
+//! Client-side TCP statistics.
+//!
+//! Samples the kernel's view of the test connection from the client side and
+//! maps it onto the same [`TCPInfo`] structure the server reports, so client
+//! measurements carry local RTT and retransmission data too.
+//!
+//! Backends:
+//! - Linux: `getsockopt(TCP_INFO)`.
+//! - macOS: `getsockopt(TCP_CONNECTION_INFO)`. It has no minimum RTT, busy
+//!   time or limited-time counters; those fields are left empty.
+//! - Windows (feature `windows-estats`): `GetPerTcpConnectionEStats`. Windows
+//!   only collects these statistics after they are enabled per connection,
+//!   which requires administrator privileges; without them no samples are
+//!   produced.
+//!
+//! On other platforms [`TcpInfoSource::new`] returns `None`.
+
+use crate::client::Transport;
+use crate::spec::TCPInfo;
+
+/// Source of TCP statistics for one test connection.
+pub struct TcpInfoSource {
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    socket: socket2::Socket,
+    #[cfg(all(windows, feature = "windows-estats"))]
+    row: windows::Row,
+}
+
+impl TcpInfoSource {
+    /// Create a source for the connection underlying `ws`, or `None` if the
+    /// platform has no supported backend.
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    pub fn new(ws: &impl Transport) -> Option<TcpInfoSource> {
+        // A duplicate descriptor keeps the source independent of the stream,
+        // which the upload test splits into halves.
+        let tcp = ws.tcp_stream()?;
+        let socket = socket2::SockRef::from(tcp).try_clone().ok()?;
+        Some(TcpInfoSource { socket })
+    }
+
+    /// Create a source for the connection underlying `ws`, or `None` if the
+    /// platform has no supported backend.
+    #[cfg(all(windows, feature = "windows-estats"))]
+    pub fn new(ws: &impl Transport) -> Option<TcpInfoSource> {
+        let tcp = ws.tcp_stream()?;
+        let row = windows::Row::new(tcp.local_addr().ok()?, tcp.peer_addr().ok()?);
+        row.enable_collection();
+        Some(TcpInfoSource { row })
+    }
+
+    /// Create a source for the connection underlying `ws`, or `None` if the
+    /// platform has no supported backend.
+    #[cfg(not(any(
+        target_os = "linux",
+        target_os = "macos",
+        all(windows, feature = "windows-estats")
+    )))]
+    pub fn new(_ws: &impl Transport) -> Option<TcpInfoSource> {
+        None
+    }
+
+    /// Take a sample of the current TCP statistics.
+    pub fn sample(&self) -> Option<TCPInfo> {
+        #[cfg(target_os = "linux")]
+        return linux::sample(&self.socket);
+        #[cfg(target_os = "macos")]
+        return macos::sample(&self.socket);
+        #[cfg(all(windows, feature = "windows-estats"))]
+        return self.row.sample();
+        #[cfg(not(any(
+            target_os = "linux",
+            target_os = "macos",
+            all(windows, feature = "windows-estats")
+        )))]
+        return None;
+    }
+
+    /// Bytes written to the socket that the peer has not received yet:
+    /// unacknowledged and unsent bytes on Linux (`SIOCOUTQ`), unsent bytes
+    /// on macOS (`SO_NWRITE`), including any framing. `None` on other
+    /// platforms.
+    pub fn unsent_bytes(&self) -> Option<u64> {
+        #[cfg(target_os = "linux")]
+        return linux::unsent_bytes(&self.socket);
+        #[cfg(target_os = "macos")]
+        return macos::unsent_bytes(&self.socket);
+        #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+        return None;
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::mem::{offset_of, size_of};
+    use std::os::fd::AsRawFd;
+
+    use crate::spec::TCPInfo;
+
+    /// Kernel `struct tcp_info` (linux/tcp.h) up to `tcpi_snd_wnd`.
+    /// The `libc` definition for glibc stops at `tcpi_total_retrans`.
+    #[repr(C)]
+    #[derive(Default)]
+    struct KernelTcpInfo {
+        state: u8,
+        ca_state: u8,
+        retransmits: u8,
+        probes: u8,
+        backoff: u8,
+        options: u8,
+        wscale: u8,
+        app_limited: u8,
+        rto: u32,
+        ato: u32,
+        snd_mss: u32,
+        rcv_mss: u32,
+        unacked: u32,
+        sacked: u32,
+        lost: u32,
+        retrans: u32,
+        fackets: u32,
+        last_data_sent: u32,
+        last_ack_sent: u32,
+        last_data_recv: u32,
+        last_ack_recv: u32,
+        pmtu: u32,
+        rcv_ssthresh: u32,
+        rtt: u32,
+        rttvar: u32,
+        snd_ssthresh: u32,
+        snd_cwnd: u32,
+        advmss: u32,
+        reordering: u32,
+        rcv_rtt: u32,
+        rcv_space: u32,
+        total_retrans: u32,
+        pacing_rate: u64,
+        max_pacing_rate: u64,
+        bytes_acked: u64,
+        bytes_received: u64,
+        segs_out: u32,
+        segs_in: u32,
+        notsent_bytes: u32,
+        min_rtt: u32,
+        data_segs_in: u32,
+        data_segs_out: u32,
+        delivery_rate: u64,
+        busy_time: u64,
+        rwnd_limited: u64,
+        sndbuf_limited: u64,
+        delivered: u32,
+        delivered_ce: u32,
+        bytes_sent: u64,
+        bytes_retrans: u64,
+        dsack_dups: u32,
+        reord_seen: u32,
+        rcv_ooopack: u32,
+        snd_wnd: u32,
+    }
+
+    pub(super) fn sample(socket: &socket2::Socket) -> Option<TCPInfo> {
+        let mut info = KernelTcpInfo::default();
+        let mut len = size_of::<KernelTcpInfo>() as libc::socklen_t;
+        // SAFETY: `info` is a plain repr(C) struct of `len` bytes; the kernel
+        // writes at most `len` bytes and reports how many it filled.
+        let rc = unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_INFO,
+                (&mut info as *mut KernelTcpInfo).cast(),
+                &mut len,
+            )
+        };
+        if rc != 0 {
+            return None;
+        }
+
+        // Older kernels fill a shorter struct; only report fields they set.
+        let len = len as usize;
+        macro_rules! field {
+            ($name:ident) => {
+                (offset_of!(KernelTcpInfo, $name) + size_of_val(&info.$name) <= len)
+                    .then(|| (info.$name as i64).into())
+            };
+        }
+        Some(TCPInfo {
+            ato: field!(ato),
+            adv_mss: field!(advmss),
+            app_limited: field!(app_limited),
+            backoff: field!(backoff),
+            busy_time: field!(busy_time),
+            bytes_acked: field!(bytes_acked),
+            bytes_received: field!(bytes_received),
+            bytes_sent: field!(bytes_sent),
+            bytes_retrans: field!(bytes_retrans),
+            ca_state: field!(ca_state),
+            dsack_dups: field!(dsack_dups),
+            data_segs_in: field!(data_segs_in),
+            data_segs_out: field!(data_segs_out),
+            delivered: field!(delivered),
+            delivered_ce: field!(delivered_ce),
+            delivery_rate: field!(delivery_rate),
+            elapsed_time: None,
+            fackets: field!(fackets),
+            last_ack_recv: field!(last_ack_recv),
+            last_ack_sent: field!(last_ack_sent),
+            last_data_recv: field!(last_data_recv),
+            last_data_sent: field!(last_data_sent),
+            lost: field!(lost),
+            max_pacing_rate: field!(max_pacing_rate),
+            min_rtt: field!(min_rtt),
+            notsent_bytes: field!(notsent_bytes),
+            options: field!(options),
+            pmtu: field!(pmtu),
+            pacing_rate: field!(pacing_rate),
+            probes: field!(probes),
+            rto: field!(rto),
+            rtt: field!(rtt),
+            rtt_var: field!(rttvar),
+            rwnd_limited: field!(rwnd_limited),
+            rcv_mss: field!(rcv_mss),
+            rcv_ooopack: field!(rcv_ooopack),
+            rcv_rtt: field!(rcv_rtt),
+            rcv_space: field!(rcv_space),
+            rcv_ssthresh: field!(rcv_ssthresh),
+            reord_seen: field!(reord_seen),
+            reordering: field!(reordering),
+            retrans: field!(retrans),
+            retransmits: field!(retransmits),
+            segs_in: field!(segs_in),
+            segs_out: field!(segs_out),
+            snd_buf_limited: field!(sndbuf_limited),
+            snd_cwnd: field!(snd_cwnd),
+            snd_mss: field!(snd_mss),
+            snd_ssthresh: field!(snd_ssthresh),
+            snd_wnd: field!(snd_wnd),
+            sacked: field!(sacked),
+            state: field!(state),
+            total_retrans: field!(total_retrans),
+            unacked: field!(unacked),
+            wscale: field!(wscale),
+            ..Default::default()
+        })
+    }
+
+    pub(super) fn unsent_bytes(socket: &socket2::Socket) -> Option<u64> {
+        let mut queued: libc::c_int = 0;
+        // SAFETY: SIOCOUTQ writes a single int to the pointer.
+        let rc = unsafe { libc::ioctl(socket.as_raw_fd(), libc::TIOCOUTQ, &mut queued) };
+        (rc == 0).then_some(queued as u64)
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod macos {
+    use std::mem::size_of;
+    use std::os::fd::AsRawFd;
+
+    use crate::spec::{ByteCount, Micros, TCPInfo};
+
+    pub(super) fn sample(socket: &socket2::Socket) -> Option<TCPInfo> {
+        // SAFETY: `tcp_connection_info` is plain old data; all-zero is valid.
+        let mut info: libc::tcp_connection_info = unsafe { std::mem::zeroed() };
+        let mut len = size_of::<libc::tcp_connection_info>() as libc::socklen_t;
+        // SAFETY: `info` is `len` bytes; the kernel writes at most that many.
+        let rc = unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_CONNECTION_INFO,
+                (&mut info as *mut libc::tcp_connection_info).cast(),
+                &mut len,
+            )
+        };
+        if rc != 0 {
+            return None;
+        }
+
+        // RTTs are reported in milliseconds; TCPInfo uses microseconds.
+        let ms = |v: u32| Some(Micros(i64::from(v) * 1000));
+        Some(TCPInfo {
+            bytes_received: Some(ByteCount(info.tcpi_rxbytes as i64)),
+            bytes_sent: Some(ByteCount(info.tcpi_txbytes as i64)),
+            bytes_retrans: Some(ByteCount(info.tcpi_txretransmitbytes as i64)),
+            rtt: ms(info.tcpi_srtt),
+            rtt_var: ms(info.tcpi_rttvar),
+            ..Default::default()
+        })
+    }
+
+    pub(super) fn unsent_bytes(socket: &socket2::Socket) -> Option<u64> {
+        let mut unsent: libc::c_int = 0;
+        let mut len = size_of::<libc::c_int>() as libc::socklen_t;
+        // SAFETY: `unsent` is an int of `len` bytes.
+        let rc = unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_NWRITE,
+                (&mut unsent as *mut libc::c_int).cast(),
+                &mut len,
+            )
+        };
+        (rc == 0).then_some(unsent as u64)
+    }
+}
+
+#[cfg(all(windows, feature = "windows-estats"))]
+mod windows {
+    use std::mem::size_of;
+    use std::net::SocketAddr;
+
+    use windows_sys::Win32::NetworkManagement::IpHelper::{
+        GetPerTcp6ConnectionEStats, GetPerTcpConnectionEStats, MIB_TCP_STATE_ESTAB, MIB_TCP6ROW,
+        MIB_TCPROW_LH, MIB_TCPROW_LH_0, SetPerTcp6ConnectionEStats, SetPerTcpConnectionEStats,
+        TCP_ESTATS_DATA_ROD_v0, TCP_ESTATS_DATA_RW_v0, TCP_ESTATS_PATH_ROD_v0,
+        TCP_ESTATS_PATH_RW_v0, TCP_ESTATS_TYPE, TcpConnectionEstatsData, TcpConnectionEstatsPath,
+    };
+    use windows_sys::Win32::Networking::WinSock::{IN6_ADDR, IN6_ADDR_0};
+
+    use crate::spec::{ByteCount, Micros, TCPInfo};
+
+    /// Connection table row identifying the test connection.
+    pub(super) enum Row {
+        V4(MIB_TCPROW_LH),
+        V6(MIB_TCP6ROW),
+    }
+
+    // The rows only hold addresses and ports.
+    unsafe impl Send for Row {}
+    unsafe impl Sync for Row {}
+
+    impl Row {
+        pub(super) fn new(local: SocketAddr, peer: SocketAddr) -> Row {
+            // Ports are stored in network byte order in the low 16 bits.
+            let port = |a: &SocketAddr| u32::from(a.port().to_be());
+            match (local, peer) {
+                (SocketAddr::V4(l), SocketAddr::V4(p)) => Row::V4(MIB_TCPROW_LH {
+                    Anonymous: MIB_TCPROW_LH_0 {
+                        State: MIB_TCP_STATE_ESTAB,
+                    },
+                    dwLocalAddr: u32::from_ne_bytes(l.ip().octets()),
+                    dwLocalPort: port(&local),
+                    dwRemoteAddr: u32::from_ne_bytes(p.ip().octets()),
+                    dwRemotePort: port(&peer),
+                }),
+                _ => {
+                    let ip6 = |a: &SocketAddr| match a {
+                        SocketAddr::V4(a) => a.ip().to_ipv6_mapped().octets(),
+                        SocketAddr::V6(a) => a.ip().octets(),
+                    };
+                    let scope = |a: &SocketAddr| match a {
+                        SocketAddr::V4(_) => 0,
+                        SocketAddr::V6(a) => a.scope_id(),
+                    };
+                    Row::V6(MIB_TCP6ROW {
+                        State: MIB_TCP_STATE_ESTAB,
+                        LocalAddr: IN6_ADDR {
+                            u: IN6_ADDR_0 { Byte: ip6(&local) },
+                        },
+                        dwLocalScopeId: scope(&local),
+                        dwLocalPort: port(&local),
+                        RemoteAddr: IN6_ADDR {
+                            u: IN6_ADDR_0 { Byte: ip6(&peer) },
+                        },
+                        dwRemoteScopeId: scope(&peer),
+                        dwRemotePort: port(&peer),
+                    })
+                }
+            }
+        }
+
+        /// Ask Windows to start collecting statistics for this connection.
+        /// Fails silently without administrator privileges.
+        pub(super) fn enable_collection(&self) {
+            let data = TCP_ESTATS_DATA_RW_v0 {
+                EnableCollection: true,
+            };
+            let path = TCP_ESTATS_PATH_RW_v0 {
+                EnableCollection: true,
+            };
+            self.set(TcpConnectionEstatsData, &data);
+            self.set(TcpConnectionEstatsPath, &path);
+        }
+
+        pub(super) fn sample(&self) -> Option<TCPInfo> {
+            let data: TCP_ESTATS_DATA_ROD_v0 = self.get(TcpConnectionEstatsData)?;
+            let path: TCP_ESTATS_PATH_ROD_v0 = self.get(TcpConnectionEstatsPath)?;
+            // EStats reports RTTs in milliseconds; TCPInfo uses microseconds.
+            let ms = |v: u32| Some(Micros(i64::from(v) * 1000));
+            Some(TCPInfo {
+                bytes_acked: Some(ByteCount(data.ThruBytesAcked as i64)),
+                bytes_received: Some(ByteCount(data.ThruBytesReceived as i64)),
+                bytes_sent: Some(ByteCount(data.DataBytesOut as i64)),
+                bytes_retrans: Some(ByteCount(i64::from(path.BytesRetrans))),
+                min_rtt: ms(path.MinRtt),
+                rtt: ms(path.SmoothedRtt),
+                rtt_var: ms(path.RttVar),
+                ..Default::default()
+            })
+        }
+
+        fn set<T>(&self, kind: TCP_ESTATS_TYPE, rw: &T) {
+            let rw = (rw as *const T).cast();
+            let size = size_of::<T>() as u32;
+            // SAFETY: `rw` points to a live struct of `size` bytes.
+            unsafe {
+                match self {
+                    Row::V4(row) => SetPerTcpConnectionEStats(row, kind, rw, 0, size, 0),
+                    Row::V6(row) => SetPerTcp6ConnectionEStats(row, kind, rw, 0, size, 0),
+                };
+            }
+        }
+
+        fn get<T: Default>(&self, kind: TCP_ESTATS_TYPE) -> Option<T> {
+            let mut rod = T::default();
+            let ptr = (&mut rod as *mut T).cast();
+            let size = size_of::<T>() as u32;
+            let null = std::ptr::null_mut();
+            // SAFETY: `rod` is a live struct of `size` bytes; the unused
+            // read/write and static sections are passed as null with size 0.
+            let rc = unsafe {
+                match self {
+                    Row::V4(row) => {
+                        GetPerTcpConnectionEStats(row, kind, null, 0, 0, null, 0, 0, ptr, 0, size)
+                    }
+                    Row::V6(row) => {
+                        GetPerTcp6ConnectionEStats(row, kind, null, 0, 0, null, 0, 0, ptr, 0, size)
+                    }
+                }
+            };
+            (rc == 0).then_some(rod)
+        }
+    }
+}
+
+#[cfg(all(test, any(target_os = "linux", target_os = "macos")))]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn sample_local_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let _ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            futures_util::future::pending::<()>().await;
+        });
+
+        let (ws, _) = tokio_tungstenite::connect_async(format!("ws://{addr}"))
+            .await
+            .unwrap();
+        let source = TcpInfoSource::new(&ws).unwrap();
+        let info = source.sample().unwrap();
+
+        // The handshake was sent.
+        assert!(info.bytes_sent.unwrap().0 > 0);
+        assert!(info.rtt.is_some());
+        #[cfg(target_os = "linux")]
+        assert!(info.segs_out.unwrap() > 0 && info.snd_cwnd.is_some());
+        // ...and acknowledged, once the ACK for the client's last bytes is
+        // in.
+        let acked = async {
+            while source.unsent_bytes() != Some(0) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), acked)
+            .await
+            .expect("queued bytes were not acknowledged");
+    }
+}
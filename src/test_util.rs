@@ -0,0 +1,38 @@
+//! Helpers shared by the unit tests of several modules.
+
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+use tokio::net::TcpStream;
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::http::{Request, Response};
+
+/// Writer whose contents stay readable after it is moved into a trace or
+/// recorder.
+#[derive(Clone, Default)]
+pub(crate) struct SharedBuf(pub(crate) Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Accept a WebSocket handshake, echoing the requested subprotocol.
+pub(crate) async fn accept_ndt7(stream: TcpStream) -> WebSocketStream<TcpStream> {
+    #[allow(clippy::result_large_err)]
+    tokio_tungstenite::accept_hdr_async(stream, |req: &Request<()>, mut resp: Response<()>| {
+        // to mitigate SecWebSocketSubProtocolError(NoSubProtocol)
+        if let Some(proto) = req.headers().get("Sec-WebSocket-Protocol") {
+            resp.headers_mut()
+                .insert("Sec-WebSocket-Protocol", proto.clone());
+        }
+        Ok(resp)
+    })
+    .await
+    .unwrap()
+}
@@ -0,0 +1,185 @@
+//! In-process ndt7 server for offline tests.
+//!
+//! [`MockServer`] runs a [`crate::server`] on a local port, configured for
+//! quick tests: the download endpoint streams fixed-size binary messages,
+//! the upload endpoint reads what it is sent, and both report server-side
+//! measurements on a fixed interval before closing the connection normally.
+//! The [`TCPInfo`](crate::spec::TCPInfo) of the measurements is synthetic,
+//! derived from the byte counts and the configured round-trip time, so
+//! results do not depend on the platform.
+//!
+//! It is meant for testing code built on this crate without network access,
+//! not as a server for real measurements.
+//!
+//! ```no_run
+//! use ndt7_client::client::ClientBuilder;
+//! use ndt7_client::testing::MockServer;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let server = MockServer::start().await?;
+//! let client = ClientBuilder::new("my-app", "0.1.0").no_tls().build();
+//! let mut handle = client.start_download(Some(&server.download_url())).await?;
+//! while let Some(result) = handle.rx.recv().await {
+//!     println!("{:?}", result?);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::task::JoinHandle;
+
+use crate::params;
+use crate::server::ServerBuilder;
+
+/// Configures and starts a [`MockServer`].
+#[derive(Debug, Clone)]
+pub struct MockServerBuilder {
+    duration: Duration,
+    message_size: usize,
+    measurement_interval: Duration,
+    rtt: Duration,
+}
+
+impl Default for MockServerBuilder {
+    fn default() -> Self {
+        MockServerBuilder {
+            duration: Duration::from_secs(1),
+            message_size: params::INITIAL_MESSAGE_SIZE,
+            measurement_interval: params::UPDATE_INTERVAL,
+            rtt: Duration::from_millis(10),
+        }
+    }
+}
+
+impl MockServerBuilder {
+    /// How long each test runs before the server closes the connection.
+    /// Default: 1 second.
+    pub fn duration(mut self, duration: Duration) -> Self {
+        self.duration = duration;
+        self
+    }
+
+    /// Size of the binary messages of the download test.
+    /// Default: [`params::INITIAL_MESSAGE_SIZE`].
+    pub fn message_size(mut self, size: usize) -> Self {
+        self.message_size = size;
+        self
+    }
+
+    /// Interval between server measurements.
+    /// Default: [`params::UPDATE_INTERVAL`].
+    pub fn measurement_interval(mut self, interval: Duration) -> Self {
+        self.measurement_interval = interval;
+        self
+    }
+
+    /// Round-trip time reported in the server's TCP statistics.
+    /// Default: 10 ms.
+    pub fn rtt(mut self, rtt: Duration) -> Self {
+        self.rtt = rtt;
+        self
+    }
+
+    /// Listen on an ephemeral port of the loopback interface and serve
+    /// tests in the background until the server is dropped.
+    pub async fn start(self) -> std::io::Result<MockServer> {
+        let mut server = ServerBuilder::new()
+            .duration(self.duration)
+            .measurement_interval(self.measurement_interval)
+            .initial_message_size(self.message_size)
+            .max_message_size(self.message_size);
+        server.synthetic_rtt = Some(self.rtt);
+        let server = server.bind("127.0.0.1:0").await?;
+        let addr = server.local_addr()?;
+        let task = tokio::spawn(server.serve());
+        Ok(MockServer { addr, task })
+    }
+}
+
+/// A local ndt7 server, see the [module documentation](self).
+///
+/// Serves any number of tests, including concurrent ones, until dropped.
+#[derive(Debug)]
+pub struct MockServer {
+    addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl MockServer {
+    /// Configure a server.
+    pub fn builder() -> MockServerBuilder {
+        MockServerBuilder::default()
+    }
+
+    /// Start a server with the default configuration.
+    pub async fn start() -> std::io::Result<MockServer> {
+        MockServerBuilder::default().start().await
+    }
+
+    /// Address the server listens on.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// `ws://` URL of the download test.
+    pub fn download_url(&self) -> String {
+        format!("ws://{}{}", self.addr, params::DOWNLOAD_URL_PATH)
+    }
+
+    /// `ws://` URL of the upload test.
+    pub fn upload_url(&self) -> String {
+        format!("ws://{}{}", self.addr, params::UPLOAD_URL_PATH)
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ClientBuilder;
+    use crate::spec::{Micros, Origin, TestKind};
+
+    #[tokio::test]
+    async fn serves_both_tests() {
+        let server = MockServer::builder()
+            .duration(Duration::from_millis(600))
+            .measurement_interval(Duration::from_millis(100))
+            .start()
+            .await
+            .unwrap();
+        let client = ClientBuilder::new("test", "test").no_tls().build();
+
+        for (test, url) in [
+            (TestKind::Download, server.download_url()),
+            (TestKind::Upload, server.upload_url()),
+        ] {
+            let mut handle = match test {
+                TestKind::Download => client.start_download(Some(&url)).await,
+                TestKind::Upload => client.start_upload(Some(&url)).await,
+            }
+            .unwrap();
+            let mut server_measurements = Vec::new();
+            while let Some(result) = handle.rx.recv().await {
+                let m = result.unwrap();
+                if m.origin == Some(Origin::Server) {
+                    server_measurements.push(m);
+                }
+            }
+            let last = server_measurements.last().unwrap();
+            assert!(last.app_info.as_ref().unwrap().num_bytes.0 > 0);
+            assert_eq!(
+                last.tcp_info.as_ref().unwrap().min_rtt,
+                Some(Micros(10_000))
+            );
+        }
+    }
+}
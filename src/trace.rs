@@ -0,0 +1,152 @@
+//! Raw wire trace of test connections.
+//!
+//! A [`WireTrace`] records every WebSocket message a test sends or receives
+//! as one JSON object per line: direction, message type, size, timestamps
+//! and, for text messages, the payload itself. Comparing the trace with the
+//! measurements helps explain discrepancies between the client and server
+//! views of a test.
+//!
+//! Pongs sent automatically in reply to server pings are not visible to the
+//! client and do not appear in the trace.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::spec::TestKind;
+
+/// Whether a traced message was sent or received by the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// Sent by the client.
+    Sent,
+    /// Received from the server.
+    Received,
+}
+
+/// One line of the trace.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct TraceRecord<'a> {
+    /// Microseconds since the Unix epoch.
+    timestamp: u128,
+    /// Microseconds since the start of the test.
+    elapsed_time: i64,
+    test: TestKind,
+    direction: Direction,
+    #[serde(rename = "Type")]
+    kind: &'static str,
+    size: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<&'a str>,
+}
+
+/// Recorder of WebSocket messages, shared by the tests of a client.
+///
+/// Set with [`ClientBuilder::wire_trace`](crate::client::ClientBuilder::wire_trace).
+/// Write errors are ignored so that tracing never fails a test.
+pub struct WireTrace {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl WireTrace {
+    /// Trace to a new file at `path`, replacing an existing one.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<WireTrace> {
+        Ok(WireTrace::new(BufWriter::new(File::create(path)?)))
+    }
+
+    /// Trace to `out`.
+    pub fn new(out: impl Write + Send + 'static) -> WireTrace {
+        WireTrace {
+            out: Mutex::new(Box::new(out)),
+        }
+    }
+
+    /// Record `msg`, sent or received during the `test` that started at
+    /// `start`.
+    pub(crate) fn record(
+        &self,
+        test: TestKind,
+        start: Instant,
+        direction: Direction,
+        msg: &Message,
+    ) {
+        let (kind, size, text) = match msg {
+            Message::Text(text) => ("text", text.len(), Some(text.as_str())),
+            Message::Binary(data) => ("binary", data.len(), None),
+            Message::Ping(data) => ("ping", data.len(), None),
+            Message::Pong(data) => ("pong", data.len(), None),
+            // A close frame carries a 2-byte code before the reason.
+            Message::Close(frame) => (
+                "close",
+                frame.as_ref().map_or(0, |f| 2 + f.reason.len()),
+                None,
+            ),
+            Message::Frame(frame) => ("frame", frame.len(), None),
+        };
+        let record = TraceRecord {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_micros(),
+            elapsed_time: start.elapsed().as_micros() as i64,
+            test,
+            direction,
+            kind,
+            size,
+            text,
+        };
+        let mut out = self.out.lock().unwrap_or_else(|e| e.into_inner());
+        if serde_json::to_writer(&mut *out, &record).is_ok() {
+            let _ = out.write_all(b"\n");
+        }
+    }
+
+    /// Flush buffered records, e.g. at the end of a test.
+    pub fn flush(&self) {
+        let _ = self.out.lock().unwrap_or_else(|e| e.into_inner()).flush();
+    }
+}
+
+impl std::fmt::Debug for WireTrace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WireTrace").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::SharedBuf;
+
+    #[tokio::test]
+    async fn records_jsonl() {
+        let buf = SharedBuf::default();
+        let trace = WireTrace::new(buf.clone());
+        let start = Instant::now();
+        let text = Message::Text(r#"{"AppInfo":{}}"#.into());
+        trace.record(TestKind::Upload, start, Direction::Received, &text);
+        let binary = Message::Binary(vec![0u8; 1024].into());
+        trace.record(TestKind::Upload, start, Direction::Sent, &binary);
+
+        let out = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<serde_json::Value> = out
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["Direction"], "received");
+        assert_eq!(lines[0]["Type"], "text");
+        assert_eq!(lines[0]["Text"], r#"{"AppInfo":{}}"#);
+        assert_eq!(lines[1]["Test"], "upload");
+        assert_eq!(lines[1]["Size"], 1024);
+        assert!(lines[1].get("Text").is_none());
+    }
+}
@@ -0,0 +1,442 @@
+//! Full-screen terminal interface.
+//!
+//! [`TuiEmitter`] takes over the terminal while the tests run and draws a
+//! panel with the server and the test's status, live charts of the
+//! throughput and round-trip time of both subtests, and the final summary,
+//! for diagnosing a connection interactively. Pressing `q`, `Esc` or
+//! `Ctrl-C` cancels the tests.
+
+use std::time::Duration;
+
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Style, Stylize};
+use ratatui::symbols::Marker;
+use ratatui::text::Line;
+use ratatui::widgets::{Axis, Block, Chart, Dataset, GraphType, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+
+use crate::client::ConnectInfo;
+use crate::emitter::{Emitter, Progress};
+use crate::error::{Ndt7Error, Result};
+use crate::metrics::Metrics;
+use crate::ping::PingResult;
+use crate::spec::{Measurement, Origin, TestKind};
+use crate::summary::Summary;
+use crate::sweep::SweepReport;
+
+/// Messages kept for the bottom panel.
+const MAX_MESSAGES: usize = 5;
+
+/// Shortest time span of the charts, in seconds, so they do not rescale
+/// constantly early in a test.
+const MIN_CHART_SECS: f64 = 10.0;
+
+/// Time series of one subtest.
+struct Series {
+    metrics: Metrics,
+    /// Smoothed throughput in Mbit/s by seconds since the subtest started.
+    throughput: Vec<(f64, f64)>,
+    /// Round-trip time in ms by seconds since the subtest started.
+    rtt: Vec<(f64, f64)>,
+}
+
+impl Series {
+    fn new(test: TestKind) -> Self {
+        Series {
+            metrics: Metrics::new(test),
+            throughput: Vec::new(),
+            rtt: Vec::new(),
+        }
+    }
+
+    fn update(&mut self, m: &Measurement) {
+        let last_elapsed = self.metrics.sample().elapsed_time;
+        if !self.metrics.update(m) {
+            return;
+        }
+        let sample = self.metrics.sample();
+        if sample.elapsed_time != last_elapsed
+            && let Some(mbps) = sample.smoothed_mbps
+        {
+            self.throughput
+                .push((sample.elapsed_time.as_secs_f64(), mbps));
+        }
+        if m.origin == Some(Origin::Server)
+            && let Some(tcp) = &m.tcp_info
+            && let (Some(rtt), Some(elapsed)) = (tcp.rtt, tcp.elapsed_time)
+        {
+            self.rtt.push((elapsed.as_secs_f64(), rtt.as_millis_f64()));
+        }
+    }
+}
+
+/// What the interface shows, kept apart from the terminal so it can be
+/// rendered to any backend.
+struct State {
+    status: String,
+    server: Option<String>,
+    connect_info: Option<ConnectInfo>,
+    series: [Series; 2],
+    messages: Vec<String>,
+    summary: Option<Summary>,
+}
+
+impl State {
+    fn new() -> Self {
+        State {
+            status: "locating server".into(),
+            server: None,
+            connect_info: None,
+            series: [
+                Series::new(TestKind::Download),
+                Series::new(TestKind::Upload),
+            ],
+            messages: Vec::new(),
+            summary: None,
+        }
+    }
+
+    fn message(&mut self, message: String) {
+        if self.messages.len() == MAX_MESSAGES {
+            self.messages.remove(0);
+        }
+        self.messages.push(message);
+    }
+
+    fn render(&self, frame: &mut Frame) {
+        let [info, charts, bottom] = Layout::vertical([
+            Constraint::Length(5),
+            Constraint::Min(8),
+            Constraint::Length(MAX_MESSAGES as u16 + 2),
+        ])
+        .areas(frame.area());
+        let [throughput, rtt] = Layout::horizontal([Constraint::Percentage(50); 2]).areas(charts);
+
+        self.render_info(frame, info);
+        render_chart(
+            frame,
+            throughput,
+            "Throughput (Mbit/s)",
+            self.series.each_ref().map(|s| s.throughput.as_slice()),
+        );
+        render_chart(
+            frame,
+            rtt,
+            "RTT (ms)",
+            self.series.each_ref().map(|s| s.rtt.as_slice()),
+        );
+        match &self.summary {
+            Some(summary) => render_summary(frame, bottom, summary),
+            None => {
+                let lines: Vec<Line> = self
+                    .messages
+                    .iter()
+                    .map(|m| Line::raw(m.as_str()))
+                    .collect();
+                let block = Block::bordered().title("Messages").title_bottom("q: quit");
+                frame.render_widget(Paragraph::new(lines).block(block), bottom);
+            }
+        }
+    }
+
+    fn render_info(&self, frame: &mut Frame, area: Rect) {
+        let location = self
+            .summary
+            .as_ref()
+            .and_then(|s| s.server_location.as_ref())
+            .map(|loc| format!(" ({}, {})", loc.city, loc.country))
+            .unwrap_or_default();
+        let software = self
+            .connect_info
+            .as_ref()
+            .and_then(|info| info.server.as_deref())
+            .unwrap_or("-");
+        let speeds = self.series.each_ref().map(|s| {
+            s.metrics
+                .sample()
+                .average_mbps
+                .map_or("-".into(), |mbps| format!("{mbps:.1} Mbit/s"))
+        });
+        let lines = vec![
+            Line::from(vec![
+                "Server: ".bold(),
+                self.server.as_deref().unwrap_or("-").into(),
+                location.into(),
+                "  Software: ".bold(),
+                software.into(),
+            ]),
+            Line::from(vec!["Status: ".bold(), self.status.as_str().into()]),
+            Line::from(vec![
+                "Download: ".bold(),
+                speeds[0].clone().into(),
+                "  Upload: ".bold(),
+                speeds[1].clone().into(),
+            ]),
+        ];
+        let block = Block::bordered().title("ndt7");
+        frame.render_widget(Paragraph::new(lines).block(block), area);
+    }
+}
+
+/// Draw the download and upload series in one chart.
+fn render_chart(frame: &mut Frame, area: Rect, title: &str, series: [&[(f64, f64)]; 2]) {
+    let max_x = series
+        .iter()
+        .flat_map(|s| s.iter().map(|p| p.0))
+        .fold(MIN_CHART_SECS, f64::max);
+    let max_y = series
+        .iter()
+        .flat_map(|s| s.iter().map(|p| p.1))
+        .fold(0.0, f64::max)
+        .max(1.0)
+        * 1.1;
+    let datasets = [("Download", series[0]), ("Upload", series[1])]
+        .into_iter()
+        .zip([Style::new().cyan(), Style::new().magenta()])
+        .map(|((name, data), style)| {
+            Dataset::default()
+                .name(name)
+                .marker(Marker::Braille)
+                .graph_type(GraphType::Line)
+                .style(style)
+                .data(data)
+        })
+        .collect();
+    let chart = Chart::new(datasets)
+        .block(Block::bordered().title(title))
+        .x_axis(
+            Axis::default()
+                .title("s")
+                .bounds([0.0, max_x])
+                .labels(["0".to_string(), format!("{max_x:.0}")]),
+        )
+        .y_axis(
+            Axis::default()
+                .bounds([0.0, max_y])
+                .labels(["0".to_string(), format!("{max_y:.0}")]),
+        );
+    frame.render_widget(chart, area);
+}
+
+fn render_summary(frame: &mut Frame, area: Rect, s: &Summary) {
+    let mut lines = Vec::new();
+    for (name, subtest) in [("Download", &s.download), ("Upload", &s.upload)] {
+        if let Some(t) = subtest {
+            let mut line = format!(
+                "{name:>8}: {:.1} Mbit/s, latency {:.1} ms",
+                t.throughput_mbps, t.latency_ms
+            );
+            if let Some(retrans) = t.retransmission_pct.or(t.client_retransmission_pct) {
+                line.push_str(&format!(", retransmission {retrans:.1} %"));
+            }
+            if !t.complete {
+                line.push_str(" (partial)");
+            }
+            lines.push(Line::raw(line));
+        }
+    }
+    if let Some(grade) = &s.grade {
+        lines.push(Line::raw(format!(
+            "{:>8}: {} (streaming {}, gaming {}, video calls {})",
+            "Grade", grade.letter, grade.streaming, grade.gaming, grade.video_call
+        )));
+    }
+    let block = Block::bordered()
+        .title("Results")
+        .title_bottom("press any key to exit");
+    frame.render_widget(Paragraph::new(lines).block(block), area);
+}
+
+/// Shows the tests in a full-screen terminal interface.
+///
+/// The terminal is switched to the alternate screen on creation and
+/// restored when the emitter is dropped. The final summary stays on screen
+/// until a key is pressed, so [`Emitter::on_summary`] blocks.
+pub struct TuiEmitter {
+    terminal: DefaultTerminal,
+    state: State,
+}
+
+impl TuiEmitter {
+    /// Take over the terminal.
+    pub fn new() -> Self {
+        TuiEmitter {
+            terminal: ratatui::init(),
+            state: State::new(),
+        }
+    }
+
+    /// Redraw, then check for a key asking to quit.
+    fn draw(&mut self) -> Result<()> {
+        self.terminal.draw(|frame| self.state.render(frame))?;
+        while event::poll(Duration::ZERO)? {
+            if let Event::Key(key) = event::read()?
+                && key.kind == KeyEventKind::Press
+                && (matches!(key.code, KeyCode::Char('q') | KeyCode::Esc)
+                    || (key.code == KeyCode::Char('c')
+                        && key.modifiers.contains(KeyModifiers::CONTROL)))
+            {
+                return Err(Ndt7Error::Cancelled);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for TuiEmitter {
+    fn default() -> Self {
+        TuiEmitter::new()
+    }
+}
+
+impl Drop for TuiEmitter {
+    fn drop(&mut self) {
+        ratatui::restore();
+    }
+}
+
+impl Emitter for TuiEmitter {
+    fn on_starting(&mut self, test: TestKind) -> Result<()> {
+        self.state.status = format!("{test:?}: connecting");
+        self.draw()
+    }
+
+    fn on_error(&mut self, test: TestKind, err: &str) -> Result<()> {
+        self.state.message(format!("{test:?} failed: {err}"));
+        self.draw()
+    }
+
+    fn on_server_closed(&mut self, test: TestKind, code: u16, reason: &str) -> Result<()> {
+        self.state.message(format!(
+            "{test:?} failed: server closed connection (code {code}): {reason}"
+        ));
+        self.draw()
+    }
+
+    fn on_server_unhealthy(&mut self, test: TestKind, fqdn: &str, reason: &str) -> Result<()> {
+        self.state
+            .message(format!("{test:?}: skipping {fqdn}: {reason}"));
+        self.draw()
+    }
+
+    fn on_connected(&mut self, test: TestKind, fqdn: &str, info: &ConnectInfo) -> Result<()> {
+        self.state.status = format!("{test:?} in progress");
+        self.state.server = Some(fqdn.to_string());
+        self.state.connect_info = Some(info.clone());
+        self.draw()
+    }
+
+    fn on_download_event(&mut self, m: &Measurement) -> Result<()> {
+        self.state.series[TestKind::Download as usize].update(m);
+        self.draw()
+    }
+
+    fn on_upload_event(&mut self, m: &Measurement) -> Result<()> {
+        self.state.series[TestKind::Upload as usize].update(m);
+        self.draw()
+    }
+
+    fn on_progress(&mut self, test: TestKind, progress: &Progress) -> Result<()> {
+        self.state.status = format!(
+            "{test:?} in progress, {:.0} s left",
+            progress.remaining_time.as_secs_f64()
+        );
+        Ok(())
+    }
+
+    fn on_complete(&mut self, test: TestKind) -> Result<()> {
+        self.state.status = format!("{test:?} complete");
+        self.draw()
+    }
+
+    fn on_summary(&mut self, s: &Summary) -> Result<()> {
+        self.state.status = "done".into();
+        self.state.summary = Some(s.clone());
+        self.terminal.draw(|frame| self.state.render(frame))?;
+        loop {
+            if let Event::Key(key) = event::read()?
+                && key.kind == KeyEventKind::Press
+            {
+                return Ok(());
+            }
+        }
+    }
+
+    fn on_ping(&mut self, p: &PingResult) -> Result<()> {
+        let rtt = p
+            .best_rtt_ms()
+            .map_or("-".into(), |rtt| format!("{rtt:.1} ms"));
+        self.state.message(format!(
+            "latency probe to {}: connect {:.1} ms, RTT {rtt}",
+            p.server_fqdn, p.connect_ms
+        ));
+        self.draw()
+    }
+
+    fn on_sweep(&mut self, report: &SweepReport) -> Result<()> {
+        let fastest = report.fastest.as_deref().unwrap_or("none");
+        self.state.message(format!(
+            "sweep of {} servers, fastest: {fastest}",
+            report.servers.len()
+        ));
+        self.draw()
+    }
+
+    fn on_warning(&mut self, warning: &str) -> Result<()> {
+        self.state.message(format!("warning: {warning}"));
+        self.draw()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ratatui::Terminal;
+    use ratatui::backend::TestBackend;
+
+    use super::*;
+    use crate::spec::{AppInfo, ByteCount, Micros, TCPInfo};
+
+    #[test]
+    fn render_live_charts() {
+        let mut state = State::new();
+        state.server = Some("mlab1-lga06".into());
+        for (elapsed_ms, bytes) in [(250, 1_000_000), (500, 2_500_000)] {
+            state.series[0].update(&Measurement {
+                origin: Some(Origin::Client),
+                app_info: Some(AppInfo {
+                    elapsed_time: Micros(elapsed_ms * 1000),
+                    num_bytes: ByteCount(bytes),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            });
+        }
+        state.series[0].update(&Measurement {
+            origin: Some(Origin::Server),
+            tcp_info: Some(TCPInfo {
+                rtt: Some(Micros(12_000)),
+                elapsed_time: Some(Micros(400_000)),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        state.message("warning: low buffers".into());
+        assert_eq!(state.series[0].throughput.len(), 2);
+        assert_eq!(state.series[0].rtt, [(0.4, 12.0)]);
+
+        let mut terminal = Terminal::new(TestBackend::new(100, 30)).unwrap();
+        terminal.draw(|frame| state.render(frame)).unwrap();
+        let screen: String = terminal
+            .backend()
+            .buffer()
+            .content()
+            .iter()
+            .map(|cell| cell.symbol())
+            .collect();
+        assert!(screen.contains("Server: mlab1-lga06"));
+        assert!(screen.contains("Download: 40.0 Mbit/s"));
+        assert!(screen.contains("Throughput (Mbit/s)"));
+        assert!(screen.contains("warning: low buffers"));
+    }
+}
@@ -1,31 +1,194 @@
 //! ndt7 upload test implementation.
 //!
 //! Sends random binary WebSocket messages to the server while reading
-//! server counter-flow measurements, until [`params::UPLOAD_TIMEOUT`] elapses.
+//! server counter-flow measurements, until [`TestParams::upload_duration`]
+//! elapses.
+//!
+//! Every message is a prefix of a single random corpus of
+//! [`params::MAX_MESSAGE_SIZE`] bytes, see [`PayloadConfig`].
+
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
 use bytes::Bytes;
 use futures_util::{SinkExt, StreamExt, stream::SplitSink, stream::SplitStream};
 use rand::RngCore;
 use rand::SeedableRng;
 use rand::rngs::SmallRng;
+use rand_chacha::ChaCha8Rng;
 use tokio::sync::mpsc;
-use tokio::time::{Instant, timeout};
+use tokio::time::{Instant, sleep, timeout};
 use tokio_tungstenite::tungstenite::Message;
 
-use crate::client::WsStream;
-use crate::error::{Ndt7Error, Result};
-use crate::params;
-use crate::spec::{AppInfo, Measurement, Origin, TestKind};
+use crate::client::{SkippedMeasurements, Transport, io_timeout};
+use crate::download::{Pinger, TestContext, UpdateSchedule};
+use crate::error::{ConfigError, Ndt7Error, Result};
+use crate::overhead::Framing;
+use crate::params::{self, TestParams};
+use crate::spec::{Measurement, TestKind};
+use crate::tcpinfo::TcpInfoSource;
+use crate::trace::{Direction, WireTrace};
+
+/// Controls the bytes sent during the upload test.
+///
+/// By default a fresh random corpus is drawn from the OS random source for
+/// every test. For controlled experiments, a seed makes the corpus
+/// bit-identical across runs, platforms and clients, and a cache file avoids
+/// regenerating it on slow CPUs.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PayloadConfig {
+    /// How the corpus is filled.
+    pub fill: PayloadFill,
+    /// Seed for the corpus generator (ChaCha8).
+    pub seed: Option<u64>,
+    /// File the seeded corpus is loaded from, or written to if it does not
+    /// exist yet or was made from another seed or for a smaller size.
+    /// Requires `seed`, as an unseeded corpus must differ between tests.
+    pub cache_path: Option<PathBuf>,
+}
+
+/// Start of a cached corpus file, followed by the seed it was made from.
+const CACHE_MAGIC: &[u8; 8] = b"ndt7cor1";
+
+/// Content of the upload corpus.
+///
+/// Random data is incompressible. Comparing it with a compressible fill
+/// reveals middleboxes or link layers that compress traffic on the path,
+/// since they inflate the throughput measured with compressible data.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum PayloadFill {
+    /// Random bytes, see [`PayloadConfig::seed`].
+    #[default]
+    Random,
+    /// All zero bytes.
+    Zeros,
+    /// The given bytes, repeated. An empty pattern is equivalent to
+    /// [`PayloadFill::Zeros`].
+    Pattern(Vec<u8>),
+}
+
+impl PayloadConfig {
+    /// Whether every call produces the same corpus: a seeded or
+    /// non-random fill.
+    pub fn is_reproducible(&self) -> bool {
+        self.fill != PayloadFill::Random || self.seed.is_some()
+    }
+
+    /// Produce the upload corpus of [`params::MAX_MESSAGE_SIZE`] bytes.
+    ///
+    /// `seed` and `cache_path` only apply to [`PayloadFill::Random`].
+    pub fn corpus(&self) -> Result<Bytes> {
+        self.corpus_of_size(params::MAX_MESSAGE_SIZE)
+    }
+
+    /// Produce an upload corpus of `size` bytes, enough for messages of up
+    /// to [`TestParams::max_message_size`].
+    ///
+    /// A seeded corpus is a prefix of the seeded corpus of any larger size,
+    /// and a larger cached corpus is truncated.
+    pub fn corpus_of_size(&self, size: usize) -> Result<Bytes> {
+        match &self.fill {
+            PayloadFill::Random => {}
+            PayloadFill::Zeros => return Ok(Bytes::from(vec![0u8; size])),
+            PayloadFill::Pattern(pattern) if pattern.is_empty() => {
+                return Ok(Bytes::from(vec![0u8; size]));
+            }
+            PayloadFill::Pattern(pattern) => {
+                let buf = pattern
+                    .iter()
+                    .copied()
+                    .cycle()
+                    .take(size)
+                    .collect::<Vec<_>>();
+                return Ok(Bytes::from(buf));
+            }
+        }
+
+        let cache = match (&self.cache_path, self.seed) {
+            (Some(path), Some(seed)) => Some((path, cache_header(seed))),
+            (Some(_), None) => return Err(ConfigError::Requires("cache_path", "seed").into()),
+            (None, _) => None,
+        };
+        if let Some((path, header)) = &cache {
+            match std::fs::read(path) {
+                Ok(data) if data.starts_with(header) && data.len() >= header.len() + size => {
+                    let start = header.len();
+                    return Ok(Bytes::from(data).slice(start..start + size));
+                }
+                // Made from another seed, or too small: regenerate.
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        let mut buf = vec![0u8; size];
+        match self.seed {
+            Some(seed) => ChaCha8Rng::seed_from_u64(seed).fill_bytes(&mut buf),
+            None => SmallRng::from_os_rng().fill_bytes(&mut buf),
+        }
+        if let Some((path, header)) = &cache {
+            let mut file = std::fs::File::create(path)?;
+            file.write_all(header)?;
+            file.write_all(&buf)?;
+        }
+        Ok(Bytes::from(buf))
+    }
+}
+
+/// Header of a corpus cached for `seed`.
+fn cache_header(seed: u64) -> [u8; 16] {
+    let mut header = [0u8; 16];
+    header[..8].copy_from_slice(CACHE_MAGIC);
+    header[8..].copy_from_slice(&seed.to_le_bytes());
+    header
+}
 
-/// Run the upload test on an established WebSocket connection.
+/// Run the upload test on an established WebSocket connection, sending
+/// prefixes of `corpus`, which bounds the message size together with
+/// [`TestParams::max_message_size`].
 ///
 /// Measurements are sent on `tx` as they arrive. The function returns when
-/// the timeout expires or the server closes the connection.
-pub async fn run(ws: WsStream, tx: mpsc::Sender<Result<Measurement>>) {
+/// the timeout expires, the server closes the connection or, if
+/// [`TestParams::max_bytes`] is set, that many bytes have been sent and the
+/// connection is closed. With [`TestParams::upload_rate`] set, messages are
+/// paced by a token bucket and kept small enough to send several per
+/// measurement interval.
+///
+/// Client measurements leave out bytes still queued in the socket buffer,
+/// where the platform reports them (see [`TcpInfoSource::unsent_bytes`]), so
+/// they count what the server received rather than what was written.
+/// Messages are recorded to `trace`, if set. Malformed server measurements
+/// are handled as in [`download::run`](crate::download::run).
+pub async fn run<T: Transport>(
+    ws: T,
+    corpus: Bytes,
+    test_params: TestParams,
+    trace: Option<Arc<WireTrace>>,
+    skipped: SkippedMeasurements,
+    tx: mpsc::Sender<Result<Measurement>>,
+) {
+    // Sampled through its own handle, so it must be taken before the split.
+    let tcp_info = TcpInfoSource::new(&ws);
+    let ctx = TestContext {
+        test: TestKind::Upload,
+        start: Instant::now(),
+        tcp_info: tcp_info.as_ref(),
+        framing: test_params
+            .wire_overhead
+            .then(|| Framing::new(&ws, TestKind::Upload)),
+        trace: trace.as_deref(),
+        strict_parsing: test_params.strict_parsing,
+        skipped: Some(&skipped),
+        utc_timestamps: test_params.utc_timestamps,
+    };
     let (sink, stream) = ws.split();
 
+    let upload = upload_loop(sink, corpus, &ctx, test_params, &tx);
     let result = tokio::select! {
-       r = timeout(params::UPLOAD_TIMEOUT, upload_loop(sink, &tx)) => {
+       r = timeout(test_params.upload_duration, upload) => {
            match r {
                Ok(inner) => inner,
                // Overall timeout is normal completion, test ran its full duration.
@@ -34,84 +197,346 @@ pub async fn run(ws: WsStream, tx: mpsc::Sender<Result<Measurement>>) {
                Err(_) => Ok(()),
            }
        }
-       r = read_counterflow(stream, &tx) => r
+       r = read_counterflow(stream, &ctx, &tx) => r
     };
+    if let Some(trace) = &trace {
+        trace.flush();
+    }
 
     if let Err(e) = result {
         let _ = tx.send(Err(e)).await;
     }
 }
 
-// Reads server counter-flow measurements
-async fn read_counterflow(
-    mut stream: SplitStream<WsStream>,
+// Reads server counter-flow measurements and the pongs to our pings
+async fn read_counterflow<T: Transport>(
+    mut stream: SplitStream<T>,
+    ctx: &TestContext<'_>,
     tx: &mpsc::Sender<Result<Measurement>>,
 ) -> Result<()> {
     loop {
-        let msg = timeout(params::IO_TIMEOUT, stream.next()).await?;
+        let msg = io_timeout(stream.next()).await?;
         let Some(msg) = msg else { break };
         let msg = msg?;
+        ctx.record(Direction::Received, &msg);
         match msg {
             Message::Text(text) => {
-                let mut measurement: Measurement = serde_json::from_str(&text)?;
-                measurement.origin = Some(Origin::Server);
-                measurement.test = Some(TestKind::Upload);
-                let _ = tx.send(Ok(measurement)).await;
+                ctx.server_measurement(&text, tx).await?;
             }
             Message::Binary(_) => {
                 return Err(Ndt7Error::ProtocolViolation(
                     "server sent unexpected binary message during upload".into(),
                 ));
             }
-            Message::Close(_) => break,
-            _ => {} // Ping/Pong handled by tokio-tungstenite
+            Message::Close(frame) => {
+                Ndt7Error::check_close(frame)?;
+                break;
+            }
+            Message::Pong(payload) => {
+                if let Some(m) = ctx.pong(&payload) {
+                    let _ = tx.send(Ok(m)).await;
+                }
+            }
+            _ => {} // Pings are answered by tokio-tungstenite
         }
     }
     Ok(())
 }
 
-async fn upload_loop(
-    mut sink: SplitSink<WsStream, Message>,
+async fn upload_loop<T: Transport>(
+    mut sink: SplitSink<T, Message>,
+    corpus: Bytes,
+    ctx: &TestContext<'_>,
+    test_params: TestParams,
     tx: &mpsc::Sender<Result<Measurement>>,
 ) -> Result<()> {
-    let start = Instant::now();
-    let mut prev_update = start;
+    let mut pinger = Pinger::new(test_params.ws_ping_interval, ctx.start);
+    let mut updates = UpdateSchedule::new(test_params.measurement_interval, ctx.start);
     let mut total_bytes: i64 = 0;
+    let mut stream_bytes: u64 = 0;
+    let max_bytes = test_params.max_bytes;
 
-    let mut rng = SmallRng::from_os_rng();
-    let mut msg_size = params::INITIAL_MESSAGE_SIZE;
-    let mut buf = vec![0u8; msg_size];
-    rng.fill_bytes(&mut buf);
-    let mut payload = Bytes::from(buf);
+    let mut pacer = test_params.upload_rate.map(Pacer::new);
+    let mut max_msg_size = test_params.max_message_size.min(corpus.len());
+    if let Some(pacer) = &pacer {
+        max_msg_size = max_msg_size.min(pacer.burst as usize);
+    }
+    let mut msg_size = test_params.initial_message_size.min(max_msg_size);
+    let mut payload = corpus.slice(..msg_size);
 
     loop {
-        timeout(
-            params::IO_TIMEOUT,
-            sink.send(Message::Binary(payload.clone())),
-        )
-        .await??;
-        total_bytes += payload.len() as i64;
-        if msg_size < params::MAX_MESSAGE_SIZE
-            && msg_size <= total_bytes as usize / params::SCALING_FRACTION
+        // Trim the last message so exactly `max_bytes` are sent.
+        let remaining = max_bytes.map(|max| max.saturating_sub(total_bytes as u64));
+        let message = match remaining {
+            Some(remaining) if remaining < payload.len() as u64 => {
+                payload.slice(..remaining as usize)
+            }
+            _ => payload.clone(),
+        };
+        let len = message.len();
+        // Queued behind the data already in flight, so the round trip
+        // includes the queueing delay the upload causes.
+        if pinger.is_due() {
+            let ping = pinger.ping();
+            ctx.record(Direction::Sent, &ping);
+            io_timeout(sink.send(ping)).await??;
+        }
+        if let Some(pacer) = &mut pacer {
+            pacer.acquire(len).await;
+        }
+        let message = Message::Binary(message);
+        ctx.record(Direction::Sent, &message);
+        io_timeout(sink.send(message)).await??;
+        total_bytes += len as i64;
+        if let Some(framing) = ctx.framing {
+            stream_bytes += framing.stream_bytes(len);
+        }
+        if msg_size < max_msg_size
+            && msg_size <= total_bytes as usize / test_params.scaling_fraction
         {
-            msg_size *= 2;
-            let mut new_buf = vec![0u8; msg_size];
-            rng.fill_bytes(&mut new_buf);
-            payload = Bytes::from(new_buf);
+            msg_size = (msg_size * 2).min(max_msg_size);
+            payload = corpus.slice(..msg_size);
+        }
+        let capped = max_bytes.is_some_and(|max| total_bytes as u64 >= max);
+        if capped || updates.due(total_bytes) {
+            // Bytes still in the socket buffer have not reached the server;
+            // leaving them out keeps the client's view close to the server's.
+            let unsent = ctx.tcp_info.and_then(|t| t.unsent_bytes()).unwrap_or(0);
+            let num_bytes = total_bytes
+                .saturating_sub(unsent_payload(unsent, total_bytes, stream_bytes))
+                .max(0);
+            let m = ctx.measurement(num_bytes, stream_bytes.saturating_sub(unsent));
+            let _ = tx.send(Ok(m)).await;
+        }
+        if capped {
+            ctx.record(Direction::Sent, &Message::Close(None));
+            let _ = io_timeout(sink.close()).await;
+            return Ok(());
+        }
+    }
+}
+
+/// Payload bytes among `unsent` bytes of the stream, given that
+/// `total_bytes` of payload took `stream_bytes` on the stream. Without
+/// framing figures the unsent bytes are taken as payload, overstating them
+/// by the framing overhead, a fraction of a percent at the message sizes
+/// the upload ramps up to.
+fn unsent_payload(unsent: u64, total_bytes: i64, stream_bytes: u64) -> i64 {
+    if stream_bytes == 0 {
+        return unsent as i64;
+    }
+    (u128::from(unsent) * total_bytes.max(0) as u128 / u128::from(stream_bytes)) as i64
+}
+
+/// Token bucket limiting the upload to a fixed byte rate.
+///
+/// The bucket holds one measurement interval worth of bytes, which also
+/// bounds the message size, so a paced upload still reports smooth progress.
+struct Pacer {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Pacer {
+    fn new(bytes_per_sec: u64) -> Self {
+        let rate = bytes_per_sec as f64;
+        let burst = (rate * params::UPDATE_INTERVAL.as_secs_f64())
+            .clamp(1.0, params::MAX_MESSAGE_SIZE as f64)
+            .floor();
+        Pacer {
+            rate,
+            burst,
+            tokens: burst,
+            last: Instant::now(),
+        }
+    }
+
+    /// Wait until `bytes` may be sent and take them from the bucket.
+    async fn acquire(&mut self, bytes: usize) {
+        self.refill();
+        let bytes = bytes as f64;
+        if self.tokens < bytes {
+            sleep(Duration::from_secs_f64((bytes - self.tokens) / self.rate)).await;
+            self.refill();
         }
-        if prev_update.elapsed() >= params::UPDATE_INTERVAL {
-            prev_update = Instant::now();
-            let _ = tx
-                .send(Ok(Measurement {
-                    app_info: Some(AppInfo {
-                        elapsed_time: start.elapsed().as_micros() as i64,
-                        num_bytes: total_bytes,
-                    }),
-                    origin: Some(Origin::Client),
-                    test: Some(TestKind::Upload),
-                    ..Default::default()
-                }))
-                .await;
+        self.tokens -= bytes;
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn unsent_bytes_scaled_to_payload() {
+        // 1000 bytes of payload took 1010 on the stream.
+        assert_eq!(unsent_payload(101, 1000, 1010), 100);
+        assert_eq!(unsent_payload(101, 1000, 0), 101);
+        assert_eq!(unsent_payload(0, 1000, 1010), 0);
+    }
+
+    #[test]
+    fn seeded_corpus_is_reproducible() {
+        let config = PayloadConfig {
+            seed: Some(42),
+            ..Default::default()
+        };
+        let a = config.corpus().unwrap();
+        let b = config.corpus().unwrap();
+        assert_eq!(a.len(), params::MAX_MESSAGE_SIZE);
+        assert_eq!(a, b);
+
+        let other = PayloadConfig {
+            seed: Some(43),
+            ..Default::default()
+        };
+        assert_ne!(a, other.corpus().unwrap());
+    }
+
+    #[test]
+    fn corpus_cache_round_trip() {
+        let path = std::env::temp_dir().join(format!("ndt7-corpus-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let config = PayloadConfig {
+            seed: Some(42),
+            cache_path: Some(path.clone()),
+            ..Default::default()
+        };
+        let written = config.corpus().unwrap();
+        let cached = config.corpus().unwrap();
+        assert_eq!(written, cached);
+
+        // A cache made from another seed is regenerated.
+        let other = PayloadConfig {
+            seed: Some(43),
+            ..config.clone()
+        };
+        let regenerated = other.corpus().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let uncached = PayloadConfig {
+            seed: Some(43),
+            ..Default::default()
+        };
+        assert_eq!(regenerated, uncached.corpus().unwrap());
+
+        let unseeded = PayloadConfig {
+            seed: None,
+            ..config
+        };
+        assert!(matches!(
+            unseeded.corpus(),
+            Err(Ndt7Error::Config(ConfigError::Requires(
+                "cache_path",
+                "seed"
+            )))
+        ));
+    }
+
+    #[test]
+    fn reproducible_payloads() {
+        assert!(!PayloadConfig::default().is_reproducible());
+        let seeded = PayloadConfig {
+            seed: Some(1),
+            ..Default::default()
+        };
+        assert!(seeded.is_reproducible());
+        let zeros = PayloadConfig {
+            fill: PayloadFill::Zeros,
+            ..Default::default()
+        };
+        assert!(zeros.is_reproducible());
+    }
+
+    #[test]
+    fn compressible_corpus() {
+        let zeros = PayloadConfig {
+            fill: PayloadFill::Zeros,
+            ..Default::default()
+        };
+        let corpus = zeros.corpus().unwrap();
+        assert_eq!(corpus.len(), params::MAX_MESSAGE_SIZE);
+        assert!(corpus.iter().all(|&b| b == 0));
+
+        let pattern = PayloadConfig {
+            fill: PayloadFill::Pattern(b"ndt7".to_vec()),
+            seed: Some(42),
+            ..Default::default()
+        };
+        let corpus = pattern.corpus().unwrap();
+        assert_eq!(corpus.len(), params::MAX_MESSAGE_SIZE);
+        assert_eq!(&corpus[..8], b"ndt7ndt7");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pacer_limits_rate() {
+        let mut pacer = Pacer::new(10_000);
+        assert_eq!(pacer.burst, 2500.0);
+
+        let start = Instant::now();
+        for _ in 0..10 {
+            pacer.acquire(2500).await;
         }
+        // The first message is covered by the full bucket, the other nine
+        // wait for a quarter second each.
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(2250), "{elapsed:?}");
+        assert!(elapsed < Duration::from_millis(2300), "{elapsed:?}");
+    }
+
+    #[tokio::test]
+    async fn test_message_sizing() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let mut sizes = Vec::new();
+            while let Some(Ok(Message::Binary(data))) = ws.next().await {
+                sizes.push(data.len());
+            }
+            sizes
+        });
+
+        let (ws_stream, _response) = tokio_tungstenite::connect_async(format!("ws://{addr}"))
+            .await
+            .unwrap();
+        let test_params = TestParams {
+            max_bytes: Some(64 * 1024),
+            initial_message_size: 1024,
+            max_message_size: 4096,
+            scaling_fraction: 2,
+            ..Default::default()
+        };
+        let corpus = PayloadConfig::default().corpus_of_size(4096).unwrap();
+        let (tx, mut rx) = mpsc::channel(8);
+        tokio::spawn(async move {
+            run(
+                ws_stream,
+                corpus,
+                test_params,
+                None,
+                SkippedMeasurements::default(),
+                tx,
+            )
+            .await
+        });
+        while rx.recv().await.is_some() {}
+
+        let sizes = server.await.unwrap();
+        assert_eq!(&sizes[..4], [1024, 1024, 2048, 4096]);
+        assert!(sizes.iter().all(|&size| size <= 4096));
+        assert_eq!(sizes.iter().sum::<usize>(), 64 * 1024);
     }
 }
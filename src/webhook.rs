@@ -0,0 +1,332 @@
+//! Test results pushed to an HTTP endpoint.
+//!
+//! [`WebhookEmitter`] POSTs the summary as JSON, and optionally every other
+//! event, to a configured URL, so results reach collection services without
+//! a wrapper script. Requests are sent by a background task in the order of
+//! the events and retried on failure, so a slow endpoint does not hold up
+//! the tests; [`WebhookDelivery::finish`] waits for the outstanding ones.
+
+use std::time::Duration;
+
+use reqwest::header::{CONTENT_TYPE, HeaderMap, HeaderName, HeaderValue};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::client::ConnectInfo;
+use crate::emitter::{Emitter, Progress, TestEvent};
+use crate::error::{ConfigError, Ndt7Error, Result};
+use crate::ping::PingResult;
+use crate::spec::{Measurement, TestKind};
+use crate::summary::Summary;
+use crate::sweep::SweepReport;
+
+/// Retries of a failed request by default.
+pub const DEFAULT_RETRIES: u32 = 3;
+
+/// Wait before the first retry, doubled for each further one.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Settings of a webhook, turned into an emitter with [`Webhook::spawn`].
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    url: String,
+    headers: Vec<(String, String)>,
+    all_events: bool,
+    retries: u32,
+}
+
+impl Webhook {
+    /// POST results to `url`.
+    pub fn new(url: impl Into<String>) -> Self {
+        Webhook {
+            url: url.into(),
+            headers: Vec::new(),
+            all_events: false,
+            retries: DEFAULT_RETRIES,
+        }
+    }
+
+    /// Send `name: value` with every request, e.g. an `Authorization`
+    /// header. May be called repeatedly.
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// POST every event, not just the summary. Each request then carries
+    /// one event as [`JsonEmitter`](crate::emitter::JsonEmitter) writes it,
+    /// tagged with its `Type`.
+    pub fn all_events(mut self) -> Self {
+        self.all_events = true;
+        self
+    }
+
+    /// Retry a request that failed with a network error, HTTP 429 or a
+    /// server error up to `retries` times. Defaults to [`DEFAULT_RETRIES`].
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Start the background task sending the requests, returning the
+    /// emitter feeding it and the handle to wait for it with.
+    ///
+    /// Fails if the URL or a header is invalid.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside a tokio runtime.
+    pub fn spawn(self) -> Result<(WebhookEmitter, WebhookDelivery)> {
+        let url = reqwest::Url::parse(&self.url)?;
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        for (name, value) in &self.headers {
+            let invalid = || ConfigError::InvalidHeader(name.clone());
+            let name = HeaderName::try_from(name.as_str()).map_err(|_| invalid())?;
+            let value = HeaderValue::try_from(value.as_str()).map_err(|_| invalid())?;
+            headers.append(name, value);
+        }
+        let http = reqwest::Client::builder()
+            .user_agent(concat!("ndt7-client-rust/", env!("CARGO_PKG_VERSION")))
+            .default_headers(headers)
+            .timeout(crate::params::IO_TIMEOUT)
+            .build()?;
+
+        let (tx, mut rx) = mpsc::unbounded_channel::<Vec<u8>>();
+        let retries = self.retries;
+        let task = tokio::spawn(async move {
+            // Later requests are still attempted after one fails; the first
+            // error is reported.
+            let mut result = Ok(());
+            while let Some(body) = rx.recv().await {
+                let sent = post(&http, &url, body, retries).await;
+                if result.is_ok() {
+                    result = sent;
+                }
+            }
+            result
+        });
+        let emitter = WebhookEmitter {
+            tx,
+            all_events: self.all_events,
+        };
+        Ok((emitter, WebhookDelivery { task }))
+    }
+}
+
+/// POST `body` to `url`, retrying with exponential backoff.
+async fn post(
+    http: &reqwest::Client,
+    url: &reqwest::Url,
+    body: Vec<u8>,
+    retries: u32,
+) -> Result<()> {
+    let mut delay = RETRY_DELAY;
+    let mut attempt = 0;
+    loop {
+        let error = match http.post(url.clone()).body(body.clone()).send().await {
+            Ok(response) if response.status().is_success() => return Ok(()),
+            Ok(response) => {
+                let status = response.status();
+                let retryable =
+                    status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS;
+                if !retryable {
+                    return Err(Ndt7Error::WebhookFailed(format!("{url}: HTTP {status}")));
+                }
+                format!("{url}: HTTP {status}")
+            }
+            Err(e) => e.to_string(),
+        };
+        if attempt == retries {
+            return Err(Ndt7Error::WebhookFailed(error));
+        }
+        attempt += 1;
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+    }
+}
+
+/// Sends test results to a webhook, see [`Webhook`].
+///
+/// Events are queued and never block; delivery errors are reported by
+/// [`WebhookDelivery::finish`].
+pub struct WebhookEmitter {
+    tx: mpsc::UnboundedSender<Vec<u8>>,
+    all_events: bool,
+}
+
+impl WebhookEmitter {
+    fn send(&self, body: Vec<u8>) {
+        // The task only stops once the emitter is dropped.
+        let _ = self.tx.send(body);
+    }
+
+    fn event(&self, event: &TestEvent) -> Result<()> {
+        if self.all_events {
+            self.send(serde_json::to_vec(event)?);
+        }
+        Ok(())
+    }
+}
+
+/// Background delivery of a [`WebhookEmitter`]'s requests.
+pub struct WebhookDelivery {
+    task: JoinHandle<Result<()>>,
+}
+
+impl WebhookDelivery {
+    /// Wait until every queued request was sent, after the emitter has been
+    /// dropped. Returns the first request that failed for good.
+    pub async fn finish(self) -> Result<()> {
+        match self.task.await {
+            Ok(result) => result,
+            Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+            Err(_) => Err(Ndt7Error::Cancelled),
+        }
+    }
+}
+
+impl Emitter for WebhookEmitter {
+    fn on_starting(&mut self, test: TestKind) -> Result<()> {
+        self.event(&TestEvent::Starting { test })
+    }
+
+    fn on_error(&mut self, test: TestKind, error: &str) -> Result<()> {
+        self.event(&TestEvent::Error { test, error })
+    }
+
+    fn on_server_closed(&mut self, test: TestKind, code: u16, reason: &str) -> Result<()> {
+        self.event(&TestEvent::ServerClosed { test, code, reason })
+    }
+
+    fn on_server_unhealthy(&mut self, test: TestKind, fqdn: &str, reason: &str) -> Result<()> {
+        self.event(&TestEvent::ServerUnhealthy { test, fqdn, reason })
+    }
+
+    fn on_connected(&mut self, test: TestKind, fqdn: &str, info: &ConnectInfo) -> Result<()> {
+        self.event(&TestEvent::Connected {
+            test,
+            fqdn,
+            connect_info: info,
+        })
+    }
+
+    fn on_download_event(&mut self, m: &Measurement) -> Result<()> {
+        self.event(&TestEvent::Measurement {
+            test: TestKind::Download,
+            measurement: m,
+        })
+    }
+
+    fn on_upload_event(&mut self, m: &Measurement) -> Result<()> {
+        self.event(&TestEvent::Measurement {
+            test: TestKind::Upload,
+            measurement: m,
+        })
+    }
+
+    fn on_progress(&mut self, test: TestKind, progress: &Progress) -> Result<()> {
+        self.event(&TestEvent::Progress { test, progress })
+    }
+
+    fn on_complete(&mut self, test: TestKind) -> Result<()> {
+        self.event(&TestEvent::Complete { test })
+    }
+
+    fn on_summary(&mut self, summary: &Summary) -> Result<()> {
+        if self.all_events {
+            self.event(&TestEvent::Summary { summary })
+        } else {
+            self.send(serde_json::to_vec(summary)?);
+            Ok(())
+        }
+    }
+
+    fn on_ping(&mut self, ping: &PingResult) -> Result<()> {
+        self.event(&TestEvent::Ping { ping })
+    }
+
+    fn on_sweep(&mut self, report: &SweepReport) -> Result<()> {
+        self.event(&TestEvent::Sweep { sweep: report })
+    }
+
+    fn on_warning(&mut self, warning: &str) -> Result<()> {
+        self.event(&TestEvent::Warning { warning })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Read one HTTP request and answer it with `status`, returning the
+    /// request.
+    async fn respond(listener: &TcpListener, status: &str) -> String {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+            let text = String::from_utf8_lossy(&request);
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let length = head
+                    .lines()
+                    .find_map(|l| {
+                        l.to_ascii_lowercase()
+                            .strip_prefix("content-length: ")?
+                            .parse()
+                            .ok()
+                    })
+                    .unwrap_or(0);
+                if body.len() >= length {
+                    break;
+                }
+            }
+        }
+        let response =
+            format!("HTTP/1.1 {status}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n");
+        stream.write_all(response.as_bytes()).await.unwrap();
+        String::from_utf8(request).unwrap()
+    }
+
+    #[tokio::test]
+    async fn post_summary_with_retry() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/results", listener.local_addr().unwrap());
+        let (mut emitter, delivery) = Webhook::new(url)
+            .header("Authorization", "Bearer token")
+            .spawn()
+            .unwrap();
+        emitter.on_starting(TestKind::Download).unwrap();
+        emitter
+            .on_summary(&Summary::from_measurements("a".into(), None, None, None))
+            .unwrap();
+        drop(emitter);
+
+        let failed = respond(&listener, "503 Service Unavailable").await;
+        let request = respond(&listener, "204 No Content").await;
+        assert_eq!(failed, request);
+        assert!(request.starts_with("POST /results "));
+        assert!(request.contains("authorization: Bearer token\r\n"));
+        let body = request.split_once("\r\n\r\n").unwrap().1;
+        let value: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert!(value.get("Type").is_none());
+        assert!(value.get("ServerFQDN").is_some());
+        delivery.finish().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn invalid_header() {
+        let result = Webhook::new("http://localhost/")
+            .header("bad header", "x")
+            .spawn();
+        assert!(matches!(
+            result,
+            Err(Ndt7Error::Config(ConfigError::InvalidHeader(_)))
+        ));
+    }
+}